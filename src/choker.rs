@@ -0,0 +1,463 @@
+//! Upload side choking.
+//!
+//! Every `interval` the choker decides which interested peers we upload to. Regular slots go to
+//! the peers that give us the most (tit-for-tat), on top of that one optimistic slot is rotated
+//! every `optimistic_interval` so new peers get a chance to prove themselves. Peers that connected
+//! recently are three times as likely to get the optimistic slot, they have nothing to offer yet.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::SocketAddrV4;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Peers connected for less than this count as new for the optimistic unchoke
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+/// Extra weight of new peers in the optimistic unchoke draw
+const NEW_PEER_WEIGHT: usize = 3;
+/// Upload rate the first extra slot of `RateBased` needs, every further slot needs this much more
+const RATE_STEP: f64 = 1024.0;
+
+/// How the regular unchoke slots are handed out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChokerVariant {
+    /// Like `FastestUpload`, but opens more slots than configured as long as every extra peer is
+    /// uploaded to fast enough
+    RateBased,
+    /// While seeding the slots rotate, peers that waited the longest are unchoked first
+    RoundRobin,
+    /// Slots go to the peers with the best download rate, or the best upload rate while seeding
+    FastestUpload,
+}
+
+impl FromStr for ChokerVariant {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rate" | "rate-based" => Ok(Self::RateBased),
+            "round-robin" => Ok(Self::RoundRobin),
+            "fastest" | "fastest-upload" => Ok(Self::FastestUpload),
+            other => Err(anyhow::anyhow!("unknown choker variant {other}")),
+        }
+    }
+}
+
+impl Display for ChokerVariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::RateBased => "rate-based",
+            Self::RoundRobin => "round-robin",
+            Self::FastestUpload => "fastest-upload",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChokerConfig {
+    /// Regular unchoke slots, the optimistic unchoke comes on top
+    pub unchoke_slots: usize,
+    pub variant: ChokerVariant,
+    pub interval: Duration,
+    pub optimistic_interval: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            unchoke_slots: 4,
+            variant: ChokerVariant::FastestUpload,
+            interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What the choker knows about one connection. The byte counters are kept up to date by the peer
+/// task, `choked` is the decision it should apply.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub connected_at: Instant,
+    pub peer_interested: bool,
+    /// Payload bytes received from the peer
+    pub downloaded: u64,
+    /// Payload bytes sent to the peer
+    pub uploaded: u64,
+    /// Whether we choke the peer
+    pub choked: bool,
+    /// Bytes per second over the last round
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// When the peer was last unchoked, or choked if it is choked
+    pub choke_changed_at: Instant,
+    last_downloaded: u64,
+    last_uploaded: u64,
+}
+
+impl Default for PeerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerStats {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            connected_at: now,
+            peer_interested: false,
+            downloaded: 0,
+            uploaded: 0,
+            choked: true,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            choke_changed_at: now,
+            last_downloaded: 0,
+            last_uploaded: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Choker {
+    pub config: ChokerConfig,
+    optimistic: Option<SocketAddrV4>,
+    last_round: Instant,
+    last_optimistic: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(config: ChokerConfig) -> Self {
+        Self {
+            config,
+            optimistic: None,
+            last_round: Instant::now(),
+            last_optimistic: None,
+        }
+    }
+
+    /// Run one choke round over all connections, updates their rates and `choked`
+    pub fn rechoke(&mut self, peers: &mut HashMap<SocketAddrV4, PeerStats>, seeding: bool) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_round).as_secs_f64().max(1.0);
+        self.last_round = now;
+
+        for stats in peers.values_mut() {
+            stats.download_rate = (stats.downloaded - stats.last_downloaded) as f64 / elapsed;
+            stats.upload_rate = (stats.uploaded - stats.last_uploaded) as f64 / elapsed;
+            stats.last_downloaded = stats.downloaded;
+            stats.last_uploaded = stats.uploaded;
+        }
+
+        let mut candidates: Vec<(SocketAddrV4, &PeerStats)> = peers
+            .iter()
+            .filter(|(_, stats)| stats.peer_interested)
+            .map(|(addr, stats)| (*addr, stats))
+            .collect();
+
+        let by_rate = |stats: &PeerStats| {
+            if seeding {
+                stats.upload_rate
+            } else {
+                stats.download_rate
+            }
+        };
+        match self.config.variant {
+            ChokerVariant::RoundRobin if seeding => {
+                // choked peers waiting the longest first, then the peers unchoked the longest ago
+                candidates.sort_by_key(|(_, stats)| (!stats.choked, stats.choke_changed_at))
+            }
+            _ => candidates.sort_by(|(_, a), (_, b)| by_rate(b).total_cmp(&by_rate(a))),
+        }
+
+        let mut slots = self.config.unchoke_slots.min(candidates.len());
+        if self.config.variant == ChokerVariant::RateBased {
+            // every further slot has to be worth more than the last
+            let mut threshold = RATE_STEP;
+            while slots < candidates.len() && candidates[slots].1.upload_rate > threshold {
+                slots += 1;
+                threshold += RATE_STEP;
+            }
+        }
+        let mut unchoke: Vec<SocketAddrV4> =
+            candidates[..slots].iter().map(|(addr, _)| *addr).collect();
+
+        // the optimistic peer keeps its slot until the next rotation, unless it left
+        let rotate = self
+            .last_optimistic
+            .is_none_or(|last| now.duration_since(last) >= self.config.optimistic_interval)
+            || self
+                .optimistic
+                .is_none_or(|addr| !peers.get(&addr).is_some_and(|s| s.peer_interested));
+        if rotate {
+            let rest: Vec<_> = candidates[slots..].iter().collect();
+            let weight = |stats: &PeerStats| {
+                if now.duration_since(stats.connected_at) < NEW_PEER_AGE {
+                    NEW_PEER_WEIGHT
+                } else {
+                    1
+                }
+            };
+            let total: usize = rest.iter().map(|(_, stats)| weight(stats)).sum();
+
+            self.optimistic = None;
+            if total > 0 {
+                let mut pick = rand::random_range(0..total);
+                for (addr, stats) in rest {
+                    if pick < weight(stats) {
+                        info!("Optimistically unchoking {addr}");
+                        self.optimistic = Some(*addr);
+                        break;
+                    }
+                    pick -= weight(stats);
+                }
+            }
+            self.last_optimistic = Some(now);
+        }
+        if let Some(addr) = self.optimistic.filter(|addr| !unchoke.contains(addr)) {
+            unchoke.push(addr);
+        }
+
+        for (addr, stats) in peers.iter_mut() {
+            let choked = !unchoke.contains(addr);
+            if choked != stats.choked {
+                stats.choked = choked;
+                stats.choke_changed_at = now;
+            }
+        }
+    }
+
+    /// Unchoke interested peers, those waiting the longest first, while the regular and
+    /// optimistic slots aren't all taken. Unlike a round this leaves the rates alone, so it can run
+    /// whenever a peer becomes interested.
+    pub fn fill_free_slots(&self, peers: &mut HashMap<SocketAddrV4, PeerStats>) {
+        let unchoked = peers.values().filter(|stats| !stats.choked).count();
+        let free = (self.config.unchoke_slots + 1).saturating_sub(unchoked);
+        let mut waiting: Vec<_> = peers
+            .iter_mut()
+            .filter(|(_, stats)| stats.choked && stats.peer_interested)
+            .collect();
+        waiting.sort_by_key(|(_, stats)| stats.choke_changed_at);
+
+        let now = Instant::now();
+        for (_, stats) in waiting.into_iter().take(free) {
+            stats.choked = false;
+            stats.choke_changed_at = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> SocketAddrV4 {
+        SocketAddrV4::new([10, 0, 0, last].into(), 6881)
+    }
+
+    /// An interested peer that moved `bytes` both ways since the last round. Rounds run back to
+    /// back count as one second, so that is also its rate.
+    fn peer(bytes: u64) -> PeerStats {
+        PeerStats {
+            peer_interested: true,
+            downloaded: bytes,
+            uploaded: bytes,
+            ..PeerStats::new()
+        }
+    }
+
+    fn choker(variant: ChokerVariant, unchoke_slots: usize) -> Choker {
+        Choker::new(ChokerConfig {
+            unchoke_slots,
+            variant,
+            optimistic_interval: Duration::from_secs(3600),
+            ..ChokerConfig::default()
+        })
+    }
+
+    fn unchoked(peers: &HashMap<SocketAddrV4, PeerStats>) -> Vec<u8> {
+        let mut unchoked: Vec<u8> = peers
+            .iter()
+            .filter(|(_, stats)| !stats.choked)
+            .map(|(addr, _)| addr.ip().octets()[3])
+            .collect();
+        unchoked.sort();
+        unchoked
+    }
+
+    #[test]
+    fn unchokes_the_fastest_peers() {
+        for seeding in [true, false] {
+            let mut peers: HashMap<_, _> =
+                (1..=6).map(|i| (addr(i), peer(i as u64 * 1000))).collect();
+            // only the rate that matters counts
+            let other = peers.get_mut(&addr(1)).unwrap();
+            if seeding {
+                other.downloaded = 1_000_000;
+            } else {
+                other.uploaded = 1_000_000;
+            }
+            // not interested, however fast
+            peers.insert(
+                addr(7),
+                PeerStats {
+                    peer_interested: false,
+                    ..peer(1_000_000)
+                },
+            );
+
+            let mut choker = choker(ChokerVariant::FastestUpload, 3);
+            choker.rechoke(&mut peers, seeding);
+            assert_eq!(peers[&addr(6)].upload_rate, 6000.0);
+            let optimistic = choker.optimistic.unwrap().ip().octets()[3];
+            assert!((1..=3).contains(&optimistic));
+            let mut expected = vec![optimistic, 4, 5, 6];
+            expected.sort();
+            assert_eq!(unchoked(&peers), expected);
+        }
+    }
+
+    #[test]
+    fn rotates_the_optimistic_unchoke() {
+        let mut peers: HashMap<_, _> = (1..=4).map(|i| (addr(i), peer(0))).collect();
+        let mut choker = choker(ChokerVariant::FastestUpload, 1);
+        choker.rechoke(&mut peers, true);
+        let first = choker.optimistic.unwrap();
+        assert_eq!(unchoked(&peers).len(), 2);
+
+        // kept until the interval is over
+        for _ in 0..20 {
+            choker.rechoke(&mut peers, true);
+            assert_eq!(choker.optimistic, Some(first));
+        }
+        // or until the peer loses interest
+        peers.get_mut(&first).unwrap().peer_interested = false;
+        choker.rechoke(&mut peers, true);
+        assert_ne!(choker.optimistic, Some(first));
+        assert!(peers[&first].choked);
+
+        peers.get_mut(&first).unwrap().peer_interested = true;
+        choker.config.optimistic_interval = Duration::ZERO;
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..100 {
+            choker.rechoke(&mut peers, true);
+            seen.insert(choker.optimistic.unwrap());
+        }
+        assert!(seen.len() > 1);
+    }
+
+    #[test]
+    fn new_peers_are_favored_for_the_optimistic_unchoke() {
+        let old = PeerStats {
+            connected_at: Instant::now() - NEW_PEER_AGE * 2,
+            ..peer(0)
+        };
+        let mut peers = HashMap::from([(addr(1), old), (addr(2), peer(0))]);
+        let mut choker = choker(ChokerVariant::FastestUpload, 0);
+        choker.config.optimistic_interval = Duration::ZERO;
+
+        let rounds = 4000;
+        let mut new = 0;
+        for _ in 0..rounds {
+            choker.rechoke(&mut peers, true);
+            if choker.optimistic == Some(addr(2)) {
+                new += 1;
+            }
+        }
+        // three to one
+        let share = new as f64 / rounds as f64;
+        assert!((0.68..0.82).contains(&share), "{share}");
+    }
+
+    #[test]
+    fn rate_based_opens_slots_for_fast_peers() {
+        let rates = [
+            (1, 5000),
+            (2, 4000),
+            (3, 1500),
+            (4, 2000),
+            (5, 100),
+            (6, 50),
+        ];
+        let mut peers: HashMap<_, _> = rates
+            .iter()
+            .map(|(i, rate)| (addr(*i), peer(*rate)))
+            .collect();
+        let mut choker = choker(ChokerVariant::RateBased, 1);
+        choker.rechoke(&mut peers, true);
+
+        // one regular slot and an extra one for 4000 > 1024, 2000 isn't over the next 2048
+        let optimistic = choker.optimistic.unwrap().ip().octets()[3];
+        assert!([3, 4, 5, 6].contains(&optimistic));
+        let mut expected = vec![1, 2, optimistic];
+        expected.sort();
+        assert_eq!(unchoked(&peers), expected);
+
+        // the configured slots are always handed out
+        let mut slow: HashMap<_, _> = (1..=3).map(|i| (addr(i), peer(10))).collect();
+        let mut choker = self::choker(ChokerVariant::RateBased, 2);
+        choker.rechoke(&mut slow, true);
+        assert_eq!(unchoked(&slow).len(), 3);
+    }
+
+    #[test]
+    fn round_robin_unchokes_the_longest_waiting() {
+        let now = Instant::now();
+        let mut peers: HashMap<_, _> = (1..=5)
+            .map(|i| {
+                let stats = PeerStats {
+                    choke_changed_at: now - Duration::from_secs(60 - i as u64 * 10),
+                    ..peer(i as u64 * 1000)
+                };
+                (addr(i), stats)
+            })
+            .collect();
+        let mut choker = choker(ChokerVariant::RoundRobin, 2);
+
+        // rates don't matter while seeding
+        choker.rechoke(&mut peers, true);
+        let optimistic = choker.optimistic.unwrap().ip().octets()[3];
+        assert!([3, 4, 5].contains(&optimistic));
+        assert!(!peers[&addr(1)].choked && !peers[&addr(2)].choked);
+
+        // the peers that waited take over, the optimistic one keeps its slot
+        choker.rechoke(&mut peers, true);
+        assert_eq!(unchoked(&peers), vec![3, 4, 5]);
+        choker.rechoke(&mut peers, true);
+        let mut expected = vec![1, 2, optimistic];
+        expected.sort();
+        assert_eq!(unchoked(&peers), expected);
+
+        // downloading it picks by rate like the others
+        for i in [4, 5] {
+            peers.get_mut(&addr(i)).unwrap().downloaded += 1000;
+        }
+        choker.rechoke(&mut peers, false);
+        assert!(!peers[&addr(4)].choked && !peers[&addr(5)].choked);
+    }
+
+    #[test]
+    fn filling_free_slots_keeps_the_rates() {
+        let mut peers: HashMap<_, _> = (1..=2).map(|i| (addr(i), peer(1000))).collect();
+        let mut choker = choker(ChokerVariant::FastestUpload, 2);
+        choker.rechoke(&mut peers, true);
+        assert_eq!(unchoked(&peers), vec![1, 2]);
+
+        let now = Instant::now();
+        for i in 3..=5 {
+            let stats = PeerStats {
+                choke_changed_at: now - Duration::from_secs(i as u64),
+                ..peer(0)
+            };
+            peers.insert(addr(i), stats);
+        }
+        peers.get_mut(&addr(1)).unwrap().uploaded += 5000;
+        choker.fill_free_slots(&mut peers);
+        // the optimistic slot was free, the peer waiting the longest gets it
+        assert_eq!(unchoked(&peers), vec![1, 2, 5]);
+        assert_eq!(peers[&addr(1)].upload_rate, 1000.0);
+
+        choker.fill_free_slots(&mut peers);
+        assert_eq!(unchoked(&peers), vec![1, 2, 5]);
+    }
+}
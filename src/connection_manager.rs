@@ -0,0 +1,462 @@
+//! Deciding which peers to connect to.
+//!
+//! Every peer we hear about becomes a candidate, deduplicated by address. Candidates are
+//! connected in order of their score while the connection limits allow it: a cap on open
+//! connections per torrent, and global caps on open and half-open (still connecting or
+//! handshaking) connections shared by all torrents. After the handshake connections are also
+//! deduplicated by peer ID, the same client can show up under several addresses.
+//!
+//! A candidate that fails is retried with an exponential backoff and evicted after failing too
+//! often in a row. Evicted addresses aren't taken back from discovery for a while.
+//!
+//! Connections peers make to us take a [`ConnectionSlot`] from the same global caps.
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::ip_filter::IpFilter;
+use crate::peer_connection::{Peer, PeerSource};
+use crate::smart_ban::BanList;
+
+const BASE_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+/// Failures in a row after which a candidate is dropped
+const MAX_FAILURES: u32 = 5;
+/// How long an evicted candidate is ignored when discovery finds it again
+const EVICTION: Duration = Duration::from_secs(60 * 60);
+
+/// Connection limits shared by every torrent of the session
+#[derive(Debug)]
+pub struct GlobalConnections {
+    max_connections: AtomicUsize,
+    max_half_open: AtomicUsize,
+    connections: AtomicUsize,
+    half_open: AtomicUsize,
+}
+
+impl Default for GlobalConnections {
+    fn default() -> Self {
+        Self::new(200, 20)
+    }
+}
+
+impl GlobalConnections {
+    pub fn new(max_connections: usize, max_half_open: usize) -> Self {
+        Self {
+            max_connections: AtomicUsize::new(max_connections),
+            max_half_open: AtomicUsize::new(max_half_open),
+            connections: AtomicUsize::new(0),
+            half_open: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserve a slot for a connection a peer made to us, `None` if the limits are reached
+    pub fn try_incoming(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        self.try_reserve().then(|| ConnectionSlot {
+            global: Arc::clone(self),
+            half_open: true,
+        })
+    }
+
+    /// Reserve a connection that starts out half-open
+    fn try_reserve(&self) -> bool {
+        let reserve = |counter: &AtomicUsize, max: usize| {
+            counter
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                    (n < max).then_some(n + 1)
+                })
+                .is_ok()
+        };
+        if !reserve(&self.connections, self.max_connections()) {
+            return false;
+        }
+        if !reserve(&self.half_open, self.max_half_open()) {
+            self.connections.fetch_sub(1, Ordering::AcqRel);
+            return false;
+        }
+        true
+    }
+
+    fn finish_connecting(&self) {
+        self.half_open.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    fn release(&self) {
+        self.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections.load(Ordering::Acquire)
+    }
+
+    pub fn max_half_open(&self) -> usize {
+        self.max_half_open.load(Ordering::Acquire)
+    }
+
+    /// Change the limits, connections over them are kept but no new ones are made until they
+    /// close
+    pub fn set_limits(&self, max_connections: usize, max_half_open: usize) {
+        self.max_connections
+            .store(max_connections, Ordering::Release);
+        self.max_half_open.store(max_half_open, Ordering::Release);
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Acquire)
+    }
+}
+
+/// An incoming connection counted against the [`GlobalConnections`] limits, half-open until
+/// [`ConnectionSlot::connected`] and given back when dropped
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    global: Arc<GlobalConnections>,
+    half_open: bool,
+}
+
+impl ConnectionSlot {
+    /// The handshake finished
+    pub fn connected(&mut self) {
+        if std::mem::take(&mut self.half_open) {
+            self.global.finish_connecting();
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.connected();
+        self.global.release();
+    }
+}
+
+#[derive(Debug)]
+struct Candidate {
+    source: PeerSource,
+    /// Swarm the peer was found in
+    info_hash: [u8; 20],
+    failures: u32,
+    retry_at: Option<Instant>,
+    /// Payload bytes we got from this peer over all connections
+    downloaded: u64,
+}
+
+impl Candidate {
+    /// Peers that gave us data before come first, failures push a peer back
+    fn score(&self) -> f64 {
+        (self.downloaded as f64).ln_1p() - 2.0 * self.failures as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    HalfOpen,
+    Connected,
+}
+
+#[derive(Debug)]
+pub struct ConnectionManager {
+    /// Open connections of this torrent
+    pub max_connections: usize,
+    global: Arc<GlobalConnections>,
+    bans: Arc<BanList>,
+    ip_filter: Arc<IpFilter>,
+    candidates: HashMap<SocketAddrV4, Candidate>,
+    /// Evicted addresses and when they may become candidates again
+    evicted: HashMap<SocketAddrV4, Instant>,
+    connections: HashMap<SocketAddrV4, State>,
+    peer_ids: HashMap<[u8; 20], SocketAddrV4>,
+}
+
+impl ConnectionManager {
+    pub fn new(
+        max_connections: usize,
+        global: Arc<GlobalConnections>,
+        bans: Arc<BanList>,
+        ip_filter: Arc<IpFilter>,
+    ) -> Self {
+        Self {
+            max_connections,
+            global,
+            bans,
+            ip_filter,
+            candidates: HashMap::new(),
+            evicted: HashMap::new(),
+            connections: HashMap::new(),
+            peer_ids: HashMap::new(),
+        }
+    }
+
+    /// Add peers from a discovery round, known addresses keep their history and evicted ones
+    /// are skipped until their eviction expires
+    pub fn add_candidates(&mut self, peers: impl IntoIterator<Item = Peer>) {
+        let now = Instant::now();
+        self.evicted.retain(|_, until| *until > now);
+
+        let mut added = 0;
+        let mut blocked = 0;
+        for peer in peers {
+            if self.bans.is_banned(peer.sock_ip.ip()) {
+                continue;
+            }
+            if self.ip_filter.is_blocked(peer.sock_ip.ip()) {
+                blocked += 1;
+                continue;
+            }
+            if self.candidates.contains_key(&peer.sock_ip)
+                || self.evicted.contains_key(&peer.sock_ip)
+            {
+                continue;
+            }
+            added += 1;
+            self.candidates.insert(
+                peer.sock_ip,
+                Candidate {
+                    source: peer.source,
+                    info_hash: peer.info_hash,
+                    failures: 0,
+                    retry_at: None,
+                    downloaded: 0,
+                },
+            );
+        }
+        info!(
+            "{added} new peer candidates, {} known, {blocked} blocked by the IP filter",
+            self.candidates.len()
+        );
+    }
+
+    /// Pick the best candidates that may be connected right now. They count as half-open until
+    /// reported with [`ConnectionManager::connected`] or [`ConnectionManager::failed`].
+    pub fn next_connects(&mut self) -> Vec<Peer> {
+        let now = Instant::now();
+        let mut eligible: Vec<(SocketAddrV4, f64)> = self
+            .candidates
+            .iter()
+            .filter(|(addr, c)| {
+                !self.connections.contains_key(addr)
+                    && c.retry_at.is_none_or(|at| at <= now)
+                    && !self.bans.is_banned(addr.ip())
+                    && !self.ip_filter.is_blocked(addr.ip())
+            })
+            .map(|(addr, c)| (*addr, c.score()))
+            .collect();
+        eligible.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let mut peers = Vec::new();
+        for (addr, _) in eligible {
+            if self.connections.len() >= self.max_connections || !self.global.try_reserve() {
+                break;
+            }
+            let candidate = &self.candidates[&addr];
+            let mut peer = Peer::new(addr, candidate.source);
+            peer.info_hash = candidate.info_hash;
+            self.connections.insert(addr, State::HalfOpen);
+            peers.push(peer);
+        }
+        peers
+    }
+
+    /// The handshake with `addr` finished. Returns `false` if we are already connected to the
+    /// same peer ID, the caller drops the connection and the address is forgotten.
+    pub fn connected(&mut self, addr: SocketAddrV4, peer_id: [u8; 20]) -> bool {
+        if self.connections.insert(addr, State::Connected) == Some(State::HalfOpen) {
+            self.global.finish_connecting();
+        }
+
+        if let Some(other) = self.peer_ids.get(&peer_id) {
+            if *other != addr && self.connections.contains_key(other) {
+                info!("{addr} is the same peer as {other}, dropping it");
+                self.close(addr);
+                self.candidates.remove(&addr);
+                return false;
+            }
+        }
+        self.peer_ids.insert(peer_id, addr);
+
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.failures = 0;
+            candidate.retry_at = None;
+        }
+        true
+    }
+
+    /// The connection to `addr` failed or the peer misbehaved, back off or evict it
+    pub fn failed(&mut self, addr: SocketAddrV4) {
+        self.close(addr);
+
+        let Some(candidate) = self.candidates.get_mut(&addr) else {
+            return;
+        };
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES {
+            info!("Evicting peer {addr} after {} failures", candidate.failures);
+            self.candidates.remove(&addr);
+            self.evicted.insert(addr, Instant::now() + EVICTION);
+            return;
+        }
+
+        let backoff = (BASE_BACKOFF * 2u32.pow(candidate.failures - 1)).min(MAX_BACKOFF);
+        candidate.retry_at = Some(Instant::now() + backoff);
+    }
+
+    /// The connection to `addr` ended without an error, `downloaded` is what it gave us
+    pub fn disconnected(&mut self, addr: SocketAddrV4, downloaded: u64) {
+        self.close(addr);
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.downloaded += downloaded;
+        }
+    }
+
+    /// Count of connections including half-open ones
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    fn close(&mut self, addr: SocketAddrV4) {
+        match self.connections.remove(&addr) {
+            Some(State::HalfOpen) => {
+                self.global.finish_connecting();
+                self.global.release();
+            }
+            Some(State::Connected) => self.global.release(),
+            None => {}
+        }
+        self.peer_ids.retain(|_, a| *a != addr);
+    }
+}
+
+/// A download that is stopped, e.g. paused, gives its slots back to the other torrents
+impl Drop for ConnectionManager {
+    fn drop(&mut self) {
+        for addr in self.connections.keys().copied().collect::<Vec<_>>() {
+            self.close(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> SocketAddrV4 {
+        SocketAddrV4::new([10, 0, 0, last].into(), 6881)
+    }
+
+    fn peers(addrs: impl IntoIterator<Item = u8>) -> Vec<Peer> {
+        addrs
+            .into_iter()
+            .map(|last| Peer::new(addr(last), PeerSource::Tracker))
+            .collect()
+    }
+
+    fn manager(max_connections: usize, global: &Arc<GlobalConnections>) -> ConnectionManager {
+        ConnectionManager::new(
+            max_connections,
+            Arc::clone(global),
+            Arc::default(),
+            Arc::default(),
+        )
+    }
+
+    #[test]
+    fn dedups_addresses_and_peer_ids() {
+        let global = Arc::new(GlobalConnections::default());
+        let mut manager = manager(10, &global);
+        manager.add_candidates(peers([1, 2, 1]));
+        manager.add_candidates(peers([2]));
+        assert_eq!(manager.candidates.len(), 2);
+
+        assert_eq!(manager.next_connects().len(), 2);
+        assert!(manager.connected(addr(1), [7; 20]));
+        // the same client under another address
+        assert!(!manager.connected(addr(2), [7; 20]));
+        assert_eq!(manager.connection_count(), 1);
+        assert_eq!(global.connections(), 1);
+        assert!(!manager.candidates.contains_key(&addr(2)));
+    }
+
+    #[test]
+    fn backs_off_then_evicts() {
+        let global = Arc::new(GlobalConnections::default());
+        let mut manager = manager(10, &global);
+        manager.add_candidates(peers([1]));
+        assert_eq!(manager.next_connects().len(), 1);
+        manager.failed(addr(1));
+        assert_eq!(global.connections(), 0);
+
+        let retry_at = manager.candidates[&addr(1)].retry_at.unwrap();
+        assert!(retry_at > Instant::now() + BASE_BACKOFF / 2);
+        assert!(manager.next_connects().is_empty());
+        manager.failed(addr(1));
+        let later = manager.candidates[&addr(1)].retry_at.unwrap();
+        assert!(later > retry_at + BASE_BACKOFF / 2);
+
+        for _ in 2..MAX_FAILURES {
+            manager.failed(addr(1));
+        }
+        assert!(manager.candidates.is_empty());
+        // discovery finding it again doesn't undo the eviction
+        manager.add_candidates(peers([1]));
+        assert!(manager.candidates.is_empty());
+
+        manager.evicted.insert(addr(1), Instant::now());
+        manager.add_candidates(peers([1]));
+        assert_eq!(manager.candidates[&addr(1)].failures, 0);
+    }
+
+    #[test]
+    fn respects_the_caps() {
+        let global = Arc::new(GlobalConnections::new(3, 2));
+        let mut first = manager(10, &global);
+        first.add_candidates(peers(1..=5));
+        // half-open cap
+        let connects = first.next_connects();
+        assert_eq!(connects.len(), 2);
+        assert!(first.next_connects().is_empty());
+        for (i, peer) in connects.iter().enumerate() {
+            first.connected(peer.sock_ip, [i as u8; 20]);
+        }
+
+        // the global cap is shared with other torrents
+        let mut second = manager(10, &global);
+        second.add_candidates(peers(6..=9));
+        assert_eq!(second.next_connects().len(), 1);
+        assert_eq!(global.connections(), 3);
+        assert!(first.next_connects().is_empty());
+
+        // stopped torrents give their slots back
+        drop(first);
+        assert_eq!(global.connections(), 1);
+
+        // the per-torrent cap
+        let mut third = manager(1, &global);
+        third.add_candidates(peers(10..=12));
+        assert_eq!(third.next_connects().len(), 1);
+        assert!(third.next_connects().is_empty());
+    }
+
+    #[test]
+    fn incoming_connections_take_slots() {
+        let global = Arc::new(GlobalConnections::new(2, 1));
+        let mut first = global.try_incoming().unwrap();
+        assert!(global.try_incoming().is_none());
+        first.connected();
+        let second = global.try_incoming().unwrap();
+        assert!(global.try_incoming().is_none());
+        assert_eq!(global.connections(), 2);
+
+        // outgoing connections see the same counts
+        let mut manager = manager(10, &global);
+        manager.add_candidates(peers([1]));
+        assert!(manager.next_connects().is_empty());
+
+        drop(second);
+        drop(first);
+        assert_eq!(global.connections(), 0);
+        assert_eq!(manager.next_connects().len(), 1);
+    }
+}
@@ -0,0 +1,515 @@
+//! Create metainfo (.torrent) files from a file or a directory on disk.
+//!
+//! Files of a directory are sorted by their path components so the same directory always produces
+//! the same torrent. Symlinks are skipped unless `follow_symlinks` is set, in which case they are
+//! treated like the file or directory they point to. Content of only empty files is refused, it
+//! has no pieces to hash.
+use anyhow::anyhow;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::parser::{AnnounceUrl, FileInfo, FileTree, Info, Torrent};
+
+/// Smallest piece length allowed by the spec
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+/// Anything above this makes a single bad block cost way too much to redownload
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+/// Amount of pieces we aim for when picking the piece length ourselves
+const TARGET_PIECE_COUNT: usize = 1500;
+
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Tracker tiers, the first URL of the first tier becomes `announce`
+    pub trackers: Vec<Vec<String>>,
+    /// Must be a power of two of at least 16KiB, picked from the content size if `None`
+    pub piece_length: Option<usize>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Defaults to the current time if `None`
+    pub creation_date: Option<i64>,
+    pub private: bool,
+    pub source: Option<String>,
+    /// BEP 19 `url-list` entries
+    pub web_seeds: Vec<String>,
+    pub follow_symlinks: bool,
+    /// Number of hashing threads, defaults to the available parallelism
+    pub threads: Option<usize>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            trackers: Vec::new(),
+            piece_length: None,
+            comment: None,
+            created_by: Some(format!("rBittorrent/{}", env!("CARGO_PKG_VERSION"))),
+            creation_date: None,
+            private: false,
+            source: None,
+            web_seeds: Vec::new(),
+            follow_symlinks: false,
+            threads: None,
+        }
+    }
+}
+
+/// A file that goes into the torrent
+#[derive(Debug)]
+struct SourceFile {
+    /// Path on disk
+    full_path: PathBuf,
+    /// Path components relative to the torrent root
+    path: Vec<String>,
+    length: usize,
+}
+
+/// Pick a power of two piece length that results in roughly `TARGET_PIECE_COUNT` pieces
+pub fn choose_piece_length(total_length: usize) -> usize {
+    (total_length / TARGET_PIECE_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Walk `path` and build a `Torrent` for it
+pub fn create_torrent<P: AsRef<Path>>(
+    path: P,
+    options: &CreateOptions,
+) -> Result<Torrent, anyhow::Error> {
+    let root = path.as_ref();
+    let name = root
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Cannot derive a UTF-8 torrent name from {}", root.display()))?
+        .to_string();
+
+    let mut trackers = options
+        .trackers
+        .iter()
+        .map(|tier| {
+            tier.iter()
+                .map(|url| AnnounceUrl::parse(url).map_err(|e| anyhow!(e)))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    trackers.retain(|tier| !tier.is_empty());
+
+    let announce = trackers
+        .first()
+        .and_then(|tier| tier.first())
+        .cloned()
+        .ok_or_else(|| anyhow!("At least one tracker URL is required"))?;
+
+    let metadata = if options.follow_symlinks {
+        std::fs::metadata(root)?
+    } else {
+        std::fs::symlink_metadata(root)?
+    };
+
+    let (files, file_tree) = if metadata.is_file() {
+        let length = metadata.len() as usize;
+        let files = vec![SourceFile {
+            full_path: root.to_path_buf(),
+            path: vec![name.clone()],
+            length,
+        }];
+        (files, FileTree::SingleFile { length, attr: None })
+    } else if metadata.is_dir() {
+        let mut files = Vec::new();
+        let mut visited = HashSet::new();
+        collect_files(root, &mut Vec::new(), options, &mut visited, &mut files)?;
+        if files.is_empty() {
+            return Err(anyhow!("{} contains no files", root.display()));
+        }
+
+        // deterministic order, independent of what the filesystem hands us
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let file_infos = files
+            .iter()
+            .map(|f| FileInfo {
+                length: f.length,
+                path: f.path.clone(),
+                attr: None,
+                symlink_path: None,
+                sha1: None,
+            })
+            .collect();
+        (files, FileTree::MultiFile { files: file_infos })
+    } else {
+        return Err(anyhow!(
+            "{} is neither a file nor a directory",
+            root.display()
+        ));
+    };
+
+    let total_length: usize = files.iter().map(|f| f.length).sum();
+    // without a single piece `pieces` would be empty and left out of the metainfo
+    if total_length == 0 {
+        return Err(anyhow!("{} has no data, only empty files", root.display()));
+    }
+    let piece_length = match options.piece_length {
+        Some(len) if len < MIN_PIECE_LENGTH || !len.is_power_of_two() => {
+            return Err(anyhow!(
+                "Piece length must be a power of two of at least {MIN_PIECE_LENGTH} bytes"
+            ))
+        }
+        Some(len) => len,
+        None => choose_piece_length(total_length),
+    };
+
+    let threads = options
+        .threads
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1)
+        .max(1);
+
+    info!(
+        "Hashing {} files ({} bytes) with piece length {} on {} threads",
+        files.len(),
+        total_length,
+        piece_length,
+        threads
+    );
+    let pieces = hash_pieces(&files, total_length, piece_length, threads)?;
+
+    let creation_date = match options.creation_date {
+        Some(date) => date,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+    };
+
+    Ok(Torrent {
+        announce,
+        announce_list: (trackers.iter().map(Vec::len).sum::<usize>() > 1).then_some(trackers),
+        info: Info {
+            name,
+            piece_length,
+            pieces,
+            file_tree,
+            private: options.private.then_some(1),
+            source: options.source.clone(),
+            meta_version: None,
+            v2_file_tree: None,
+        },
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+        creation_date: Some(creation_date),
+        encoding: Some("UTF-8".to_string()),
+        url_list: options.web_seeds.clone(),
+        http_seeds: Vec::new(),
+        piece_layers: HashMap::new(),
+    })
+}
+
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    options: &CreateOptions,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<SourceFile>,
+) -> Result<(), anyhow::Error> {
+    // following symlinked directories can loop forever, so remember where we have been
+    if !visited.insert(dir.canonicalize()?) {
+        warn!("Skipping {}, directory was already visited", dir.display());
+        return Ok(());
+    }
+
+    // sorted so it is deterministic which path wins if two symlinks lead to the same directory
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let full_path = entry.path();
+        let file_name = entry
+            .file_name()
+            .into_string()
+            .map_err(|n| anyhow!("File name {:?} is not valid UTF-8", n))?;
+
+        let mut metadata = entry.metadata()?;
+        if metadata.is_symlink() {
+            if !options.follow_symlinks {
+                warn!("Skipping symlink {}", full_path.display());
+                continue;
+            }
+            metadata = match std::fs::metadata(&full_path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!("Skipping broken symlink {}: {e}", full_path.display());
+                    continue;
+                }
+            };
+        }
+
+        prefix.push(file_name);
+        if metadata.is_dir() {
+            collect_files(&full_path, prefix, options, visited, files)?;
+        } else if metadata.is_file() {
+            files.push(SourceFile {
+                full_path,
+                path: prefix.clone(),
+                length: metadata.len() as usize,
+            });
+        } else {
+            warn!("Skipping special file {}", full_path.display());
+        }
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+/// Hash every piece, the piece range is split evenly between `threads` workers which each read
+/// their own part of the files
+fn hash_pieces(
+    files: &[SourceFile],
+    total_length: usize,
+    piece_length: usize,
+    threads: usize,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let piece_count = total_length.div_ceil(piece_length);
+    let per_thread = piece_count.div_ceil(threads).max(1);
+
+    let results: Vec<Result<Vec<u8>, anyhow::Error>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..piece_count)
+            .step_by(per_thread)
+            .map(|first| {
+                let last = (first + per_thread).min(piece_count);
+                scope.spawn(move || {
+                    let mut hashes = Vec::with_capacity((last - first) * 20);
+                    let mut buf = vec![0u8; piece_length];
+                    for index in first..last {
+                        let offset = index * piece_length;
+                        let len = piece_length.min(total_length - offset);
+                        read_range(files, offset, &mut buf[..len])?;
+                        hashes.extend_from_slice(&Sha1::digest(&buf[..len]));
+                    }
+                    Ok(hashes)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow!("Hashing thread panicked")))
+            })
+            .collect()
+    });
+
+    let mut pieces = Vec::with_capacity(piece_count * 20);
+    for result in results {
+        pieces.extend_from_slice(&result?);
+    }
+    Ok(pieces)
+}
+
+/// Fill `buf` with the bytes starting at `offset` of the concatenated files
+fn read_range(
+    files: &[SourceFile],
+    mut offset: usize,
+    buf: &mut [u8],
+) -> Result<(), anyhow::Error> {
+    let mut written = 0;
+    let mut file_start = 0;
+
+    for file in files {
+        let file_end = file_start + file.length;
+        if written < buf.len() && offset < file_end {
+            let in_file = offset - file_start;
+            let len = (file.length - in_file).min(buf.len() - written);
+
+            let mut handle = File::open(&file.full_path)?;
+            handle.seek(SeekFrom::Start(in_file as u64))?;
+            handle
+                .read_exact(&mut buf[written..written + len])
+                .map_err(|e| anyhow!("Failed reading {}: {e}", file.full_path.display()))?;
+
+            written += len;
+            offset += len;
+        }
+        file_start = file_end;
+    }
+
+    if written != buf.len() {
+        return Err(anyhow!("Files changed size while hashing"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, calculate_info_hash_bytes};
+
+    fn options() -> CreateOptions {
+        CreateOptions {
+            trackers: vec![vec!["http://127.0.0.1:7070/announce".to_string()]],
+            creation_date: Some(0),
+            ..CreateOptions::default()
+        }
+    }
+
+    /// Write the torrent and parse it again, like a client that gets the file would
+    fn round_trip(torrent: &Torrent) -> Torrent {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.torrent");
+        parser::write_torrent_file(torrent, &path).unwrap();
+        parser::parse_torrent_file(&path).unwrap()
+    }
+
+    fn paths(info: &Info) -> Vec<String> {
+        info.files().iter().map(|f| f.path.join("/")).collect()
+    }
+
+    /// `content` with `c`, `a`, `b/2` and `b/1` written in that order
+    fn content() -> (tempfile::TempDir, PathBuf, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        std::fs::create_dir_all(root.join("b")).unwrap();
+        let files = [("c", 3), ("a", 20_000), ("b/2", 30_000), ("b/1", 1)];
+        for (name, length) in files {
+            let data: Vec<u8> = (0..length)
+                .map(|i| (i % 13) as u8 + name.len() as u8)
+                .collect();
+            std::fs::write(root.join(name), data).unwrap();
+        }
+        let mut data = Vec::new();
+        for name in ["a", "b/1", "b/2", "c"] {
+            data.extend(std::fs::read(root.join(name)).unwrap());
+        }
+        (dir, root, data)
+    }
+
+    #[test]
+    fn round_trips_a_directory() {
+        let (_dir, root, data) = content();
+        let options = CreateOptions {
+            piece_length: Some(16 * 1024),
+            private: true,
+            source: Some("tracker.example".to_string()),
+            comment: Some("hello".to_string()),
+            ..options()
+        };
+        let torrent = create_torrent(&root, &options).unwrap();
+        let parsed = round_trip(&torrent);
+        let info = &parsed.info;
+
+        assert_eq!(info.name, "content");
+        assert_eq!(paths(info), ["a", "b/1", "b/2", "c"]);
+        assert_eq!(info.piece_length, 16 * 1024);
+        assert_eq!(info.total_length(), data.len());
+        let pieces: Vec<u8> = data
+            .chunks(16 * 1024)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        assert_eq!(info.pieces, pieces);
+        assert!(info.is_private());
+        assert_eq!(info.source.as_deref(), Some("tracker.example"));
+        assert_eq!(parsed.comment.as_deref(), Some("hello"));
+        assert_eq!(
+            parsed.announce.to_string(),
+            "http://127.0.0.1:7070/announce"
+        );
+        assert_eq!(
+            calculate_info_hash_bytes(info).unwrap(),
+            calculate_info_hash_bytes(&torrent.info).unwrap()
+        );
+
+        // the same content gives the same torrent, whatever the thread count
+        let again = create_torrent(
+            &root,
+            &CreateOptions {
+                threads: Some(3),
+                ..options
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            serde_bencode::to_bytes(&again).unwrap(),
+            serde_bencode::to_bytes(&torrent).unwrap()
+        );
+    }
+
+    #[test]
+    fn picks_the_piece_length() {
+        let (_dir, root, data) = content();
+        let torrent = round_trip(&create_torrent(&root, &options()).unwrap());
+        assert_eq!(torrent.info.piece_length, MIN_PIECE_LENGTH);
+        assert_eq!(
+            torrent.info.piece_count(),
+            data.len().div_ceil(MIN_PIECE_LENGTH)
+        );
+
+        assert_eq!(choose_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(choose_piece_length(1500 * 64 * 1024), 64 * 1024);
+        assert_eq!(choose_piece_length(1501 * 64 * 1024), 128 * 1024);
+        assert_eq!(choose_piece_length(usize::MAX / 2), MAX_PIECE_LENGTH);
+
+        for piece_length in [0, 1000, 8 * 1024, 48 * 1024] {
+            let options = CreateOptions {
+                piece_length: Some(piece_length),
+                ..options()
+            };
+            assert!(create_torrent(&root, &options).is_err(), "{piece_length}");
+        }
+    }
+
+    #[test]
+    fn single_files_and_refused_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("single.bin");
+        std::fs::write(&file, [1u8; 100]).unwrap();
+        let torrent = round_trip(&create_torrent(&file, &options()).unwrap());
+        assert!(torrent.info.is_single_file());
+        assert_eq!(torrent.info.total_length(), 100);
+        assert_eq!(torrent.info.pieces, Sha1::digest([1u8; 100]).to_vec());
+
+        let no_trackers = CreateOptions {
+            trackers: Vec::new(),
+            ..options()
+        };
+        assert!(create_torrent(&file, &no_trackers).is_err());
+
+        // nothing to hash: no `pieces` key could be written
+        let empty = dir.path().join("empty");
+        std::fs::create_dir_all(empty.join("sub")).unwrap();
+        assert!(create_torrent(&empty, &options()).is_err());
+        std::fs::write(empty.join("sub/zero"), b"").unwrap();
+        std::fs::write(empty.join("zero"), b"").unwrap();
+        assert!(create_torrent(&empty, &options()).is_err());
+        assert!(create_torrent(empty.join("zero"), &options()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_symlinks_unless_followed() {
+        let (dir, root, _) = content();
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(outside.join("linked"), [9u8; 10]).unwrap();
+        std::os::unix::fs::symlink(root.join("a"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("dir")).unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("broken")).unwrap();
+        // a loop back to the root
+        std::os::unix::fs::symlink(&root, root.join("b/loop")).unwrap();
+
+        let skipped = round_trip(&create_torrent(&root, &options()).unwrap());
+        assert_eq!(paths(&skipped.info), ["a", "b/1", "b/2", "c"]);
+
+        let options = CreateOptions {
+            follow_symlinks: true,
+            ..options()
+        };
+        let followed = round_trip(&create_torrent(&root, &options).unwrap());
+        assert_eq!(
+            paths(&followed.info),
+            ["a", "b/1", "b/2", "c", "dir/linked", "link"]
+        );
+        assert_eq!(followed.info.files()[5].length, 20_000);
+    }
+}
@@ -1,43 +1,43 @@
 use serde_bencode::de;
+use serde_bencode::value::Value;
 use std::net::SocketAddr;
 use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::task::JoinSet;
 use tracing::{error, info};
 use url::form_urlencoded;
 
-use crate::parser::{calculate_info_hash_bytes, AnnounceUrl, Torrent};
-use crate::peer_connection::Peer;
+use crate::events::{Event, EventSender};
+use crate::parser::{swarm_info_hashes, AnnounceUrl, Torrent};
+use crate::peer_connection::{Peer, PeerSource};
 use crate::tracker_response::TrackerResponse;
 use crate::udp_tracker::{
-    Action, AnnounceRequest, AnnounceResponse, ConnectRequest, ConnectResponse, TrackerError,
+    Action, AnnounceRequest, AnnounceResponse, ConnectRequest, ConnectResponse, ScrapeRequest,
+    ScrapeResponse, ScrapeStats, TrackerError,
 };
 
 #[derive(Clone)]
 pub struct PeerDiscoverer {
     announce_urls: Vec<AnnounceUrl>,
-    infohash: [u8; 20],
-    peer_id: Vec<u8>,
+    /// One hash per swarm, hybrid torrents announce to both the v1 and the v2 swarm
+    infohashes: Vec<[u8; 20]>,
+    peer_id: [u8; 20],
     port: u16,
-    uploaded: usize,
+    pub uploaded: usize,
     pub downloaded: usize,
     pub left: usize,
     compact: usize,
+    pub events: EventSender,
+    /// How long a tracker gets to answer
+    pub timeout: Duration,
 }
 
 impl PeerDiscoverer {
-    pub async fn new(peer_id: &str, port: u16, torrent: Torrent) -> Self {
-        let mut peer_id_bytes = peer_id.as_bytes();
-
-        if peer_id_bytes.len() > 20 {
-            error!("Peer ID was too long, using default: 'defaultBittorrentclient'");
-            peer_id_bytes = b"defaultBittorrentclient";
-        }
-
-        let mut padded_peer_id = [0u8; 20];
-        let len = peer_id_bytes.len().min(20);
-        padded_peer_id[..len].copy_from_slice(&peer_id_bytes[..len]);
-
+    /// `peer_id` is the ID of the session, see [`crate::peer_id::generate`]
+    pub async fn new(
+        peer_id: [u8; 20],
+        port: u16,
+        torrent: Torrent,
+    ) -> Result<Self, anyhow::Error> {
         let announce_urls: Vec<AnnounceUrl> = torrent
             .announce_list
             .map(|list| list.into_iter().flatten().collect::<Vec<_>>())
@@ -48,117 +48,77 @@ impl PeerDiscoverer {
             .collect();
 
         if announce_urls.is_empty() {
-            info!("No HTTP or UDP announce URL found in the list");
-            todo!("Any url type but HTTP or UDP hasnt been implemented yet");
+            // web seeds have every piece, there just won't be any peers
+            if torrent.url_list.is_empty() && torrent.http_seeds.is_empty() {
+                anyhow::bail!("{} has no HTTP or UDP tracker", torrent.info.name);
+            }
+            info!("No HTTP or UDP announce URL found in the list, only using web seeds");
         }
 
-        Self {
+        Ok(Self {
             announce_urls,
-            infohash: calculate_info_hash_bytes(&torrent.info).unwrap(),
-            peer_id: padded_peer_id.to_vec(),
+            infohashes: swarm_info_hashes(&torrent.info)?,
+            peer_id,
             port,
             uploaded: 0,
             downloaded: 0,
-            left: match torrent.info.file_tree {
-                crate::parser::FileTree::SingleFile { length } => length,
-                crate::parser::FileTree::MultiFile { files } => {
-                    files.iter().map(|file| file.length).sum()
-                }
-            },
+            left: torrent.info.total_length(),
             compact: 1,
-        }
+            events: EventSender::default(),
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// The hash of the first swarm, what identifies the torrent
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.infohashes[0]
     }
 
     pub async fn announce_http(
         &self,
         announce_url: &str,
+        infohash: &[u8; 20],
     ) -> Result<TrackerResponse, anyhow::Error> {
         let url = format!(
             "{}/?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}",
             announce_url,
-            form_urlencoded::byte_serialize(&self.infohash).collect::<String>(),
-            String::from_utf8_lossy(&self.peer_id),
+            form_urlencoded::byte_serialize(infohash).collect::<String>(),
+            form_urlencoded::byte_serialize(&self.peer_id).collect::<String>(),
             self.port,
             self.uploaded,
             self.downloaded,
             self.left,
             self.compact
         );
-        let resp = reqwest::get(url).await?;
+        let resp = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .build()?
+            .get(url)
+            .send()
+            .await?;
         let body = resp.bytes().await?;
 
         Ok(de::from_bytes(&body)?)
     }
 
-    pub async fn announce_udp(&self, announce_url: &str) -> Result<TrackerResponse, anyhow::Error> {
-        let parsed_url = url::Url::parse(announce_url)?;
-        let host = parsed_url
-            .host_str()
-            .ok_or_else(|| anyhow::anyhow!("UDP announce URL has no host: {}", announce_url))?;
-        let port = parsed_url
-            .port()
-            .ok_or_else(|| anyhow::anyhow!("UDP announce URL has no port: {}", announce_url))?;
-
-        // host may be a domain name, so this needs an actual DNS lookup rather than
-        // a naive SocketAddr::parse on the raw string
-        let remote_addr: SocketAddr = tokio::net::lookup_host((host, port))
-            .await?
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("Could not resolve host: {}", host))?;
-
-        let local_addr: SocketAddr = if remote_addr.is_ipv4() {
-            "0.0.0.0:0"
-        } else {
-            unimplemented!();
-        }
-        .parse()?;
-
-        let socket = UdpSocket::bind(local_addr).await?;
-        socket.connect(&remote_addr).await?;
-
-        // 1. Send Connect Request
-        let my_transaction_id = rand::random::<i32>();
-        let connect_request = ConnectRequest::new(my_transaction_id);
-        socket.send(&connect_request.serialize()).await?;
-        info!("Sent Connect Request");
-
-        // 2. Receive Connect Response
-        let mut data = vec![0u8; 2048]; // 2KB buffer is plenty
-
-        let len = match tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut data)).await {
-            Ok(recv_result) => recv_result?, // socket.recv succeeded within 5 seconds
-            Err(_) => anyhow::bail!("Timed out waiting for connect response from tracker"),
-        };
-
-        let response_bytes = &data[..len];
-
-        info!("Got Connect Response");
-
-        // Check for tracker errors on connect
-        if len >= 4 {
-            let action_id = i32::from_be_bytes(response_bytes[0..4].try_into()?);
-            if action_id == Action::Error as i32 {
-                let err = TrackerError::parse(response_bytes)?;
-                anyhow::bail!("Tracker returned error on connect: {}", err.error_string);
-            }
-        }
-
-        // Parse standard connect response
-        let connect_response = ConnectResponse::parse(response_bytes)?;
-        info!(
-            "Successfully connected! Connection ID: {}",
-            connect_response.connection_id
-        );
+    pub async fn announce_udp(
+        &self,
+        announce_url: &str,
+        infohash: &[u8; 20],
+    ) -> Result<TrackerResponse, anyhow::Error> {
+        let (socket, connection_id) = connect_udp(announce_url, self.timeout).await?;
 
         // 3. Send Announce Request
         let announce_transaction_id = rand::random::<i32>();
-        let mut peer_id_bytes = [0u8; 20];
-        peer_id_bytes.copy_from_slice(&self.peer_id);
         let announce_request = AnnounceRequest::new(
-            connect_response.connection_id,
+            connection_id,
             announce_transaction_id,
-            self.infohash,
-            peer_id_bytes,
+            *infohash,
+            self.peer_id,
             self.downloaded as i64,
             self.left as i64,
             self.uploaded as i64,
@@ -169,9 +129,10 @@ impl PeerDiscoverer {
         info!("Sent Announce");
 
         // 4. Receive Announce Response
-        let len = match tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut data)).await {
-            Ok(recv_result) => recv_result?, // socket.recv succeeded within 5 seconds
-            Err(_) => anyhow::bail!("Timed out waiting for connect response from tracker"),
+        let mut data = vec![0u8; 2048];
+        let len = match tokio::time::timeout(self.timeout, socket.recv(&mut data)).await {
+            Ok(recv_result) => recv_result?, // socket.recv succeeded in time
+            Err(_) => anyhow::bail!("Timed out waiting for announce response from tracker"),
         };
 
         let announce_resp_bytes = &data[..len];
@@ -193,65 +154,70 @@ impl PeerDiscoverer {
             peers: announce_response
                 .peers
                 .iter()
-                .map(|p| Peer {
-                    sock_ip: *p,
-                    available: Vec::new(),
-                    conn: None,
-                    peer_choking: true,
-                })
+                .map(|p| Peer::new(*p, PeerSource::Tracker))
                 .collect(),
         })
     }
 
     /// function to disvoer your peers, after a new peer is discovered we get its handshake
-    pub async fn discover(&mut self, torrent: &Torrent) -> Result<TrackerResponse, anyhow::Error> {
+    pub async fn discover(&mut self) -> Result<TrackerResponse, anyhow::Error> {
         let mut last_error = anyhow::anyhow!("No announce URLs available to contact");
 
         for announce_url in &self.announce_urls {
-            info!("Attempting tracker announce with: {announce_url:?}");
-
-            let response_result: Result<TrackerResponse, anyhow::Error> = match announce_url {
-                AnnounceUrl::Http(url) => self.announce_http(url).await,
-                AnnounceUrl::Udp(url) => self.announce_udp(url).await,
-                _ => {
-                    error!("Unsupported announce URL scheme, skipping...");
-                    continue;
-                }
-            };
-
-            match response_result {
-                Ok(mut response) => {
-                    info!("Successfully received peers from tracker!");
-
-                    let info = std::sync::Arc::new(torrent.info.clone());
-                    let mut task_handle = JoinSet::new();
-
-                    for mut peer in response.peers.drain(..) {
-                        let info = info.clone();
-                        task_handle.spawn(async move {
-                            let result = peer.perform_handshake(&info).await;
-                            (peer, result)
-                        });
+            let mut combined: Option<TrackerResponse> = None;
+
+            for infohash in &self.infohashes {
+                info!(
+                    "Attempting tracker announce with: {announce_url:?} for swarm {}",
+                    hex::encode(infohash)
+                );
+
+                let response_result: Result<TrackerResponse, anyhow::Error> = match announce_url {
+                    AnnounceUrl::Http(url) => self.announce_http(url, infohash).await,
+                    AnnounceUrl::Udp(url) => self.announce_udp(url, infohash).await,
+                    _ => {
+                        error!("Unsupported announce URL scheme, skipping...");
+                        continue;
                     }
+                };
+
+                match response_result {
+                    Ok(mut response) => {
+                        info!("Successfully received peers from tracker!");
+                        self.events.send(Event::TrackerReply {
+                            info_hash: self.infohashes[0],
+                            url: announce_url.to_string(),
+                            peers: response.peers.len(),
+                        });
 
-                    let mut peers = Vec::new();
-                    for (peer, result) in task_handle.join_all().await {
-                        if let Err(e) = &result {
-                            error!("Failed handshake with peer {:?}: {e}", peer.sock_ip);
-                        } else {
-                            // only keep peers where the handshake was successfull
-                            peers.push(peer);
+                        for peer in &mut response.peers {
+                            peer.info_hash = *infohash;
+                        }
+                        match combined.as_mut() {
+                            Some(combined) => {
+                                combined.interval = combined.interval.min(response.interval);
+                                combined.peers.append(&mut response.peers);
+                            }
+                            None => {
+                                combined = Some(response);
+                            }
                         }
                     }
-                    response.peers = peers;
-
-                    return Ok(response);
-                }
-                Err(err) => {
-                    error!("Tracker announce failed for {announce_url:?}: {err}");
-                    last_error = err; // in case every tracker fails
+                    Err(err) => {
+                        error!("Tracker announce failed for {announce_url:?}: {err}");
+                        self.events.send(Event::TrackerError {
+                            info_hash: self.infohashes[0],
+                            url: announce_url.to_string(),
+                            message: err.to_string(),
+                        });
+                        last_error = err; // in case every tracker fails
+                    }
                 }
             }
+
+            if let Some(response) = combined {
+                return Ok(response);
+            }
         }
 
         // if we exhausted every URL without returning a valid response
@@ -260,3 +226,183 @@ impl PeerDiscoverer {
         ))
     }
 }
+
+/// Resolves a `udp://` tracker and does the connect exchange of BEP 15, the socket is connected
+/// to the tracker and comes with the connection ID to put in the following request
+async fn connect_udp(
+    announce_url: &str,
+    timeout: Duration,
+) -> Result<(UdpSocket, i64), anyhow::Error> {
+    let parsed_url = url::Url::parse(announce_url)?;
+    let host = parsed_url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("UDP announce URL has no host: {}", announce_url))?;
+    let port = parsed_url
+        .port()
+        .ok_or_else(|| anyhow::anyhow!("UDP announce URL has no port: {}", announce_url))?;
+
+    if let Some(url::Host::Ipv6(_)) = parsed_url.host() {
+        anyhow::bail!("IPv6 trackers aren't supported");
+    }
+
+    // host may be a domain name, so this needs an actual DNS lookup rather than
+    // a naive SocketAddr::parse on the raw string
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    if addrs.is_empty() {
+        anyhow::bail!("Could not resolve host: {}", host);
+    }
+    // IPv6 trackers answer with IPv6 peers, which we can't connect to
+    let remote_addr = addrs.into_iter().find(SocketAddr::is_ipv4).ok_or_else(|| {
+        anyhow::anyhow!("{host} has no IPv4 address, IPv6 trackers aren't supported")
+    })?;
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(&remote_addr).await?;
+
+    // 1. Send Connect Request
+    let my_transaction_id = rand::random::<i32>();
+    let connect_request = ConnectRequest::new(my_transaction_id);
+    socket.send(&connect_request.serialize()).await?;
+    info!("Sent Connect Request");
+
+    // 2. Receive Connect Response
+    let mut data = vec![0u8; 2048]; // 2KB buffer is plenty
+
+    let len = match tokio::time::timeout(timeout, socket.recv(&mut data)).await {
+        Ok(recv_result) => recv_result?, // socket.recv succeeded in time
+        Err(_) => anyhow::bail!("Timed out waiting for connect response from tracker"),
+    };
+
+    let response_bytes = &data[..len];
+
+    info!("Got Connect Response");
+
+    // Check for tracker errors on connect
+    if len >= 4 {
+        let action_id = i32::from_be_bytes(response_bytes[0..4].try_into()?);
+        if action_id == Action::Error as i32 {
+            let err = TrackerError::parse(response_bytes)?;
+            anyhow::bail!("Tracker returned error on connect: {}", err.error_string);
+        }
+    }
+
+    // Parse standard connect response
+    let connect_response = ConnectResponse::parse(response_bytes)?;
+    info!(
+        "Successfully connected! Connection ID: {}",
+        connect_response.connection_id
+    );
+    Ok((socket, connect_response.connection_id))
+}
+
+/// Asks a tracker how big the swarm of `info_hash` is, without joining it
+pub async fn scrape(
+    url: &AnnounceUrl,
+    info_hash: &[u8; 20],
+    timeout: Duration,
+) -> Result<ScrapeStats, anyhow::Error> {
+    match url {
+        AnnounceUrl::Http(url) => scrape_http(url, info_hash, timeout).await,
+        AnnounceUrl::Udp(url) => scrape_udp(url, info_hash, timeout).await,
+        _ => anyhow::bail!("Unsupported tracker URL scheme: {url}"),
+    }
+}
+
+/// BEP 48, the scrape URL is the announce URL with `announce` in the last path segment replaced
+/// by `scrape`, trackers whose URL doesnt fit that dont support scraping
+async fn scrape_http(
+    announce_url: &str,
+    info_hash: &[u8; 20],
+    timeout: Duration,
+) -> Result<ScrapeStats, anyhow::Error> {
+    let mut url = url::Url::parse(announce_url)?;
+    let segment = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|segment| segment.starts_with("announce"))
+        .ok_or_else(|| anyhow::anyhow!("Tracker doesnt support scraping: {announce_url}"))?
+        .replacen("announce", "scrape", 1);
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid tracker URL: {announce_url}"))?
+        .pop()
+        .push(&segment);
+
+    let query = format!(
+        "info_hash={}",
+        form_urlencoded::byte_serialize(info_hash).collect::<String>()
+    );
+    let query = match url.query() {
+        Some(existing) => format!("{existing}&{query}"),
+        None => query,
+    };
+    url.set_query(Some(&query));
+
+    let body = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .bytes()
+        .await?;
+
+    let Value::Dict(reply) = de::from_bytes::<Value>(&body)? else {
+        anyhow::bail!("Scrape reply is not a dictionary");
+    };
+    if let Some(Value::Bytes(reason)) = reply.get(b"failure reason".as_slice()) {
+        anyhow::bail!("Tracker failure: {}", String::from_utf8_lossy(reason));
+    }
+    let Some(Value::Dict(files)) = reply.get(b"files".as_slice()) else {
+        anyhow::bail!("Scrape reply has no files");
+    };
+    // a swarm the tracker doesnt know is left out rather than listed with zeros
+    let Some(Value::Dict(entry)) = files.get(info_hash.as_slice()) else {
+        return Ok(ScrapeStats::default());
+    };
+    let field = |key: &[u8]| match entry.get(key) {
+        Some(Value::Int(n)) => *n as i32,
+        _ => 0,
+    };
+    Ok(ScrapeStats {
+        seeders: field(b"complete"),
+        completed: field(b"downloaded"),
+        leechers: field(b"incomplete"),
+    })
+}
+
+async fn scrape_udp(
+    announce_url: &str,
+    info_hash: &[u8; 20],
+    timeout: Duration,
+) -> Result<ScrapeStats, anyhow::Error> {
+    let (socket, connection_id) = connect_udp(announce_url, timeout).await?;
+
+    let transaction_id = rand::random::<i32>();
+    let request = ScrapeRequest::new(connection_id, transaction_id, vec![*info_hash]);
+    socket.send(&request.serialize()).await?;
+
+    let mut data = vec![0u8; 2048];
+    let len = match tokio::time::timeout(timeout, socket.recv(&mut data)).await {
+        Ok(recv_result) => recv_result?,
+        Err(_) => anyhow::bail!("Timed out waiting for scrape response from tracker"),
+    };
+    let response_bytes = &data[..len];
+
+    if len >= 4 {
+        let action_id = i32::from_be_bytes(response_bytes[0..4].try_into()?);
+        if action_id == Action::Error as i32 {
+            let err = TrackerError::parse(response_bytes)?;
+            anyhow::bail!("Tracker returned error on scrape: {}", err.error_string);
+        }
+    }
+
+    let response = ScrapeResponse::parse(response_bytes)?;
+    if response.transaction_id != transaction_id {
+        anyhow::bail!("Scrape response is for another transaction");
+    }
+    response
+        .stats
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow::anyhow!("Scrape response has no stats"))
+}
@@ -1,121 +1,218 @@
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::{
     task::JoinSet,
     time::{self, Duration},
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::{discovery::PeerDiscoverer, parser::Torrent};
+use crate::{
+    choker::{Choker, ChokerConfig, PeerStats},
+    connection_manager::{ConnectionManager, GlobalConnections},
+    discovery::PeerDiscoverer,
+    events::{Event, EventSender},
+    ip_filter::IpFilter,
+    merkle,
+    parser::{Info, Torrent},
+    peer_connection::{HashMismatch, Peer, PeerConfig},
+    picker::Picker,
+    rate_limit::Bandwidth,
+    smart_ban::{BanList, SmartBan},
+    status::{PeerFlags, PeerInfo, PeerList},
+    storage::Storage,
+    web_seed::{self, WebSeed},
+};
+
+/// Peers that get pieces with a deadline, the ones we download from fastest
+const DEADLINE_PEERS: usize = 3;
 
 /// Responsible for downloading the file
 pub struct Downloader {
     discoverer: PeerDiscoverer,
-    pub file_buffer: Vec<u8>,
+    /// Where the pieces are written as they pass their hash check
+    storage: Arc<Storage>,
+    /// Decides which pieces are downloaded and in which order. Shared so that can change while the
+    /// download is running.
+    pub picker: Arc<std::sync::Mutex<Picker>>,
+    /// Pieces that passed their hash check, updated as they come in
+    pub have: Arc<std::sync::Mutex<Vec<bool>>>,
     torrent: Torrent,
+    pub choker: ChokerConfig,
+    /// Limits of this torrent, usually a child of the global limits
+    pub bandwidth: Bandwidth,
+    /// Open connections of this torrent
+    pub max_connections: usize,
+    /// Connection limits shared with the other torrents
+    pub global_connections: Arc<GlobalConnections>,
+    /// Peers banned for sending corrupt data, shared with the other torrents
+    pub bans: Arc<BanList>,
+    pub ip_filter: Arc<IpFilter>,
+    pub events: EventSender,
+    /// Connected peers, for status snapshots
+    pub peers: PeerList,
+    /// Timeouts and request size of every connection
+    pub peer_config: PeerConfig,
 }
 
 impl Downloader {
-    pub fn new(discoverer: &PeerDiscoverer, torrent: &Torrent) -> Self {
+    pub fn new(discoverer: &PeerDiscoverer, torrent: &Torrent, storage: Arc<Storage>) -> Self {
         Self {
             discoverer: discoverer.clone(),
-            file_buffer: Vec::new(),
+            storage,
+            picker: Arc::new(std::sync::Mutex::new(Picker::new(
+                torrent.info.piece_count(),
+            ))),
+            have: Arc::new(std::sync::Mutex::new(vec![
+                false;
+                torrent.info.piece_count()
+            ])),
             torrent: torrent.clone(),
+            choker: ChokerConfig::default(),
+            bandwidth: Bandwidth::default(),
+            max_connections: 50,
+            global_connections: Arc::new(GlobalConnections::default()),
+            bans: Arc::new(BanList::default()),
+            ip_filter: Arc::new(IpFilter::default()),
+            events: discoverer.events.clone(),
+            peers: PeerList::default(),
+            peer_config: PeerConfig::default(),
         }
     }
 
-    pub async fn download(&mut self) {
-        let total_pieces = self.torrent.info.pieces.len() / 20;
+    /// Download the pieces of `picker` into the storage, returns once all of them are done. Fails
+    /// if a piece can't be written.
+    pub async fn download(&mut self) -> Result<(), anyhow::Error> {
+        let total_pieces = self.picker.lock().unwrap().len();
 
-        let mut initial_queue = Vec::with_capacity(total_pieces);
-        for i in 0..total_pieces {
-            initial_queue.push(i);
-        }
-        let work_queue = Arc::new(Mutex::new(initial_queue));
-
-        let total_length = match &self.torrent.info.file_tree {
-            crate::parser::FileTree::SingleFile { length } => *length,
-            crate::parser::FileTree::MultiFile { files } => files.iter().map(|f| f.length).sum(),
-        };
+        let total_length = self.torrent.info.total_length();
 
-        let shared_buffer = Arc::new(Mutex::new(vec![0u8; total_length]));
         let torrent_info = Arc::new(self.torrent.info.clone());
+        // only keep piece layers that match their pieces root, anything else is asked from peers
+        let piece_layers = Arc::new(Mutex::new(
+            self.torrent
+                .piece_layers
+                .iter()
+                .filter(|(root, layer)| {
+                    merkle::verify_piece_layer(layer, root, self.torrent.info.piece_length)
+                })
+                .map(|(root, layer)| (root.clone(), layer.clone()))
+                .collect::<HashMap<_, _>>(),
+        ));
 
         let mut sleep = Box::pin(time::sleep(Duration::from_secs(0)));
         let mut active_tasks = JoinSet::new();
 
+        let peer_stats = Arc::new(Mutex::new(HashMap::new()));
+        let mut choker = Choker::new(self.choker.clone());
+        let mut choke_round = time::interval(self.choker.interval);
+
         info!(
             "Starting download of {} pieces ({} bytes total)",
             total_pieces, total_length
         );
+        if self.torrent.info.is_private() {
+            info!("Private torrent, DHT, PEX and LSD are disabled");
+        }
+
+        let shared = Shared {
+            picker: Arc::clone(&self.picker),
+            storage: Arc::clone(&self.storage),
+            storage_error: Arc::new(std::sync::Mutex::new(None)),
+            have: Arc::clone(&self.have),
+            info: torrent_info,
+            piece_layers,
+            peer_stats,
+            connections: Arc::new(Mutex::new(ConnectionManager::new(
+                self.max_connections,
+                Arc::clone(&self.global_connections),
+                Arc::clone(&self.bans),
+                Arc::clone(&self.ip_filter),
+            ))),
+            smart_ban: Arc::new(Mutex::new(SmartBan::default())),
+            bans: Arc::clone(&self.bans),
+            ip_filter: Arc::clone(&self.ip_filter),
+            peer_id: self.discoverer.peer_id(),
+            info_hash: self.discoverer.info_hash(),
+            events: self.events.clone(),
+            peers: self.peers.clone(),
+        };
+        let mut connect_round = time::interval(Duration::from_secs(2));
+
+        // web seeds have every piece, they pull from the same picker as the peers
+        for mut seed in web_seed::from_torrent(&self.torrent) {
+            seed.bandwidth = self.bandwidth.for_peer();
+            active_tasks.spawn(run_web_seed(seed, shared.clone()));
+        }
 
         loop {
-            {
-                let queue = work_queue.lock().await;
-                if queue.is_empty() && active_tasks.is_empty() {
-                    info!("All pieces downloaded successfully!");
-                    break;
-                }
+            if let Some(e) = shared.storage_error.lock().unwrap().take() {
+                return Err(e);
+            }
+            if shared.picker.lock().unwrap().is_empty() && active_tasks.is_empty() {
+                info!("All pieces downloaded successfully!");
+                break;
             }
 
             tokio::select! {
                 _ = &mut sleep  => {
 
-                    let discovery = self.discoverer.discover(&self.torrent).await.unwrap_or_else(|e| {
-                        info!("Discovery service threw an error: {}", e);
-                        panic!()
-                    });
+                    let discovery = match self.discoverer.discover().await {
+                        Ok(discovery) => discovery,
+                        // web seeds may still be working, so try the trackers again later
+                        Err(e) if !self.torrent.url_list.is_empty() || !self.torrent.http_seeds.is_empty() => {
+                            error!("Discovery service threw an error: {}", e);
+                            sleep = Box::pin(time::sleep(Duration::from_secs(60)));
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("Discovery service threw an error: {}", e);
+                            return Err(e);
+                        }
+                    };
 
                     info!("Peers updated: {} peers", discovery.peers.len());
 
                     discovery.peers.iter().for_each(|p| info!("{}", p));
 
-                    // TODO: Dedup peers across discovery rounds
                     sleep = Box::pin(time::sleep(Duration::from_secs(discovery.interval as u64)));
 
-                    for mut peer in discovery.peers {
-
-                        let queue_clone = Arc::clone(&work_queue);
-                        let buffer_clone = Arc::clone(&shared_buffer);
-                        let info_clone = Arc::clone(&torrent_info);
-
-                        active_tasks.spawn(async move
-                        {
-                            loop {
-                                // Pop a piece from the queue
-                                // TODO piece selection algorithm
-                                let piece_index = {
-                                    let mut q = queue_clone.lock().await;
-                                    match q.pop() {
-                                        Some(idx) => idx,
-                                        None => break,
-                                    }
-                                };
-
-
-                                match peer.get_piece(&info_clone, piece_index).await {
-                                    Ok(piece_data) => {
-                                        let offset = piece_index * info_clone.piece_length;
-
-                                        let mut buf = buffer_clone.lock().await;
-                                        let end = (offset + piece_data.len()).min(buf.len());
-                                        buf[offset..end].copy_from_slice(&piece_data[..end - offset]);
-
-                                        info!("Successfully downloaded piece {} from {}", piece_index, peer.sock_ip);
-                                    }
-                                    Err(e) => {
-                                        error!("Peer {} failed piece {}: {}", peer.sock_ip, piece_index, e);
-
-                                        // put the piece back in the queue so another peer can try it
-                                        queue_clone.lock().await.push(piece_index);
-
-                                        // The peer threw an error (likely a disconnect or bad hash), so we kill this task
-                                        break;
-                                    }
-                                }
-                            }
-                        });
+                    let peers = discovery.peers.into_iter().filter(|peer| {
+                        let allowed = peer.source.is_allowed(&self.torrent.info);
+                        if !allowed {
+                            info!("Ignoring {:?} peer {} for private torrent", peer.source, peer.sock_ip);
+                        }
+                        allowed
+                    });
+                    shared.connections.lock().await.add_candidates(peers);
+                    // connect right away instead of waiting for the next connect round
+                    connect_round.reset_immediately();
+                }
+                _ = connect_round.tick() => {
+                    if shared.picker.lock().unwrap().is_empty() {
+                        continue;
+                    }
+                    let mut connections = shared.connections.lock().await;
+                    let peers = connections.next_connects();
+                    if !peers.is_empty() {
+                        info!(
+                            "Connecting to {} peers ({} connections for this torrent, {} in total)",
+                            peers.len(),
+                            connections.connection_count(),
+                            self.global_connections.connections()
+                        );
                     }
+                    drop(connections);
+                    for mut peer in peers {
+                        peer.bandwidth = self.bandwidth.for_peer();
+                        peer.config = self.peer_config;
+                        active_tasks.spawn(run_peer(peer, shared.clone()));
+                    }
+                }
+                _ = choke_round.tick() => {
+                    choker.rechoke(&mut *shared.peer_stats.lock().await, false);
                 }
                 // reap completed or failed peer tasks
                 Some(res) = active_tasks.join_next(), if !active_tasks.is_empty() => {
@@ -126,9 +223,289 @@ impl Downloader {
             }
         }
 
-        // Extract our completed byte stream from the Arc<Mutex>
-        self.file_buffer = Arc::try_unwrap(shared_buffer)
-            .expect("Fatal error: Multiple references to the file buffer still exist")
-            .into_inner();
+        Ok(())
+    }
+}
+
+/// State every peer and web seed task of one download works on
+#[derive(Clone)]
+struct Shared {
+    picker: Arc<std::sync::Mutex<Picker>>,
+    storage: Arc<Storage>,
+    /// Set when a piece couldn't be written, the download fails with it
+    storage_error: Arc<std::sync::Mutex<Option<anyhow::Error>>>,
+    have: Arc<std::sync::Mutex<Vec<bool>>>,
+    info: Arc<Info>,
+    piece_layers: Arc<Mutex<HashMap<ByteBuf, ByteBuf>>>,
+    /// Choke state of every connected peer, the peer tasks apply the choker's decisions
+    peer_stats: Arc<Mutex<HashMap<SocketAddrV4, PeerStats>>>,
+    connections: Arc<Mutex<ConnectionManager>>,
+    smart_ban: Arc<Mutex<SmartBan>>,
+    bans: Arc<BanList>,
+    ip_filter: Arc<IpFilter>,
+    /// Our peer ID
+    peer_id: [u8; 20],
+    /// Identifies the torrent in events
+    info_hash: [u8; 20],
+    events: EventSender,
+    peers: PeerList,
+}
+
+impl Shared {
+    /// Pick the next piece for `peer` among the ones it has, skipping pieces it sent corrupt
+    /// before while someone else can send them. Pieces with a deadline only go to the fastest
+    /// peers.
+    async fn next_piece(&self, peer: &Peer) -> Option<usize> {
+        let smart_ban = self.smart_ban.lock().await;
+        let fast = self.peers.download_rank(&peer.sock_ip) < DEADLINE_PEERS;
+        let mut picker = self.picker.lock().unwrap();
+        let avoided = smart_ban.avoided_pieces(
+            &peer.sock_ip,
+            |index| picker.availability(index),
+            |ip| self.peers.contains_ip(ip),
+        );
+        picker.pick(
+            |index| {
+                // without a bitfield we don't know, so try
+                peer.available.get(index).copied().unwrap_or(true) && !avoided.contains(&index)
+            },
+            fast,
+        )
+    }
+
+    /// Publish the state of `peer` to the peer list
+    fn update_peer(&self, peer: &Peer) {
+        let piece_count = self.info.piece_count();
+        self.peers.update(&peer.sock_ip, |info| {
+            info.client = peer.client.clone();
+            info.flags = PeerFlags {
+                interested: true,
+                choked: peer.am_choking,
+                remote_interested: peer.peer_interested,
+                remote_choked: peer.peer_choking,
+                incoming: false,
+            };
+            info.pieces_available = peer
+                .available
+                .iter()
+                .take(piece_count)
+                .filter(|has| **has)
+                .count();
+        });
+    }
+
+    /// Write a piece that passed its hash check. If that fails the piece goes back to the picker
+    /// and the download fails with the error, returns whether it was written.
+    async fn store_piece(&self, index: usize, piece_data: &[u8]) -> bool {
+        let storage = Arc::clone(&self.storage);
+        let data = piece_data.to_vec();
+        let written = tokio::task::spawn_blocking(move || storage.write_piece(index, &data))
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+        if let Err(e) = written {
+            error!("Failed to write piece {index}: {e}");
+            self.events.send(Event::StorageError {
+                info_hash: self.info_hash,
+                message: e.to_string(),
+            });
+            self.picker.lock().unwrap().put_back(index);
+            self.storage_error.lock().unwrap().get_or_insert(e);
+            return false;
+        }
+
+        self.have.lock().unwrap()[index] = true;
+        self.picker.lock().unwrap().finished(index);
+        self.events.send(Event::PieceFinished {
+            info_hash: self.info_hash,
+            index,
+        });
+        true
+    }
+}
+
+/// Handshake `peer` and download pieces from it until the picker runs dry or it fails
+async fn run_peer(mut peer: Peer, shared: Shared) {
+    let info_hash = peer.info_hash;
+    if let Err(e) = peer
+        .perform_handshake(&shared.info, &info_hash, &shared.peer_id, &shared.ip_filter)
+        .await
+    {
+        error!("Failed handshake with peer {:?}: {e}", peer.sock_ip);
+        shared.connections.lock().await.failed(peer.sock_ip);
+        return;
+    }
+    let peer_id = peer.peer_id.unwrap_or_default();
+    if !shared
+        .connections
+        .lock()
+        .await
+        .connected(peer.sock_ip, peer_id)
+    {
+        return;
+    }
+    info!(
+        "Connected to {} running {}",
+        peer.sock_ip,
+        peer.client
+            .as_ref()
+            .map_or_else(|| "an unknown client".to_string(), ToString::to_string)
+    );
+    shared.events.send(Event::PeerConnected {
+        info_hash: shared.info_hash,
+        addr: peer.sock_ip,
+        client: peer.client.clone(),
+    });
+    shared.peers.insert(
+        PeerInfo::new(peer.sock_ip, peer.client.clone(), PeerFlags::default()),
+        peer.bandwidth.clone(),
+        Arc::clone(&peer.queue_depth),
+    );
+    shared.update_peer(&peer);
+    shared.picker.lock().unwrap().add_peer(&peer.available);
+
+    shared
+        .peer_stats
+        .lock()
+        .await
+        .insert(peer.sock_ip, PeerStats::new());
+    let mut downloaded = 0u64;
+    let mut failed = false;
+
+    loop {
+        let Some(piece_index) = shared.next_piece(&peer).await else {
+            break;
+        };
+
+        match peer
+            .get_piece(&shared.info, &shared.piece_layers, piece_index)
+            .await
+        {
+            Ok(piece_data) => {
+                if !shared.store_piece(piece_index, &piece_data).await {
+                    break;
+                }
+                downloaded += piece_data.len() as u64;
+
+                let offenders = shared
+                    .smart_ban
+                    .lock()
+                    .await
+                    .piece_passed(piece_index, &piece_data);
+                for addr in offenders {
+                    shared.bans.ban(addr);
+                }
+
+                info!(
+                    "Successfully downloaded piece {} from {}",
+                    piece_index, peer.sock_ip
+                );
+
+                let choke = {
+                    let mut stats = shared.peer_stats.lock().await;
+                    let entry = stats.entry(peer.sock_ip).or_insert_with(PeerStats::new);
+                    entry.downloaded += piece_data.len() as u64;
+                    entry.peer_interested = peer.peer_interested;
+                    entry.choked
+                };
+                let result = peer.set_choking(choke).await;
+                shared.update_peer(&peer);
+                if let Err(e) = result {
+                    error!("Failed to update choke state of {}: {}", peer.sock_ip, e);
+                    failed = true;
+                    break;
+                }
+
+                // the peer may have been found out by a piece someone else finished
+                if shared.bans.is_banned(peer.sock_ip.ip()) {
+                    info!("Disconnecting banned peer {}", peer.sock_ip);
+                    failed = true;
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("Peer {} failed piece {}: {}", peer.sock_ip, piece_index, e);
+
+                if let Some(mismatch) = e.downcast_ref::<HashMismatch>() {
+                    shared.events.send(Event::HashFailed {
+                        info_hash: shared.info_hash,
+                        index: piece_index,
+                        peer: Some(peer.sock_ip),
+                    });
+                    shared.smart_ban.lock().await.piece_failed(
+                        piece_index,
+                        peer.sock_ip,
+                        &mismatch.piece,
+                    );
+                }
+
+                // put the piece back so another peer can try it
+                shared.picker.lock().unwrap().put_back(piece_index);
+
+                // The peer threw an error (likely a disconnect or bad hash), so we kill this task
+                failed = true;
+                break;
+            }
+        }
+    }
+
+    shared.peer_stats.lock().await.remove(&peer.sock_ip);
+    shared.picker.lock().unwrap().remove_peer(&peer.available);
+    shared.peers.remove(&peer.sock_ip);
+    shared.events.send(Event::PeerDisconnected {
+        info_hash: shared.info_hash,
+        addr: peer.sock_ip,
+    });
+    let mut connections = shared.connections.lock().await;
+    if failed {
+        connections.failed(peer.sock_ip);
+    } else {
+        connections.disconnected(peer.sock_ip, downloaded);
+    }
+}
+
+/// Download pieces from a web seed until the picker runs dry or the seed is given up on
+async fn run_web_seed(mut seed: WebSeed, shared: Shared) {
+    loop {
+        // a mirror has every piece and is usually fast, so it gets pieces with a deadline too
+        let Some(piece_index) = shared.picker.lock().unwrap().pick(|_| true, true) else {
+            break;
+        };
+
+        match seed
+            .get_piece(&shared.info, &shared.piece_layers, piece_index)
+            .await
+        {
+            Ok(piece_data) => {
+                seed.succeeded();
+                if !shared.store_piece(piece_index, &piece_data).await {
+                    break;
+                }
+
+                info!(
+                    "Successfully downloaded piece {} from {}",
+                    piece_index, seed
+                );
+            }
+            Err(e) => {
+                error!("{} failed piece {}: {}", seed, piece_index, e);
+                if e.is::<HashMismatch>() {
+                    shared.events.send(Event::HashFailed {
+                        info_hash: shared.info_hash,
+                        index: piece_index,
+                        peer: None,
+                    });
+                }
+                shared.picker.lock().unwrap().put_back(piece_index);
+
+                // unlike a peer a mirror is retried, it's usually just overloaded
+                match seed.backoff() {
+                    Some(delay) => time::sleep(delay).await,
+                    None => {
+                        warn!("Giving up on {}", seed);
+                        break;
+                    }
+                }
+            }
+        }
     }
 }
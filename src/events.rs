@@ -0,0 +1,249 @@
+//! Typed events about what a session is doing.
+//!
+//! Everything that happens is also logged, events are for embedders that want to react to it
+//! without parsing log lines. Subscribers pick the categories they care about and get their own
+//! [`EventReceiver`], a subscriber that falls more than [`CAPACITY`] events behind loses the
+//! oldest ones.
+use std::net::SocketAddrV4;
+use tokio::sync::broadcast;
+
+use crate::peer_id::ClientId;
+
+/// Events buffered for each subscriber
+pub const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventCategory {
+    Peer,
+    Piece,
+    Tracker,
+    Torrent,
+    Storage,
+}
+
+impl EventCategory {
+    pub const ALL: &[EventCategory] = &[
+        EventCategory::Peer,
+        EventCategory::Piece,
+        EventCategory::Tracker,
+        EventCategory::Torrent,
+        EventCategory::Storage,
+    ];
+}
+
+/// `info_hash` always identifies the torrent as the session does, by its first swarm
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Handshake done, in either direction
+    PeerConnected {
+        info_hash: [u8; 20],
+        addr: SocketAddrV4,
+        client: Option<ClientId>,
+    },
+    PeerDisconnected {
+        info_hash: [u8; 20],
+        addr: SocketAddrV4,
+    },
+    /// A piece passed its hash check
+    PieceFinished { info_hash: [u8; 20], index: usize },
+    /// A piece from `peer` didn't match its hash, `None` for web seeds
+    HashFailed {
+        info_hash: [u8; 20],
+        index: usize,
+        peer: Option<SocketAddrV4>,
+    },
+    TrackerReply {
+        info_hash: [u8; 20],
+        url: String,
+        peers: usize,
+    },
+    TrackerError {
+        info_hash: [u8; 20],
+        url: String,
+        message: String,
+    },
+    /// The last missing wanted piece arrived, a torrent that was complete already when it started
+    /// doesn't send this
+    TorrentFinished { info_hash: [u8; 20] },
+    /// Reading or writing the files of the torrent failed
+    StorageError {
+        info_hash: [u8; 20],
+        message: String,
+    },
+    /// The info dictionary of a torrent added from a magnet link arrived. Not sent yet, the
+    /// session can't add magnet links before it has the metadata exchange of BEP 9.
+    MetadataReceived { info_hash: [u8; 20] },
+}
+
+impl Event {
+    pub fn category(&self) -> EventCategory {
+        match self {
+            Event::PeerConnected { .. } | Event::PeerDisconnected { .. } => EventCategory::Peer,
+            Event::PieceFinished { .. } | Event::HashFailed { .. } => EventCategory::Piece,
+            Event::TrackerReply { .. } | Event::TrackerError { .. } => EventCategory::Tracker,
+            Event::TorrentFinished { .. } | Event::MetadataReceived { .. } => {
+                EventCategory::Torrent
+            }
+            Event::StorageError { .. } => EventCategory::Storage,
+        }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        match self {
+            Event::PeerConnected { info_hash, .. }
+            | Event::PeerDisconnected { info_hash, .. }
+            | Event::PieceFinished { info_hash, .. }
+            | Event::HashFailed { info_hash, .. }
+            | Event::TrackerReply { info_hash, .. }
+            | Event::TrackerError { info_hash, .. }
+            | Event::TorrentFinished { info_hash }
+            | Event::StorageError { info_hash, .. }
+            | Event::MetadataReceived { info_hash } => *info_hash,
+        }
+    }
+}
+
+/// Where the parts of a session post their events, cheap to clone
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventSender {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::Sender::new(CAPACITY),
+        }
+    }
+}
+
+impl EventSender {
+    /// Post `event` to every subscriber, it is dropped if there are none
+    pub fn send(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Receive the events of `categories` posted from now on
+    pub fn subscribe(&self, categories: &[EventCategory]) -> EventReceiver {
+        EventReceiver {
+            receiver: self.sender.subscribe(),
+            categories: categories.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EventReceiver {
+    receiver: broadcast::Receiver<Event>,
+    categories: Vec<EventCategory>,
+}
+
+impl EventReceiver {
+    /// The next event of the subscribed categories. Fails with `Lagged` after events were lost
+    /// because the subscriber fell behind, receiving again continues with the oldest one left.
+    /// Fails with `Closed` once the session is gone.
+    pub async fn recv(&mut self) -> Result<Event, broadcast::error::RecvError> {
+        loop {
+            let event = self.receiver.recv().await?;
+            if self.categories.contains(&event.category()) {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// The unfiltered broadcast receiver
+    pub fn into_inner(self) -> broadcast::Receiver<Event> {
+        self.receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddrV4 {
+        SocketAddrV4::new([10, 0, 0, 1].into(), 6881)
+    }
+
+    #[tokio::test]
+    async fn receivers_only_get_their_categories() {
+        let sender = EventSender::default();
+        let pieces = sender.subscribe(&[EventCategory::Piece]);
+        let torrents = sender.subscribe(&[EventCategory::Torrent, EventCategory::Storage]);
+        let everything = sender.subscribe(EventCategory::ALL);
+        let nothing = sender.subscribe(&[]);
+
+        let events = [
+            Event::PeerConnected {
+                info_hash: [1; 20],
+                addr: addr(),
+                client: None,
+            },
+            Event::PieceFinished {
+                info_hash: [1; 20],
+                index: 3,
+            },
+            Event::TrackerError {
+                info_hash: [1; 20],
+                url: "http://127.0.0.1:7070/announce".to_string(),
+                message: "timed out".to_string(),
+            },
+            Event::MetadataReceived { info_hash: [2; 20] },
+            Event::HashFailed {
+                info_hash: [1; 20],
+                index: 4,
+                peer: Some(addr()),
+            },
+            Event::StorageError {
+                info_hash: [1; 20],
+                message: "disk full".to_string(),
+            },
+        ];
+        for event in events.iter().cloned() {
+            sender.send(event);
+        }
+        // the broadcast receiver underneath isn't filtered
+        let unfiltered = nothing.into_inner().len();
+        drop(sender);
+
+        let received = |mut receiver: EventReceiver| async move {
+            let mut received = Vec::new();
+            while let Ok(event) = receiver.recv().await {
+                received.push(event);
+            }
+            received
+        };
+        assert_eq!(
+            received(pieces).await,
+            [events[1].clone(), events[4].clone()]
+        );
+        assert_eq!(
+            received(torrents).await,
+            [events[3].clone(), events[5].clone()]
+        );
+        assert_eq!(received(everything).await, events);
+        assert_eq!(unfiltered, events.len());
+    }
+
+    #[tokio::test]
+    async fn slow_receivers_lose_the_oldest_events() {
+        let sender = EventSender::default();
+        let mut receiver = sender.subscribe(&[EventCategory::Piece]);
+        for index in 0..CAPACITY + 10 {
+            sender.send(Event::PieceFinished {
+                info_hash: [1; 20],
+                index,
+            });
+        }
+        assert_eq!(
+            receiver.recv().await,
+            Err(broadcast::error::RecvError::Lagged(10))
+        );
+        for expected in [10, 11] {
+            assert!(matches!(
+                receiver.recv().await,
+                Ok(Event::PieceFinished { index, .. }) if index == expected
+            ));
+        }
+    }
+}
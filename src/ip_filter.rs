@@ -0,0 +1,321 @@
+//! IP blocklists.
+//!
+//! Understands the common list formats, one entry per line, and they can be mixed:
+//!
+//! * eMule `ipfilter.dat`: `001.002.003.000 - 001.002.003.255 , 100 , description`, entries with
+//!   an access level above 127 are allowed and skipped
+//! * PeerGuardian P2P text: `description:1.2.3.0-1.2.3.255`
+//! * CIDR blocks `1.2.3.0/24`, plain ranges `1.2.3.0-1.2.3.255` and single addresses
+//!
+//! Lines starting with `#` or `//` are comments. The ranges are sorted and merged once when a
+//! list is loaded, so a lookup is a binary search no matter how long the list is.
+use anyhow::anyhow;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Highest eMule access level that still means blocked
+const EMULE_BLOCK_LEVEL: u32 = 127;
+
+/// Sorted, non overlapping, inclusive ranges
+#[derive(Debug, Default, Clone)]
+pub struct IpRanges {
+    ranges: Vec<(u32, u32)>,
+}
+
+impl IpRanges {
+    /// Parse a list in any of the supported formats. Lines that can't be parsed are skipped and
+    /// counted in the returned number.
+    pub fn parse(content: &str) -> (Self, usize) {
+        let mut ranges = Vec::new();
+        let mut invalid = 0;
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some(range)) => ranges.push(range),
+                // allowed by its eMule access level
+                Some(None) => {}
+                None => invalid += 1,
+            }
+        }
+
+        (Self::from_ranges(ranges), invalid)
+    }
+
+    fn from_ranges(mut ranges: Vec<(u32, u32)>) -> Self {
+        ranges.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Self { ranges: merged }
+    }
+
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        let ip = u32::from(*ip);
+        // first range starting after `ip`, the one before it is the only candidate
+        let next = self.ranges.partition_point(|(start, _)| *start <= ip);
+        next > 0 && self.ranges[next - 1].1 >= ip
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// `Some(None)` for eMule entries that allow the range, `None` for lines that can't be parsed
+fn parse_line(line: &str) -> Option<Option<(u32, u32)>> {
+    // eMule, `range , level , description`
+    if let Some((range, rest)) = line.split_once(',') {
+        if let Some(range) = parse_range(range) {
+            let level = rest
+                .split(',')
+                .next()
+                .and_then(|level| level.trim().parse::<u32>().ok())
+                .unwrap_or(0);
+            return Some((level <= EMULE_BLOCK_LEVEL).then_some(range));
+        }
+    }
+
+    if let Some((ip, prefix)) = line.split_once('/') {
+        if let (Some(ip), Some(prefix)) = (
+            parse_ip(ip),
+            prefix.trim().parse::<u32>().ok().filter(|p| *p <= 32),
+        ) {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            return Some(Some((ip & mask, ip | !mask)));
+        }
+    }
+
+    // P2P, the description may contain colons itself
+    let range = line.rsplit_once(':').map_or(line, |(_, range)| range);
+    Some(Some(parse_range(range)?))
+}
+
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    match range.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse_ip(start)?, parse_ip(end)?);
+            (start <= end).then_some((start, end))
+        }
+        None => parse_ip(range).map(|ip| (ip, ip)),
+    }
+}
+
+/// Like `Ipv4Addr::from_str`, but allows the zero padded octets eMule lists use
+fn parse_ip(ip: &str) -> Option<u32> {
+    let mut octets = ip.trim().split('.');
+    let mut value = 0u32;
+    for _ in 0..4 {
+        let octet: u8 = octets.next()?.parse().ok()?;
+        value = value << 8 | octet as u32;
+    }
+    octets.next().is_none().then_some(value)
+}
+
+/// The blocklist of the session, loaded from one or more files
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    sources: std::sync::Mutex<Vec<PathBuf>>,
+    ranges: std::sync::RwLock<IpRanges>,
+}
+
+impl IpFilter {
+    pub fn load(sources: Vec<PathBuf>) -> Result<Self, anyhow::Error> {
+        let filter = Self::default();
+        filter.set_sources(sources)?;
+        Ok(filter)
+    }
+
+    /// Read every source file again. On error the old ranges stay in place.
+    pub fn reload(&self) -> Result<(), anyhow::Error> {
+        let sources = self.sources.lock().unwrap().clone();
+        self.load_ranges(&sources)
+    }
+
+    /// Switch to other source files. On error the old sources and ranges stay in place.
+    pub fn set_sources(&self, sources: Vec<PathBuf>) -> Result<(), anyhow::Error> {
+        self.load_ranges(&sources)?;
+        *self.sources.lock().unwrap() = sources;
+        Ok(())
+    }
+
+    fn load_ranges(&self, sources: &[PathBuf]) -> Result<(), anyhow::Error> {
+        let mut all = Vec::new();
+        for path in sources {
+            let content = read_list(path)?;
+            let (ranges, invalid) = IpRanges::parse(&content);
+            if invalid > 0 {
+                warn!("Skipped {invalid} invalid lines in {}", path.display());
+            }
+            all.extend(ranges.ranges);
+        }
+
+        let ranges = IpRanges::from_ranges(all);
+        info!("IP filter loaded with {} ranges", ranges.len());
+        *self.ranges.write().unwrap() = ranges;
+        Ok(())
+    }
+
+    pub fn is_blocked(&self, ip: &Ipv4Addr) -> bool {
+        self.ranges.read().unwrap().contains(ip)
+    }
+}
+
+/// Lists are mostly ASCII, but descriptions in the wild come in all kinds of encodings
+fn read_list(path: &Path) -> Result<String, anyhow::Error> {
+    let bytes =
+        std::fs::read(path).map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> u32 {
+        u32::from(ip.parse::<Ipv4Addr>().unwrap())
+    }
+
+    #[test]
+    fn parses_lines() {
+        let blocked = |start, end| Some(Some((ip(start), ip(end))));
+        let cases = [
+            // eMule
+            (
+                "001.002.003.000 - 001.002.003.255 , 100 , Some ISP",
+                blocked("1.2.3.0", "1.2.3.255"),
+            ),
+            (
+                "001.002.003.000 - 001.002.003.255 , 127 , Some ISP",
+                blocked("1.2.3.0", "1.2.3.255"),
+            ),
+            (
+                "001.002.003.000 - 001.002.003.255 , 128 , Friends",
+                Some(None),
+            ),
+            ("1.2.3.0 - 1.2.3.255 ,", blocked("1.2.3.0", "1.2.3.255")),
+            // P2P
+            (
+                "Some ISP:1.2.3.0-1.2.3.255",
+                blocked("1.2.3.0", "1.2.3.255"),
+            ),
+            ("a:b:c:1.2.3.0-1.2.3.255", blocked("1.2.3.0", "1.2.3.255")),
+            (
+                "Bad, Inc:1.2.3.0-1.2.3.255",
+                blocked("1.2.3.0", "1.2.3.255"),
+            ),
+            ("Nothing:1.2.3.0", blocked("1.2.3.0", "1.2.3.0")),
+            // CIDR
+            ("1.2.3.0/24", blocked("1.2.3.0", "1.2.3.255")),
+            ("1.2.3.77/24", blocked("1.2.3.0", "1.2.3.255")),
+            ("10.0.0.0/8", blocked("10.0.0.0", "10.255.255.255")),
+            ("1.2.3.4/32", blocked("1.2.3.4", "1.2.3.4")),
+            ("1.2.3.4/0", blocked("0.0.0.0", "255.255.255.255")),
+            // plain
+            ("1.2.3.0-1.2.3.9", blocked("1.2.3.0", "1.2.3.9")),
+            ("1.2.3.4", blocked("1.2.3.4", "1.2.3.4")),
+            // invalid
+            ("1.2.3.0/33", None),
+            ("1.2.3.0/x", None),
+            ("1.2.3", None),
+            ("1.2.3.4.5", None),
+            ("1.2.3.256", None),
+            ("1.2.3.9-1.2.3.0", None),
+            ("Some ISP:", None),
+            ("nonsense", None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_line(line), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn parse_skips_comments_and_counts_invalid_lines() {
+        let list = "# comment\n// comment\n\n1.2.3.4\nnonsense\n1.2.3.0/33\n";
+        let (ranges, invalid) = IpRanges::parse(list);
+        assert_eq!(ranges.ranges, [(ip("1.2.3.4"), ip("1.2.3.4"))]);
+        assert_eq!(invalid, 2);
+    }
+
+    #[test]
+    fn merges_ranges() {
+        let range = |start, end| (ip(start), ip(end));
+        let cases = [
+            (vec![], vec![]),
+            (
+                // overlapping, out of order
+                vec![range("1.0.0.5", "1.0.0.20"), range("1.0.0.0", "1.0.0.10")],
+                vec![range("1.0.0.0", "1.0.0.20")],
+            ),
+            (
+                // adjacent
+                vec![range("1.0.0.0", "1.0.0.9"), range("1.0.0.10", "1.0.0.19")],
+                vec![range("1.0.0.0", "1.0.0.19")],
+            ),
+            (
+                // contained
+                vec![range("1.0.0.0", "1.0.0.255"), range("1.0.0.7", "1.0.0.8")],
+                vec![range("1.0.0.0", "1.0.0.255")],
+            ),
+            (
+                // one apart
+                vec![range("1.0.0.0", "1.0.0.9"), range("1.0.0.11", "1.0.0.19")],
+                vec![range("1.0.0.0", "1.0.0.9"), range("1.0.0.11", "1.0.0.19")],
+            ),
+            (
+                // up to the last address, which must not overflow
+                vec![
+                    range("255.255.255.255", "255.255.255.255"),
+                    range("255.255.255.0", "255.255.255.255"),
+                ],
+                vec![range("255.255.255.0", "255.255.255.255")],
+            ),
+        ];
+        for (ranges, merged) in cases {
+            assert_eq!(
+                IpRanges::from_ranges(ranges.clone()).ranges,
+                merged,
+                "{ranges:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn looks_up() {
+        let (ranges, _) = IpRanges::parse("0.0.0.0\n1.0.0.0-1.0.0.9\n2.0.0.0/8\n255.255.255.255\n");
+        let cases = [
+            ("0.0.0.0", true),
+            ("0.0.0.1", false),
+            ("0.255.255.255", false),
+            ("1.0.0.0", true),
+            ("1.0.0.5", true),
+            ("1.0.0.9", true),
+            ("1.0.0.10", false),
+            ("1.255.255.255", false),
+            ("2.0.0.0", true),
+            ("2.255.255.255", true),
+            ("3.0.0.0", false),
+            ("255.255.255.254", false),
+            ("255.255.255.255", true),
+        ];
+        for (address, blocked) in cases {
+            assert_eq!(
+                ranges.contains(&address.parse().unwrap()),
+                blocked,
+                "{address}"
+            );
+        }
+        assert!(!IpRanges::default().contains(&Ipv4Addr::LOCALHOST));
+    }
+}
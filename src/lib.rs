@@ -0,0 +1,50 @@
+//! # rBittorrent
+//! Simple implementation of the BitTorrent protocoll in rust with minimal dependencies
+//!
+//! Embedders start a [`Session`] and add torrents to it, each one is controlled through its
+//! [`TorrentHandle`]:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), anyhow::Error> {
+//! use rbittorrent::{Session, SessionConfig};
+//!
+//! let session = Session::new(SessionConfig::default()).await?;
+//! let handle = session.add_torrent_file("debian.torrent")?;
+//! handle.wait().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod choker;
+pub mod connection_manager;
+pub mod create;
+pub mod discovery;
+pub mod downloader;
+pub mod events;
+pub mod ip_filter;
+pub mod magnet;
+pub mod merkle;
+pub mod parser;
+pub mod peer_connection;
+pub mod peer_id;
+pub mod picker;
+pub mod rate_limit;
+pub mod resume;
+pub mod seeder;
+pub mod session;
+pub mod settings;
+pub mod smart_ban;
+pub mod status;
+pub mod storage;
+pub mod stream_server;
+pub mod super_seed;
+pub mod tracker_response;
+pub mod tracker_server;
+pub mod udp_tracker;
+pub mod web_seed;
+
+pub use events::{Event, EventCategory, EventReceiver};
+pub use session::{Session, SessionConfig, TorrentHandle, TorrentReader, TorrentState};
+pub use settings::Settings;
+pub use status::{PeerFlags, PeerInfo, TorrentStatus};
+pub use storage::FilePriority;
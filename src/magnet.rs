@@ -0,0 +1,289 @@
+//! Magnet links, what identifies a torrent without its metainfo (BEP 9).
+//!
+//! `magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>`, the v1 info hash comes as 40 hex or
+//! 32 base32 characters. A v2 info hash (BEP 52) comes as a SHA-256 multihash,
+//! `xt=urn:btmh:1220<hex>`, hybrid torrents have both. Keys may carry a `.1`, `.2`... suffix to
+//! tell several values apart, it is ignored.
+use anyhow::anyhow;
+use std::fmt::Display;
+use url::form_urlencoded;
+
+use crate::parser::{self, Torrent};
+
+/// Multihash prefix of a SHA-256 digest: code 0x12, length 0x20
+const SHA256_MULTIHASH: &str = "1220";
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: Option<[u8; 20]>,
+    pub info_hash_v2: Option<[u8; 32]>,
+    /// `dn`, a name to show until the metainfo is there
+    pub name: Option<String>,
+    /// `tr`, in the order they were given
+    pub trackers: Vec<String>,
+    /// `ws`, web seeds (BEP 19)
+    pub web_seeds: Vec<String>,
+    /// `xl`, the total size in bytes
+    pub length: Option<u64>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self, anyhow::Error> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| anyhow!("Not a magnet link: {uri}"))?;
+
+        let mut magnet = Self::default();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let key = key.split('.').next().unwrap_or_default();
+            match key {
+                "xt" => magnet.parse_exact_topic(&value)?,
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                "ws" => magnet.web_seeds.push(value.into_owned()),
+                "xl" => magnet.length = value.parse().ok(),
+                _ => {}
+            }
+        }
+        if magnet.info_hash.is_none() && magnet.info_hash_v2.is_none() {
+            anyhow::bail!("Magnet link has no BitTorrent info hash");
+        }
+        Ok(magnet)
+    }
+
+    fn parse_exact_topic(&mut self, topic: &str) -> Result<(), anyhow::Error> {
+        if let Some(hash) = topic.strip_prefix("urn:btih:") {
+            let bytes = match hash.len() {
+                40 => hex::decode(hash)?,
+                32 => base32_decode(hash).ok_or_else(|| anyhow!("Invalid base32 info hash"))?,
+                _ => anyhow::bail!("Invalid info hash {hash}"),
+            };
+            self.info_hash = Some(bytes.try_into().unwrap());
+        } else if let Some(multihash) = topic.strip_prefix("urn:btmh:") {
+            let hash = multihash
+                .strip_prefix(SHA256_MULTIHASH)
+                .filter(|hash| hash.len() == 64)
+                .ok_or_else(|| anyhow!("Invalid v2 info hash {multihash}"))?;
+            self.info_hash_v2 = Some(hex::decode(hash)?.try_into().unwrap());
+        }
+        // other kinds of exact topics belong to other networks
+        Ok(())
+    }
+
+    pub fn from_torrent(torrent: &Torrent) -> Result<Self, anyhow::Error> {
+        let info = &torrent.info;
+        let mut trackers = vec![torrent.announce.to_string()];
+        for url in torrent.announce_list.iter().flatten().flatten() {
+            let url = url.to_string();
+            if !trackers.contains(&url) {
+                trackers.push(url);
+            }
+        }
+        Ok(Self {
+            info_hash: info
+                .is_v1()
+                .then(|| parser::calculate_info_hash_bytes(info))
+                .transpose()?,
+            info_hash_v2: info
+                .is_v2()
+                .then(|| parser::calculate_info_hash_v2_bytes(info))
+                .transpose()?,
+            name: Some(info.name.clone()),
+            trackers,
+            web_seeds: torrent.url_list.clone(),
+            length: Some(info.total_length() as u64),
+        })
+    }
+
+    /// The hashes of the swarms the torrent is in, like [`parser::swarm_info_hashes`]
+    pub fn swarm_info_hashes(&self) -> Vec<[u8; 20]> {
+        let v2 = self.info_hash_v2.map(|hash| hash[..20].try_into().unwrap());
+        self.info_hash.into_iter().chain(v2).collect()
+    }
+}
+
+impl Display for MagnetLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let encode =
+            |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+        let mut params: Vec<String> = Vec::new();
+        if let Some(hash) = self.info_hash {
+            params.push(format!("xt=urn:btih:{}", hex::encode(hash)));
+        }
+        if let Some(hash) = self.info_hash_v2 {
+            params.push(format!(
+                "xt=urn:btmh:{SHA256_MULTIHASH}{}",
+                hex::encode(hash)
+            ));
+        }
+        if let Some(name) = &self.name {
+            params.push(format!("dn={}", encode(name)));
+        }
+        if let Some(length) = self.length {
+            params.push(format!("xl={length}"));
+        }
+        for tracker in &self.trackers {
+            params.push(format!("tr={}", encode(tracker)));
+        }
+        for seed in &self.web_seeds {
+            params.push(format!("ws={}", encode(seed)));
+        }
+        write!(f, "magnet:?{}", params.join("&"))
+    }
+}
+
+/// RFC 4648 base32 without padding, upper or lower case
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = buffer << 5 | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+    const HASH_BASE32: &str = "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+    const HASH_V2: &str = "2d711642b726b04401627ca9fbac32f5c8530fb1903cc4db02258717921a4881";
+
+    fn v1() -> [u8; 20] {
+        hex::decode(HASH).unwrap().try_into().unwrap()
+    }
+
+    fn v2() -> [u8; 32] {
+        hex::decode(HASH_V2).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn parses_info_hashes() {
+        let lower = HASH_BASE32.to_lowercase();
+        let cases = [
+            (format!("xt=urn:btih:{HASH}"), Some(v1()), None),
+            (
+                format!("xt=urn:btih:{}", HASH.to_uppercase()),
+                Some(v1()),
+                None,
+            ),
+            (format!("xt=urn:btih:{HASH_BASE32}"), Some(v1()), None),
+            (format!("xt=urn:btih:{lower}"), Some(v1()), None),
+            (format!("xt=urn:btmh:1220{HASH_V2}"), None, Some(v2())),
+            (
+                format!("xt.1=urn:btih:{HASH}&xt.2=urn:btmh:1220{HASH_V2}"),
+                Some(v1()),
+                Some(v2()),
+            ),
+            // other networks are ignored
+            (
+                format!("xt=urn:ed2k:abc&xt=urn:btih:{HASH}"),
+                Some(v1()),
+                None,
+            ),
+        ];
+        for (query, info_hash, info_hash_v2) in cases {
+            let magnet = MagnetLink::parse(&format!("magnet:?{query}")).unwrap();
+            assert_eq!(magnet.info_hash, info_hash, "{query}");
+            assert_eq!(magnet.info_hash_v2, info_hash_v2, "{query}");
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_links() {
+        let cases = [
+            format!("http://example.com/?xt=urn:btih:{HASH}"),
+            "magnet:?dn=nothing".to_string(),
+            "magnet:?xt=urn:ed2k:abc".to_string(),
+            format!("magnet:?xt=urn:btih:{}", &HASH[..39]),
+            format!("magnet:?xt=urn:btih:{}", "z".repeat(40)),
+            format!("magnet:?xt=urn:btih:{}", "1".repeat(32)),
+            // multihashes other than SHA-256 and truncated digests
+            format!("magnet:?xt=urn:btmh:1114{HASH_V2}"),
+            format!("magnet:?xt=urn:btmh:1220{}", &HASH_V2[..62]),
+        ];
+        for uri in cases {
+            assert!(MagnetLink::parse(&uri).is_err(), "{uri}");
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let magnet = MagnetLink {
+            info_hash: Some(v1()),
+            info_hash_v2: Some(v2()),
+            name: Some("a name & more/ü".to_string()),
+            trackers: vec![
+                "udp://tracker.example:1337/announce".to_string(),
+                "http://tracker.example/announce?key=a&b=c".to_string(),
+            ],
+            web_seeds: vec!["https://seed.example/files/".to_string()],
+            length: Some(12345),
+        };
+        let uri = magnet.to_string();
+        assert!(uri.starts_with(&format!(
+            "magnet:?xt=urn:btih:{HASH}&xt=urn:btmh:1220{HASH_V2}&dn=a+name+%26+more%2F%C3%BC"
+        )));
+        assert_eq!(MagnetLink::parse(&uri).unwrap(), magnet);
+
+        let minimal = MagnetLink {
+            info_hash: Some(v1()),
+            ..MagnetLink::default()
+        };
+        assert_eq!(minimal.to_string(), format!("magnet:?xt=urn:btih:{HASH}"));
+        assert_eq!(MagnetLink::parse(&minimal.to_string()).unwrap(), minimal);
+        assert_eq!(minimal.swarm_info_hashes(), [v1()]);
+
+        let v2_only = MagnetLink {
+            info_hash_v2: Some(v2()),
+            ..MagnetLink::default()
+        };
+        let parsed = MagnetLink::parse(&v2_only.to_string()).unwrap();
+        assert_eq!(parsed, v2_only);
+        let truncated: [u8; 20] = v2()[..20].try_into().unwrap();
+        assert_eq!(parsed.swarm_info_hashes(), [truncated]);
+    }
+
+    #[test]
+    fn describes_torrents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, [1u8; 1000]).unwrap();
+        let options = crate::create::CreateOptions {
+            trackers: vec![
+                vec!["http://127.0.0.1:7070/announce".to_string()],
+                vec![
+                    "http://127.0.0.1:7070/announce".to_string(),
+                    "udp://127.0.0.1:7071".to_string(),
+                ],
+            ],
+            web_seeds: vec!["http://127.0.0.1:8080/".to_string()],
+            ..Default::default()
+        };
+        let torrent = crate::create::create_torrent(&path, &options).unwrap();
+
+        let magnet = MagnetLink::from_torrent(&torrent).unwrap();
+        assert_eq!(
+            magnet.swarm_info_hashes(),
+            parser::swarm_info_hashes(&torrent.info).unwrap()
+        );
+        assert_eq!(magnet.name.as_deref(), Some("file"));
+        assert_eq!(magnet.length, Some(1000));
+        assert_eq!(
+            magnet.trackers,
+            ["http://127.0.0.1:7070/announce", "udp://127.0.0.1:7071"]
+        );
+        assert_eq!(magnet.web_seeds, ["http://127.0.0.1:8080/"]);
+        assert_eq!(MagnetLink::parse(&magnet.to_string()).unwrap(), magnet);
+    }
+}
@@ -1,101 +1,1033 @@
 //! # rBittorrent
 //! Simple implementation of the BitTorrent protocoll in rust with minimal dependencies
 
-use anyhow::Ok;
-use std::{fs::File, io::Write, path::PathBuf};
-use tokio::task::JoinSet;
-use tracing::{error, info, Level};
-use tracing_subscriber::FmtSubscriber;
+use anyhow::anyhow;
+use rbittorrent::create::{self, CreateOptions};
+use rbittorrent::discovery;
+use rbittorrent::magnet::MagnetLink;
+use rbittorrent::parser::{self, AnnounceUrl, Torrent};
+use rbittorrent::peer_connection;
+use rbittorrent::settings::{self, Settings};
+use rbittorrent::storage::{self, Storage};
+use rbittorrent::tracker_server::{self, TrackerConfig};
+use rbittorrent::{FilePriority, Session, SessionConfig, TorrentHandle, TorrentState};
+use serde::Serialize;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
 
-use crate::{discovery::PeerDiscoverer, downloader::Downloader, parser::FileTree};
-
-mod discovery;
-mod downloader;
-mod parser;
-mod peer_connection;
-mod tracker_response;
-mod udp_tracker;
+/// A download failed, the data is incomplete or no tracker answered
+const EXIT_FAILURE: i32 = 1;
+/// The command line or the settings are invalid
+const EXIT_USAGE: i32 = 2;
+/// A torrent file or magnet link couldn't be parsed
+const EXIT_PARSE: i32 = 3;
+/// How long `seed` waits for its torrent to leave the queue, the active limits may keep it there
+const SEED_QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .with_target(false) // Hides the module path to keep it clean, set to true if you want it
-        .with_thread_ids(true) // Helpful for debugging concurrent peer tasks
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber).expect("Setting default subscriber failed");
+    // the level comes from the settings, which can change while running. The log goes to stderr,
+    // stdout is for what the commands print.
+    let (level, log_level) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(level)
+        .with(
+            fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_target(false) // Hides the module path to keep it clean, set to true if you want it
+                .with_thread_ids(true), // Helpful for debugging concurrent peer tasks
+        )
+        .init();
 
     let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        error!("Error: Specify at least one torrent file");
-        info!("Usage: {} /path/to/file.torrent", args[0]);
-        return;
-    }
-
-    // TODO validate the files and accept magnet links
-    // We have to potentially make announce an array in the Torrent struct...
-    // magnet link is censored idk if github scans for maliscous file content liek that
-    // magnet:?xt=urn:btih:1A8CXXXXXXXXXXXX4D2DDC3401C0DCF52C9CF9F&dn=XXXXXXXXXXXXXXXXXX&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=udp%3A%2F%2Fopen.demonii.com%3A1337%2Fannounce&tr=http%3A%2F%2Fopen.tracker.cl%3A1337%2Fannounce&tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce&tr=udp%3A%2F%2Ftracker.torrent.eu.org%3A451%2Fannounce&tr=udp%3A%2F%2Fexplodie.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fexodus.desync.com%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.ololosh.space%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.dump.cl%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.bittor.pw%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker-udp.gbitt.info%3A80%2Fannounce&tr=udp%3A%2F%2Fretracker01-msk-virt.corbina.net%3A80%2Fannounce&tr=udp%3A%2F%2Fopen.free-tracker.ga%3A6969%2Fannounce&tr=udp%3A%2F%2Fns-1.x-fins.com%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=http%3A%2F%2Ftracker.openbittorrent.com%3A80%2Fannounce&tr=udp%3A%2F%2Fopentracker.i2p.rocks%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.internetwarriors.net%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.leechers-paradise.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fcoppersurfer.tk%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.zer0day.to%3A1337%2Fannounce
-
-    let mut task_handle = JoinSet::new();
-
-    // download each torrent file
-    for file in &args[1..args.len()] {
-        let copy = file.clone();
-        task_handle.spawn(async move {
-            let torrent = parser::parse_torrent_file(copy.clone()).unwrap_or_else(|e| {
-                error!("Parser error: {e}");
-                panic!()
-            });
-            let file_name = torrent.info.name.clone();
-
-            info!("Downloading {}:\n{}", copy, torrent);
-
-            let discoverer = PeerDiscoverer::new("rBittorrent", 6969, torrent.clone()).await;
-            let mut downloader = Downloader::new(&discoverer, &torrent);
-            downloader.download().await;
-
-            // writing file to disk
-            match &torrent.info.file_tree {
-                FileTree::SingleFile { .. } => {
-                    let mut out_file = File::create_new(file_name)?;
-                    out_file.write_all(&downloader.file_buffer)?;
-                }
-                FileTree::MultiFile { files } => {
-                    let base_dir = PathBuf::from(&file_name);
-                    std::fs::create_dir_all(&base_dir)?;
+    let program = args[0].clone();
+    let Some(command) = args.get(1) else {
+        eprint!("{}", usage(&program));
+        std::process::exit(EXIT_USAGE);
+    };
+    let rest = &args[2..];
+
+    // the one-shot commands print their results, their log only shows problems
+    if matches!(
+        command.as_str(),
+        "info" | "create" | "verify" | "magnet" | "scrape"
+    ) {
+        log_level.modify(|level| *level = LevelFilter::WARN).ok();
+    }
+
+    let result = match command.as_str() {
+        "download" => run_download(rest, log_level).await,
+        "info" => run_info(rest),
+        "create" => run_create(rest),
+        "verify" => run_verify(rest),
+        "seed" => run_seed(rest, log_level).await,
+        "magnet" => run_magnet(rest),
+        "scrape" => run_scrape(rest).await,
+        "tracker" => run_tracker(rest).await,
+        "help" | "-h" | "--help" => {
+            print!("{}", usage(&program));
+            return;
+        }
+        // a bare list of torrents is a download, like before there were commands
+        other
+            if other.starts_with('-')
+                || other.ends_with(".torrent")
+                || Path::new(other).is_file() =>
+        {
+            run_download(&args[1..], log_level).await
+        }
+        other => Err(CliError::Usage(anyhow!("unknown command: {other}"))),
+    };
+
+    if let Err(e) = result {
+        error!("{e}");
+        if let CliError::Usage(_) = e {
+            eprintln!("See `{program} help` for usage");
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn usage(program: &str) -> String {
+    format!(
+        "\
+Usage: {program} COMMAND [ARGS]
+
+Commands:
+  download [OPTIONS] TORRENT...    Download torrents, `{program} TORRENT...` does the same
+  info [--json] TORRENT|MAGNET     Show what a torrent file or magnet link describes
+  create PATH [OPTIONS]            Make a torrent of a file or directory
+  verify [-d DIR] TORRENT          Check the data in DIR against the torrent
+  seed [OPTIONS] TORRENT           Seed data that is already complete
+  magnet TORRENT...                Print the magnet link of torrent files
+  scrape [--timeout SECS] TORRENT|MAGNET
+                                   Ask the trackers how many seeders and leechers there are
+  tracker [OPTIONS]                Run a tracker
+  help                             Show this
+
+download: [--config FILE] [--set SECTION.KEY=VALUE]... [--log-level LEVEL] [-o|--output DIR]
+  [--select INDEX[-INDEX][,...]] [--seed-ratio RATIO] [--sequential] [--state-dir DIR]
+  [--stream-port PORT] [--active-downloads N] [--active-seeds N] [--active-limit N] [--port PORT]
+  [--seed] [--super-seed] [--upload-slots N] [--choker rate-based|round-robin|fastest-upload]
+  [--[torrent-|peer-]download-limit BYTES/S] [--[torrent-|peer-]upload-limit BYTES/S]
+  [--[torrent-]max-connections N] [--max-half-open N] [--ban-file FILE] [--ip-filter FILE]...
+seed: [--config FILE] [--set SECTION.KEY=VALUE]... [-d DIR] [--port PORT] [--super-seed]
+  [--upload-slots N] [--choker rate-based|round-robin|fastest-upload] [--upload-limit BYTES/S]
+  [--peer-upload-limit BYTES/S] [--[torrent-]max-connections N] [--ip-filter FILE]...
+create: [-o OUT] [-t URL[,URL]]... [--piece-length BYTES] [--comment TEXT] [--private]
+  [--source TEXT] [--web-seed URL]... [--follow-symlinks] [--threads N]
+tracker: [--http-port PORT|off] [--udp-port PORT|off] [--interval SECS] [--allow-list FILE]
+  [--trust-announced-ip]
+
+Exit codes: 0 success, {EXIT_FAILURE} failure, {EXIT_USAGE} invalid arguments or settings, {EXIT_PARSE} unparsable torrent
+"
+    )
+}
+
+/// Why a command failed, the exit code tells the kinds apart
+#[derive(Debug)]
+enum CliError {
+    Usage(anyhow::Error),
+    Parse(anyhow::Error),
+    Failed(anyhow::Error),
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::Parse(_) => EXIT_PARSE,
+            CliError::Failed(_) => EXIT_FAILURE,
+        }
+    }
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Usage(e) => write!(f, "Invalid arguments: {e:#}"),
+            CliError::Parse(e) | CliError::Failed(e) => write!(f, "{e:#}"),
+        }
+    }
+}
 
-                    let mut buffer_offset = 0;
+// anything that isn't marked as a usage or parse error where it happens is a failure
+impl From<anyhow::Error> for CliError {
+    fn from(e: anyhow::Error) -> Self {
+        CliError::Failed(e)
+    }
+}
 
-                    for file_info in files {
-                        let mut current_file_path = base_dir.clone();
+type CliResult = Result<(), CliError>;
 
-                        for path_segment in &file_info.path {
-                            current_file_path.push(path_segment);
-                        }
+/// The value that follows `flag`
+fn value(args: &mut std::slice::Iter<String>, flag: &str) -> Result<String, anyhow::Error> {
+    args.next()
+        .cloned()
+        .ok_or_else(|| anyhow!("missing value for {flag}"))
+}
 
-                        if let Some(parent) = current_file_path.parent() {
-                            std::fs::create_dir_all(parent)?;
-                        }
+fn load_torrent(path: &str) -> Result<Torrent, CliError> {
+    parser::parse_torrent_file(path)
+        .map_err(|e| CliError::Parse(e.context(format!("Failed to parse {path}"))))
+}
 
-                        let mut out_file = File::create(&current_file_path)?;
-                        let file_data = &downloader.file_buffer
-                            [buffer_offset..buffer_offset + file_info.length];
-                        out_file.write_all(file_data)?;
+/// What `info` and `scrape` take, a torrent file or a magnet link. Torrent files come with the
+/// magnet link made of them.
+struct Input {
+    magnet: MagnetLink,
+    torrent: Option<Torrent>,
+}
 
-                        buffer_offset += file_info.length;
+fn load_input(arg: &str) -> Result<Input, CliError> {
+    if arg.starts_with("magnet:") {
+        let magnet = MagnetLink::parse(arg)
+            .map_err(|e| CliError::Parse(e.context("Failed to parse the magnet link")))?;
+        return Ok(Input {
+            magnet,
+            torrent: None,
+        });
+    }
+    let torrent = load_torrent(arg)?;
+    let magnet = MagnetLink::from_torrent(&torrent).map_err(CliError::Parse)?;
+    Ok(Input {
+        magnet,
+        torrent: Some(torrent),
+    })
+}
+
+async fn run_download(args: &[String], log_level: LogLevel) -> CliResult {
+    let download = parse_download_args(args).map_err(CliError::Usage)?;
+    if download.torrent_files.is_empty() {
+        return Err(CliError::Usage(anyhow!(
+            "specify at least one torrent file"
+        )));
+    }
+    // TODO download magnet links, that needs the metadata exchange of BEP 9
+    if let Some(magnet) = download
+        .torrent_files
+        .iter()
+        .find(|f| f.starts_with("magnet:"))
+    {
+        return Err(CliError::Usage(anyhow!(
+            "magnet links can't be downloaded yet, use the torrent file: {magnet}"
+        )));
+    }
+    // every torrent is checked before anything starts
+    let mut torrents = Vec::new();
+    for file in &download.torrent_files {
+        let torrent = load_torrent(file)?;
+        if let Some(select) = &download.select {
+            let count = torrent.info.files().len();
+            if let Some(index) = select.iter().find(|index| **index >= count) {
+                return Err(CliError::Usage(anyhow!(
+                    "{file} has {count} files, there is no file {index}"
+                )));
+            }
+        }
+        torrents.push((file, torrent));
+    }
+    let config = download
+        .settings
+        .load(&log_level)
+        .map_err(CliError::Usage)?;
+
+    let seed = config.seed;
+    let streaming = config.stream_port.is_some();
+    let session = Arc::new(
+        Session::new(config)
+            .await
+            .map_err(|e| e.context("Failed to start the session"))?,
+    );
+    reload_on_hangup(&session, download.settings, log_level);
+
+    let mut failed = false;
+    for (file, torrent) in torrents {
+        // torrents saved in the state directory are already back
+        let added = parser::swarm_info_hashes(&torrent.info).and_then(|info_hashes| match session
+            .find_torrent(&info_hashes[0])
+        {
+            Some(handle) => Ok(handle),
+            None => session.add_torrent(torrent),
+        });
+        match added {
+            Ok(handle) => {
+                info!("Downloading {}:\n{}", file, handle.torrent());
+                if let Some(select) = &download.select {
+                    let priorities = (0..handle.torrent().info.files().len())
+                        .map(|index| {
+                            if select.contains(&index) {
+                                FilePriority::Normal
+                            } else {
+                                FilePriority::Skip
+                            }
+                        })
+                        .collect();
+                    if let Err(e) = handle.set_file_priorities(priorities) {
+                        error!("Failed to select files of {file}: {e}");
                     }
                 }
             }
+            Err(e) => {
+                error!("Failed to add {file}: {e}");
+                failed = true;
+            }
+        }
+    }
+
+    // paused torrents would be waited for forever
+    let handles: Vec<_> = session
+        .torrents()
+        .into_iter()
+        .filter(|handle| handle.status().state != TorrentState::Paused)
+        .collect();
+    let wait_all = async {
+        for handle in &handles {
+            if let Err(e) = handle.wait().await {
+                error!("{} failed: {e}", handle.torrent().info.name);
+                failed = true;
+            }
+        }
+        if let Some(ratio) = download.seed_ratio {
+            seed_to_ratio(&handles, ratio).await;
+        } else if (seed || streaming) && !handles.is_empty() {
+            info!("Seeding or streaming, press Ctrl-C to stop");
+            tokio::signal::ctrl_c().await.ok();
+        }
+    };
+    tokio::select! {
+        _ = wait_all => {}
+        _ = tokio::signal::ctrl_c() => info!("Stopping"),
+    }
+    session.save_state();
+    if failed {
+        return Err(CliError::Failed(anyhow!(
+            "Not every torrent could be downloaded"
+        )));
+    }
+    Ok(())
+}
+
+/// Seed until every torrent has uploaded `ratio` times what it has, each one is paused once it
+/// gets there
+async fn seed_to_ratio(handles: &[TorrentHandle], ratio: f64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let mut done = true;
+        for handle in handles {
+            let status = handle.status();
+            match status.state {
+                TorrentState::Seeding if status.ratio >= ratio => {
+                    info!(
+                        "{} reached a ratio of {:.2}, stopping",
+                        status.name, status.ratio
+                    );
+                    handle.pause();
+                }
+                TorrentState::Paused | TorrentState::Finished | TorrentState::Error(_) => {}
+                _ => done = false,
+            }
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+/// Where the settings come from, the settings file and the overrides from the command line. The
+/// environment is read on every load.
+#[derive(Debug, Clone, Default)]
+struct SettingsSource {
+    file: Option<PathBuf>,
+    overrides: Vec<String>,
+    /// Seed mode always seeds and listens, on 6881 unless a port is set
+    seed: bool,
+}
 
-            Ok(())
+impl SettingsSource {
+    /// Load the settings, apply their log level and make the session config of them
+    fn load(&self, log_level: &LogLevel) -> Result<SessionConfig, anyhow::Error> {
+        let file = self
+            .file
+            .clone()
+            .or_else(|| std::env::var_os(settings::CONFIG_ENV).map(PathBuf::from));
+        let settings = Settings::load(file.as_deref(), &self.overrides)?;
+        log_level.modify(|level| *level = settings.log_level().unwrap_or(LevelFilter::INFO))?;
+        let mut config = settings.session_config()?;
+        if self.seed {
+            config.seed = true;
+            config.listen_port = config.listen_port.or(Some(6881));
+        }
+        Ok(config)
+    }
+
+    /// Override `key` with `value`, quoted so it stays a string whatever it looks like
+    fn set_string(&mut self, key: &str, value: &str) {
+        let value = toml::Value::String(value.to_string());
+        self.overrides.push(format!("{key}={value}"));
+    }
+}
+
+type LogLevel = reload::Handle<LevelFilter, Registry>;
+
+/// SIGHUP loads the settings again and reloads the IP filter lists
+fn reload_on_hangup(session: &Arc<Session>, source: SettingsSource, log_level: LogLevel) {
+    #[cfg(unix)]
+    {
+        let session = Arc::clone(session);
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+                return;
+            };
+            while hangup.recv().await.is_some() {
+                let applied = match source.load(&log_level) {
+                    Ok(config) => session.set_config(config).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = applied {
+                    error!("Failed to reload the settings, keeping the old ones: {e}");
+                }
+                if let Err(e) = session.reload_ip_filter() {
+                    error!("Failed to reload IP filter, keeping the old one: {e}");
+                }
+            }
         });
     }
+    #[cfg(not(unix))]
+    let _ = (session, source, log_level);
+}
+
+/// Download mode: settings, torrent files and the files to download of each
+struct DownloadArgs {
+    settings: SettingsSource,
+    torrent_files: Vec<String>,
+    /// Indices into the file list of each torrent, the other files are skipped
+    select: Option<Vec<usize>>,
+    /// Seed each torrent until it uploaded this many times its size, then exit
+    seed_ratio: Option<f64>,
+}
+
+fn parse_download_args(args: &[String]) -> Result<DownloadArgs, anyhow::Error> {
+    let mut source = SettingsSource::default();
+    let mut torrent_files = Vec::new();
+    let mut select = None;
+    let mut seed_ratio = None;
+    let mut ip_filters = Vec::new();
+    let mut args = args.iter();
+
+    // everything that isnt an option is a torrent file
+    while let Some(arg) = args.next() {
+        // flags that are shorthands for a setting
+        let key = match arg.as_str() {
+            "--port" => "network.listen_port",
+            "--stream-port" => "network.stream_port",
+            "--active-downloads" => "queue.active_downloads",
+            "--active-seeds" => "queue.active_seeds",
+            "--active-limit" => "queue.active_limit",
+            "--upload-slots" => "seeding.upload_slots",
+            "--max-connections" => "limits.connections",
+            "--max-half-open" => "limits.half_open",
+            "--torrent-max-connections" => "limits.torrent_connections",
+            "--download-limit" => "limits.download_rate",
+            "--upload-limit" => "limits.upload_rate",
+            "--torrent-download-limit" => "limits.torrent_download_rate",
+            "--torrent-upload-limit" => "limits.torrent_upload_rate",
+            "--peer-download-limit" => "limits.peer_download_rate",
+            "--peer-upload-limit" => "limits.peer_upload_rate",
+            _ => "",
+        };
+        if !key.is_empty() {
+            let value: u64 = value(&mut args, arg)?.parse()?;
+            source.overrides.push(format!("{key}={value}"));
+            continue;
+        }
+
+        match arg.as_str() {
+            "--config" => source.file = Some(PathBuf::from(value(&mut args, arg)?)),
+            "--set" => source.overrides.push(value(&mut args, arg)?),
+            "--log-level" => source.set_string("log.level", &value(&mut args, arg)?),
+            "-o" | "--output" | "-d" | "--download-dir" => {
+                source.set_string("directories.download", &value(&mut args, arg)?)
+            }
+            "--state-dir" => source.set_string("directories.state", &value(&mut args, arg)?),
+            "--choker" => source.set_string("seeding.choker", &value(&mut args, arg)?),
+            "--ban-file" => source.set_string("filter.ban_file", &value(&mut args, arg)?),
+            "--ip-filter" => ip_filters.push(toml::Value::String(value(&mut args, arg)?)),
+            "--select" | "--files" => select = Some(parse_selection(&value(&mut args, arg)?)?),
+            "--seed-ratio" => {
+                let ratio: f64 = value(&mut args, arg)?.parse()?;
+                if !ratio.is_finite() || ratio < 0.0 {
+                    anyhow::bail!("invalid seed ratio {ratio}");
+                }
+                seed_ratio = Some(ratio);
+                source.overrides.push("seeding.enabled=true".to_string());
+            }
+            "--seed" => source.overrides.push("seeding.enabled=true".to_string()),
+            "--sequential" => source
+                .overrides
+                .push("downloading.sequential=true".to_string()),
+            "--super-seed" => source
+                .overrides
+                .push("seeding.super_seeding=true".to_string()),
+            other if other.starts_with("--") => anyhow::bail!("unknown argument: {other}"),
+            _ => torrent_files.push(arg.clone()),
+        }
+    }
+    if !ip_filters.is_empty() {
+        let ip_filters = toml::Value::Array(ip_filters);
+        source
+            .overrides
+            .push(format!("filter.ip_filters={ip_filters}"));
+    }
+
+    Ok(DownloadArgs {
+        settings: source,
+        torrent_files,
+        select,
+        seed_ratio,
+    })
+}
 
-    for res in task_handle.join_all().await {
-        if let Err(e) = res {
-            error!("Task panicked: {:?}", e);
+/// File indices like `0,2-4`
+fn parse_selection(selection: &str) -> Result<Vec<usize>, anyhow::Error> {
+    let mut indices = Vec::new();
+    for part in selection.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.trim().parse()?, last.trim().parse()?);
+                if first > last {
+                    anyhow::bail!("invalid file range {part}");
+                }
+                indices.extend(first..=last);
+            }
+            None => indices.push(part.trim().parse()?),
+        }
+    }
+    Ok(indices)
+}
+
+fn parse_tracker_args(args: &[String]) -> Result<TrackerConfig, anyhow::Error> {
+    let mut config = TrackerConfig::default();
+    let mut args = args.iter();
+
+    // `off` disables that front end
+    let parse_port = |value: Option<&String>| -> Result<Option<u16>, anyhow::Error> {
+        match value.map(String::as_str) {
+            Some("off") => Ok(None),
+            Some(port) => Ok(Some(port.parse()?)),
+            None => Err(anyhow::anyhow!("missing port value")),
+        }
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--http-port" => config.http_port = parse_port(args.next())?,
+            "--udp-port" => config.udp_port = parse_port(args.next())?,
+            "--interval" => {
+                let interval: u32 = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("missing interval value"))?
+                    .parse()?;
+                if interval == 0 {
+                    anyhow::bail!("interval must be at least one second");
+                }
+                config.interval = interval;
+                config.peer_timeout = std::time::Duration::from_secs(2 * u64::from(interval) + 60);
+            }
+            "--trust-announced-ip" => config.trust_announced_ip = true,
+            "--allow-list" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("missing allow-list path"))?;
+                config.allow_list = Some(tracker_server::load_allow_list(path)?);
+            }
+            other => anyhow::bail!("unknown argument: {other}"),
         }
     }
+
+    Ok(config)
+}
+
+async fn run_tracker(args: &[String]) -> CliResult {
+    let config = parse_tracker_args(args).map_err(CliError::Usage)?;
+    tracker_server::run(config)
+        .await
+        .map_err(|e| e.context("Tracker stopped"))?;
+    Ok(())
+}
+
+fn parse_create_args(
+    args: &[String],
+) -> Result<(PathBuf, Option<PathBuf>, CreateOptions), anyhow::Error> {
+    let mut options = CreateOptions::default();
+    let mut input = None;
+    let mut output = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(PathBuf::from(value(&mut args, arg)?)),
+            // every `-t` is its own tier, comma separated URLs share a tier
+            "-t" | "--tracker" => options.trackers.push(
+                value(&mut args, arg)?
+                    .split(',')
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            "--piece-length" => options.piece_length = Some(value(&mut args, arg)?.parse()?),
+            "--comment" => options.comment = Some(value(&mut args, arg)?),
+            "--private" => options.private = true,
+            "--source" => options.source = Some(value(&mut args, arg)?),
+            "--web-seed" => options.web_seeds.push(value(&mut args, arg)?),
+            "--follow-symlinks" => options.follow_symlinks = true,
+            "--threads" => options.threads = Some(value(&mut args, arg)?.parse()?),
+            other if input.is_none() && !other.starts_with('-') => {
+                input = Some(PathBuf::from(other))
+            }
+            other => anyhow::bail!("unknown argument: {other}"),
+        }
+    }
+
+    let input = input.ok_or_else(|| anyhow!("missing path to create a torrent from"))?;
+    Ok((input, output, options))
+}
+
+fn run_create(args: &[String]) -> CliResult {
+    let (input, output, options) = parse_create_args(args).map_err(CliError::Usage)?;
+    let torrent = create::create_torrent(&input, &options)
+        .map_err(|e| e.context("Failed to create torrent"))?;
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.torrent", torrent.info.name)));
+
+    parser::write_torrent_file(&torrent, &output)?;
+    println!(
+        "Created {} with info hash {}",
+        output.display(),
+        parser::calculate_info_hash(&torrent.info)?
+    );
+    Ok(())
+}
+
+fn parse_seed_args(args: &[String]) -> Result<(String, SettingsSource), anyhow::Error> {
+    let mut torrent_file = None;
+    let mut source = SettingsSource::default();
+    let mut ip_filters = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let key = match arg.as_str() {
+            "--port" => "network.listen_port",
+            "--upload-slots" => "seeding.upload_slots",
+            "--upload-limit" => "limits.upload_rate",
+            "--peer-upload-limit" => "limits.peer_upload_rate",
+            "--max-connections" => "limits.connections",
+            "--torrent-max-connections" => "limits.torrent_connections",
+            _ => "",
+        };
+        if !key.is_empty() {
+            let value: u64 = value(&mut args, arg)?.parse()?;
+            source.overrides.push(format!("{key}={value}"));
+            continue;
+        }
+
+        match arg.as_str() {
+            "--config" => source.file = Some(PathBuf::from(value(&mut args, arg)?)),
+            "--set" => source.overrides.push(value(&mut args, arg)?),
+            "-d" | "--download-dir" => {
+                source.set_string("directories.download", &value(&mut args, arg)?)
+            }
+            "--super-seed" => source
+                .overrides
+                .push("seeding.super_seeding=true".to_string()),
+            "--choker" => source.set_string("seeding.choker", &value(&mut args, arg)?),
+            "--ip-filter" => ip_filters.push(toml::Value::String(value(&mut args, arg)?)),
+            other if torrent_file.is_none() && !other.starts_with('-') => {
+                torrent_file = Some(other.to_string())
+            }
+            other => anyhow::bail!("unknown argument: {other}"),
+        }
+    }
+    if !ip_filters.is_empty() {
+        let ip_filters = toml::Value::Array(ip_filters);
+        source
+            .overrides
+            .push(format!("filter.ip_filters={ip_filters}"));
+    }
+    source.seed = true;
+
+    let torrent_file = torrent_file.ok_or_else(|| anyhow!("missing torrent file"))?;
+    Ok((torrent_file, source))
+}
+
+async fn run_seed(args: &[String], log_level: LogLevel) -> CliResult {
+    let (torrent_file, source) = parse_seed_args(args).map_err(CliError::Usage)?;
+    let torrent = load_torrent(&torrent_file)?;
+    let config = source.load(&log_level).map_err(CliError::Usage)?;
+
+    let session = Arc::new(Session::new(config).await?);
+    reload_on_hangup(&session, source, log_level);
+
+    // seeding needs the complete data, don't go looking for peers to fill the gaps
+    let handle = session.add_torrent(torrent)?;
+    let added = Instant::now();
+    loop {
+        match handle.status().state {
+            // takes as long as hashing the data does
+            TorrentState::Checking => {}
+            TorrentState::Queued if added.elapsed() < SEED_QUEUE_TIMEOUT => {}
+            TorrentState::Queued => {
+                return Err(CliError::Failed(anyhow!(
+                    "{} is still queued after {}s, are the active limits too low?",
+                    handle.torrent().info.name,
+                    SEED_QUEUE_TIMEOUT.as_secs()
+                )))
+            }
+            _ => break,
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let status = handle.status();
+    if !status.is_complete() {
+        return Err(CliError::Failed(anyhow!(
+            "only {} of {} pieces of {} are there",
+            status.pieces_done,
+            status.piece_count,
+            status.name
+        )));
+    }
+    handle.wait().await?;
+    info!("Seeding {}, press Ctrl-C to stop", status.name);
+    tokio::signal::ctrl_c().await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// What `info` shows, also its JSON output. Magnet links only know a part of it.
+#[derive(Debug, Serialize)]
+struct InfoDump {
+    name: Option<String>,
+    info_hash: Option<String>,
+    info_hash_v2: Option<String>,
+    /// v1, v2 or hybrid
+    version: Option<&'static str>,
+    total_length: Option<u64>,
+    piece_length: Option<usize>,
+    piece_count: Option<usize>,
+    private: bool,
+    trackers: Vec<String>,
+    web_seeds: Vec<String>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    /// Seconds since the epoch
+    creation_date: Option<i64>,
+    source: Option<String>,
+    files: Vec<FileDump>,
+    magnet: String,
+}
+
+#[derive(Debug, Serialize)]
+struct FileDump {
+    path: String,
+    length: usize,
+}
+
+impl InfoDump {
+    fn new(input: &Input) -> Self {
+        let magnet = &input.magnet;
+        let mut dump = Self {
+            name: magnet.name.clone(),
+            info_hash: magnet.info_hash.map(hex::encode),
+            info_hash_v2: magnet.info_hash_v2.map(hex::encode),
+            version: None,
+            total_length: magnet.length,
+            piece_length: None,
+            piece_count: None,
+            private: false,
+            trackers: magnet.trackers.clone(),
+            web_seeds: magnet.web_seeds.clone(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            source: None,
+            files: Vec::new(),
+            magnet: magnet.to_string(),
+        };
+        let Some(torrent) = &input.torrent else {
+            return dump;
+        };
+
+        let info = &torrent.info;
+        dump.version = Some(match (info.is_v1(), info.is_v2()) {
+            (true, true) => "hybrid",
+            (false, true) => "v2",
+            _ => "v1",
+        });
+        dump.piece_length = Some(info.piece_length);
+        dump.piece_count = Some(info.piece_count());
+        dump.private = info.is_private();
+        dump.comment = torrent.comment.clone();
+        dump.created_by = torrent.created_by.clone();
+        dump.creation_date = torrent.creation_date;
+        dump.source = info.source.clone();
+        dump.files = info
+            .files()
+            .into_iter()
+            .filter(|file| !file.is_padding())
+            .map(|file| FileDump {
+                path: file.path.join("/"),
+                length: file.length,
+            })
+            .collect();
+        dump
+    }
+}
+
+impl Display for InfoDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = &self.name {
+            writeln!(f, "Name:          {name}")?;
+        }
+        if let Some(hash) = &self.info_hash {
+            writeln!(f, "Info hash:     {hash}")?;
+        }
+        if let Some(hash) = &self.info_hash_v2 {
+            writeln!(f, "Info hash v2:  {hash}")?;
+        }
+        if let Some(version) = self.version {
+            writeln!(f, "Version:       {version}")?;
+        }
+        if let Some(length) = self.total_length {
+            writeln!(f, "Size:          {length} bytes")?;
+        }
+        if let (Some(count), Some(length)) = (self.piece_count, self.piece_length) {
+            writeln!(f, "Pieces:        {count} of {length} bytes")?;
+        }
+        if self.private {
+            writeln!(f, "Private:       yes")?;
+        }
+        if let Some(comment) = &self.comment {
+            writeln!(f, "Comment:       {comment}")?;
+        }
+        if let Some(created_by) = &self.created_by {
+            writeln!(f, "Created by:    {created_by}")?;
+        }
+        if let Some(date) = self.creation_date {
+            writeln!(f, "Creation date: {date}")?;
+        }
+        if let Some(source) = &self.source {
+            writeln!(f, "Source:        {source}")?;
+        }
+        if !self.trackers.is_empty() {
+            writeln!(f, "Trackers:")?;
+            for tracker in &self.trackers {
+                writeln!(f, "  {tracker}")?;
+            }
+        }
+        if !self.web_seeds.is_empty() {
+            writeln!(f, "Web seeds:")?;
+            for seed in &self.web_seeds {
+                writeln!(f, "  {seed}")?;
+            }
+        }
+        if !self.files.is_empty() {
+            writeln!(f, "Files:")?;
+            for file in &self.files {
+                writeln!(f, "  {:>14}  {}", file.length, file.path)?;
+            }
+        }
+        writeln!(f, "Magnet:        {}", self.magnet)
+    }
+}
+
+fn run_info(args: &[String]) -> CliResult {
+    let mut json = false;
+    let mut input = None;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            other if input.is_none() && !other.starts_with('-') => input = Some(other),
+            other => return Err(CliError::Usage(anyhow!("unknown argument: {other}"))),
+        }
+    }
+    let input =
+        input.ok_or_else(|| CliError::Usage(anyhow!("missing torrent file or magnet link")))?;
+
+    let dump = InfoDump::new(&load_input(input)?);
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&dump).map_err(anyhow::Error::from)?
+        );
+    } else {
+        print!("{dump}");
+    }
+    Ok(())
+}
+
+fn run_magnet(args: &[String]) -> CliResult {
+    if args.is_empty() {
+        return Err(CliError::Usage(anyhow!("missing torrent file")));
+    }
+    if let Some(other) = args.iter().find(|arg| arg.starts_with('-')) {
+        return Err(CliError::Usage(anyhow!("unknown argument: {other}")));
+    }
+    for file in args {
+        let torrent = load_torrent(file)?;
+        let magnet = MagnetLink::from_torrent(&torrent).map_err(CliError::Parse)?;
+        println!("{magnet}");
+    }
+    Ok(())
+}
+
+/// Hash check the data in a download directory, prints how complete each file is and fails if
+/// anything is missing
+fn run_verify(args: &[String]) -> CliResult {
+    let mut download_dir = PathBuf::from(".");
+    let mut torrent_file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-d" | "--download-dir" => {
+                download_dir = PathBuf::from(value(&mut args, arg).map_err(CliError::Usage)?)
+            }
+            other if torrent_file.is_none() && !other.starts_with('-') => {
+                torrent_file = Some(other)
+            }
+            other => return Err(CliError::Usage(anyhow!("unknown argument: {other}"))),
+        }
+    }
+    let torrent_file =
+        torrent_file.ok_or_else(|| CliError::Usage(anyhow!("missing torrent file")))?;
+    let torrent = load_torrent(torrent_file)?;
+    let info = &torrent.info;
+
+    let data = Storage::new(
+        info,
+        &download_dir,
+        vec![FilePriority::default(); info.files().len()],
+    );
+    let parts = data.load_part_file()?;
+    let check = |index| -> Result<bool, anyhow::Error> {
+        peer_connection::check_piece(&torrent, index, &data.read_piece(index)?)
+    };
+    let mut have = vec![false; info.piece_count()];
+    for (index, have) in have.iter_mut().enumerate() {
+        *have = check(index)?;
+    }
+    // boundary pieces of skipped files are read from the part file, unless it is corrupt
+    for index in parts {
+        if !have[index] {
+            data.forget_part(index);
+            have[index] = check(index)?;
+        }
+    }
+
+    let mut offset = 0;
+    for (file, path) in info
+        .files()
+        .into_iter()
+        .zip(storage::file_paths(info, &download_dir))
+    {
+        let start = offset;
+        offset += file.length;
+        let Some(path) = path else {
+            continue;
+        };
+        let pieces = if file.length == 0 {
+            0..0
+        } else {
+            start / info.piece_length..offset.div_ceil(info.piece_length)
+        };
+        let good = pieces.clone().filter(|index| have[*index]).count();
+        let status = if !path.exists() && file.length > 0 {
+            "missing".to_string()
+        } else if good == pieces.len() {
+            "ok".to_string()
+        } else {
+            format!("{good}/{} pieces", pieces.len())
+        };
+        println!("{status:>14}  {}", file.path.join("/"));
+    }
+
+    let done = have.iter().filter(|have| **have).count();
+    println!(
+        "{done} of {} pieces of {} are complete",
+        have.len(),
+        info.name
+    );
+    if done < have.len() {
+        return Err(CliError::Failed(anyhow!(
+            "{} is incomplete in {}",
+            info.name,
+            download_dir.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Ask every tracker of the torrent about each of its swarms, fails if none of them answers
+async fn run_scrape(args: &[String]) -> CliResult {
+    let mut timeout = Duration::from_secs(5);
+    let mut input = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let secs: u64 = value(&mut args, arg)
+                    .and_then(|secs| Ok(secs.parse()?))
+                    .map_err(CliError::Usage)?;
+                timeout = Duration::from_secs(secs);
+            }
+            other if input.is_none() && !other.starts_with('-') => input = Some(other),
+            other => return Err(CliError::Usage(anyhow!("unknown argument: {other}"))),
+        }
+    }
+    let input =
+        input.ok_or_else(|| CliError::Usage(anyhow!("missing torrent file or magnet link")))?;
+    let input = load_input(input)?;
+    let magnet = &input.magnet;
+    if magnet.trackers.is_empty() {
+        return Err(CliError::Failed(anyhow!("There are no trackers to scrape")));
+    }
+
+    let info_hashes = magnet.swarm_info_hashes();
+    let mut answered = false;
+    for tracker in &magnet.trackers {
+        let url = match AnnounceUrl::parse(tracker) {
+            Ok(url) if url.is_supported() => url,
+            _ => {
+                error!("{tracker}: scraping isn't supported for this kind of tracker");
+                continue;
+            }
+        };
+        for info_hash in &info_hashes {
+            // hybrid torrents are in two swarms
+            let swarm = if info_hashes.len() > 1 {
+                format!(" ({})", hex::encode(info_hash))
+            } else {
+                String::new()
+            };
+            match discovery::scrape(&url, info_hash, timeout).await {
+                Ok(stats) => {
+                    answered = true;
+                    println!(
+                        "{tracker}{swarm}: {} seeders, {} leechers, {} downloads",
+                        stats.seeders, stats.leechers, stats.completed
+                    );
+                }
+                Err(e) => error!("{tracker}{swarm}: {e}"),
+            }
+        }
+    }
+    if !answered {
+        return Err(CliError::Failed(anyhow!("No tracker answered")));
+    }
+    Ok(())
 }
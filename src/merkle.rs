@@ -0,0 +1,340 @@
+//! SHA-256 merkle trees as used by BitTorrent v2 (BEP 52).
+//!
+//! Every file has its own tree. The leaves are the hashes of 16KiB blocks, the number of leaves
+//! is padded to a power of two with zero hashes. The `piece layers` in the metainfo hold the layer
+//! of the tree where one node covers exactly one piece, so a whole piece can be checked without
+//! knowing the rest of the file.
+use sha2::{Digest, Sha256};
+
+/// Size of a leaf block
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+pub fn hash_block(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a subtree with `width` leaves where every leaf is zero
+fn zero_root(width: usize) -> Hash {
+    let mut hash = [0u8; 32];
+    let mut w = 1;
+    while w < width {
+        hash = hash_pair(&hash, &hash);
+        w *= 2;
+    }
+    hash
+}
+
+/// Root of a tree with `width` leaves, `width` must be a power of two. Missing nodes are filled
+/// with `pad`, which is the root of an all zero subtree at the height of `nodes`.
+pub fn root_from_layer(nodes: &[Hash], width: usize, pad: Hash) -> Hash {
+    debug_assert!(width.is_power_of_two() && nodes.len() <= width);
+
+    let mut layer = nodes.to_vec();
+    layer.resize(width, pad);
+    while layer.len() > 1 {
+        layer = parent_layer(&layer);
+    }
+    layer[0]
+}
+
+fn parent_layer(layer: &[Hash]) -> Vec<Hash> {
+    layer
+        .chunks_exact(2)
+        .map(|pair| hash_pair(&pair[0], &pair[1]))
+        .collect()
+}
+
+/// Leaf hashes of `data`
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(hash_block).collect()
+}
+
+/// The node covering one piece, as listed in `piece layers`. The last piece of a file is padded
+/// with zero leaves to the full piece width.
+pub fn piece_root(data: &[u8], piece_length: usize) -> Hash {
+    root_from_layer(&block_hashes(data), piece_length / BLOCK_SIZE, [0u8; 32])
+}
+
+/// Root of the whole file tree for a file that fits into one piece, those have no piece layer and
+/// are checked against `pieces root` directly
+pub fn file_root(data: &[u8]) -> Hash {
+    let leaves = block_hashes(data);
+    let width = leaves.len().max(1).next_power_of_two();
+    root_from_layer(&leaves, width, [0u8; 32])
+}
+
+/// Check a piece layer against the `pieces root` of its file
+pub fn verify_piece_layer(layer: &[u8], pieces_root: &[u8], piece_length: usize) -> bool {
+    if layer.is_empty() || !layer.len().is_multiple_of(32) {
+        return false;
+    }
+
+    let nodes: Vec<Hash> = layer
+        .chunks_exact(32)
+        .map(|c| c.try_into().unwrap())
+        .collect();
+    let width = nodes.len().next_power_of_two();
+    let pad = zero_root(piece_length / BLOCK_SIZE);
+    root_from_layer(&nodes, width, pad) == pieces_root
+}
+
+/// Check a piece of a file. `piece_layer` is the verified piece layer of the file, or `None` if
+/// the file fits into a single piece.
+pub fn verify_piece(
+    data: &[u8],
+    piece_in_file: usize,
+    piece_length: usize,
+    pieces_root: &[u8],
+    piece_layer: Option<&[u8]>,
+) -> bool {
+    match piece_layer {
+        Some(layer) => {
+            let start = piece_in_file * 32;
+            layer
+                .get(start..start + 32)
+                .is_some_and(|expected| piece_root(data, piece_length) == expected)
+        }
+        None => piece_in_file == 0 && file_root(data) == pieces_root,
+    }
+}
+
+/// The `length` nodes at `index` of a piece layer followed by the uncle hashes of `proof_layers`
+/// layers above them, what a `hashes` message carries. `None` if they reach past the tree.
+pub fn piece_layer_hashes(
+    layer: &[u8],
+    piece_length: usize,
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<Hash>> {
+    if layer.is_empty() || !layer.len().is_multiple_of(32) {
+        return None;
+    }
+    let mut nodes: Vec<Hash> = layer
+        .chunks_exact(32)
+        .map(|c| c.try_into().unwrap())
+        .collect();
+    let width = nodes.len().next_power_of_two();
+    if !length.is_power_of_two()
+        || !index.is_multiple_of(length)
+        || index + length > width
+        || proof_layers > (width / length).trailing_zeros() as usize
+    {
+        return None;
+    }
+    nodes.resize(width, zero_root(piece_length / BLOCK_SIZE));
+
+    let mut hashes = nodes[index..index + length].to_vec();
+    // up to the layer where the run is a single node, the uncles start there
+    let mut layer = nodes;
+    for _ in 0..length.trailing_zeros() {
+        layer = parent_layer(&layer);
+    }
+    let mut position = index / length;
+    for _ in 0..proof_layers {
+        hashes.push(layer[position ^ 1]);
+        layer = parent_layer(&layer);
+        position /= 2;
+    }
+    Some(hashes)
+}
+
+/// Verify that `hashes`, a run of nodes starting at `index` in some layer of the tree, belongs to
+/// the tree with `root`. `proof` holds the uncle hashes from the layer above the run upwards, as
+/// sent in a `hashes` message.
+pub fn verify_hashes(hashes: &[Hash], index: usize, proof: &[Hash], root: &[u8]) -> bool {
+    if hashes.is_empty() || !hashes.len().is_power_of_two() || !index.is_multiple_of(hashes.len()) {
+        return false;
+    }
+
+    // reduce the run to a single subtree root first
+    let mut node = root_from_layer(hashes, hashes.len(), [0u8; 32]);
+    let mut position = index / hashes.len();
+
+    for uncle in proof {
+        node = if position.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        position /= 2;
+    }
+
+    node == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECE_LENGTH: usize = 2 * BLOCK_SIZE;
+    // known vectors, computed separately with Python's hashlib
+    const LEAF_0: &str = "111ce3c2a38d83a2e4706bde4abddd509d7f8248116c6832b06745bdc349e09f";
+    const PIECE_0: &str = "1a1fb8144cac6f79b4f40c57031dc35f63b3c3f9fbe828413bc0d9210865d72a";
+    const PIECE_1: &str = "729dd197ee4b3105b86eefb543fdc53def9abbd5a37d61c69efb12be4bf1fcce";
+    const PIECE_2: &str = "23f0cd1ac061c8eede08c3952b57790d69547b5af8949e46088116e24afb40f3";
+    const PIECES_01: &str = "8aa46b99f2907a2e06a12eed5ab6fcd5bd86bc7f49fe062eeaa78a4304b314eb";
+    const PIECE_2_PAD: &str = "f5594b052446af16bfe5f82c34193cf404753088e538bbbc2b6dfa5dae4e00ce";
+    const ZERO_PIECE: &str = "f5a5fd42d16a20302798ef6ed309979b43003d2320d9f0e8ea9831a92759fb4b";
+    const ROOT: &str = "884e2538aa97b45e54dfb419d95244756ef6c95f4d087d4a09a45418b424f89b";
+    const SMALL_ROOT: &str = "5b4528a01480e6bf24a0aeca62f9e4081e736697bc43d52db8bf88480e3b040e";
+
+    /// Four full blocks, block `i` filled with `i + 1`, and 1000 bytes of 5: three pieces
+    fn file() -> Vec<u8> {
+        let mut data: Vec<u8> = (1..=4).flat_map(|i| [i; BLOCK_SIZE]).collect();
+        data.extend([5; 1000]);
+        data
+    }
+
+    fn hash(hex: &str) -> Hash {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    fn piece_layer(data: &[u8]) -> Vec<u8> {
+        data.chunks(PIECE_LENGTH)
+            .flat_map(|piece| piece_root(piece, PIECE_LENGTH))
+            .collect()
+    }
+
+    #[test]
+    fn hashes_blocks_and_pieces() {
+        let data = file();
+        assert_eq!(
+            hex::encode(hash_block(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(block_hashes(&data).len(), 5);
+        assert_eq!(block_hashes(&data)[0], hash(LEAF_0));
+        assert_eq!(
+            piece_root(&data[..PIECE_LENGTH], PIECE_LENGTH),
+            hash(PIECE_0)
+        );
+        // the last piece is padded with a zero leaf
+        assert_eq!(
+            piece_root(&data[2 * PIECE_LENGTH..], PIECE_LENGTH),
+            hash(PIECE_2)
+        );
+        assert_eq!(file_root(&data), hash(ROOT));
+        assert_eq!(file_root(&[9; 20_000]), hash(SMALL_ROOT));
+    }
+
+    #[test]
+    fn root_from_layer_pads_with_zero_subtrees() {
+        let pieces = [hash(PIECE_0), hash(PIECE_1), hash(PIECE_2)];
+        assert_eq!(root_from_layer(&pieces, 4, hash(ZERO_PIECE)), hash(ROOT));
+        assert_ne!(root_from_layer(&pieces, 4, [0; 32]), hash(ROOT));
+        assert_eq!(root_from_layer(&[hash(ROOT)], 1, [0; 32]), hash(ROOT));
+    }
+
+    #[test]
+    fn verifies_piece_layers() {
+        let layer = piece_layer(&file());
+        assert!(verify_piece_layer(&layer, &hash(ROOT), PIECE_LENGTH));
+
+        let mut tampered = layer.clone();
+        tampered[40] ^= 1;
+        assert!(!verify_piece_layer(&tampered, &hash(ROOT), PIECE_LENGTH));
+        assert!(!verify_piece_layer(&layer[..64], &hash(ROOT), PIECE_LENGTH));
+        assert!(!verify_piece_layer(&layer[..50], &hash(ROOT), PIECE_LENGTH));
+        assert!(!verify_piece_layer(&[], &hash(ROOT), PIECE_LENGTH));
+    }
+
+    #[test]
+    fn verifies_pieces() {
+        let data = file();
+        let layer = piece_layer(&data);
+        let pieces: Vec<&[u8]> = data.chunks(PIECE_LENGTH).collect();
+        for (index, piece) in pieces.iter().enumerate() {
+            assert!(verify_piece(
+                piece,
+                index,
+                PIECE_LENGTH,
+                &hash(ROOT),
+                Some(&layer)
+            ));
+        }
+        assert!(!verify_piece(
+            pieces[0],
+            1,
+            PIECE_LENGTH,
+            &hash(ROOT),
+            Some(&layer)
+        ));
+        assert!(!verify_piece(
+            pieces[0],
+            3,
+            PIECE_LENGTH,
+            &hash(ROOT),
+            Some(&layer)
+        ));
+
+        // a file that fits into one piece is checked against its root
+        let small = [9; 20_000];
+        assert!(verify_piece(
+            &small,
+            0,
+            PIECE_LENGTH,
+            &hash(SMALL_ROOT),
+            None
+        ));
+        assert!(!verify_piece(
+            &small[1..],
+            0,
+            PIECE_LENGTH,
+            &hash(SMALL_ROOT),
+            None
+        ));
+        assert!(!verify_piece(
+            &small,
+            1,
+            PIECE_LENGTH,
+            &hash(SMALL_ROOT),
+            None
+        ));
+    }
+
+    #[test]
+    fn serves_and_verifies_hashes_with_proofs() {
+        let layer = piece_layer(&file());
+        let cases = [
+            // index, length, proof layers, expected hashes
+            (0, 4, 0, vec![PIECE_0, PIECE_1, PIECE_2, ZERO_PIECE]),
+            (2, 2, 1, vec![PIECE_2, ZERO_PIECE, PIECES_01]),
+            (0, 1, 2, vec![PIECE_0, PIECE_1, PIECE_2_PAD]),
+        ];
+        for (index, length, proof_layers, expected) in cases {
+            let hashes =
+                piece_layer_hashes(&layer, PIECE_LENGTH, index, length, proof_layers).unwrap();
+            let expected: Vec<Hash> = expected.into_iter().map(hash).collect();
+            assert_eq!(hashes, expected, "{index} {length} {proof_layers}");
+            // every case reaches up to the root
+            let (run, proof) = hashes.split_at(length);
+            assert!(verify_hashes(run, index, proof, &hash(ROOT)));
+        }
+
+        let (run, proof) = (&[hash(PIECE_2), hash(ZERO_PIECE)], &[hash(PIECES_01)]);
+        assert!(verify_hashes(run, 2, proof, &hash(ROOT)));
+        assert!(!verify_hashes(run, 0, proof, &hash(ROOT)));
+        assert!(!verify_hashes(&run[..1], 2, proof, &hash(ROOT)));
+        assert!(!verify_hashes(&[hash(PIECE_0); 3], 0, &[], &hash(ROOT)));
+        assert!(!verify_hashes(&[], 0, &[], &hash(ROOT)));
+
+        for (index, length, proof_layers) in [(1, 2, 0), (0, 3, 0), (4, 1, 0), (0, 2, 2), (0, 8, 0)]
+        {
+            assert_eq!(
+                piece_layer_hashes(&layer, PIECE_LENGTH, index, length, proof_layers),
+                None,
+                "{index} {length} {proof_layers}"
+            );
+        }
+    }
+}
@@ -1,12 +1,15 @@
 use anyhow::anyhow;
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::Path;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 /// Metainfo files (also known as .torrent files)
 pub struct Torrent {
     /// The URL of the tracker.
@@ -19,17 +22,42 @@ pub struct Torrent {
     /// each inner Vec is a tier, tried in order,
     /// tiers themselves are shuffled/tried in order per BEP 12.
     #[serde(default)]
-    #[serde(rename(deserialize = "announce-list"))]
+    #[serde(rename = "announce-list")]
     #[serde(deserialize_with = "deserialize_nested_announce_list")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub announce_list: Option<Vec<Vec<AnnounceUrl>>>,
     pub info: Info,
     /// Purely informational
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub comment: Option<String>,
-    #[serde(rename(deserialize = "created by"))]
+    #[serde(rename = "created by")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub created_by: Option<String>,
+    /// Creation time of the torrent in standard UNIX epoch format
+    #[serde(rename = "creation date")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub creation_date: Option<i64>,
     /// Indicates the character encoding used for string fields in the torrent
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub encoding: Option<String>,
+    /// Web seed URLs (BEP 19), the key may either be a single string or a list of strings
+    #[serde(default)]
+    #[serde(rename = "url-list")]
+    #[serde(deserialize_with = "deserialize_url_list")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub url_list: Vec<String>,
+    /// Hoffman style HTTP seed scripts (BEP 17)
+    #[serde(default)]
+    #[serde(rename = "httpseeds")]
+    #[serde(deserialize_with = "deserialize_url_list")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub http_seeds: Vec<String>,
+    /// BEP 52: maps the `pieces root` of every file larger than one piece to the concatenated
+    /// SHA-256 hashes of the merkle tree layer where each node covers one piece
+    #[serde(default)]
+    #[serde(rename = "piece layers")]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub piece_layers: HashMap<ByteBuf, ByteBuf>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -45,14 +73,36 @@ pub struct Info {
     #[serde(rename(serialize = "piece length", deserialize = "piece length"))]
     pub piece_length: usize,
     /// Each entry is the SHA1 hash of the piece at the corresponding index. Should be a multiple
-    /// of 20.
+    /// of 20. Missing in v2 only torrents.
+    #[serde(default)]
     #[serde(with = "serde_bytes")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub pieces: Vec<u8>,
     #[serde(flatten)]
     pub file_tree: FileTree,
+    /// BEP 27: if set to 1 the client must only get peers from the trackers in the metainfo
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    /// Tags the torrent with its origin, so the same content published in different places ends
+    /// up with a distinct info hash
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// BEP 52: 2 for v2 and hybrid torrents, v1 torrents dont have this key
+    #[serde(default)]
+    #[serde(rename = "meta version")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta_version: Option<u8>,
+    /// BEP 52 file tree, directories are dictionaries keyed by their name and files are
+    /// dictionaries with a single empty key
+    #[serde(default)]
+    #[serde(rename = "file tree")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v2_file_tree: Option<BTreeMap<String, FileTreeNode>>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub enum AnnounceUrl {
     #[serde(rename = "http")]
     Http(String),
@@ -72,6 +122,16 @@ impl Display for AnnounceUrl {
     }
 }
 
+// announce URLs are plain strings in the metainfo, the variant is only derived from the scheme
+impl Serialize for AnnounceUrl {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
 impl AnnounceUrl {
     pub fn parse(s: &str) -> Result<Self, String> {
         if s.starts_with("http://") || s.starts_with("https://") {
@@ -123,6 +183,24 @@ where
     }
 }
 
+fn deserialize_url_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlList {
+        Single(String),
+        Multiple(Vec<String>),
+    }
+
+    Ok(match UrlList::deserialize(deserializer)? {
+        UrlList::Single(url) if url.is_empty() => Vec::new(),
+        UrlList::Single(url) => vec![url],
+        UrlList::Multiple(urls) => urls,
+    })
+}
+
 /// Distinguish between multi- and singlefile torrents as they need to be handles differently
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
@@ -132,9 +210,55 @@ pub enum FileTree {
         /// Length of the file in bytes. Presence of this field indicates that the dictionary
         /// describes a file, not a directory. Which means it must not have any sibling entries.
         length: usize,
+        /// BEP 47 attributes of the file, see `FileInfo.attr`
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        attr: Option<String>,
     },
     /// Set of files that go in a directory structure
     MultiFile { files: Vec<FileInfo> },
+    /// v2 only torrents describe their files in `Info.v2_file_tree` instead
+    V2Only {},
+}
+
+/// A node of the BEP 52 file tree
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    File {
+        #[serde(rename = "")]
+        file: V2File,
+    },
+    Directory(BTreeMap<String, FileTreeNode>),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct V2File {
+    pub length: usize,
+    /// Root hash of the merkle tree over the 16KiB blocks of the file, absent for empty files
+    #[serde(default)]
+    #[serde(rename = "pieces root")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pieces_root: Option<ByteBuf>,
+    /// BEP 47 attributes of the file, see `FileInfo.attr`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    #[serde(default)]
+    #[serde(rename = "symlink path")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlink_path: Option<Vec<String>>,
+}
+
+/// Where a file of a v2 torrent lives in the piece address space
+#[derive(Debug, Clone)]
+pub struct V2FileLocation {
+    pub path: Vec<String>,
+    pub file: V2File,
+    /// Index of the first piece of this file
+    pub first_piece: usize,
+    /// Byte offset of the file in the torrent, always a multiple of the piece length
+    pub offset: usize,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -143,6 +267,48 @@ pub struct FileInfo {
     pub length: usize,
     /// Subdirectory names
     pub path: Vec<String>,
+    /// BEP 47 file attributes, a set of characters in no particular order:
+    /// `p` padding file, `x` executable, `h` hidden, `l` symlink. Unknown characters are ignored.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    /// Target of the symlink relative to the torrent root, only present with attribute `l`
+    #[serde(default)]
+    #[serde(rename = "symlink path")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlink_path: Option<Vec<String>>,
+    /// SHA1 of the whole file, purely informational
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<ByteBuf>,
+}
+
+impl FileInfo {
+    fn has_attr(&self, flag: char) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains(flag))
+    }
+
+    /// Padding files only exist to align the next file to a piece boundary, they take up piece
+    /// space but are never written to disk. Older BitComet torrents mark them by name only.
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+            || self
+                .path
+                .last()
+                .is_some_and(|name| name.starts_with("_____padding_file_"))
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.has_attr('h')
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.has_attr('l') && self.symlink_path.is_some()
+    }
 }
 
 // Parse the torretn file into a `Torrent` object
@@ -151,9 +317,179 @@ pub fn parse_torrent_file<P: AsRef<Path>>(path: P) -> Result<Torrent, anyhow::Er
     let mut buf = Vec::new();
     file.read_to_end(&mut buf)?;
     let torrent: Torrent = serde_bencode::de::from_bytes(&buf)?;
+    let info = &torrent.info;
+    if let Some(version) = info.meta_version.filter(|version| *version != 2) {
+        anyhow::bail!("Unsupported meta version {version}");
+    }
+    // without `length` or `files` any info dictionary deserializes as v2 only
+    if matches!(info.file_tree, FileTree::V2Only {}) && !info.is_v2() {
+        anyhow::bail!("Info dictionary has neither `length`, `files` nor a v2 `file tree`");
+    }
     Ok(torrent)
 }
 
+/// Bencode the `Torrent` and write it to `path`
+pub fn write_torrent_file<P: AsRef<Path>>(torrent: &Torrent, path: P) -> Result<(), anyhow::Error> {
+    let buf = serde_bencode::ser::to_bytes(torrent)?;
+    let mut file = File::create(path)?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+impl Info {
+    /// BEP 27 private torrent, peers may only come from the trackers in the metainfo
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Whether the torrent has a BEP 52 file tree
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.v2_file_tree.is_some()
+    }
+
+    /// Whether the torrent can be downloaded by v1 peers, true for v1 and hybrid torrents
+    pub fn is_v1(&self) -> bool {
+        !matches!(self.file_tree, FileTree::V2Only {})
+    }
+
+    /// Files of a v2 torrent in file tree order
+    pub fn v2_files(&self) -> Vec<V2FileLocation> {
+        fn walk(
+            tree: &BTreeMap<String, FileTreeNode>,
+            prefix: &mut Vec<String>,
+            out: &mut Vec<(Vec<String>, V2File)>,
+        ) {
+            for (name, node) in tree {
+                prefix.push(name.clone());
+                match node {
+                    FileTreeNode::File { file } => out.push((prefix.clone(), file.clone())),
+                    FileTreeNode::Directory(children) => walk(children, prefix, out),
+                }
+                prefix.pop();
+            }
+        }
+
+        let mut files = Vec::new();
+        if let Some(tree) = &self.v2_file_tree {
+            walk(tree, &mut Vec::new(), &mut files);
+        }
+
+        let mut locations = Vec::new();
+        let mut first_piece = 0;
+        for (path, file) in files {
+            // empty files and symlinks dont take up any pieces
+            let pieces = file.length.div_ceil(self.piece_length);
+            locations.push(V2FileLocation {
+                path,
+                file,
+                first_piece,
+                offset: first_piece * self.piece_length,
+            });
+            first_piece += pieces;
+        }
+        locations
+    }
+
+    /// Whether the content is a single file named `Info.name`
+    pub fn is_single_file(&self) -> bool {
+        match &self.file_tree {
+            FileTree::SingleFile { .. } => true,
+            FileTree::MultiFile { .. } => false,
+            FileTree::V2Only {} => self.v2_file_tree.as_ref().is_some_and(|tree| {
+                tree.len() == 1 && matches!(tree.values().next(), Some(FileTreeNode::File { .. }))
+            }),
+        }
+    }
+
+    /// The files in the order they occupy the piece address space. For v2 only torrents the
+    /// alignment gaps between files are returned as `.pad` files, the same way hybrid torrents
+    /// list them.
+    pub fn files(&self) -> Vec<FileInfo> {
+        match &self.file_tree {
+            FileTree::SingleFile { length, attr } => vec![FileInfo {
+                length: *length,
+                path: vec![self.name.clone()],
+                attr: attr.clone(),
+                symlink_path: None,
+                sha1: None,
+            }],
+            FileTree::MultiFile { files } => files.clone(),
+            FileTree::V2Only {} => {
+                let mut files = Vec::new();
+                let mut end = 0;
+                for location in self.v2_files() {
+                    if location.file.length > 0 && location.offset > end {
+                        let gap = location.offset - end;
+                        files.push(FileInfo {
+                            length: gap,
+                            path: vec![".pad".to_string(), gap.to_string()],
+                            attr: Some("p".to_string()),
+                            symlink_path: None,
+                            sha1: None,
+                        });
+                    }
+                    end = location.offset + location.file.length;
+                    files.push(FileInfo {
+                        length: location.file.length,
+                        path: location.path,
+                        attr: location.file.attr,
+                        symlink_path: location.file.symlink_path,
+                        sha1: None,
+                    });
+                }
+                files
+            }
+        }
+    }
+
+    /// Total size of the piece address space in bytes
+    pub fn total_length(&self) -> usize {
+        self.files().iter().map(|f| f.length).sum()
+    }
+
+    pub fn piece_count(&self) -> usize {
+        if self.is_v1() {
+            self.pieces.len() / 20
+        } else {
+            self.v2_files()
+                .last()
+                .map(|l| l.first_piece + l.file.length.div_ceil(self.piece_length))
+                .unwrap_or(0)
+        }
+    }
+
+    /// Size of the piece at `index`, only the last piece of the torrent (or in v2 only torrents,
+    /// of a file) is shorter than `piece_length`
+    pub fn piece_size(&self, index: usize) -> usize {
+        if self.is_v1() {
+            let total_length = self.total_length();
+            if index + 1 == self.piece_count() {
+                total_length - index * self.piece_length
+            } else {
+                self.piece_length
+            }
+        } else {
+            self.v2_piece_location(index)
+                .map(|(location, piece)| {
+                    let start = piece * self.piece_length;
+                    self.piece_length.min(location.file.length - start)
+                })
+                .unwrap_or(0)
+        }
+    }
+
+    /// The v2 file containing the piece at `index` and the index of the piece within that file
+    pub fn v2_piece_location(&self, index: usize) -> Option<(V2FileLocation, usize)> {
+        self.v2_files().into_iter().find_map(|location| {
+            let pieces = location.file.length.div_ceil(self.piece_length);
+            (index >= location.first_piece && index < location.first_piece + pieces).then(|| {
+                let piece = index - location.first_piece;
+                (location, piece)
+            })
+        })
+    }
+}
+
 /// Calculate info hash as a hex encoded string
 pub fn calculate_info_hash(info_dict: &Info) -> Result<String, anyhow::Error> {
     Ok(hex::encode(calculate_info_hash_bytes(info_dict)?))
@@ -167,6 +503,26 @@ pub fn calculate_info_hash_bytes(info_dict: &Info) -> Result<[u8; 20], anyhow::E
     Ok(hasher.finalize().into())
 }
 
+/// Calculate the BEP 52 info hash, the SHA-256 of the bencoded info dictionary
+pub fn calculate_info_hash_v2_bytes(info_dict: &Info) -> Result<[u8; 32], anyhow::Error> {
+    let bencoded_info_dict = serde_bencode::ser::to_bytes(info_dict)?;
+    Ok(Sha256::digest(&bencoded_info_dict).into())
+}
+
+/// The info hashes of every swarm the torrent takes part in. v2 swarms use the v2 info hash
+/// truncated to 20 bytes, hybrid torrents join both swarms.
+pub fn swarm_info_hashes(info_dict: &Info) -> Result<Vec<[u8; 20]>, anyhow::Error> {
+    let mut hashes = Vec::new();
+    if info_dict.is_v1() {
+        hashes.push(calculate_info_hash_bytes(info_dict)?);
+    }
+    if info_dict.is_v2() {
+        let v2 = calculate_info_hash_v2_bytes(info_dict)?;
+        hashes.push(v2[..20].try_into()?);
+    }
+    Ok(hashes)
+}
+
 /// Get the hash of each piece in the metainfo file
 pub fn get_pieces_hashes(info_dict: &Info) -> Result<Vec<String>, anyhow::Error> {
     // check if info hash is a multiple of 20
@@ -209,6 +565,291 @@ impl Display for Torrent {
         if let Some(encoding) = &self.encoding {
             writeln!(f, "Encoding: {}", encoding)?;
         }
+        if self.info.is_private() {
+            writeln!(f, "Private: yes")?;
+        }
+        if let Some(source) = &self.info.source {
+            writeln!(f, "Source: {}", source)?;
+        }
+        if !self.url_list.is_empty() {
+            writeln!(f, "Web seeds: {:?}", self.url_list)?;
+        }
+        if !self.http_seeds.is_empty() {
+            writeln!(f, "HTTP seeds: {:?}", self.http_seeds)?;
+        }
+        if self.info.is_v2() {
+            writeln!(
+                f,
+                "Info hash v2: {}",
+                hex::encode(calculate_info_hash_v2_bytes(&self.info).unwrap())
+            )?;
+            writeln!(
+                f,
+                "Protocol: {}",
+                if self.info.is_v1() { "hybrid" } else { "v2" }
+            )?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle;
+    use serde_bencode::value::Value;
+
+    const PIECE_LENGTH: usize = 32 * 1024;
+
+    fn bytes(bytes: impl Into<Vec<u8>>) -> Value {
+        Value::Bytes(bytes.into())
+    }
+
+    fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    fn v2_file(data: &[u8]) -> Value {
+        let root = match data.len() > PIECE_LENGTH {
+            true => merkle::root_from_layer(
+                &data
+                    .chunks(PIECE_LENGTH)
+                    .map(|piece| merkle::piece_root(piece, PIECE_LENGTH))
+                    .collect::<Vec<_>>(),
+                data.len().div_ceil(PIECE_LENGTH).next_power_of_two(),
+                merkle::piece_root(&[], PIECE_LENGTH),
+            ),
+            false => merkle::file_root(data),
+        };
+        dict([(
+            "",
+            dict([
+                ("length", Value::Int(data.len() as i64)),
+                ("pieces root", bytes(root)),
+            ]),
+        )])
+    }
+
+    /// `a` spanning two pieces and `b/c` in a third, the info dictionary of a v2 only torrent or
+    /// with `hybrid` of a hybrid one
+    fn info(a: &[u8], c: &[u8], hybrid: bool) -> Value {
+        let Value::Dict(mut info) = dict([
+            ("name", bytes("test")),
+            ("piece length", Value::Int(PIECE_LENGTH as i64)),
+            ("meta version", Value::Int(2)),
+            (
+                "file tree",
+                dict([("a", v2_file(a)), ("b", dict([("c", v2_file(c))]))]),
+            ),
+        ]) else {
+            unreachable!()
+        };
+        if hybrid {
+            let pad = 2 * PIECE_LENGTH - a.len();
+            let file = |length: usize, path: &[&str], attr: Option<&str>| {
+                let Value::Dict(mut file) = dict([
+                    ("length", Value::Int(length as i64)),
+                    (
+                        "path",
+                        Value::List(path.iter().map(|p| bytes(*p)).collect()),
+                    ),
+                ]) else {
+                    unreachable!()
+                };
+                if let Some(attr) = attr {
+                    file.insert(b"attr".to_vec(), bytes(attr));
+                }
+                Value::Dict(file)
+            };
+            let files = vec![
+                file(a.len(), &["a"], None),
+                file(pad, &[".pad", &pad.to_string()], Some("p")),
+                file(c.len(), &["b", "c"], None),
+            ];
+            let mut content = a.to_vec();
+            content.resize(2 * PIECE_LENGTH, 0);
+            content.extend_from_slice(c);
+            let pieces: Vec<u8> = content
+                .chunks(PIECE_LENGTH)
+                .flat_map(|piece| Sha1::digest(piece).to_vec())
+                .collect();
+            info.insert(b"files".to_vec(), Value::List(files));
+            info.insert(b"pieces".to_vec(), bytes(pieces));
+        }
+        Value::Dict(info)
+    }
+
+    fn write(torrent: &Value) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.torrent");
+        std::fs::write(&path, serde_bencode::to_bytes(torrent).unwrap()).unwrap();
+        (dir, path)
+    }
+
+    fn torrent(info: Value, piece_layers: Value) -> Value {
+        dict([
+            ("announce", bytes("http://127.0.0.1:7070/announce")),
+            ("info", info),
+            ("piece layers", piece_layers),
+        ])
+    }
+
+    fn check_v2(hybrid: bool) {
+        let a: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        let c = [7u8; 100];
+        let info = info(&a, &c, hybrid);
+        let info_bytes = serde_bencode::to_bytes(&info).unwrap();
+        let layer: Vec<u8> = a
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| merkle::piece_root(piece, PIECE_LENGTH))
+            .collect();
+        let Value::Dict(file_tree) = &v2_file(&a) else {
+            unreachable!()
+        };
+        let Some(Value::Dict(file)) = file_tree.get(b"".as_slice()) else {
+            unreachable!()
+        };
+        let Some(Value::Bytes(root_a)) = file.get(b"pieces root".as_slice()) else {
+            unreachable!()
+        };
+        let piece_layers = Value::Dict(HashMap::from([(root_a.clone(), bytes(layer))]));
+        let (_dir, path) = write(&torrent(info, piece_layers));
+
+        let torrent = parse_torrent_file(&path).unwrap();
+        let info = &torrent.info;
+        assert!(info.is_v2());
+        assert_eq!(info.is_v1(), hybrid);
+        assert_eq!(info.piece_count(), 3);
+        // v1 peers transfer the padding too
+        let second_piece = if hybrid {
+            PIECE_LENGTH
+        } else {
+            40_000 - PIECE_LENGTH
+        };
+        assert_eq!(info.piece_size(1), second_piece);
+        assert_eq!(info.piece_size(2), 100);
+        assert_eq!(info.total_length(), 2 * PIECE_LENGTH + 100);
+
+        let files = info.v2_files();
+        let locations: Vec<_> = files
+            .iter()
+            .map(|l| (l.path.join("/"), l.first_piece, l.offset))
+            .collect();
+        assert_eq!(
+            locations,
+            [
+                ("a".to_string(), 0, 0),
+                ("b/c".to_string(), 2, 2 * PIECE_LENGTH)
+            ]
+        );
+        let paths: Vec<_> = info.files().iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, ["a", ".pad/25536", "b/c"]);
+        assert!(info.files()[1].is_padding());
+
+        let layer = &torrent.piece_layers[&ByteBuf::from(root_a.clone())];
+        assert!(merkle::verify_piece_layer(layer, root_a, PIECE_LENGTH));
+
+        // the info dictionary serializes back to the same bytes, so the hashes match
+        let v1: [u8; 20] = Sha1::digest(&info_bytes).into();
+        let v2: [u8; 32] = Sha256::digest(&info_bytes).into();
+        let v2_swarm: [u8; 20] = v2[..20].try_into().unwrap();
+        let expected = match hybrid {
+            true => vec![v1, v2_swarm],
+            false => vec![v2_swarm],
+        };
+        assert_eq!(swarm_info_hashes(info).unwrap(), expected);
+        assert_eq!(calculate_info_hash_v2_bytes(info).unwrap(), v2);
+    }
+
+    #[test]
+    fn parses_v2_only_torrents() {
+        check_v2(false);
+    }
+
+    #[test]
+    fn parses_hybrid_torrents() {
+        check_v2(true);
+    }
+
+    #[test]
+    fn private_and_source_change_the_info_hash() {
+        let info = |extra: &[(&str, Value)]| {
+            let Value::Dict(mut info) = dict([
+                ("name", bytes("test")),
+                ("piece length", Value::Int(PIECE_LENGTH as i64)),
+                ("length", Value::Int(100)),
+                ("pieces", bytes(Sha1::digest([7u8; 100]).to_vec())),
+            ]) else {
+                unreachable!()
+            };
+            for (key, value) in extra {
+                info.insert(key.as_bytes().to_vec(), value.clone());
+            }
+            Value::Dict(info)
+        };
+        let variants = [
+            info(&[]),
+            info(&[("private", Value::Int(1))]),
+            info(&[("source", bytes("tracker A"))]),
+            info(&[("source", bytes("tracker B"))]),
+            info(&[("private", Value::Int(1)), ("source", bytes("tracker A"))]),
+        ];
+
+        let mut hashes = std::collections::HashSet::new();
+        for info in variants {
+            let expected: [u8; 20] = Sha1::digest(serde_bencode::to_bytes(&info).unwrap()).into();
+            let (_dir, path) = write(&dict([
+                ("announce", bytes("http://127.0.0.1:7070/announce")),
+                ("info", info),
+            ]));
+            let torrent = parse_torrent_file(&path).unwrap();
+            assert_eq!(calculate_info_hash_bytes(&torrent.info).unwrap(), expected);
+            assert_eq!(swarm_info_hashes(&torrent.info).unwrap(), [expected]);
+            hashes.insert(expected);
+        }
+        assert_eq!(hashes.len(), 5);
+    }
+
+    #[test]
+    fn rejects_unknown_meta_versions_and_missing_files() {
+        let info = |entries: Value| {
+            let (_dir, path) = write(&torrent(entries, dict([])));
+            parse_torrent_file(&path)
+        };
+        let base = || {
+            [
+                ("name", bytes("test")),
+                ("piece length", Value::Int(PIECE_LENGTH as i64)),
+            ]
+        };
+        let [name, length] = base();
+        assert!(info(dict([name, length])).is_err());
+
+        let [name, length] = base();
+        let no_tree = dict([name, length, ("meta version", Value::Int(2))]);
+        assert!(info(no_tree).is_err());
+
+        let [name, length] = base();
+        let v3 = dict([
+            name,
+            length,
+            ("meta version", Value::Int(3)),
+            ("file tree", dict([("a", v2_file(&[1]))])),
+        ]);
+        assert!(info(v3).is_err());
+
+        let [name, length] = base();
+        let v2 = dict([
+            name,
+            length,
+            ("meta version", Value::Int(2)),
+            ("file tree", dict([("a", v2_file(&[1]))])),
+        ]);
+        assert!(info(v2).is_ok());
+    }
+}
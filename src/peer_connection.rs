@@ -1,15 +1,29 @@
 use anyhow::anyhow;
-use bytes::BufMut;
+use bytes::{Buf, BufMut};
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
-use std::{fmt::Display, net::SocketAddrV4, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddrV4,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
     sync::Mutex,
 };
-use tracing::info;
+use tracing::{error, info};
 
-use crate::parser::{calculate_info_hash_bytes, FileTree, Info};
+use crate::ip_filter::IpFilter;
+use crate::merkle::{self, BLOCK_SIZE};
+use crate::parser::{Info, Torrent};
+use crate::peer_id::{ClientId, ExtendedHandshake};
+use crate::rate_limit::Bandwidth;
 
 /// Peer connections are symmetrical. Messages sent in both directions look the same, and data can
 /// flow in either direction.
@@ -34,6 +48,57 @@ pub struct Peer {
     /// per the spec, unchoke is a state, not a per-request event, sooooo that means if i keep
     /// waiting for new unchoke events i can wait untile the heat death of the universe
     pub peer_choking: bool,
+    /// Whether we choke the peer, decided by the choker
+    pub am_choking: bool,
+    /// Whether the peer wants data from us
+    pub peer_interested: bool,
+    /// How we learned about this peer
+    pub source: PeerSource,
+    /// Limiters every transfer on this connection goes through
+    pub bandwidth: Bandwidth,
+    /// Swarm the peer was found in, for v2 swarms this is the truncated v2 info hash
+    pub info_hash: [u8; 20],
+    /// Sent by the peer in its handshake
+    pub peer_id: Option<[u8; 20]>,
+    /// Client the peer runs, from its peer ID or extended handshake
+    pub client: Option<ClientId>,
+    /// Block requests sent and not answered yet
+    pub queue_depth: Arc<AtomicUsize>,
+    pub config: PeerConfig,
+}
+
+/// Timeouts and request size of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerConfig {
+    pub connect_timeout: Duration,
+    /// For every message of the handshake and every answer to a hash request
+    pub handshake_timeout: Duration,
+    /// Bytes asked for per block request. Most clients refuse requests over 16 KiB.
+    pub block_size: usize,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(5),
+            block_size: BLOCK_SIZE,
+        }
+    }
+}
+
+/// Where a peer came from, private torrents only accept some sources. Trackers are the only one
+/// so far, DHT, PEX and LSD get a variant once they find peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+}
+
+impl PeerSource {
+    /// BEP 27: a private torrent only gets peers from the trackers in its metainfo
+    pub fn is_allowed(&self, info_dict: &Info) -> bool {
+        !info_dict.is_private() || matches!(self, PeerSource::Tracker)
+    }
 }
 
 impl Display for Peer {
@@ -57,7 +122,7 @@ impl Display for Peer {
 // infohash: [u8; 20],
 // peer_id: [u8; 20],
 // TODO: Make this a struct and read the direct memory into a buffer
-struct Handshake {
+pub struct Handshake {
     length: u8,
     protocol_string: [u8; 19],
     zero_bytes: [u8; 8],
@@ -66,18 +131,25 @@ struct Handshake {
 }
 
 impl Handshake {
-    fn new(infohash: &[u8; 20]) -> Self {
+    pub fn new(infohash: &[u8; 20], peer_id: &[u8; 20], v2: bool) -> Self {
+        let mut zero_bytes = [0u8; 8];
+        // BEP 10 extension protocol
+        zero_bytes[5] |= EXTENSION_PROTOCOL_BIT;
+        if v2 {
+            // BEP 52: tells v1 peers of a hybrid torrent that we could also talk v2
+            zero_bytes[7] |= 0x10;
+        }
+
         Self {
             length: 19,
             protocol_string: *b"BitTorrent protocol",
-            zero_bytes: [0u8; 8],
+            zero_bytes,
             infohash: *infohash,
-            // TOOD peer id generation
-            peer_id: *b"00112233445566778899",
+            peer_id: *peer_id,
         }
     }
 
-    fn serialize(&self) -> Vec<u8> {
+    pub fn serialize(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(self.length as usize);
         buf.put_u8(self.length);
         buf.put_slice(&self.protocol_string);
@@ -89,20 +161,271 @@ impl Handshake {
     }
 }
 
+/// The client named in an extended handshake, other extension messages are not supported
+pub fn extended_client(payload: &[u8]) -> Option<ClientId> {
+    let (&id, handshake) = payload.split_first()?;
+    if id != EXTENDED_HANDSHAKE_ID {
+        return None;
+    }
+    serde_bencode::from_bytes::<ExtendedHandshake>(handshake)
+        .inspect_err(|e| error!("Invalid extended handshake: {e}"))
+        .ok()?
+        .client()
+}
+
+/// Our extended handshake as a complete message
+pub fn extended_handshake_message() -> Result<Vec<u8>, anyhow::Error> {
+    let payload = serde_bencode::to_bytes(&ExtendedHandshake::ours())?;
+    let mut msg_buf = Vec::with_capacity(6 + payload.len());
+    msg_buf.extend_from_slice(&(2 + payload.len() as u32).to_be_bytes());
+    msg_buf.push(EXTENDED_ID);
+    msg_buf.push(EXTENDED_HANDSHAKE_ID);
+    msg_buf.extend_from_slice(&payload);
+    Ok(msg_buf)
+}
+
+/// BEP 52 `hash request`, `hash reject` uses the same layout with a different message ID
+///
+/// ```text
+/// <len=0x0031><id=21><pieces root><base layer><index><length><proof layers>
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HashRequest {
+    pieces_root: [u8; 32],
+    /// Layer of the tree the hashes are taken from, 0 are the 16KiB block hashes
+    base_layer: u32,
+    /// Offset of the first hash in the base layer
+    index: u32,
+    /// Number of hashes, a power of two
+    length: u32,
+    /// Number of uncle layers to include for verifying the hashes against the root
+    proof_layers: u32,
+}
+
+/// BEP 10, set in byte 5 of the reserved handshake bytes
+pub const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+pub const EXTENDED_ID: u8 = 20;
+/// Extended message ID of the extended handshake itself
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+pub const HASH_REQUEST_ID: u8 = 21;
+const HASHES_ID: u8 = 22;
+const HASH_REJECT_ID: u8 = 23;
+/// Peers may reject requests for more hashes than this
+const MAX_HASHES_PER_REQUEST: usize = 512;
+/// Longer messages are rejected instead of buffered, the largest legit ones are big bitfields
+const MAX_MESSAGE_LEN: u32 = 2 * 1024 * 1024;
+
+impl HashRequest {
+    fn serialize(&self, message_id: u8) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(53);
+        buf.put_u32(49);
+        buf.put_u8(message_id);
+        buf.put_slice(&self.pieces_root);
+        buf.put_u32(self.base_layer);
+        buf.put_u32(self.index);
+        buf.put_u32(self.length);
+        buf.put_u32(self.proof_layers);
+        buf
+    }
+
+    /// Parse the payload of a `hash request`, `hashes` or `hash reject` message (without the
+    /// message ID)
+    fn parse(mut src: &[u8]) -> Result<Self, anyhow::Error> {
+        if src.len() < 48 {
+            return Err(anyhow!("Packet too short for hash request"));
+        }
+        let mut pieces_root = [0u8; 32];
+        src.copy_to_slice(&mut pieces_root);
+        Ok(Self {
+            pieces_root,
+            base_layer: src.get_u32(),
+            index: src.get_u32(),
+            length: src.get_u32(),
+            proof_layers: src.get_u32(),
+        })
+    }
+}
+
+/// BEP 52 `hashes` message, the requested hashes followed by the uncle hashes of the proof
+#[derive(Debug)]
+struct Hashes {
+    request: HashRequest,
+    hashes: Vec<merkle::Hash>,
+}
+
+impl Hashes {
+    fn serialize(&self) -> Vec<u8> {
+        let mut buf = self.request.serialize(HASHES_ID);
+        buf[..4].copy_from_slice(&(49 + 32 * self.hashes.len() as u32).to_be_bytes());
+        for hash in &self.hashes {
+            buf.extend_from_slice(hash);
+        }
+        buf
+    }
+
+    fn parse(src: &[u8]) -> Result<Self, anyhow::Error> {
+        let request = HashRequest::parse(src)?;
+        let rest = &src[48..];
+        if !rest.len().is_multiple_of(32) {
+            return Err(anyhow!("Invalid payload size for hashes"));
+        }
+        Ok(Self {
+            request,
+            hashes: rest
+                .chunks_exact(32)
+                .map(|c| c.try_into().unwrap())
+                .collect(),
+        })
+    }
+}
+
+/// Answer the payload of a `hash request` from the piece layers we have, with the hashes and
+/// their proof or with a `hash reject` if we can't. Only hashes of the piece layer are served.
+pub fn answer_hash_request(
+    payload: &[u8],
+    piece_length: usize,
+    piece_layers: &HashMap<ByteBuf, ByteBuf>,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let request = HashRequest::parse(payload)?;
+    let base_layer = (piece_length / BLOCK_SIZE).trailing_zeros();
+    let hashes = piece_layers
+        .get(&ByteBuf::from(request.pieces_root.to_vec()))
+        .filter(|_| {
+            request.base_layer == base_layer && request.length as usize <= MAX_HASHES_PER_REQUEST
+        })
+        .and_then(|layer| {
+            merkle::piece_layer_hashes(
+                layer,
+                piece_length,
+                request.index as usize,
+                request.length as usize,
+                request.proof_layers as usize,
+            )
+        });
+    Ok(match hashes {
+        Some(hashes) => Hashes { request, hashes }.serialize(),
+        None => request.serialize(HASH_REJECT_ID),
+    })
+}
+
+/// Length of a message from its length prefix, refusing anything over [`MAX_MESSAGE_LEN`]
+fn message_len(len_buf: [u8; 4]) -> Result<usize, anyhow::Error> {
+    let msg_len = u32::from_be_bytes(len_buf);
+    if msg_len > MAX_MESSAGE_LEN {
+        return Err(anyhow!("Message of {msg_len} bytes is too long"));
+    }
+    Ok(msg_len as usize)
+}
+
+/// Read one message, keep-alives are returned as `None`
+pub async fn read_message<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<(u8, Vec<u8>)>, anyhow::Error> {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf).await?;
+    let msg_len = message_len(len_buf)?;
+    if msg_len == 0 {
+        return Ok(None);
+    }
+
+    let mut msg_buf = vec![0u8; msg_len];
+    stream.read_exact(&mut msg_buf).await?;
+    let payload = msg_buf.split_off(1);
+    Ok(Some((msg_buf[0], payload)))
+}
+
+/// Ask the peer for the piece layer of a file with `file_pieces` pieces and check it against the
+/// file's `pieces root`
+async fn request_piece_layer(
+    stream: &mut TcpStream,
+    piece_layers: &Mutex<HashMap<ByteBuf, ByteBuf>>,
+    pieces_root: &[u8; 32],
+    file_pieces: usize,
+    piece_length: usize,
+    timeout: Duration,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let base_layer = (piece_length / BLOCK_SIZE).trailing_zeros();
+    let padded = file_pieces.next_power_of_two().max(2);
+    let chunk = padded.min(MAX_HASHES_PER_REQUEST);
+    // big layers come in several chunks, each with the uncles needed to check it on its own
+    let proof_layers = (padded / chunk).trailing_zeros();
+    let mut layer = Vec::with_capacity(padded * 32);
+
+    for index in (0..padded).step_by(chunk) {
+        let request = HashRequest {
+            pieces_root: *pieces_root,
+            base_layer,
+            index: index as u32,
+            length: chunk as u32,
+            proof_layers,
+        };
+        stream
+            .write_all(&request.serialize(HASH_REQUEST_ID))
+            .await?;
+
+        loop {
+            let Some((id, payload)) = tokio::time::timeout(timeout, read_message(stream)).await??
+            else {
+                continue;
+            };
+            match id {
+                HASHES_ID => {
+                    let hashes = Hashes::parse(&payload)?;
+                    if hashes.request.index != request.index
+                        || hashes.request.pieces_root != request.pieces_root
+                        || hashes.hashes.len() < chunk + proof_layers as usize
+                    {
+                        return Err(anyhow!("Peer answered with the wrong hashes"));
+                    }
+                    let (run, proof) = hashes.hashes.split_at(chunk);
+                    if proof_layers > 0 && !merkle::verify_hashes(run, index, proof, pieces_root) {
+                        return Err(anyhow!("Hashes from peer dont match the pieces root"));
+                    }
+                    for hash in run {
+                        layer.extend_from_slice(hash);
+                    }
+                    break;
+                }
+                HASH_REJECT_ID => return Err(anyhow!("Peer rejected our hash request")),
+                HASH_REQUEST_ID => {
+                    let answer =
+                        answer_hash_request(&payload, piece_length, &*piece_layers.lock().await)?;
+                    stream.write_all(&answer).await?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    layer.truncate(file_pieces * 32);
+    if !merkle::verify_piece_layer(&layer, pieces_root, piece_length) {
+        return Err(anyhow!(
+            "Piece layer from peer doesnt match the pieces root"
+        ));
+    }
+    Ok(layer)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn download_piece(
     stream: &mut TcpStream,
+    info_dict: &Info,
+    piece_layers: &Mutex<HashMap<ByteBuf, ByteBuf>>,
     piece_index: u32,
-    piece_length: usize,
+    peer_interested: &mut bool,
+    bandwidth: &Bandwidth,
+    queue_depth: &AtomicUsize,
+    block_size: usize,
 ) -> Result<Vec<u8>, anyhow::Error> {
-    const BLOCK_SIZE: usize = 16 * 1024;
+    let piece_length = info_dict.piece_size(piece_index as usize);
     let mut piece_buffer = vec![0u8; piece_length];
 
     // send requests
-    for offset in (0..piece_length).step_by(BLOCK_SIZE) {
-        let block_len = if offset + BLOCK_SIZE > piece_length {
+    for offset in (0..piece_length).step_by(block_size) {
+        let block_len = if offset + block_size > piece_length {
             piece_length - offset
         } else {
-            BLOCK_SIZE
+            block_size
         };
 
         let mut request = Vec::with_capacity(13);
@@ -116,6 +439,8 @@ async fn download_piece(
         full_request.extend_from_slice(&(request.len() as u32).to_be_bytes());
         full_request.extend_from_slice(&request);
         stream.write_all(&full_request).await?;
+        bandwidth.upload.record_overhead(full_request.len());
+        queue_depth.fetch_add(1, Ordering::Relaxed);
     }
 
     let mut received_bytes = 0;
@@ -124,15 +449,27 @@ async fn download_piece(
     while received_bytes < piece_length {
         // read length prefix
         stream.read_exact(&mut u32_buf).await?;
-        let msg_len = u32::from_be_bytes(u32_buf);
+        let msg_len = message_len(u32_buf)?;
+
+        bandwidth.download.record_overhead(u32_buf.len());
 
         if msg_len == 0 {
             info!("Received keep-alive");
             continue;
         }
 
-        let mut msg_buf = vec![0u8; msg_len as usize];
-        stream.read_exact(&mut msg_buf).await?;
+        let mut msg_buf = vec![0u8; msg_len];
+        stream.read_exact(&mut msg_buf[..1]).await?;
+
+        // only the block data of a piece message is payload, it waits for the limiters before
+        // it is read off the socket
+        if msg_buf[0] == 7 && msg_buf.len() > 9 {
+            bandwidth.download.record_overhead(9);
+            bandwidth.download.acquire(msg_buf.len() - 9).await;
+        } else {
+            bandwidth.download.record_overhead(msg_buf.len());
+        }
+        stream.read_exact(&mut msg_buf[1..]).await?;
 
         let msg_id = msg_buf[0];
         let payload = &msg_buf[1..];
@@ -152,11 +489,23 @@ async fn download_piece(
                 piece_buffer[offset..offset + block_data.len()].copy_from_slice(block_data);
 
                 received_bytes += block_data.len();
+                queue_depth.fetch_sub(1, Ordering::Relaxed);
             }
             0 => {
                 // skip choke
                 tokio::time::sleep(Duration::new(0, 100_000)).await; // wait 100ms
             }
+            2 => *peer_interested = true,
+            3 => *peer_interested = false,
+            HASH_REQUEST_ID => {
+                let answer = answer_hash_request(
+                    payload,
+                    info_dict.piece_length,
+                    &*piece_layers.lock().await,
+                )?;
+                stream.write_all(&answer).await?;
+                bandwidth.upload.record_overhead(answer.len());
+            }
             _ => {
                 // TODO handle other types
                 // skip for now
@@ -169,19 +518,76 @@ async fn download_piece(
 }
 
 impl Peer {
-    pub async fn perform_handshake(&mut self, info_dict: &Info) -> Result<(), anyhow::Error> {
+    pub fn new(sock_ip: SocketAddrV4, source: PeerSource) -> Self {
+        Self {
+            sock_ip,
+            available: Vec::new(),
+            conn: None,
+            peer_choking: true,
+            am_choking: true,
+            peer_interested: false,
+            source,
+            bandwidth: Bandwidth::default(),
+            info_hash: [0u8; 20],
+            peer_id: None,
+            client: None,
+            queue_depth: Arc::new(AtomicUsize::new(0)),
+            config: PeerConfig::default(),
+        }
+    }
+
+    /// Send a choke or unchoke message if `choke` differs from what the peer was last told
+    pub async fn set_choking(&mut self, choke: bool) -> Result<(), anyhow::Error> {
+        if choke == self.am_choking {
+            return Ok(());
+        }
+        let conn = self
+            .conn
+            .as_ref()
+            .ok_or_else(|| anyhow!("Peer {} is not connected", self.sock_ip))?;
+
+        let mut msg_buf = Vec::with_capacity(5);
+        msg_buf.extend_from_slice(&1u32.to_be_bytes());
+        msg_buf.push(if choke { 0 } else { 1 });
+        conn.lock().await.write_all(&msg_buf).await?;
+        self.bandwidth.upload.record_overhead(msg_buf.len());
+
+        info!(
+            "{} peer {}",
+            if choke { "Choked" } else { "Unchoked" },
+            self.sock_ip
+        );
+        self.am_choking = choke;
+        Ok(())
+    }
+
+    /// `infohash` is the hash of the swarm the peer was found in, which for v2 swarms is the
+    /// truncated v2 info hash
+    pub async fn perform_handshake(
+        &mut self,
+        info_dict: &Info,
+        infohash: &[u8; 20],
+        peer_id: &[u8; 20],
+        ip_filter: &IpFilter,
+    ) -> Result<(), anyhow::Error> {
+        if ip_filter.is_blocked(self.sock_ip.ip()) {
+            return Err(anyhow!("{} is blocked by the IP filter", self.sock_ip));
+        }
         info!("performing handshake on peer {}", self.sock_ip);
         // all messages follow <length prefix: 4 bytes><message ID: 1 byte><optional payload>
 
         // Step 1
         // perform handshake
-        let infohash = calculate_info_hash_bytes(info_dict)?;
-        let handshake = Handshake::new(&infohash);
+        let infohash = *infohash;
+        let handshake = Handshake::new(&infohash, peer_id, info_dict.is_v2());
         self.conn = Some(Arc::new(Mutex::new(
-            match tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(self.sock_ip))
-                .await
+            match tokio::time::timeout(
+                self.config.connect_timeout,
+                TcpStream::connect(self.sock_ip),
+            )
+            .await
             {
-                Ok(recv_result) => recv_result?, // socket.recv succeeded within 5 seconds
+                Ok(recv_result) => recv_result?, // socket.recv succeeded in time
                 Err(_) => anyhow::bail!("Timed out waiting for connect response from peer"),
             },
         )));
@@ -190,8 +596,9 @@ impl Peer {
         stream.write_all(&handshake.serialize()).await?;
 
         let mut buf = vec![0u8; 68];
-        match tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf)).await {
-            Ok(recv_result) => recv_result?, // socket.recv succeeded within 5 seconds
+        let timeout = self.config.handshake_timeout;
+        match tokio::time::timeout(timeout, stream.read_exact(&mut buf)).await {
+            Ok(recv_result) => recv_result?, // socket.recv succeeded in time
             Err(_) => anyhow::bail!("Timed out waiting for connect response from peer"),
         };
 
@@ -208,6 +615,14 @@ impl Peer {
                 "The received handshake doesnt match the handshake generated by the client",
             ));
         }
+        self.peer_id = buf[48..68].try_into().ok();
+        self.client = self.peer_id.as_ref().and_then(ClientId::from_peer_id);
+
+        if buf[25] & EXTENSION_PROTOCOL_BIT != 0 {
+            let msg_buf = extended_handshake_message()?;
+            stream.write_all(&msg_buf).await?;
+            self.bandwidth.upload.record_overhead(msg_buf.len());
+        }
 
         // Step 2
         // read bitfield packaged
@@ -217,25 +632,40 @@ impl Peer {
         // don't have anything yet may skip the 'bitfield' message. The first byte of the bitfield
         // corresponds to indices 0 - 7 from high bit to low bit, respectively. The next one 8-15,
         // etc. Spare bits at the end are set to zero.
+        //
+        // An extended handshake (BEP 10) may come before it
         // read in length first
         let mut len_buf = [0u8; 4];
-        match tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut len_buf)).await {
-            Ok(recv_result) => recv_result?, // socket.recv succeeded within 5 seconds
-            Err(_) => anyhow::bail!("Timed out waiting for length of the buffer from peer"),
+        let msg_buf = loop {
+            match tokio::time::timeout(timeout, stream.read_exact(&mut len_buf)).await {
+                Ok(recv_result) => recv_result?, // socket.recv succeeded in time
+                Err(_) => anyhow::bail!("Timed out waiting for length of the buffer from peer"),
+            };
+            let msg_len = message_len(len_buf)?;
+
+            info!("length of the buffer is = {msg_len}");
+
+            let mut msg_buf = vec![0u8; msg_len];
+            match tokio::time::timeout(timeout, stream.read_exact(&mut msg_buf)).await {
+                Ok(recv_result) => recv_result?, // socket.recv succeeded in time
+                Err(_) => anyhow::bail!("Timed out waiting for bitfield from peer"),
+            };
+
+            if msg_buf.first() == Some(&EXTENDED_ID) {
+                if let Some(client) = extended_client(&msg_buf[1..]) {
+                    self.client = Some(client);
+                }
+                continue;
+            }
+            break msg_buf;
         };
-        let msg_len = u32::from_be_bytes(len_buf);
 
-        info!("length of the buffer is = {msg_len}");
-
-        let mut msg_buf = vec![0u8; msg_len as usize];
-        match tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut msg_buf)).await {
-            Ok(recv_result) => recv_result?, // socket.recv succeeded within 5 seconds
-            Err(_) => anyhow::bail!("Timed out waiting for bitfield from peer"),
+        // a peer without pieces may skip the bitfield, its pieces are unknown then
+        let bitfield = match msg_buf.split_first() {
+            Some((5, bitfield)) => bitfield,
+            _ => &[],
         };
-
-        let _message_id = msg_buf[0];
-        let bitfield = msg_buf[1..].to_vec();
-        for byte in &bitfield {
+        for byte in bitfield {
             for i in (0..8).rev() {
                 let bit = (byte >> i) & 1;
                 if bit == 1 {
@@ -251,9 +681,12 @@ impl Peer {
         Ok(())
     }
 
+    /// `piece_layers` are the verified BEP 52 piece layers keyed by `pieces root`, layers missing
+    /// from the metainfo are requested from the peer and added
     pub async fn get_piece(
         &mut self,
         info_dict: &Info,
+        piece_layers: &Mutex<HashMap<ByteBuf, ByteBuf>>,
         index: usize,
     ) -> Result<Vec<u8>, anyhow::Error> {
         let conn = self.conn.as_ref().unwrap();
@@ -267,11 +700,12 @@ impl Peer {
             msg_buf.extend_from_slice(&(1u32.to_be_bytes()));
             msg_buf.push(2);
             stream.write_all(&msg_buf).await?;
+            self.bandwidth.upload.record_overhead(msg_buf.len());
 
             // Step 2: wait for unchoke
             loop {
                 stream.read_exact(&mut len_buf).await?;
-                let msg_len = u32::from_be_bytes(len_buf);
+                let msg_len = message_len(len_buf)?;
 
                 if msg_len == 0 {
                     info!("Received keep-alive while waiting for unchoke");
@@ -279,7 +713,7 @@ impl Peer {
                     continue;
                 }
 
-                let mut msg_buf = vec![0u8; msg_len as usize];
+                let mut msg_buf = vec![0u8; msg_len];
                 stream.read_exact(&mut msg_buf).await?;
                 let msg_id = msg_buf[0];
 
@@ -291,6 +725,13 @@ impl Peer {
                     0 => {
                         self.peer_choking = true; // stays choked
                     }
+                    2 => self.peer_interested = true,
+                    3 => self.peer_interested = false,
+                    EXTENDED_ID => {
+                        if let Some(client) = extended_client(&msg_buf[1..]) {
+                            self.client = Some(client);
+                        }
+                    }
                     _ => {
                         // have, bitfield, etc. — ignore for now but don't misinterpret them
                     }
@@ -305,36 +746,247 @@ impl Peer {
 
         // Step 3 & 4
         // send request messages and wait for piece messages putting all together
-        // calculate correct piece size
-        let total_pieces = info_dict.pieces.len() / 20;
-        let total_length = match &info_dict.file_tree {
-            FileTree::SingleFile { length } => *length,
-            FileTree::MultiFile { files } => {
-                // parse the file tree
-                files.iter().map(|f| f.length).sum()
+        info!(
+            "Downloading piece with length {}",
+            info_dict.piece_size(index)
+        );
+        let piece = download_piece(
+            &mut stream,
+            info_dict,
+            piece_layers,
+            index as u32,
+            &mut self.peer_interested,
+            &self.bandwidth,
+            &self.queue_depth,
+            self.config.block_size,
+        )
+        .await
+        // requests of a failed piece are dead, the connection is dropped
+        .inspect_err(|_| self.queue_depth.store(0, Ordering::Relaxed))?;
+        info!("Piece succesfully downloaded");
+
+        // v2 only pieces are checked against the piece layer of their file, ask the peer for it if
+        // the metainfo didn't have it
+        let layer = match needed_piece_layer(info_dict, index)? {
+            Some((pieces_root, file_pieces)) => {
+                let key = ByteBuf::from(pieces_root.to_vec());
+                let known = piece_layers.lock().await.get(&key).cloned();
+                match known {
+                    Some(layer) => Some(layer.into_vec()),
+                    None => {
+                        info!("Requesting piece layer of piece {index} from peer");
+                        let layer = request_piece_layer(
+                            &mut stream,
+                            piece_layers,
+                            &pieces_root,
+                            file_pieces,
+                            info_dict.piece_length,
+                            self.config.handshake_timeout,
+                        )
+                        .await
+                        .inspect_err(|e| error!("Failed to get piece layer: {e}"))?;
+                        piece_layers
+                            .lock()
+                            .await
+                            .insert(key, ByteBuf::from(layer.clone()));
+                        Some(layer)
+                    }
+                }
             }
+            None => None,
         };
 
-        let piece_length = if index + 1 == total_pieces {
-            total_length - (index * info_dict.piece_length)
-        } else {
-            info_dict.piece_length
-        };
+        verify_piece(info_dict, index, &piece, layer.as_deref())?;
+        Ok(piece)
+    }
+}
 
-        info!("Downloading piece with length {}", piece_length);
-        let piece = download_piece(&mut stream, index as u32, piece_length).await?;
-        info!("Piece succesfully downloaded");
+/// `pieces root` of the file piece `index` belongs to, the index of the piece inside that file and
+/// the amount of pieces of the file. Only meaningful for v2 only torrents.
+fn v2_piece_root(
+    info_dict: &Info,
+    index: usize,
+) -> Result<([u8; 32], usize, usize), anyhow::Error> {
+    let (location, piece_in_file) = info_dict
+        .v2_piece_location(index)
+        .ok_or_else(|| anyhow!("Piece {index} is not part of any file"))?;
+    let pieces_root: [u8; 32] = location
+        .file
+        .pieces_root
+        .as_ref()
+        .and_then(|root| root.as_slice().try_into().ok())
+        .ok_or_else(|| anyhow!("File {:?} has no valid pieces root", location.path))?;
+    let file_pieces = location.file.length.div_ceil(info_dict.piece_length);
+    Ok((pieces_root, piece_in_file, file_pieces))
+}
+
+/// The `pieces root` and piece count of the file whose piece layer is needed to check piece
+/// `index`, `None` if the piece can be checked without one
+pub fn needed_piece_layer(
+    info_dict: &Info,
+    index: usize,
+) -> Result<Option<([u8; 32], usize)>, anyhow::Error> {
+    if info_dict.is_v1() {
+        return Ok(None);
+    }
+    let (pieces_root, _, file_pieces) = v2_piece_root(info_dict, index)?;
+    Ok((file_pieces > 1).then_some((pieces_root, file_pieces)))
+}
 
-        // compare hash of piece with the hash in the info dict
+/// Check a piece read from disk with the piece layers the torrent file carries. Without its
+/// piece layer a v2 piece can't be checked, it counts as missing.
+pub fn check_piece(torrent: &Torrent, index: usize, piece: &[u8]) -> Result<bool, anyhow::Error> {
+    let info = &torrent.info;
+    Ok(match needed_piece_layer(info, index)? {
+        Some((pieces_root, _)) => torrent
+            .piece_layers
+            .get(&ByteBuf::from(pieces_root.to_vec()))
+            .is_some_and(|layer| verify_piece(info, index, piece, Some(layer)).is_ok()),
+        None => verify_piece(info, index, piece, None).is_ok(),
+    })
+}
+
+/// Check a downloaded piece, against `pieces` for v1 and hybrid torrents and against the merkle
+/// tree of its file for v2 only ones. `piece_layer` is the verified layer from
+/// [`needed_piece_layer`].
+pub fn verify_piece(
+    info_dict: &Info,
+    index: usize,
+    piece: &[u8],
+    piece_layer: Option<&[u8]>,
+) -> Result<(), anyhow::Error> {
+    if info_dict.is_v1() {
         let mut hasher = Sha1::new();
-        hasher.update(&piece);
+        hasher.update(piece);
         if hasher.finalize().to_vec() == info_dict.pieces[index * 20..index * 20 + 20] {
             info!("Piece hash matches the hash in the file");
-            Ok(piece)
-        } else {
-            Err(anyhow!(
-                "The received piece hash doesn't match the hash in the file"
-            ))
+            return Ok(());
+        }
+        return Err(HashMismatch {
+            index,
+            piece: piece.to_vec(),
         }
+        .into());
+    }
+
+    let (pieces_root, piece_in_file, _) = v2_piece_root(info_dict, index)?;
+    if merkle::verify_piece(
+        piece,
+        piece_in_file,
+        info_dict.piece_length,
+        &pieces_root,
+        piece_layer,
+    ) {
+        info!("Piece hash matches the merkle tree in the file");
+        Ok(())
+    } else {
+        Err(HashMismatch {
+            index,
+            piece: piece.to_vec(),
+        }
+        .into())
+    }
+}
+
+/// A downloaded piece didn't match its hash. Keeps the data around so the peer that sent the bad
+/// block can be found once a good copy arrives.
+#[derive(Debug)]
+pub struct HashMismatch {
+    pub index: usize,
+    pub piece: Vec<u8>,
+}
+
+impl Display for HashMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The received piece {} doesn't match the hash in the file",
+            self.index
+        )
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_hash_requests_from_piece_layers() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE as u32).map(|i| (i % 7) as u8).collect();
+        let layer: Vec<u8> = data
+            .chunks(piece_length)
+            .flat_map(|piece| merkle::piece_root(piece, piece_length))
+            .collect();
+        let root = merkle::file_root(&data);
+        let layers = HashMap::from([(ByteBuf::from(root.to_vec()), ByteBuf::from(layer))]);
+
+        let request = HashRequest {
+            pieces_root: root,
+            base_layer: 1,
+            index: 2,
+            length: 2,
+            proof_layers: 1,
+        };
+        let answer = answer_hash_request(
+            &request.serialize(HASH_REQUEST_ID)[5..],
+            piece_length,
+            &layers,
+        )
+        .unwrap();
+        assert_eq!(answer[4], HASHES_ID);
+        assert_eq!(
+            u32::from_be_bytes(answer[..4].try_into().unwrap()) as usize,
+            answer.len() - 4
+        );
+        let hashes = Hashes::parse(&answer[5..]).unwrap();
+        assert_eq!(hashes.request, request);
+        let (run, proof) = hashes.hashes.split_at(2);
+        assert!(merkle::verify_hashes(run, 2, proof, &root));
+
+        let rejected = [
+            HashRequest {
+                pieces_root: [1; 32],
+                ..request.clone()
+            },
+            HashRequest {
+                base_layer: 0,
+                ..request.clone()
+            },
+            HashRequest {
+                index: 1,
+                ..request.clone()
+            },
+            HashRequest {
+                proof_layers: 2,
+                ..request.clone()
+            },
+        ];
+        for request in rejected {
+            let answer = answer_hash_request(
+                &request.serialize(HASH_REQUEST_ID)[5..],
+                piece_length,
+                &layers,
+            )
+            .unwrap();
+            assert_eq!(answer, request.serialize(HASH_REJECT_ID), "{request:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_overlong_messages() {
+        let mut huge: &[u8] = &[0xff, 0xff, 0xff, 0xff, 5];
+        assert!(read_message(&mut huge).await.is_err());
+        let mut keep_alive: &[u8] = &[0, 0, 0, 0];
+        assert_eq!(read_message(&mut keep_alive).await.unwrap(), None);
+        let mut have: &[u8] = &[0, 0, 0, 5, 4, 0, 0, 0, 9];
+        assert_eq!(
+            read_message(&mut have).await.unwrap(),
+            Some((4, vec![0, 0, 0, 9]))
+        );
+        assert!(message_len(MAX_MESSAGE_LEN.to_be_bytes()).is_ok());
+        assert!(message_len((MAX_MESSAGE_LEN + 1).to_be_bytes()).is_err());
     }
 }
@@ -0,0 +1,340 @@
+//! Peer IDs and client identification.
+//!
+//! Our peer ID follows the Azureus convention: `-RB0100-` (client code and version) followed by
+//! twelve random characters. The prefix can be changed, the rest of the 20 bytes stays random. It
+//! is generated once per session and used for every handshake and announce, so trackers and peers
+//! see one client no matter how many torrents run.
+//!
+//! The other direction, telling which client a remote peer runs, understands the three peer ID
+//! conventions from BEP 20 and the `v` field of the BEP 10 extended handshake:
+//!
+//! * Azureus style `-AZ2060-...`: two letter client code and four version characters
+//! * Shadow style `S58B-----...`: one letter client code and up to five version characters
+//! * Mainline style `M4-3-6--...`: one letter client code and a dash separated version
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::fmt::Display;
+
+/// `RB` is rBittorrent, `0100` is version 0.1.0.0
+pub const PREFIX: &str = "-RB0100-";
+/// Characters of the random part, kept printable so the ID reads well in logs and URLs
+const RANDOM_CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Generate a new peer ID starting with `prefix`, call once per session. A prefix longer than
+/// the ID is cut off.
+pub fn generate(prefix: &str) -> [u8; 20] {
+    let mut id = [0u8; 20];
+    let prefix = &prefix.as_bytes()[..prefix.len().min(id.len())];
+    id[..prefix.len()].copy_from_slice(prefix);
+    for byte in &mut id[prefix.len()..] {
+        *byte = RANDOM_CHARS[rand::random_range(0..RANDOM_CHARS.len())];
+    }
+    id
+}
+
+/// What we send as `v` in the extended handshake
+pub fn client_version() -> String {
+    format!("rBittorrent {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// The client a peer runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientId {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl Display for ClientId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl ClientId {
+    /// Identify the client from its peer ID, `None` if it follows none of the known conventions
+    pub fn from_peer_id(id: &[u8; 20]) -> Option<Self> {
+        azureus_style(id)
+            .or_else(|| shadow_style(id))
+            .or_else(|| mainline_style(id))
+    }
+
+    /// Identify the client from the `v` field of its extended handshake, e.g. `µTorrent 3.5.5` or
+    /// `qBittorrent/4.6.2`
+    pub fn from_extended_version(v: &str) -> Option<Self> {
+        let v = v.trim();
+        if v.is_empty() {
+            return None;
+        }
+        let split = v
+            .rfind([' ', '/'])
+            .filter(|at| v[at + 1..].starts_with(|c: char| c.is_ascii_digit()));
+        Some(match split {
+            Some(at) => Self {
+                name: v[..at].trim().to_string(),
+                version: Some(v[at + 1..].to_string()),
+            },
+            None => Self {
+                name: v.to_string(),
+                version: None,
+            },
+        })
+    }
+}
+
+fn azureus_client(code: &[u8]) -> Option<&'static str> {
+    Some(match code {
+        b"AG" | b"A~" => "Ares",
+        b"AR" => "Arctic",
+        b"AZ" => "Vuze",
+        b"BB" => "BitBuddy",
+        b"BC" => "BitComet",
+        b"BF" => "BitFlu",
+        b"BI" => "BiglyBT",
+        b"BT" => "BitTorrent",
+        b"BW" => "BitWombat",
+        b"DE" => "Deluge",
+        b"FD" => "Free Download Manager",
+        b"FG" => "FlashGet",
+        b"FW" => "FrostWire",
+        b"HL" => "Halite",
+        b"KT" => "KTorrent",
+        b"LP" => "Lphant",
+        b"LT" => "libtorrent",
+        b"lt" => "libTorrent",
+        b"LW" => "LimeWire",
+        b"PI" => "PicoTorrent",
+        b"qB" => "qBittorrent",
+        b"RB" => "rBittorrent",
+        b"SD" => "Thunder",
+        b"ST" => "SymTorrent",
+        b"TL" => "Tribler",
+        b"TR" => "Transmission",
+        b"TX" => "Tixati",
+        b"UM" => "µTorrent Mac",
+        b"UT" => "µTorrent",
+        b"UW" => "µTorrent Web",
+        b"WW" => "WebTorrent",
+        b"XL" => "Xunlei",
+        b"ZT" => "ZipTorrent",
+        _ => return None,
+    })
+}
+
+/// `-XXabcd-`, each version character is one component, letters count from 10
+fn azureus_style(id: &[u8; 20]) -> Option<ClientId> {
+    if id[0] != b'-' || id[7] != b'-' || !id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let code = &id[1..3];
+    let name = match azureus_client(code) {
+        Some(name) => name.to_string(),
+        None => format!("unknown client {}", String::from_utf8_lossy(code)),
+    };
+
+    let mut parts: Vec<u32> = id[3..7]
+        .iter()
+        .map(|c| (*c as char).to_digit(36).unwrap_or(0))
+        .collect();
+    // 4.6.2.0 reads better as 4.6.2, but keep at least major.minor
+    while parts.len() > 2 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+    Some(ClientId {
+        name,
+        version: Some(join_version(&parts)),
+    })
+}
+
+/// `Xabcde---`, version characters from the alphabet below, terminated by a dash
+fn shadow_style(id: &[u8; 20]) -> Option<ClientId> {
+    const VERSION_CHARS: &[u8] =
+        b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.-";
+
+    let name = match id[0] {
+        b'A' => "ABC",
+        b'O' => "Osprey Permaseed",
+        b'Q' => "BTQueue",
+        b'R' => "Tribler",
+        b'S' => "Shadow",
+        b'T' => "BitTornado",
+        b'U' => "UPnP NAT Bit Torrent",
+        _ => return None,
+    };
+    if &id[6..9] != b"---" {
+        return None;
+    }
+
+    let parts = id[1..6]
+        .iter()
+        .take_while(|c| **c != b'-')
+        .map(|c| VERSION_CHARS.iter().position(|v| v == c).map(|p| p as u32))
+        .collect::<Option<Vec<_>>>()?;
+    Some(ClientId {
+        name: name.to_string(),
+        version: (!parts.is_empty()).then(|| join_version(&parts)),
+    })
+}
+
+/// `M4-3-6--` or `M4-20-8-`
+fn mainline_style(id: &[u8; 20]) -> Option<ClientId> {
+    let name = match id[0] {
+        b'M' => "BitTorrent",
+        b'Q' => "Queen Bee",
+        _ => return None,
+    };
+
+    let version = std::str::from_utf8(&id[1..8]).ok()?;
+    let parts = version
+        .trim_end_matches('-')
+        .split('-')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    (parts.len() == 3).then(|| ClientId {
+        name: name.to_string(),
+        version: Some(join_version(&parts)),
+    })
+}
+
+fn join_version(parts: &[u32]) -> String {
+    parts
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// BEP 10 extended handshake, only the fields we use
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension message IDs, we don't support any extension messages yet
+    #[serde(default)]
+    pub m: std::collections::BTreeMap<String, i64>,
+    /// Client name and version, not necessarily valid UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    pub fn ours() -> Self {
+        Self {
+            m: Default::default(),
+            v: Some(ByteBuf::from(client_version().into_bytes())),
+        }
+    }
+
+    pub fn client(&self) -> Option<ClientId> {
+        ClientId::from_extended_version(&String::from_utf8_lossy(self.v.as_deref()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `prefix` filled up to 20 bytes
+    fn id(prefix: &str) -> [u8; 20] {
+        let mut id = [b'x'; 20];
+        id[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        id
+    }
+
+    fn client(name: &str, version: Option<&str>) -> Option<ClientId> {
+        Some(ClientId {
+            name: name.to_string(),
+            version: version.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn generates_ids() {
+        let cases = [
+            (PREFIX, PREFIX),
+            ("", ""),
+            ("-XX0001-", "-XX0001-"),
+            // too long to leave room for anything random
+            ("-RB0100--RB0100--RB0100-", "-RB0100--RB0100--RB0"),
+        ];
+        for (prefix, kept) in cases {
+            let id = generate(prefix);
+            assert_eq!(&id[..kept.len()], kept.as_bytes(), "{prefix}");
+            assert!(id[kept.len()..].iter().all(|c| RANDOM_CHARS.contains(c)));
+        }
+        assert_ne!(generate(PREFIX), generate(PREFIX));
+        assert_eq!(
+            ClientId::from_peer_id(&generate(PREFIX)),
+            client("rBittorrent", Some("0.1"))
+        );
+    }
+
+    #[test]
+    fn identifies_peer_ids() {
+        let cases = [
+            // Azureus style
+            ("-qB4620-", client("qBittorrent", Some("4.6.2"))),
+            ("-TR300Z-", client("Transmission", Some("3.0.0.35"))),
+            ("-UT355S-", client("µTorrent", Some("3.5.5.28"))),
+            ("-lt0D60-", client("libTorrent", Some("0.13.6"))),
+            ("-LT1000-", client("libtorrent", Some("1.0"))),
+            ("-XY1000-", client("unknown client XY", Some("1.0"))),
+            ("-AZ20!0-", None),
+            ("-AZ2060x", None),
+            // Shadow style
+            ("S58B-----", client("Shadow", Some("5.8.11"))),
+            ("T03I-----", client("BitTornado", Some("0.3.18"))),
+            ("A--------", client("ABC", None)),
+            ("S58B!----", None),
+            ("S58B--x--", None),
+            // Mainline style
+            ("M4-3-6--", client("BitTorrent", Some("4.3.6"))),
+            ("M7-10-3-", client("BitTorrent", Some("7.10.3"))),
+            ("Q1-10-0-", client("Queen Bee", Some("1.10.0"))),
+            ("M4-3----", None),
+            ("M4-a-6--", None),
+            ("", None),
+        ];
+        for (prefix, expected) in cases {
+            assert_eq!(ClientId::from_peer_id(&id(prefix)), expected, "{prefix}");
+        }
+        assert_eq!(ClientId::from_peer_id(&[0xff; 20]), None);
+    }
+
+    #[test]
+    fn identifies_extended_versions() {
+        let cases = [
+            ("µTorrent 3.5.5", client("µTorrent", Some("3.5.5"))),
+            ("qBittorrent/4.6.2", client("qBittorrent", Some("4.6.2"))),
+            (" Transmission 2.94 ", client("Transmission", Some("2.94"))),
+            (
+                "Azureus Vuze 5.7.6.0",
+                client("Azureus Vuze", Some("5.7.6.0")),
+            ),
+            ("libtorrent", client("libtorrent", None)),
+            ("Deluge 2.1.1 beta", client("Deluge 2.1.1 beta", None)),
+            ("", None),
+            ("   ", None),
+        ];
+        for (v, expected) in cases {
+            assert_eq!(ClientId::from_extended_version(v), expected, "{v:?}");
+        }
+
+        let ours = ExtendedHandshake::ours().client().unwrap();
+        assert_eq!(ours.name, "rBittorrent");
+        assert_eq!(ours.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert_eq!(ExtendedHandshake::default().client(), None);
+    }
+
+    #[test]
+    fn displays_clients() {
+        assert_eq!(
+            client("qBittorrent", Some("4.6.2")).unwrap().to_string(),
+            "qBittorrent 4.6.2"
+        );
+        assert_eq!(
+            client("libtorrent", None).unwrap().to_string(),
+            "libtorrent"
+        );
+    }
+}
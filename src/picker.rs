@@ -0,0 +1,218 @@
+//! Which piece a peer downloads next.
+//!
+//! Pieces of higher priority files come first. Among the same priority the piece the fewest
+//! connected peers have comes first (rarest first), or the lowest index in sequential mode.
+//!
+//! Pieces with a deadline beat both, whatever their priority, the one due first is handed out
+//! first. Only the fastest peers get them, so a slow peer can't sit on a piece a reader is waiting
+//! for. Streaming sets deadlines for a window of pieces ahead of each reader, see
+//! [`crate::session::TorrentReader::set_position`]. Every reader keeps its own, a piece is due
+//! when the first of them wants it.
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::Instant;
+
+use crate::storage::FilePriority;
+
+/// Who set a deadline, each one only replaces and clears its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeadlineSource {
+    /// [`crate::session::TorrentHandle::set_piece_deadline`]
+    Manual,
+    /// A [`crate::session::TorrentReader`] by its ID
+    Reader(u64),
+}
+
+#[derive(Debug)]
+pub struct Picker {
+    /// Highest priority of the files each piece touches, `Skip` pieces are only downloaded with a
+    /// deadline
+    priorities: Vec<FilePriority>,
+    /// Missing pieces nobody is downloading right now
+    pending: BTreeSet<usize>,
+    /// Pieces handed out and neither finished nor put back yet
+    in_flight: HashSet<usize>,
+    /// Connected peers that announced each piece
+    availability: Vec<usize>,
+    deadlines: HashMap<usize, HashMap<DeadlineSource, Instant>>,
+    sequential: bool,
+}
+
+impl Picker {
+    /// Every piece missing and wanted
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            priorities: vec![FilePriority::Normal; piece_count],
+            pending: (0..piece_count).collect(),
+            in_flight: HashSet::new(),
+            availability: vec![0; piece_count],
+            deadlines: HashMap::new(),
+            sequential: false,
+        }
+    }
+
+    fn wanted(&self, index: usize) -> bool {
+        self.priorities[index] != FilePriority::Skip || self.deadlines.contains_key(&index)
+    }
+
+    /// Pieces waiting for a peer
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Change what is wanted, `have` are the pieces that passed their hash check. Pieces that are
+    /// being downloaded are left to finish.
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>, have: &[bool]) {
+        self.priorities = priorities;
+        self.pending = (0..self.priorities.len())
+            .filter(|index| !have[*index] && !self.in_flight.contains(index))
+            .filter(|index| self.wanted(*index))
+            .collect();
+    }
+
+    /// Like [`Picker::set_priorities`] for a new download, nothing is in flight and no peer is
+    /// connected
+    pub fn reset(&mut self, priorities: Vec<FilePriority>, have: &[bool]) {
+        self.in_flight.clear();
+        self.availability.fill(0);
+        self.set_priorities(priorities, have);
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// Download the missing piece `index` before `deadline`, for `source`
+    pub fn set_deadline(&mut self, source: DeadlineSource, index: usize, deadline: Instant) {
+        self.deadlines
+            .entry(index)
+            .or_default()
+            .insert(source, deadline);
+        if !self.in_flight.contains(&index) {
+            self.pending.insert(index);
+        }
+    }
+
+    /// Forget the deadlines of `source`, pieces that were only wanted for them aren't downloaded
+    /// anymore
+    pub fn clear_deadlines(&mut self, source: DeadlineSource) {
+        let mut cleared = Vec::new();
+        self.deadlines.retain(|index, sources| {
+            sources.remove(&source);
+            if sources.is_empty() {
+                cleared.push(*index);
+            }
+            !sources.is_empty()
+        });
+        for index in cleared {
+            if !self.wanted(index) {
+                self.pending.remove(&index);
+            }
+        }
+    }
+
+    /// When piece `index` is due, the earliest deadline of any source
+    fn deadline(&self, index: usize) -> Option<Instant> {
+        self.deadlines.get(&index)?.values().min().copied()
+    }
+
+    /// Count the pieces of a peer that connected
+    pub fn add_peer(&mut self, available: &[bool]) {
+        for (count, has) in self.availability.iter_mut().zip(available) {
+            *count += usize::from(*has);
+        }
+    }
+
+    /// How many connected peers have piece `index`
+    pub fn availability(&self, index: usize) -> usize {
+        self.availability[index]
+    }
+
+    /// Stop counting the pieces of a peer that disconnected
+    pub fn remove_peer(&mut self, available: &[bool]) {
+        for (count, has) in self.availability.iter_mut().zip(available) {
+            *count -= usize::from(*has);
+        }
+    }
+
+    /// Hand out the next piece for a peer that has the pieces `has` returns true for. `fast`
+    /// peers also get pieces with a deadline.
+    pub fn pick(&mut self, has: impl Fn(usize) -> bool, fast: bool) -> Option<usize> {
+        let candidates = self
+            .pending
+            .iter()
+            .copied()
+            .filter(|index| has(*index))
+            .filter(|index| fast || !self.deadlines.contains_key(index));
+
+        let due = candidates
+            .clone()
+            .filter_map(|index| Some((self.deadline(index)?, index)))
+            .min()
+            .map(|(_, index)| index);
+        let index = due.or_else(|| {
+            candidates.max_by_key(|index| {
+                let rarity = if self.sequential {
+                    0
+                } else {
+                    self.availability[*index]
+                };
+                (
+                    self.priorities[*index],
+                    std::cmp::Reverse(rarity),
+                    std::cmp::Reverse(*index),
+                )
+            })
+        })?;
+
+        self.pending.remove(&index);
+        self.in_flight.insert(index);
+        Some(index)
+    }
+
+    /// A piece that was handed out failed, another peer can try it
+    pub fn put_back(&mut self, index: usize) {
+        self.in_flight.remove(&index);
+        if self.wanted(index) {
+            self.pending.insert(index);
+        }
+    }
+
+    /// A piece that was handed out passed its hash check
+    pub fn finished(&mut self, index: usize) {
+        self.in_flight.remove(&index);
+        self.deadlines.remove(&index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn readers_clear_only_their_own_deadlines() {
+        let mut picker = Picker::new(4);
+        picker.set_priorities(vec![FilePriority::Skip; 4], &[false; 4]);
+        let now = Instant::now();
+        let (first, second) = (DeadlineSource::Reader(0), DeadlineSource::Reader(1));
+        picker.set_deadline(first, 1, now);
+        picker.set_deadline(second, 1, now + Duration::from_secs(1));
+        picker.set_deadline(second, 3, now + Duration::from_secs(2));
+
+        picker.clear_deadlines(first);
+        assert_eq!(picker.len(), 2);
+        picker.clear_deadlines(second);
+        assert!(picker.is_empty());
+
+        picker.set_deadline(first, 2, now + Duration::from_secs(1));
+        picker.set_deadline(second, 3, now);
+        picker.clear_deadlines(DeadlineSource::Manual);
+        assert_eq!(picker.pick(|_| true, false), None);
+        assert_eq!(picker.pick(|_| true, true), Some(3));
+        assert_eq!(picker.pick(|_| true, true), Some(2));
+    }
+}
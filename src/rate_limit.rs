@@ -0,0 +1,360 @@
+//! Token bucket bandwidth limits.
+//!
+//! Limiters form a tree, global > torrent > peer, and a transfer has to get its bytes from every
+//! limiter on the way up. Limits are plain atomics so they can be changed while transfers are
+//! running, all peer limiters of a torrent share one limit.
+//!
+//! Only payload (piece data) takes tokens. Protocol overhead like message headers and requests is
+//! counted separately and never throttled, otherwise a saturated limit could starve the requests
+//! that keep the transfer going.
+//!
+//! Every limiter also measures its payload rate, a rolling average over the last
+//! [`RATE_WINDOW`] seconds.
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Smallest bucket, so a whole block always fits even with a tiny limit
+const MIN_BURST: f64 = 16.0 * 1024.0;
+/// Seconds the measured rates average over
+pub const RATE_WINDOW: usize = 5;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Payload bytes in one second slots, the newest slot is the current second
+#[derive(Debug)]
+struct RateMeter {
+    started: Instant,
+    slots: [u64; RATE_WINDOW],
+    /// Seconds since `started` of the newest slot
+    second: u64,
+}
+
+impl RateMeter {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            slots: [0; RATE_WINDOW],
+            second: 0,
+        }
+    }
+
+    /// Move the window up to now, clearing the slots of the seconds nothing was recorded in
+    fn advance(&mut self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let second = elapsed as u64;
+        for skipped in (self.second + 1..=second).take(RATE_WINDOW) {
+            self.slots[skipped as usize % RATE_WINDOW] = 0;
+        }
+        self.second = self.second.max(second);
+        elapsed
+    }
+
+    fn record(&mut self, bytes: usize) {
+        self.advance();
+        self.slots[self.second as usize % RATE_WINDOW] += bytes as u64;
+    }
+
+    fn rate(&mut self) -> f64 {
+        let elapsed = self.advance();
+        // the newest slot only covers part of a second
+        let covered = elapsed
+            .min((RATE_WINDOW - 1) as f64 + elapsed.fract())
+            .max(1.0);
+        self.slots.iter().sum::<u64>() as f64 / covered
+    }
+}
+
+pub struct RateLimiter {
+    /// Bytes per second, 0 is unlimited
+    limit: Arc<AtomicU64>,
+    parent: Option<Arc<RateLimiter>>,
+    bucket: std::sync::Mutex<Bucket>,
+    payload: AtomicU64,
+    overhead: AtomicU64,
+    meter: std::sync::Mutex<RateMeter>,
+}
+
+impl Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("limit", &self.limit())
+            .field("payload", &self.payload_bytes())
+            .field("overhead", &self.overhead_bytes())
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    fn with_limit(limit: Arc<AtomicU64>, parent: Option<Arc<RateLimiter>>) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            parent,
+            bucket: std::sync::Mutex::new(Bucket {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+            payload: AtomicU64::new(0),
+            overhead: AtomicU64::new(0),
+            meter: std::sync::Mutex::new(RateMeter::new()),
+        })
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        match self.limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Payload bytes that went through this limiter
+    pub fn payload_bytes(&self) -> u64 {
+        self.payload.load(Ordering::Relaxed)
+    }
+
+    /// Protocol overhead bytes that went through this limiter
+    pub fn overhead_bytes(&self) -> u64 {
+        self.overhead.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes per second, averaged over the last [`RATE_WINDOW`] seconds
+    pub fn rate(&self) -> f64 {
+        self.meter.lock().unwrap().rate()
+    }
+
+    /// Take `bytes` tokens from this limiter, returns how long the caller has to wait for them.
+    /// The bucket may go into debt so transfers larger than the bucket still make progress.
+    fn take(&self, bytes: usize) -> Duration {
+        self.payload.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.meter.lock().unwrap().record(bytes);
+        let Some(limit) = self.limit() else {
+            return Duration::ZERO;
+        };
+
+        let rate = limit as f64;
+        let burst = rate.max(MIN_BURST);
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last_refill = now;
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    /// Wait until `bytes` of payload may be transferred, through this limiter and all its parents
+    pub async fn acquire(&self, bytes: usize) {
+        let mut limiter = Some(self);
+        while let Some(current) = limiter {
+            let wait = current.take(bytes);
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            limiter = current.parent.as_deref();
+        }
+    }
+
+    /// Count protocol overhead, it is not limited
+    pub fn record_overhead(&self, bytes: usize) {
+        let mut limiter = Some(self);
+        while let Some(current) = limiter {
+            current.overhead.fetch_add(bytes as u64, Ordering::Relaxed);
+            limiter = current.parent.as_deref();
+        }
+    }
+}
+
+/// Download and upload limiters of one level of the tree
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    pub download: Arc<RateLimiter>,
+    pub upload: Arc<RateLimiter>,
+    /// Limits shared by every peer below this level
+    peer_download: Arc<AtomicU64>,
+    peer_upload: Arc<AtomicU64>,
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl Bandwidth {
+    /// A root level, usually the global limits
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self::below(None, download, upload)
+    }
+
+    fn below(parent: Option<&Bandwidth>, download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: RateLimiter::with_limit(
+                Arc::new(AtomicU64::new(download.unwrap_or(0))),
+                parent.map(|p| p.download.clone()),
+            ),
+            upload: RateLimiter::with_limit(
+                Arc::new(AtomicU64::new(upload.unwrap_or(0))),
+                parent.map(|p| p.upload.clone()),
+            ),
+            peer_download: Arc::new(AtomicU64::new(0)),
+            peer_upload: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// A level below this one with its own limits, e.g. a torrent below the global limits
+    pub fn child(&self, download: Option<u64>, upload: Option<u64>) -> Self {
+        Self::below(Some(self), download, upload)
+    }
+
+    /// Limiters for one connection, they follow the peer limits of this level
+    pub fn for_peer(&self) -> Self {
+        Self {
+            download: RateLimiter::with_limit(
+                self.peer_download.clone(),
+                Some(self.download.clone()),
+            ),
+            upload: RateLimiter::with_limit(self.peer_upload.clone(), Some(self.upload.clone())),
+            peer_download: Arc::new(AtomicU64::new(0)),
+            peer_upload: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Change the limits of this level, running transfers pick them up with their next request
+    pub fn set_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.download.set_limit(download);
+        self.upload.set_limit(upload);
+    }
+
+    /// Change the limits of every connection created with [`Bandwidth::for_peer`], including
+    /// the ones that are already running
+    pub fn set_peer_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.peer_download
+            .store(download.unwrap_or(0), Ordering::Relaxed);
+        self.peer_upload
+            .store(upload.unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected * 0.02 + 0.02,
+            "{actual} isn't close to {expected}"
+        );
+    }
+
+    #[test]
+    fn waits_at_the_limit() {
+        let bandwidth = Bandwidth::new(Some(20_000), None);
+        let limiter = &bandwidth.download;
+        // the bucket starts out empty
+        assert_near(limiter.take(5_000).as_secs_f64(), 0.25);
+        assert_near(limiter.take(5_000).as_secs_f64(), 0.5);
+        assert_eq!(limiter.payload_bytes(), 10_000);
+
+        assert!(bandwidth.upload.take(1_000_000).is_zero());
+    }
+
+    #[test]
+    fn larger_transfers_go_into_debt() {
+        let limiter = Bandwidth::new(Some(20_000), None).download;
+        // more than the bucket holds still goes through, the wait pays it off
+        assert_near(limiter.take(100_000).as_secs_f64(), 5.0);
+        assert_near(limiter.take(1).as_secs_f64(), 5.0);
+    }
+
+    #[test]
+    fn limits_change_while_running() {
+        let bandwidth = Bandwidth::new(Some(1_000), None);
+        let limiter = &bandwidth.download;
+        assert_near(limiter.take(1_000).as_secs_f64(), 1.0);
+
+        bandwidth.set_limits(None, None);
+        assert_eq!(limiter.limit(), None);
+        assert!(limiter.take(1_000_000).is_zero());
+
+        // the debt from before is paid off at the new rate
+        bandwidth.set_limits(Some(2_000), None);
+        assert_near(limiter.take(1_000).as_secs_f64(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn transfers_wait_for_every_parent() {
+        let global = Bandwidth::new(Some(10_000), None);
+        let torrent = global.child(None, None);
+        let peer = torrent.for_peer();
+
+        let start = Instant::now();
+        peer.download.acquire(2_000).await;
+        assert_near(start.elapsed().as_secs_f64(), 0.2);
+        for limiter in [&peer.download, &torrent.download, &global.download] {
+            assert_eq!(limiter.payload_bytes(), 2_000);
+        }
+
+        peer.download.record_overhead(100);
+        assert_eq!(global.download.overhead_bytes(), 100);
+        assert_eq!(global.download.payload_bytes(), 2_000);
+    }
+
+    #[test]
+    fn peers_share_their_limit_but_not_their_bucket() {
+        let torrent = Bandwidth::default().child(None, None);
+        let first = torrent.for_peer();
+        torrent.set_peer_limits(Some(1_000), None);
+        let second = torrent.for_peer();
+        assert_eq!(first.download.limit(), Some(1_000));
+        assert_eq!(second.download.limit(), Some(1_000));
+        assert_eq!(torrent.download.limit(), None);
+
+        assert_near(first.download.take(1_000).as_secs_f64(), 1.0);
+        assert_near(second.download.take(1_000).as_secs_f64(), 1.0);
+
+        torrent.set_peer_limits(Some(4_000), Some(500));
+        assert_eq!(first.download.limit(), Some(4_000));
+        assert_eq!(second.upload.limit(), Some(500));
+        // peers below another level have their own limit
+        assert_eq!(torrent.child(None, None).for_peer().download.limit(), None);
+    }
+
+    #[test]
+    fn rates_skip_quiet_seconds() {
+        let mut meter = RateMeter::new();
+        let go_back = |meter: &mut RateMeter, secs: f64| {
+            meter.started -= Duration::from_secs_f64(secs);
+        };
+        go_back(&mut meter, 0.5);
+        meter.record(1_000);
+        // less than a second counts as one
+        assert_near(meter.rate(), 1_000.0);
+
+        go_back(&mut meter, 2.0);
+        meter.record(500);
+        assert_near(meter.rate(), 1_500.0 / 2.5);
+
+        // second 5 reuses the slot of second 0, the seconds in between were quiet
+        go_back(&mut meter, 3.0);
+        assert_near(meter.rate(), 500.0 / 4.5);
+
+        go_back(&mut meter, 10.0);
+        assert_eq!(meter.rate(), 0.0);
+        meter.record(900);
+        assert_near(meter.rate(), 900.0 / 4.5);
+    }
+}
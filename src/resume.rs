@@ -0,0 +1,351 @@
+//! Saving the torrents of a session so they survive a restart.
+//!
+//! Every torrent gets two bencoded files in the state directory, named by its info hash: the
+//! metainfo (`.torrent`) and the resume data (`.resume`). Both are written to a temporary file
+//! first and renamed over the old one, so a crash leaves either the old or the new version.
+//!
+//! The pieces in the resume data are trusted without hashing them again (fast resume), as long as
+//! every file still has the size and modification time it had when they were saved.
+//!
+//! Only torrents with their metainfo are saved, magnet links aren't.
+// TODO save magnet links as well, so one still waiting for its metainfo survives a restart. The
+// session can't add magnet links before it has the metadata exchange of BEP 9.
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+use tracing::warn;
+
+use crate::parser::{Info, Torrent};
+use crate::storage::{self, FilePriority};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "save path")]
+    pub save_path: String,
+    /// Indexed like [`Info::files`], see [`priority_level`]
+    #[serde(rename = "file priority")]
+    pub file_priorities: Vec<u8>,
+    /// 1 if the torrent was paused, bencode has no booleans
+    pub paused: u8,
+    #[serde(rename = "queue position")]
+    pub queue_position: usize,
+    /// Payload bytes over every run of the torrent
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bitfield of the pieces on disk, high bit first like the wire protocol
+    pub pieces: ByteBuf,
+    /// Size and modification time (seconds since the epoch) of every file when `pieces` was
+    /// saved, 0 for padding and missing files
+    #[serde(rename = "file sizes")]
+    pub file_sizes: Vec<u64>,
+    #[serde(rename = "file mtimes")]
+    pub file_mtimes: Vec<u64>,
+}
+
+impl ResumeData {
+    pub fn is_paused(&self) -> bool {
+        self.paused != 0
+    }
+
+    pub fn file_priorities(&self) -> Vec<FilePriority> {
+        self.file_priorities
+            .iter()
+            .map(|level| match level {
+                0 => FilePriority::Skip,
+                1 => FilePriority::Low,
+                3.. => FilePriority::High,
+                _ => FilePriority::Normal,
+            })
+            .collect()
+    }
+
+    /// The saved pieces, `None` if a file changed since they were saved and they can't be trusted
+    pub fn trusted_pieces(&self, info: &Info) -> Option<Vec<bool>> {
+        let (sizes, mtimes) = file_stamps(info, Path::new(&self.save_path));
+        if sizes != self.file_sizes || mtimes != self.file_mtimes {
+            return None;
+        }
+        Some(unpack_bitfield(&self.pieces, info.piece_count()))
+    }
+
+    /// Record `pieces` as the ones on disk, stamped with the files as they are now
+    pub fn set_pieces(&mut self, info: &Info, pieces: &[bool]) {
+        self.pieces = ByteBuf::from(pack_bitfield(pieces));
+        (self.file_sizes, self.file_mtimes) = file_stamps(info, Path::new(&self.save_path));
+    }
+}
+
+pub fn priority_level(priority: FilePriority) -> u8 {
+    match priority {
+        FilePriority::Skip => 0,
+        FilePriority::Low => 1,
+        FilePriority::Normal => 2,
+        FilePriority::High => 3,
+    }
+}
+
+fn file_stamps(info: &Info, save_path: &Path) -> (Vec<u64>, Vec<u64>) {
+    storage::file_paths(info, save_path)
+        .into_iter()
+        .map(|path| {
+            let Some(metadata) = path.and_then(|path| std::fs::metadata(path).ok()) else {
+                return (0, 0);
+            };
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |mtime| mtime.as_secs());
+            (metadata.len(), mtime)
+        })
+        .unzip()
+}
+
+fn pack_bitfield(pieces: &[bool]) -> Vec<u8> {
+    pieces
+        .chunks(8)
+        .map(|chunk| {
+            chunk.iter().enumerate().fold(0u8, |byte, (bit, have)| {
+                byte | (u8::from(*have) << (7 - bit))
+            })
+        })
+        .collect()
+}
+
+fn unpack_bitfield(bitfield: &[u8], piece_count: usize) -> Vec<bool> {
+    (0..piece_count)
+        .map(|index| {
+            bitfield
+                .get(index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+        })
+        .collect()
+}
+
+fn state_path(state_dir: &Path, info_hash: &[u8; 20], extension: &str) -> PathBuf {
+    state_dir.join(format!("{}.{extension}", hex::encode(info_hash)))
+}
+
+/// Replace `path` with `data` so that readers see either the old or the new content. Every write
+/// gets a temporary file of its own, two saves of the same torrent may run at once.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), anyhow::Error> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temporary = PathBuf::from(temporary);
+
+    let mut file = File::create(&temporary)?;
+    let written = file
+        .write_all(data)
+        .and_then(|()| file.sync_all())
+        .and_then(|()| std::fs::rename(&temporary, path));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(e.into());
+    }
+    // the rename itself is only durable once the directory is
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Save the metainfo of a torrent, it doesn't change so this is only needed once
+pub fn save_torrent(
+    state_dir: &Path,
+    info_hash: &[u8; 20],
+    torrent: &Torrent,
+) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(state_dir)?;
+    let path = state_path(state_dir, info_hash, "torrent");
+    write_atomic(&path, &serde_bencode::to_bytes(torrent)?)
+}
+
+pub fn save_resume(
+    state_dir: &Path,
+    info_hash: &[u8; 20],
+    resume: &ResumeData,
+) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(state_dir)?;
+    let path = state_path(state_dir, info_hash, "resume");
+    write_atomic(&path, &serde_bencode::to_bytes(resume)?)
+}
+
+/// Forget a torrent, missing files are fine
+pub fn remove(state_dir: &Path, info_hash: &[u8; 20]) -> Result<(), anyhow::Error> {
+    for extension in ["torrent", "resume"] {
+        match std::fs::remove_file(state_path(state_dir, info_hash, extension)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Every torrent saved in `state_dir` with its resume data, in queue order. Torrents that can't
+/// be read are skipped with a warning.
+pub fn load(state_dir: &Path) -> Result<Vec<(Torrent, ResumeData)>, anyhow::Error> {
+    let entries = match std::fs::read_dir(state_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut torrents = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_none_or(|extension| extension != "resume")
+        {
+            continue;
+        }
+        let loaded = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_bencode::from_bytes::<ResumeData>(&data)?))
+            .and_then(|resume| {
+                let torrent = crate::parser::parse_torrent_file(path.with_extension("torrent"))?;
+                Ok((torrent, resume))
+            });
+        match loaded {
+            Ok(loaded) => torrents.push(loaded),
+            Err(e) => warn!("Skipping {}: {e}", path.display()),
+        }
+    }
+    torrents.sort_by_key(|(_, resume)| resume.queue_position);
+    Ok(torrents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{create_torrent, CreateOptions};
+    use std::time::{Duration, SystemTime};
+
+    /// A torrent of one file with three pieces, downloaded into the returned directory
+    fn downloaded(name: &str) -> (tempfile::TempDir, Torrent) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, vec![7u8; 40_000]).unwrap();
+        let options = CreateOptions {
+            trackers: vec![vec!["http://127.0.0.1:7070/announce".to_string()]],
+            piece_length: Some(16384),
+            ..CreateOptions::default()
+        };
+        let torrent = create_torrent(&path, &options).unwrap();
+        (dir, torrent)
+    }
+
+    #[test]
+    fn packs_bitfields() {
+        let cases: [(&[bool], &[u8]); 4] = [
+            (&[], &[]),
+            (&[true], &[0x80]),
+            (
+                &[true, false, true, true, false, false, false, true],
+                &[0xb1],
+            ),
+            (&[false; 8], &[0]),
+        ];
+        for (pieces, bitfield) in cases {
+            assert_eq!(pack_bitfield(pieces), bitfield);
+            assert_eq!(unpack_bitfield(bitfield, pieces.len()), pieces);
+        }
+
+        let mut pieces = vec![false; 10];
+        pieces[9] = true;
+        assert_eq!(pack_bitfield(&pieces), [0, 0x40]);
+        // a short bitfield is missing the last pieces, spare bits are ignored
+        let mut expected = vec![true; 8];
+        expected.extend([false, false]);
+        assert_eq!(unpack_bitfield(&[0xff], 10), expected);
+        assert_eq!(unpack_bitfield(&[0xff, 0xff], 9), vec![true; 9]);
+    }
+
+    #[test]
+    fn trusts_pieces_of_unchanged_files() {
+        let (dir, torrent) = downloaded("data");
+        let info = &torrent.info;
+        let mut resume = ResumeData {
+            save_path: dir.path().to_string_lossy().into_owned(),
+            ..ResumeData::default()
+        };
+        resume.set_pieces(info, &[true, false, true]);
+        assert_eq!(resume.file_sizes, [40_000]);
+        assert_eq!(resume.trusted_pieces(info), Some(vec![true, false, true]));
+
+        let path = dir.path().join("data");
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(resume.trusted_pieces(info), None);
+
+        resume.set_pieces(info, &[true, true, true]);
+        assert!(resume.trusted_pieces(info).is_some());
+        file.set_len(30_000).unwrap();
+        assert_eq!(resume.trusted_pieces(info), None);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(resume.trusted_pieces(info), None);
+        resume.set_pieces(info, &[false; 3]);
+        assert_eq!(resume.file_sizes, [0]);
+        assert_eq!(resume.trusted_pieces(info), Some(vec![false; 3]));
+    }
+
+    #[test]
+    fn saves_and_loads_in_queue_order() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let state_dir = state_dir.path().join("state");
+        assert!(load(&state_dir).unwrap().is_empty());
+
+        let mut saved = Vec::new();
+        for (name, queue_position) in [("first", 1), ("second", 0)] {
+            let (_dir, torrent) = downloaded(name);
+            let info_hash = crate::parser::calculate_info_hash_bytes(&torrent.info).unwrap();
+            let resume = ResumeData {
+                save_path: format!("/downloads/{name}"),
+                file_priorities: vec![3],
+                paused: 1,
+                queue_position,
+                downloaded: 1234,
+                uploaded: 5678,
+                pieces: ByteBuf::from(vec![0xa0]),
+                file_sizes: vec![40_000],
+                file_mtimes: vec![1_700_000_000],
+            };
+            save_torrent(&state_dir, &info_hash, &torrent).unwrap();
+            save_resume(&state_dir, &info_hash, &resume).unwrap();
+            saved.push((info_hash, torrent, resume));
+        }
+        // unreadable entries and other files are skipped
+        std::fs::write(state_dir.join("broken.resume"), b"not bencode").unwrap();
+        // a resume file without its metainfo
+        let orphan = serde_bencode::to_bytes(&ResumeData::default()).unwrap();
+        std::fs::write(state_path(&state_dir, &[0xab; 20], "resume"), orphan).unwrap();
+        std::fs::write(state_dir.join("notes.txt"), b"").unwrap();
+
+        let loaded = load(&state_dir).unwrap();
+        assert_eq!(loaded.len(), 2);
+        for ((torrent, resume), (_, saved_torrent, saved_resume)) in
+            loaded.iter().zip(saved.iter().rev())
+        {
+            assert_eq!(torrent.info.name, saved_torrent.info.name);
+            assert_eq!(resume, saved_resume);
+            assert!(resume.is_paused());
+            assert_eq!(resume.file_priorities(), [FilePriority::High]);
+        }
+
+        remove(&state_dir, &saved[0].0).unwrap();
+        remove(&state_dir, &saved[0].0).unwrap();
+        assert_eq!(load(&state_dir).unwrap().len(), 1);
+    }
+}
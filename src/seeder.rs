@@ -0,0 +1,538 @@
+//! Uploading a complete torrent to the peers that connect to us.
+//!
+//! The session accepts incoming connections and hands them to the seeder of the torrent they are
+//! for. The seeder announces itself as a seed to the trackers and answers requests from the peers
+//! the choker unchoked. v2 peers also get the piece layers they ask for.
+//!
+//! With `super_seeding` the full bitfield is hidden and peers are handed one piece at a time, see
+//! [`crate::super_seed`].
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{error, info};
+
+use crate::choker::{Choker, ChokerConfig, PeerStats};
+use crate::connection_manager::ConnectionSlot;
+use crate::discovery::PeerDiscoverer;
+use crate::events::{Event, EventSender};
+use crate::parser::{swarm_info_hashes, Info, Torrent};
+use crate::peer_connection::{
+    self, answer_hash_request, extended_client, extended_handshake_message, Handshake, EXTENDED_ID,
+    EXTENSION_PROTOCOL_BIT, HASH_REQUEST_ID,
+};
+use crate::peer_id::ClientId;
+use crate::rate_limit::Bandwidth;
+use crate::status::{PeerFlags, PeerInfo, PeerList};
+use crate::storage::Storage;
+use crate::super_seed::SuperSeed;
+
+/// Largest block a peer may request, anything bigger is dropped
+const MAX_REQUEST_LENGTH: usize = 128 * 1024;
+/// Peers that send nothing, not even a keep-alive, for this long are disconnected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+#[derive(Debug)]
+pub struct Seeder {
+    info: Arc<Info>,
+    /// Where the pieces are read from, every one of them verified
+    storage: Arc<Storage>,
+    /// BEP 52 piece layers of the metainfo, served to v2 peers that ask for them
+    piece_layers: HashMap<ByteBuf, ByteBuf>,
+    info_hashes: Vec<[u8; 20]>,
+    peer_id: [u8; 20],
+    /// BEP 16, only reveal pieces one at a time
+    pub super_seeding: bool,
+    pub choker: ChokerConfig,
+    /// Limits of this torrent, usually a child of the global limits
+    pub bandwidth: Bandwidth,
+    /// Connected peers at most
+    pub max_connections: usize,
+    pub events: EventSender,
+    /// Connected peers, for status snapshots
+    pub peers: PeerList,
+    /// A peer became interested, give it a free slot now instead of letting it wait for the next
+    /// round
+    interest: Notify,
+    /// Connections the session accepted for this torrent
+    incoming: mpsc::Sender<Incoming>,
+    receiver: std::sync::Mutex<Option<mpsc::Receiver<Incoming>>>,
+}
+
+/// An accepted connection, the handshake the session already read from it and its slot in the
+/// session's connection limits
+type Incoming = (TcpStream, SocketAddrV4, [u8; 68], ConnectionSlot);
+
+/// Read the handshake of an incoming connection, its info hash says which torrent it wants
+pub async fn read_handshake(stream: &mut TcpStream) -> Result<[u8; 68], anyhow::Error> {
+    let mut buf = [0u8; 68];
+    time::timeout(Duration::from_secs(10), stream.read_exact(&mut buf))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out waiting for the handshake"))??;
+    if buf[0] != 19 || &buf[1..20] != b"BitTorrent protocol" {
+        anyhow::bail!("Not a BitTorrent handshake");
+    }
+    Ok(buf)
+}
+
+/// One connected peer
+#[derive(Debug)]
+struct Connection {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    /// Pieces the peer announced
+    has: Vec<bool>,
+    /// Whether we choke the peer, as last sent
+    am_choking: bool,
+}
+
+#[derive(Debug)]
+struct State {
+    connections: HashMap<SocketAddrV4, Connection>,
+    /// What the choker works with, same keys as `connections`
+    stats: HashMap<SocketAddrV4, PeerStats>,
+    super_seed: Option<SuperSeed>,
+}
+
+impl Seeder {
+    /// Every piece in `storage` has to be verified
+    pub fn new(
+        torrent: &Torrent,
+        storage: Arc<Storage>,
+        peer_id: [u8; 20],
+    ) -> Result<Self, anyhow::Error> {
+        let (incoming, receiver) = mpsc::channel(16);
+        Ok(Self {
+            info: Arc::new(torrent.info.clone()),
+            storage,
+            piece_layers: torrent.piece_layers.clone(),
+            info_hashes: swarm_info_hashes(&torrent.info)?,
+            peer_id,
+            super_seeding: false,
+            choker: ChokerConfig::default(),
+            bandwidth: Bandwidth::default(),
+            max_connections: 50,
+            events: EventSender::default(),
+            peers: PeerList::default(),
+            interest: Notify::new(),
+            incoming,
+            receiver: std::sync::Mutex::new(Some(receiver)),
+        })
+    }
+
+    /// Whether a handshake with `info_hash` is for this torrent
+    pub fn serves(&self, info_hash: &[u8; 20]) -> bool {
+        self.info_hashes.contains(info_hash)
+    }
+
+    /// Hand over an incoming connection, `false` if the seeder isn't running or is too busy
+    pub fn accept(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddrV4,
+        handshake: [u8; 68],
+        slot: ConnectionSlot,
+    ) -> bool {
+        self.incoming
+            .try_send((stream, addr, handshake, slot))
+            .is_ok()
+    }
+
+    /// Seed until the task is aborted, which also closes every connection. `discoverer` should
+    /// announce our listen port with nothing left.
+    pub async fn run(self: Arc<Self>, mut discoverer: PeerDiscoverer) -> Result<(), anyhow::Error> {
+        let mut incoming = self
+            .receiver
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("Seeder is already running"))?;
+        info!(
+            "Seeding {}{}",
+            self.info.name,
+            if self.super_seeding {
+                " in super-seeding mode"
+            } else {
+                ""
+            }
+        );
+
+        let state = Arc::new(Mutex::new(State {
+            connections: HashMap::new(),
+            stats: HashMap::new(),
+            super_seed: self
+                .super_seeding
+                .then(|| SuperSeed::new(self.info.piece_count())),
+        }));
+        let mut choker = Choker::new(self.choker.clone());
+        let mut choke_round = time::interval(self.choker.interval);
+        let mut announce = Box::pin(time::sleep(Duration::ZERO));
+        let mut tasks = JoinSet::new();
+
+        loop {
+            tokio::select! {
+                Some((stream, addr, handshake, slot)) = incoming.recv() => {
+                    // a task per peer from the moment it is accepted, so peers still handshaking
+                    // hold their slot too. Tasks that ended give theirs back.
+                    while let Some(res) = tasks.try_join_next() {
+                        if let Err(e) = res {
+                            error!("A peer task panicked: {e:?}");
+                        }
+                    }
+                    if tasks.len() >= self.max_connections {
+                        info!("Refusing {addr}, too many connections");
+                        continue;
+                    }
+                    let peer = Arc::clone(&self);
+                    tasks.spawn(peer.serve_peer(stream, addr, handshake, slot, Arc::clone(&state)));
+                }
+                _ = &mut announce => {
+                    discoverer.uploaded = self.bandwidth.upload.payload_bytes() as usize;
+                    let interval = match discoverer.discover().await {
+                        Ok(response) => response.interval as u64,
+                        Err(e) => {
+                            error!("Announce failed: {e}");
+                            60
+                        }
+                    };
+                    announce = Box::pin(time::sleep(Duration::from_secs(interval)));
+                }
+                // a full round would reset the rate windows, a peer toggling its interest could
+                // force those back to back
+                _ = self.interest.notified() => {
+                    let mut state = state.lock().await;
+                    choker.fill_free_slots(&mut state.stats);
+                    self.apply_chokes(&mut state);
+                }
+                _ = choke_round.tick() => {
+                    let mut state = state.lock().await;
+                    choker.rechoke(&mut state.stats, true);
+                    self.apply_chokes(&mut state);
+                }
+                Some(res) = tasks.join_next(), if !tasks.is_empty() => {
+                    if let Err(e) = res {
+                        error!("A peer task panicked: {e:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send the choker's decisions to the peers whose choke state changed
+    fn apply_chokes(&self, state: &mut State) {
+        let mut changes = Vec::new();
+        for (addr, conn) in state.connections.iter_mut() {
+            let Some(stats) = state.stats.get(addr) else {
+                continue;
+            };
+            if stats.choked != conn.am_choking {
+                conn.am_choking = stats.choked;
+                self.peers
+                    .update(addr, |info| info.flags.choked = stats.choked);
+                changes.push((*addr, Arc::clone(&conn.writer), stats.choked));
+            }
+        }
+        tokio::spawn(async move {
+            for (addr, writer, choke) in changes {
+                let msg = [0, 0, 0, 1, if choke { 0 } else { 1 }];
+                if let Err(e) = writer.lock().await.write_all(&msg).await {
+                    error!("Failed to update choke state of {addr}: {e}");
+                }
+            }
+        });
+    }
+
+    async fn serve_peer(
+        self: Arc<Self>,
+        stream: TcpStream,
+        addr: SocketAddrV4,
+        handshake: [u8; 68],
+        mut slot: ConnectionSlot,
+        state: Arc<Mutex<State>>,
+    ) {
+        if let Err(e) = self.serve(stream, addr, handshake, &mut slot, &state).await {
+            info!("Connection with {addr} ended: {e}");
+        }
+        self.peers.remove(&addr);
+        self.events.send(Event::PeerDisconnected {
+            info_hash: self.info_hashes[0],
+            addr,
+        });
+
+        let mut state = state.lock().await;
+        let state = &mut *state;
+        state.stats.remove(&addr);
+        if let Some(conn) = state.connections.remove(&addr) {
+            if let Some(super_seed) = state.super_seed.as_mut() {
+                super_seed.peer_left(&addr, &conn.has);
+            }
+        }
+    }
+
+    async fn serve(
+        &self,
+        mut stream: TcpStream,
+        addr: SocketAddrV4,
+        handshake: [u8; 68],
+        slot: &mut ConnectionSlot,
+        state: &Mutex<State>,
+    ) -> Result<(), anyhow::Error> {
+        let info_hash: [u8; 20] = handshake[28..48].try_into()?;
+        let peer_id: [u8; 20] = handshake[48..68].try_into()?;
+        let client = ClientId::from_peer_id(&peer_id);
+        info!(
+            "{addr} connected, running {}",
+            client.as_ref().map_or_else(
+                || "an unknown client".to_string(),
+                |client| client.to_string()
+            )
+        );
+        self.events.send(Event::PeerConnected {
+            info_hash: self.info_hashes[0],
+            addr,
+            client: client.clone(),
+        });
+
+        stream
+            .write_all(&Handshake::new(&info_hash, &self.peer_id, self.info.is_v2()).serialize())
+            .await?;
+        if handshake[25] & EXTENSION_PROTOCOL_BIT != 0 {
+            stream.write_all(&extended_handshake_message()?).await?;
+        }
+        slot.connected();
+
+        let piece_count = self.info.piece_count();
+        let peer_bandwidth = self.bandwidth.for_peer();
+        let (mut reader, writer) = stream.into_split();
+        let writer = Arc::new(Mutex::new(writer));
+
+        // a super-seed looks like a peer with nothing, everything else gets the full bitfield
+        let first_offer = {
+            let mut state = state.lock().await;
+            let state = &mut *state;
+            state.connections.insert(
+                addr,
+                Connection {
+                    writer: Arc::clone(&writer),
+                    has: vec![false; piece_count],
+                    am_choking: true,
+                },
+            );
+            state.stats.insert(addr, PeerStats::new());
+            // a seed never wants anything, so it is never unchoked either
+            let flags = PeerFlags {
+                choked: true,
+                remote_choked: true,
+                incoming: true,
+                ..PeerFlags::default()
+            };
+            self.peers.insert(
+                PeerInfo::new(addr, client, flags),
+                peer_bandwidth.clone(),
+                Arc::new(AtomicUsize::new(0)),
+            );
+            state
+                .super_seed
+                .as_mut()
+                .map(|super_seed| super_seed.next_offer(addr, &[]))
+        };
+        match first_offer {
+            Some(offer) => {
+                if let Some(index) = offer {
+                    send_have(&writer, index).await?;
+                }
+            }
+            None => {
+                let mut bitfield = vec![0u8; piece_count.div_ceil(8)];
+                for index in 0..piece_count {
+                    bitfield[index / 8] |= 0x80 >> (index % 8);
+                }
+                let mut msg = Vec::with_capacity(5 + bitfield.len());
+                msg.extend_from_slice(&(1 + bitfield.len() as u32).to_be_bytes());
+                msg.push(5);
+                msg.extend_from_slice(&bitfield);
+                writer.lock().await.write_all(&msg).await?;
+            }
+        }
+
+        loop {
+            let message = time::timeout(IDLE_TIMEOUT, peer_connection::read_message(&mut reader))
+                .await
+                .map_err(|_| anyhow::anyhow!("Peer was idle for too long"))??;
+            let Some((id, payload)) = message else {
+                continue;
+            };
+
+            match id {
+                2 | 3 => {
+                    if let Some(stats) = state.lock().await.stats.get_mut(&addr) {
+                        stats.peer_interested = id == 2;
+                    }
+                    self.peers
+                        .update(&addr, |info| info.flags.remote_interested = id == 2);
+                    if id == 2 {
+                        self.interest.notify_one();
+                    }
+                }
+                // have
+                4 => {
+                    let index = payload
+                        .get(..4)
+                        .map(|index| u32::from_be_bytes(index.try_into().unwrap()) as usize)
+                        .filter(|index| *index < piece_count)
+                        .ok_or_else(|| anyhow::anyhow!("Invalid have"))?;
+                    self.peer_has(addr, &[index], false, state).await?;
+                }
+                // bitfield
+                5 => {
+                    let pieces: Vec<usize> = (0..piece_count)
+                        .filter(|index| {
+                            payload
+                                .get(index / 8)
+                                .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+                        })
+                        .collect();
+                    self.peer_has(addr, &pieces, true, state).await?;
+                }
+                // request
+                6 => {
+                    if payload.len() < 12 {
+                        anyhow::bail!("Invalid request");
+                    }
+                    let index = u32::from_be_bytes(payload[0..4].try_into()?) as usize;
+                    let begin = u32::from_be_bytes(payload[4..8].try_into()?) as usize;
+                    let length = u32::from_be_bytes(payload[8..12].try_into()?) as usize;
+                    if index >= piece_count
+                        || length > MAX_REQUEST_LENGTH
+                        || begin + length > self.info.piece_size(index)
+                    {
+                        anyhow::bail!("Invalid request for piece {index}");
+                    }
+
+                    {
+                        let state = state.lock().await;
+                        let choked = state.stats.get(&addr).is_none_or(|stats| stats.choked);
+                        let hidden = state
+                            .super_seed
+                            .as_ref()
+                            .is_some_and(|super_seed| !super_seed.may_upload(&addr, index));
+                        if choked || hidden {
+                            continue;
+                        }
+                    }
+
+                    peer_bandwidth.upload.acquire(length).await;
+                    let offset = index * self.info.piece_length + begin;
+                    let storage = Arc::clone(&self.storage);
+                    let block =
+                        tokio::task::spawn_blocking(move || storage.read(offset, length)).await??;
+                    let mut msg = Vec::with_capacity(13 + length);
+                    msg.extend_from_slice(&(9 + length as u32).to_be_bytes());
+                    msg.push(7);
+                    msg.extend_from_slice(&(index as u32).to_be_bytes());
+                    msg.extend_from_slice(&(begin as u32).to_be_bytes());
+                    msg.extend_from_slice(&block);
+                    writer.lock().await.write_all(&msg).await?;
+                    peer_bandwidth.upload.record_overhead(13);
+
+                    if let Some(stats) = state.lock().await.stats.get_mut(&addr) {
+                        stats.uploaded += length as u64;
+                    }
+                }
+                HASH_REQUEST_ID => {
+                    let answer =
+                        answer_hash_request(&payload, self.info.piece_length, &self.piece_layers)?;
+                    writer.lock().await.write_all(&answer).await?;
+                    peer_bandwidth.upload.record_overhead(answer.len());
+                }
+                EXTENDED_ID => {
+                    if let Some(client) = extended_client(&payload) {
+                        info!("{addr} runs {client}");
+                        self.peers.update(&addr, |info| info.client = Some(client));
+                    }
+                }
+                // choke, unchoke, cancel and whatever else we don't act on as a seed
+                _ => {}
+            }
+        }
+    }
+
+    /// `addr` announced `pieces` with a have or a `bitfield`, with super-seeding this hands out new
+    /// pieces to the peers that spread theirs
+    async fn peer_has(
+        &self,
+        addr: SocketAddrV4,
+        pieces: &[usize],
+        bitfield: bool,
+        state: &Mutex<State>,
+    ) -> Result<(), anyhow::Error> {
+        let mut offers = Vec::new();
+        {
+            let mut state = state.lock().await;
+            let state = &mut *state;
+            let Some(conn) = state.connections.get_mut(&addr) else {
+                return Ok(());
+            };
+            let mut new_pieces = Vec::new();
+            for &index in pieces {
+                if !conn.has[index] {
+                    conn.has[index] = true;
+                    new_pieces.push(index);
+                }
+            }
+            let available = conn.has.iter().filter(|has| **has).count();
+            self.peers
+                .update(&addr, |info| info.pieces_available = available);
+
+            let Some(super_seed) = state.super_seed.as_mut() else {
+                return Ok(());
+            };
+            let mut spread = Vec::new();
+            for index in new_pieces {
+                spread.extend(super_seed.peer_has(addr, index));
+            }
+            // a have for our offer means the peer got it from us, but if its bitfield already had
+            // the piece the offer was wasted and it needs another one
+            if bitfield
+                && super_seed
+                    .current_offer(&addr)
+                    .is_some_and(|index| pieces.contains(&index))
+            {
+                spread.push(addr);
+            }
+
+            spread.sort_unstable_by_key(|addr| (*addr.ip(), addr.port()));
+            spread.dedup();
+            for peer in spread {
+                let Some(conn) = state.connections.get(&peer) else {
+                    continue;
+                };
+                if let Some(index) = super_seed.next_offer(peer, &conn.has) {
+                    info!("Piece spread, offering piece {index} to {peer}");
+                    offers.push((Arc::clone(&conn.writer), index));
+                }
+            }
+        }
+
+        for (writer, index) in offers {
+            send_have(&writer, index).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn send_have(writer: &Mutex<OwnedWriteHalf>, index: usize) -> Result<(), anyhow::Error> {
+    let mut msg = Vec::with_capacity(9);
+    msg.extend_from_slice(&5u32.to_be_bytes());
+    msg.push(4);
+    msg.extend_from_slice(&(index as u32).to_be_bytes());
+    writer.lock().await.write_all(&msg).await?;
+    Ok(())
+}
@@ -0,0 +1,1821 @@
+//! The session, what embedders use to run torrents.
+//!
+//! A session owns everything the torrents share: the listener for incoming connections, the global
+//! bandwidth and connection limits, the ban list, the IP filter and our peer ID. Every torrent
+//! added to it runs as its own task and is controlled through a [`TorrentHandle`].
+//!
+//! A torrent first checks what is already in the download directory, downloads whatever is missing
+//! and then seeds or finishes, depending on [`SessionConfig::seed`]. Pausing aborts the task but
+//! keeps the downloaded pieces, resuming picks up where it left off.
+//!
+//! Torrents wait in a queue for their turn. Going by queue position the session starts as many as
+//! the active limits allow and queues the rest again, torrents that transfer slower than the slow
+//! rates don't count towards the limits so they don't hold up the queue.
+//!
+//! With a [`SessionConfig::state_dir`] the torrents are saved there and added again when the next
+//! session starts, see [`crate::resume`].
+//!
+//! The configuration can change while the session runs, see [`Session::set_config`].
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::choker::ChokerConfig;
+use crate::connection_manager::GlobalConnections;
+use crate::discovery::PeerDiscoverer;
+use crate::downloader::Downloader;
+use crate::events::{Event, EventCategory, EventReceiver, EventSender};
+use crate::ip_filter::IpFilter;
+use crate::parser::{self, swarm_info_hashes, Torrent};
+use crate::peer_connection::{self, PeerConfig};
+use crate::picker::{DeadlineSource, Picker};
+use crate::rate_limit::Bandwidth;
+use crate::resume::{self, ResumeData};
+use crate::seeder::{self, Seeder};
+use crate::settings::EncryptionPolicy;
+use crate::smart_ban::BanList;
+use crate::status::{PeerInfo, PeerList, TorrentStatus};
+use crate::storage::{FilePriority, Storage};
+use crate::stream_server;
+
+/// Port announced to trackers when we don't listen, nobody can connect to it anyway
+const UNLISTENED_PORT: u16 = 6969;
+/// How much later each piece of the read-ahead window is due than the one before it
+pub const READ_AHEAD_STEP: Duration = Duration::from_millis(200);
+/// How often the queue is gone through, it also is whenever a torrent is added, paused or moved
+const QUEUE_INTERVAL: Duration = Duration::from_secs(5);
+/// A torrent that was just started always counts, it needs some time to find peers
+const SLOW_GRACE: Duration = Duration::from_secs(60);
+/// How often the torrents are saved to the state directory, besides when they change
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a session runs with, usually made from [`crate::settings::Settings`]. Changed through
+/// [`Session::set_config`] the limits, the queue, the ports and the IP filter take effect right
+/// away. Torrents pick up the rest when they start next, except for the download directory that
+/// only applies to torrents added later and the ban file and peer ID prefix that are only read
+/// when the session starts.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub download_dir: PathBuf,
+    /// Port for incoming connections, also announced to the trackers. `None` doesn't listen.
+    pub listen_port: Option<u16>,
+    pub choker: ChokerConfig,
+    /// Bytes per second over all torrents, `None` is unlimited
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    /// Bytes per second of every single torrent
+    pub torrent_download_limit: Option<u64>,
+    pub torrent_upload_limit: Option<u64>,
+    /// Bytes per second of every single connection
+    pub peer_download_limit: Option<u64>,
+    pub peer_upload_limit: Option<u64>,
+    /// Open connections over all torrents
+    pub max_connections: usize,
+    /// Connections still connecting or handshaking over all torrents
+    pub max_half_open: usize,
+    /// Open connections of every single torrent
+    pub torrent_max_connections: usize,
+    /// Bans are loaded from and saved to this file
+    pub ban_file: Option<PathBuf>,
+    /// Blocklists, see [`crate::ip_filter`]
+    pub ip_filter_files: Vec<PathBuf>,
+    /// Keep seeding once a torrent is complete
+    pub seed: bool,
+    /// Seed with BEP 16 super-seeding
+    pub super_seeding: bool,
+    /// Download pieces in order instead of rarest first, see [`TorrentHandle::set_sequential`]
+    pub sequential: bool,
+    /// Bytes ahead of the reader that get deadlines, see [`TorrentReader::set_position`]
+    pub read_ahead: u64,
+    /// Port of the HTTP server that streams the files, see [`crate::stream_server`]. It only
+    /// listens on loopback, `None` doesn't serve at all.
+    pub stream_port: Option<u16>,
+    /// Torrents downloading at once, `None` is unlimited
+    pub active_downloads: Option<usize>,
+    /// Torrents seeding at once
+    pub active_seeds: Option<usize>,
+    /// Torrents downloading or seeding at once
+    pub active_limit: Option<usize>,
+    /// Let torrents that transfer slower than the slow rates run without counting towards the
+    /// active limits
+    pub dont_count_slow_torrents: bool,
+    /// Bytes per second a torrent has to download or upload to count towards the active limits
+    pub slow_download_rate: u64,
+    pub slow_upload_rate: u64,
+    /// Where the torrents are saved to survive a restart, `None` forgets them
+    pub state_dir: Option<PathBuf>,
+    /// Timeouts and request size of peer connections
+    pub peer: PeerConfig,
+    /// How long trackers get to answer
+    pub tracker_timeout: Duration,
+    /// Start of our peer ID, see [`crate::peer_id::generate`]
+    pub peer_id_prefix: String,
+    /// Only [`EncryptionPolicy::Disabled`] is supported so far, `Enabled` connects in plaintext
+    /// and `Forced` is refused
+    pub encryption: EncryptionPolicy,
+    /// Peer sources besides the trackers, not supported so far. Private torrents never use them.
+    pub dht: bool,
+    pub pex: bool,
+    pub lsd: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        let connections = GlobalConnections::default();
+        Self {
+            download_dir: PathBuf::from("."),
+            listen_port: None,
+            choker: ChokerConfig::default(),
+            download_limit: None,
+            upload_limit: None,
+            torrent_download_limit: None,
+            torrent_upload_limit: None,
+            peer_download_limit: None,
+            peer_upload_limit: None,
+            max_connections: connections.max_connections(),
+            max_half_open: connections.max_half_open(),
+            torrent_max_connections: 50,
+            ban_file: None,
+            ip_filter_files: Vec::new(),
+            seed: false,
+            super_seeding: false,
+            sequential: false,
+            read_ahead: 4 * 1024 * 1024,
+            stream_port: None,
+            active_downloads: Some(3),
+            active_seeds: Some(5),
+            active_limit: Some(15),
+            dont_count_slow_torrents: true,
+            slow_download_rate: 2048,
+            slow_upload_rate: 2048,
+            state_dir: None,
+            peer: PeerConfig::default(),
+            tracker_timeout: Duration::from_secs(5),
+            peer_id_prefix: crate::peer_id::PREFIX.to_string(),
+            encryption: EncryptionPolicy::Disabled,
+            dht: false,
+            pex: false,
+            lsd: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Waiting for its turn, see [`SessionConfig::active_downloads`]
+    Queued,
+    /// Checking the data already in the download directory
+    Checking,
+    Downloading,
+    Seeding,
+    /// Every wanted piece is there and we don't seed
+    Finished,
+    Paused,
+    Error(String),
+}
+
+/// Everything the session knows about one torrent, it outlives the torrent's task
+#[derive(Debug)]
+struct TorrentEntry {
+    torrent: Torrent,
+    /// The directory the files are downloaded into
+    save_path: PathBuf,
+    /// Swarms of the torrent, the first one identifies it
+    info_hashes: Vec<[u8; 20]>,
+    state: watch::Sender<TorrentState>,
+    task: std::sync::Mutex<Option<JoinHandle<()>>>,
+    /// The files in `save_path`, pieces are written there as they pass their hash check
+    storage: Arc<Storage>,
+    /// Pieces that passed their hash check
+    have: Arc<std::sync::Mutex<Vec<bool>>>,
+    /// Indexed like [`crate::parser::Info::files`]
+    file_priorities: std::sync::Mutex<Vec<FilePriority>>,
+    /// What the downloader works through, outlives the downloads so deadlines and sequential mode
+    /// stick
+    picker: Arc<std::sync::Mutex<Picker>>,
+    bandwidth: Bandwidth,
+    /// Connected peers, downloading or seeding
+    peers: PeerList,
+    /// Set while seeding, incoming connections for the torrent go there
+    seeder: std::sync::Mutex<Option<Arc<Seeder>>>,
+    /// Whether the download directory was checked since the torrent was added or rechecked
+    checked: AtomicBool,
+    /// When the task was started, `None` while it isn't running
+    started: std::sync::Mutex<Option<Instant>>,
+    /// Pieces from the resume data the next check doesn't hash again
+    trusted: std::sync::Mutex<Option<Vec<bool>>>,
+    /// Set once the torrent is removed. Held while its state is written, so a save that already
+    /// started can't bring back what the removal deleted.
+    removed: std::sync::Mutex<bool>,
+    /// Payload bytes of earlier sessions
+    previous_downloaded: u64,
+    previous_uploaded: u64,
+}
+
+impl TorrentEntry {
+    fn info_hash(&self) -> [u8; 20] {
+        self.info_hashes[0]
+    }
+
+    /// The highest and the lowest priority of the files each piece touches. Padding and empty
+    /// files don't count, a piece without any other file is wanted like a normal one.
+    fn piece_priorities(&self) -> Vec<(FilePriority, FilePriority)> {
+        let info = &self.torrent.info;
+        let priorities = self.file_priorities.lock().unwrap();
+        let mut pieces: Vec<Option<(FilePriority, FilePriority)>> = vec![None; info.piece_count()];
+        let mut offset = 0;
+        for (file, priority) in info.files().iter().zip(priorities.iter()) {
+            let start = offset;
+            offset += file.length;
+            if file.length == 0 || file.is_padding() {
+                continue;
+            }
+            for piece in
+                &mut pieces[start / info.piece_length..(offset - 1) / info.piece_length + 1]
+            {
+                *piece = Some(match *piece {
+                    Some((highest, lowest)) => (highest.max(*priority), lowest.min(*priority)),
+                    None => (*priority, *priority),
+                });
+            }
+        }
+        pieces
+            .into_iter()
+            .map(|piece| piece.unwrap_or((FilePriority::Normal, FilePriority::Normal)))
+            .collect()
+    }
+
+    /// Which pieces touch at least one file we want
+    fn wanted(&self) -> Vec<bool> {
+        self.piece_priorities()
+            .into_iter()
+            .map(|(highest, _)| highest != FilePriority::Skip)
+            .collect()
+    }
+
+    /// Payload bytes over every session
+    fn downloaded(&self) -> u64 {
+        self.previous_downloaded + self.bandwidth.download.payload_bytes()
+    }
+
+    fn uploaded(&self) -> u64 {
+        self.previous_uploaded + self.bandwidth.upload.payload_bytes()
+    }
+
+    fn is_running(&self) -> bool {
+        self.task
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+    }
+
+    /// Whether every wanted piece is there, as far as we know before checking
+    fn is_complete(&self) -> bool {
+        let wanted = self.wanted();
+        let have = self.have.lock().unwrap();
+        self.checked.load(Ordering::Acquire)
+            && wanted
+                .into_iter()
+                .zip(have.iter())
+                .all(|(wanted, have)| !wanted || *have)
+    }
+
+    /// The priority the picker downloads each piece with
+    fn picker_priorities(&self) -> Vec<FilePriority> {
+        self.piece_priorities()
+            .into_iter()
+            .map(|(highest, _)| highest)
+            .collect()
+    }
+}
+
+impl SessionConfig {
+    /// Fail on what the session can't do, warn about what it ignores
+    fn check_supported(&self) -> Result<(), anyhow::Error> {
+        match self.encryption {
+            EncryptionPolicy::Disabled => {}
+            EncryptionPolicy::Enabled => {
+                warn!("Encryption isn't supported yet, connecting in plaintext")
+            }
+            EncryptionPolicy::Forced => {
+                anyhow::bail!("Encryption isn't supported yet, it can't be forced")
+            }
+        }
+        for (name, enabled) in [("DHT", self.dht), ("PEX", self.pex), ("LSD", self.lsd)] {
+            if enabled {
+                warn!("{name} isn't supported yet, only trackers find peers");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct SessionInner {
+    config: RwLock<SessionConfig>,
+    peer_id: [u8; 20],
+    bandwidth: Bandwidth,
+    connections: Arc<GlobalConnections>,
+    bans: Arc<BanList>,
+    ip_filter: Arc<IpFilter>,
+    events: EventSender,
+    torrents: std::sync::Mutex<HashMap<[u8; 20], Arc<TorrentEntry>>>,
+    /// Info hashes of the torrents, the first one has its turn first
+    queue: std::sync::Mutex<Vec<[u8; 20]>>,
+}
+
+impl SessionInner {
+    fn config(&self) -> RwLockReadGuard<'_, SessionConfig> {
+        self.config.read().unwrap()
+    }
+
+    fn announce_port(&self) -> u16 {
+        self.config().listen_port.unwrap_or(UNLISTENED_PORT)
+    }
+
+    fn storage_error(&self, entry: &TorrentEntry, error: &anyhow::Error) {
+        self.events.send(Event::StorageError {
+            info_hash: entry.info_hash(),
+            message: error.to_string(),
+        });
+    }
+
+    /// Add a torrent at the end of the queue, with what was saved about it if it is coming back
+    /// from an earlier session
+    fn add_torrent(
+        self: &Arc<Self>,
+        torrent: Torrent,
+        resume: Option<ResumeData>,
+    ) -> Result<TorrentHandle, anyhow::Error> {
+        let info_hashes = swarm_info_hashes(&torrent.info)?;
+        let mut torrents = self.torrents.lock().unwrap();
+        if torrents.contains_key(&info_hashes[0]) {
+            anyhow::bail!("{} was already added", torrent.info.name);
+        }
+
+        let config = self.config().clone();
+        let bandwidth = self
+            .bandwidth
+            .child(config.torrent_download_limit, config.torrent_upload_limit);
+        bandwidth.set_peer_limits(config.peer_download_limit, config.peer_upload_limit);
+        let piece_count = torrent.info.piece_count();
+        let file_count = torrent.info.files().len();
+        let mut picker = Picker::new(piece_count);
+        picker.set_sequential(config.sequential);
+        // the next session may run from another working directory
+        let save_path =
+            std::path::absolute(&config.download_dir).unwrap_or(config.download_dir.clone());
+        let resume = resume.unwrap_or_else(|| ResumeData {
+            save_path: save_path.to_string_lossy().into_owned(),
+            ..ResumeData::default()
+        });
+        let mut file_priorities = resume.file_priorities();
+        file_priorities.resize(file_count, FilePriority::default());
+        let trusted = resume.trusted_pieces(&torrent.info);
+        if trusted.is_none() && !resume.pieces.is_empty() {
+            info!(
+                "Files of {} changed since it was saved, checking them",
+                torrent.info.name
+            );
+        }
+        let state = if resume.is_paused() {
+            TorrentState::Paused
+        } else {
+            TorrentState::Queued
+        };
+
+        let entry = Arc::new(TorrentEntry {
+            info_hashes,
+            save_path: PathBuf::from(&resume.save_path),
+            state: watch::Sender::new(state),
+            task: std::sync::Mutex::new(None),
+            storage: Arc::new(Storage::new(
+                &torrent.info,
+                Path::new(&resume.save_path),
+                file_priorities.clone(),
+            )),
+            have: Arc::new(std::sync::Mutex::new(vec![false; piece_count])),
+            picker: Arc::new(std::sync::Mutex::new(picker)),
+            file_priorities: std::sync::Mutex::new(file_priorities),
+            bandwidth,
+            peers: PeerList::default(),
+            seeder: std::sync::Mutex::new(None),
+            checked: AtomicBool::new(false),
+            started: std::sync::Mutex::new(None),
+            trusted: std::sync::Mutex::new(trusted),
+            removed: std::sync::Mutex::new(false),
+            previous_downloaded: resume.downloaded,
+            previous_uploaded: resume.uploaded,
+            torrent,
+        });
+        torrents.insert(entry.info_hash(), Arc::clone(&entry));
+        drop(torrents);
+        self.queue.lock().unwrap().push(entry.info_hash());
+        if let Some(state_dir) = &config.state_dir {
+            if let Err(e) = resume::save_torrent(state_dir, &entry.info_hash(), &entry.torrent) {
+                error!("Failed to save {}: {e}", entry.torrent.info.name);
+            }
+        }
+        self.save(&entry);
+        self.manage_queue();
+
+        Ok(TorrentHandle {
+            entry,
+            session: Arc::clone(self),
+        })
+    }
+
+    /// Write the resume data of `entry` to the state directory, if there is one
+    fn save(&self, entry: &TorrentEntry) {
+        let Some(state_dir) = self.config().state_dir.clone() else {
+            return;
+        };
+        let info_hash = entry.info_hash();
+        let info = &entry.torrent.info;
+        let mut resume = ResumeData {
+            save_path: entry.save_path.to_string_lossy().into_owned(),
+            file_priorities: entry
+                .file_priorities
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|priority| resume::priority_level(*priority))
+                .collect(),
+            paused: u8::from(*entry.state.borrow() == TorrentState::Paused),
+            queue_position: self
+                .queue
+                .lock()
+                .unwrap()
+                .iter()
+                .position(|queued| *queued == info_hash)
+                .unwrap_or_default(),
+            downloaded: entry.downloaded(),
+            uploaded: entry.uploaded(),
+            ..ResumeData::default()
+        };
+        // before the first check the saved pieces are still the best we know
+        let pieces = match &*entry.trusted.lock().unwrap() {
+            Some(trusted) if !entry.checked.load(Ordering::Acquire) => trusted.clone(),
+            _ => entry.have.lock().unwrap().clone(),
+        };
+        resume.set_pieces(info, &pieces);
+        let removed = entry.removed.lock().unwrap();
+        if *removed {
+            return;
+        }
+        if let Err(e) = resume::save_resume(&state_dir, &info_hash, &resume) {
+            error!("Failed to save the state of {}: {e}", info.name);
+        }
+    }
+
+    /// Write the metainfo of every torrent to the state directory, for when it changed
+    fn save_torrents(&self) {
+        let Some(state_dir) = self.config().state_dir.clone() else {
+            return;
+        };
+        let entries: Vec<_> = self.torrents.lock().unwrap().values().cloned().collect();
+        for entry in entries {
+            let removed = entry.removed.lock().unwrap();
+            if *removed {
+                continue;
+            }
+            if let Err(e) = resume::save_torrent(&state_dir, &entry.info_hash(), &entry.torrent) {
+                error!("Failed to save {}: {e}", entry.torrent.info.name);
+            }
+        }
+    }
+
+    fn save_all(&self) {
+        let entries: Vec<_> = self.torrents.lock().unwrap().values().cloned().collect();
+        for entry in entries {
+            self.save(&entry);
+        }
+    }
+
+    /// Whether `entry` runs but transfers too slowly to count towards the active limits
+    fn is_slow(&self, entry: &TorrentEntry, config: &SessionConfig) -> bool {
+        config.dont_count_slow_torrents
+            && matches!(
+                *entry.state.borrow(),
+                TorrentState::Downloading | TorrentState::Seeding
+            )
+            && entry
+                .started
+                .lock()
+                .unwrap()
+                .is_some_and(|started| started.elapsed() >= SLOW_GRACE)
+            && entry.bandwidth.download.rate() < config.slow_download_rate as f64
+            && entry.bandwidth.upload.rate() < config.slow_upload_rate as f64
+    }
+
+    /// Go through the queue, start the torrents whose turn it is and queue the ones that are over
+    /// the active limits. Torrents that are paused, finished or failed don't take part.
+    fn manage_queue(self: &Arc<Self>) {
+        // held throughout, so two runs can't both start the same torrent
+        let queue = self.queue.lock().unwrap();
+        let entries: Vec<_> = {
+            let torrents = self.torrents.lock().unwrap();
+            queue
+                .iter()
+                .filter_map(|info_hash| torrents.get(info_hash).cloned())
+                .collect()
+        };
+
+        let config = self.config().clone();
+        let (mut downloads, mut seeds, mut active) = (0, 0, 0);
+        // start only after stopping, so the limits are never exceeded
+        let mut turns = Vec::new();
+        for entry in entries {
+            if matches!(
+                *entry.state.borrow(),
+                TorrentState::Paused | TorrentState::Finished | TorrentState::Error(_)
+            ) {
+                continue;
+            }
+            let running = entry.is_running();
+            if running && self.is_slow(&entry, &config) {
+                continue;
+            }
+
+            let (count, limit) = if entry.is_complete() {
+                (&mut seeds, config.active_seeds)
+            } else {
+                (&mut downloads, config.active_downloads)
+            };
+            let turn = limit.is_none_or(|limit| *count < limit)
+                && config.active_limit.is_none_or(|limit| active < limit);
+            let handle = TorrentHandle {
+                entry,
+                session: Arc::clone(self),
+            };
+            let name = &handle.entry.torrent.info.name;
+            if turn {
+                *count += 1;
+                active += 1;
+                if !running {
+                    turns.push(handle);
+                }
+            } else if running {
+                info!("Queueing {name}, too many active torrents");
+                handle.stop();
+                handle.entry.state.send_replace(TorrentState::Queued);
+            }
+        }
+        for handle in turns {
+            info!(
+                "Starting {}, its turn in the queue came",
+                handle.entry.torrent.info.name
+            );
+            handle.start();
+        }
+    }
+}
+
+/// Dropping the session stops every torrent, the listener and the stream server, and saves the
+/// torrents
+#[derive(Debug)]
+pub struct Session {
+    inner: Arc<SessionInner>,
+    /// Replaced when their port changes
+    listener: std::sync::Mutex<Option<JoinHandle<()>>>,
+    stream_server: std::sync::Mutex<Option<JoinHandle<()>>>,
+    housekeeping: JoinHandle<()>,
+}
+
+impl Session {
+    pub async fn new(config: SessionConfig) -> Result<Self, anyhow::Error> {
+        config.check_supported()?;
+        let bans = match &config.ban_file {
+            Some(path) => BanList::with_file(path)?,
+            None => BanList::default(),
+        };
+        let peer_id = crate::peer_id::generate(&config.peer_id_prefix);
+        info!("Peer ID {}", String::from_utf8_lossy(&peer_id));
+
+        let inner = Arc::new(SessionInner {
+            peer_id,
+            bandwidth: Bandwidth::new(config.download_limit, config.upload_limit),
+            connections: Arc::new(GlobalConnections::new(
+                config.max_connections,
+                config.max_half_open,
+            )),
+            bans: Arc::new(bans),
+            ip_filter: Arc::new(IpFilter::load(config.ip_filter_files.clone())?),
+            events: EventSender::default(),
+            torrents: std::sync::Mutex::new(HashMap::new()),
+            queue: std::sync::Mutex::new(Vec::new()),
+            config: RwLock::new(config),
+        });
+
+        let config = inner.config().clone();
+        let listener = bind_listener(&inner, config.listen_port).await?;
+        let stream_server = match bind_stream_server(&inner, config.stream_port).await {
+            Ok(stream_server) => stream_server,
+            Err(e) => {
+                listener.inspect(JoinHandle::abort);
+                return Err(e);
+            }
+        };
+
+        if let Some(state_dir) = &config.state_dir {
+            let saved = resume::load(state_dir)?;
+            info!(
+                "Adding {} torrents saved in {}",
+                saved.len(),
+                state_dir.display()
+            );
+            for (torrent, resume) in saved {
+                let name = torrent.info.name.clone();
+                if let Err(e) = inner.add_torrent(torrent, Some(resume)) {
+                    error!("Failed to add saved torrent {name}: {e}");
+                }
+            }
+        }
+
+        // torrents also finish or slow down without anybody calling into the session, and
+        // their transfer totals change all the time
+        let housekeeping = {
+            let inner = Arc::clone(&inner);
+            tokio::spawn(async move {
+                let mut queue_interval = tokio::time::interval(QUEUE_INTERVAL);
+                let mut save_interval = tokio::time::interval(SAVE_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = queue_interval.tick() => inner.manage_queue(),
+                        _ = save_interval.tick() => inner.save_all(),
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            inner,
+            listener: std::sync::Mutex::new(listener),
+            stream_server: std::sync::Mutex::new(stream_server),
+            housekeeping,
+        })
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.inner.peer_id
+    }
+
+    pub fn config(&self) -> SessionConfig {
+        self.inner.config().clone()
+    }
+
+    /// Run with `config` from now on, see [`SessionConfig`] for when the changes take effect.
+    /// Fails if a new port can't be bound or an IP filter list can't be read, nothing changes
+    /// then.
+    pub async fn set_config(&self, mut config: SessionConfig) -> Result<(), anyhow::Error> {
+        config.check_supported()?;
+        let old = self.config();
+        if config.peer_id_prefix != old.peer_id_prefix || config.ban_file != old.ban_file {
+            warn!("The peer ID prefix and the ban file only change with the next session");
+            config.peer_id_prefix = old.peer_id_prefix.clone();
+            config.ban_file = old.ban_file.clone();
+        }
+
+        // whatever can fail comes first, new tasks are aborted again if a later step fails
+        let listener = match config.listen_port != old.listen_port {
+            true => Some(bind_listener(&self.inner, config.listen_port).await?),
+            false => None,
+        };
+        let abort = |tasks: &[&Option<Option<JoinHandle<()>>>]| {
+            for task in tasks.iter().copied().flatten().flatten() {
+                task.abort();
+            }
+        };
+        let stream_server = match config.stream_port != old.stream_port {
+            true => match bind_stream_server(&self.inner, config.stream_port).await {
+                Ok(stream_server) => Some(stream_server),
+                Err(e) => {
+                    abort(&[&listener]);
+                    return Err(e);
+                }
+            },
+            false => None,
+        };
+        if config.ip_filter_files != old.ip_filter_files {
+            if let Err(e) = self
+                .inner
+                .ip_filter
+                .set_sources(config.ip_filter_files.clone())
+            {
+                abort(&[&listener, &stream_server]);
+                return Err(e);
+            }
+        }
+
+        for (slot, task) in [
+            (&self.listener, listener),
+            (&self.stream_server, stream_server),
+        ] {
+            if let Some(task) = task {
+                if let Some(old) = std::mem::replace(&mut *slot.lock().unwrap(), task) {
+                    old.abort();
+                }
+            }
+        }
+        self.inner
+            .bandwidth
+            .set_limits(config.download_limit, config.upload_limit);
+        self.inner
+            .connections
+            .set_limits(config.max_connections, config.max_half_open);
+        for entry in self.inner.torrents.lock().unwrap().values() {
+            entry
+                .bandwidth
+                .set_limits(config.torrent_download_limit, config.torrent_upload_limit);
+            entry
+                .bandwidth
+                .set_peer_limits(config.peer_download_limit, config.peer_upload_limit);
+        }
+
+        let moved_state = config.state_dir != old.state_dir;
+        *self.inner.config.write().unwrap() = config;
+        if moved_state {
+            self.inner.save_torrents();
+            self.inner.save_all();
+        }
+        info!("Configuration changed");
+        self.inner.manage_queue();
+        Ok(())
+    }
+
+    /// Add a torrent at the end of the queue, it starts once it has its turn
+    pub fn add_torrent(&self, torrent: Torrent) -> Result<TorrentHandle, anyhow::Error> {
+        self.inner.add_torrent(torrent, None)
+    }
+
+    pub fn add_torrent_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<TorrentHandle, anyhow::Error> {
+        self.add_torrent(parser::parse_torrent_file(path)?)
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        handles(&self.inner)
+    }
+
+    /// Look a torrent up by any of its info hashes, v2 hashes truncated to 20 bytes
+    pub fn find_torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.inner
+            .torrents
+            .lock()
+            .unwrap()
+            .values()
+            .find(|entry| entry.info_hashes.contains(info_hash))
+            .map(|entry| TorrentHandle {
+                entry: Arc::clone(entry),
+                session: Arc::clone(&self.inner),
+            })
+    }
+
+    /// Receive the events of `categories` from now on, [`EventCategory::ALL`] for everything
+    pub fn subscribe(&self, categories: &[EventCategory]) -> EventReceiver {
+        self.inner.events.subscribe(categories)
+    }
+
+    /// Save every torrent to the state directory now instead of waiting for the next time
+    pub fn save_state(&self) {
+        self.inner.save_all();
+    }
+
+    /// Read the IP filter lists again
+    pub fn reload_ip_filter(&self) -> Result<(), anyhow::Error> {
+        self.inner.ip_filter.reload()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for task in [&self.listener, &self.stream_server] {
+            task.lock().unwrap().take().inspect(JoinHandle::abort);
+        }
+        self.housekeeping.abort();
+        for handle in self.torrents() {
+            handle.stop();
+        }
+        self.inner.save_all();
+    }
+}
+
+/// Listen for peers on `port`, `None` doesn't
+async fn bind_listener(
+    session: &Arc<SessionInner>,
+    port: Option<u16>,
+) -> Result<Option<JoinHandle<()>>, anyhow::Error> {
+    let Some(port) = port else {
+        return Ok(None);
+    };
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("Listening for peers on port {port}");
+    Ok(Some(tokio::spawn(listen(listener, Arc::clone(session)))))
+}
+
+/// Serve the stream server on `port` of loopback, `None` doesn't
+async fn bind_stream_server(
+    session: &Arc<SessionInner>,
+    port: Option<u16>,
+) -> Result<Option<JoinHandle<()>>, anyhow::Error> {
+    let Some(port) = port else {
+        return Ok(None);
+    };
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+    info!("Streaming files on http://{}/", listener.local_addr()?);
+    let session = Arc::clone(session);
+    Ok(Some(tokio::spawn(async move {
+        if let Err(e) = stream_server::run(listener, move || handles(&session)).await {
+            error!("Stream server stopped: {e}");
+        }
+    })))
+}
+
+fn handles(session: &Arc<SessionInner>) -> Vec<TorrentHandle> {
+    session
+        .torrents
+        .lock()
+        .unwrap()
+        .values()
+        .map(|entry| TorrentHandle {
+            entry: Arc::clone(entry),
+            session: Arc::clone(session),
+        })
+        .collect()
+}
+
+/// Controls one torrent of a session, cheap to clone
+#[derive(Debug, Clone)]
+pub struct TorrentHandle {
+    entry: Arc<TorrentEntry>,
+    session: Arc<SessionInner>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.entry.info_hash()
+    }
+
+    pub fn torrent(&self) -> &Torrent {
+        &self.entry.torrent
+    }
+
+    /// The directory the files are downloaded into
+    pub fn save_path(&self) -> &Path {
+        &self.entry.save_path
+    }
+
+    /// Stop the torrent until it is resumed, it leaves its turn in the queue to the next one
+    pub fn pause(&self) {
+        self.stop();
+        self.entry.state.send_replace(TorrentState::Paused);
+        info!("Paused {}", self.entry.torrent.info.name);
+        self.session.save(&self.entry);
+        self.session.manage_queue();
+    }
+
+    /// Continue a paused torrent, or retry one that failed, once it has its turn
+    pub fn resume(&self) {
+        if matches!(
+            *self.entry.state.borrow(),
+            TorrentState::Paused | TorrentState::Error(_)
+        ) {
+            self.entry.state.send_replace(TorrentState::Queued);
+            self.session.save(&self.entry);
+            self.session.manage_queue();
+        }
+    }
+
+    /// Stop the torrent and forget it, the files stay on disk
+    pub fn remove(&self) {
+        self.stop();
+        let info_hash = self.entry.info_hash();
+        self.session.torrents.lock().unwrap().remove(&info_hash);
+        self.session
+            .queue
+            .lock()
+            .unwrap()
+            .retain(|queued| *queued != info_hash);
+        let mut removed = self.entry.removed.lock().unwrap();
+        *removed = true;
+        if let Some(state_dir) = self.session.config().state_dir.clone() {
+            if let Err(e) = resume::remove(&state_dir, &info_hash) {
+                error!(
+                    "Failed to remove the saved state of {}: {e}",
+                    self.entry.torrent.info.name
+                );
+            }
+        }
+        drop(removed);
+        info!("Removed {}", self.entry.torrent.info.name);
+        self.session.manage_queue();
+    }
+
+    /// Position in the session's queue, 0 has its turn first
+    pub fn queue_position(&self) -> usize {
+        let info_hash = self.entry.info_hash();
+        let queue = self.session.queue.lock().unwrap();
+        queue
+            .iter()
+            .position(|queued| *queued == info_hash)
+            .unwrap_or(queue.len())
+    }
+
+    /// Move the torrent to `position` in the queue, past the end moves it to the end. Torrents
+    /// start and stop right away if that changes whose turn it is.
+    pub fn set_queue_position(&self, position: usize) {
+        let info_hash = self.entry.info_hash();
+        {
+            let mut queue = self.session.queue.lock().unwrap();
+            let Some(current) = queue.iter().position(|queued| *queued == info_hash) else {
+                return;
+            };
+            queue.remove(current);
+            let position = position.min(queue.len());
+            queue.insert(position, info_hash);
+        }
+        self.session.save_all();
+        self.session.manage_queue();
+    }
+
+    pub fn queue_up(&self) {
+        self.set_queue_position(self.queue_position().saturating_sub(1));
+    }
+
+    pub fn queue_down(&self) {
+        self.set_queue_position(self.queue_position() + 1);
+    }
+
+    pub fn queue_top(&self) {
+        self.set_queue_position(0);
+    }
+
+    pub fn queue_bottom(&self) {
+        self.set_queue_position(usize::MAX);
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        let entry = &self.entry;
+        let info = &entry.torrent.info;
+        let pieces = entry.have.lock().unwrap().clone();
+        let (mut wanted_bytes, mut done_bytes) = (0, 0);
+        for (index, wanted) in entry.wanted().into_iter().enumerate() {
+            if wanted {
+                let size = info.piece_size(index) as u64;
+                wanted_bytes += size;
+                if pieces[index] {
+                    done_bytes += size;
+                }
+            }
+        }
+
+        let download_rate = entry.bandwidth.download.rate();
+        let eta = if done_bytes == wanted_bytes {
+            Some(Duration::ZERO)
+        } else if download_rate >= 1.0 {
+            Some(Duration::from_secs_f64(
+                (wanted_bytes - done_bytes) as f64 / download_rate,
+            ))
+        } else {
+            None
+        };
+
+        let peers = entry.peers.peers();
+        let piece_count = info.piece_count();
+        let downloaded = entry.downloaded();
+        let uploaded = entry.uploaded();
+        let ratio_base = downloaded.max(done_bytes);
+        TorrentStatus {
+            name: info.name.clone(),
+            info_hash: entry.info_hash(),
+            state: entry.state.borrow().clone(),
+            queue_position: self.queue_position(),
+            total_bytes: info.total_length() as u64,
+            wanted_bytes,
+            done_bytes,
+            pieces_done: pieces.iter().filter(|have| **have).count(),
+            pieces,
+            piece_count,
+            download_rate,
+            upload_rate: entry.bandwidth.upload.rate(),
+            eta,
+            seeds: peers
+                .iter()
+                .filter(|peer| peer.pieces_available == piece_count)
+                .count(),
+            peers: peers.len(),
+            downloaded,
+            uploaded,
+            ratio: if ratio_base == 0 {
+                0.0
+            } else {
+                uploaded as f64 / ratio_base as f64
+            },
+        }
+    }
+
+    /// The connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.entry.peers.peers()
+    }
+
+    /// `priorities` are indexed like [`crate::parser::Info::files`]. Pieces of higher priority
+    /// files are downloaded first, pieces only in skipped files not at all. Takes effect right
+    /// away, also while downloading.
+    pub fn set_file_priorities(&self, priorities: Vec<FilePriority>) -> Result<(), anyhow::Error> {
+        let files = self.entry.torrent.info.files().len();
+        if priorities.len() != files {
+            anyhow::bail!("Expected {files} file priorities, got {}", priorities.len());
+        }
+        *self.entry.file_priorities.lock().unwrap() = priorities.clone();
+        self.session.save(&self.entry);
+        // boundary pieces kept in the part file now belong into files that became wanted
+        self.entry
+            .storage
+            .set_priorities(priorities)
+            .inspect_err(|e| self.session.storage_error(&self.entry, e))?;
+
+        let mut picker = self.entry.picker.lock().unwrap();
+        let have = self.entry.have.lock().unwrap().clone();
+        picker.set_priorities(self.entry.picker_priorities(), &have);
+        info!(
+            "{} pieces of {} left to download",
+            picker.len(),
+            self.entry.torrent.info.name
+        );
+        self.restart_if_finished(&picker);
+        Ok(())
+    }
+
+    /// Download pieces in order instead of rarest first, for playing a file while it downloads
+    pub fn set_sequential(&self, sequential: bool) {
+        self.entry.picker.lock().unwrap().set_sequential(sequential);
+    }
+
+    /// Download piece `index` within `deadline` from now, ahead of everything else and even if
+    /// its files are skipped. Only the fastest peers get it.
+    pub fn set_piece_deadline(
+        &self,
+        index: usize,
+        deadline: Duration,
+    ) -> Result<(), anyhow::Error> {
+        let piece_count = self.entry.torrent.info.piece_count();
+        if index >= piece_count {
+            anyhow::bail!("Piece {index} out of range, the torrent has {piece_count} pieces");
+        }
+        if self.entry.have.lock().unwrap()[index] {
+            return Ok(());
+        }
+        let mut picker = self.entry.picker.lock().unwrap();
+        picker.set_deadline(DeadlineSource::Manual, index, Instant::now() + deadline);
+        self.restart_if_finished(&picker);
+        Ok(())
+    }
+
+    /// Drop the deadlines set through [`TorrentHandle::set_piece_deadline`], readers keep theirs
+    pub fn clear_piece_deadlines(&self) {
+        self.entry
+            .picker
+            .lock()
+            .unwrap()
+            .clear_deadlines(DeadlineSource::Manual);
+    }
+
+    /// A reader of the torrent's data with its own read-ahead window, one per stream
+    pub fn reader(&self) -> TorrentReader {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TorrentReader {
+            handle: self.clone(),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Queue the torrent again when pieces are wanted after it finished. Takes the picker lock
+    /// the torrent task enters Downloading under, so it either already saw the change or is
+    /// finished.
+    fn restart_if_finished(&self, picker: &Picker) {
+        if *self.entry.state.borrow() == TorrentState::Finished && !picker.is_empty() {
+            self.entry.state.send_replace(TorrentState::Queued);
+            self.session.manage_queue();
+        }
+    }
+
+    /// Forget which pieces we have and check the download directory again once it is the
+    /// torrent's turn
+    pub fn force_recheck(&self) {
+        self.stop();
+        self.entry.checked.store(false, Ordering::Release);
+        self.entry.trusted.lock().unwrap().take();
+        self.entry.have.lock().unwrap().fill(false);
+        if !matches!(*self.entry.state.borrow(), TorrentState::Paused) {
+            self.entry.state.send_replace(TorrentState::Queued);
+        }
+        self.session.manage_queue();
+    }
+
+    /// Wait until the torrent is done, that is seeding or finished. Fails if it runs into an
+    /// error.
+    pub async fn wait(&self) -> Result<TorrentState, anyhow::Error> {
+        let mut state = self.entry.state.subscribe();
+        let state = state
+            .wait_for(|state| {
+                matches!(
+                    state,
+                    TorrentState::Seeding | TorrentState::Finished | TorrentState::Error(_)
+                )
+            })
+            .await?
+            .clone();
+        match state {
+            TorrentState::Error(e) => Err(anyhow::anyhow!(e)),
+            state => Ok(state),
+        }
+    }
+
+    fn start(&self) {
+        let task = tokio::spawn(run_torrent(
+            Arc::clone(&self.session),
+            Arc::clone(&self.entry),
+        ));
+        if let Some(old) = self.entry.task.lock().unwrap().replace(task) {
+            old.abort();
+        }
+        *self.entry.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn stop(&self) {
+        if let Some(task) = self.entry.task.lock().unwrap().take() {
+            task.abort();
+        }
+        self.entry.seeder.lock().unwrap().take();
+        self.entry.peers.clear();
+        self.entry.started.lock().unwrap().take();
+    }
+}
+
+/// Reads a torrent's data as it downloads, see [`TorrentHandle::reader`]. Missing pieces ahead of
+/// it get deadlines, they are dropped along with the reader.
+#[derive(Debug)]
+pub struct TorrentReader {
+    handle: TorrentHandle,
+    id: u64,
+}
+
+impl TorrentReader {
+    /// Move the read-ahead window to byte `offset` of the torrent. The missing pieces of the
+    /// next [`SessionConfig::read_ahead`] bytes get deadlines, the piece at `offset` right away
+    /// and every following one [`READ_AHEAD_STEP`] later than the one before. Deadlines this
+    /// reader set behind it are dropped, other readers keep theirs.
+    pub fn set_position(&self, offset: u64) {
+        let info = &self.handle.entry.torrent.info;
+        let piece_length = info.piece_length as u64;
+        let first = (offset / piece_length) as usize;
+        let last =
+            ((offset + self.handle.session.config().read_ahead.max(1) - 1) / piece_length) as usize;
+        let last = last.min(info.piece_count().saturating_sub(1));
+
+        let have = self.handle.entry.have.lock().unwrap().clone();
+        let mut picker = self.handle.entry.picker.lock().unwrap();
+        let source = DeadlineSource::Reader(self.id);
+        picker.clear_deadlines(source);
+        let now = Instant::now();
+        let missing = (first..=last).filter(|index| !have[*index]);
+        for (step, index) in (0u32..).zip(missing) {
+            picker.set_deadline(source, index, now + READ_AHEAD_STEP * step);
+        }
+        self.handle.restart_if_finished(&picker);
+    }
+
+    /// Read `length` bytes at `offset` of the torrent, once the pieces holding them passed their
+    /// hash check. Missing pieces get deadlines through [`TorrentReader::set_position`], so
+    /// they are downloaded first even if their files are skipped. Fails if the torrent is paused
+    /// or runs into an error while waiting.
+    pub async fn read(&self, offset: u64, length: usize) -> Result<Vec<u8>, anyhow::Error> {
+        let info = &self.handle.entry.torrent.info;
+        let end = offset + length as u64;
+        if end > info.total_length() as u64 {
+            anyhow::bail!("Bytes {offset}..{end} are beyond the end of {}", info.name);
+        }
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+
+        let piece_length = info.piece_length as u64;
+        let pieces = (offset / piece_length) as usize..=((end - 1) / piece_length) as usize;
+        let there = || {
+            self.handle.entry.have.lock().unwrap()[pieces.clone()]
+                .iter()
+                .all(|have| *have)
+        };
+        // subscribe before looking, so no piece finishes unnoticed
+        let mut finished = self
+            .handle
+            .session
+            .events
+            .subscribe(&[EventCategory::Piece]);
+        if !there() {
+            self.set_position(offset);
+        }
+        while !there() {
+            let state = self.handle.entry.state.borrow().clone();
+            if let TorrentState::Paused | TorrentState::Error(_) = state {
+                anyhow::bail!("{} is {state:?}", info.name);
+            }
+            // a state change doesn't come with an event, so look again every now and then
+            let _ = tokio::time::timeout(Duration::from_secs(1), finished.recv()).await;
+        }
+
+        let storage = Arc::clone(&self.handle.entry.storage);
+        tokio::task::spawn_blocking(move || storage.read(offset as usize, length)).await?
+    }
+}
+
+impl Drop for TorrentReader {
+    fn drop(&mut self) {
+        self.handle
+            .entry
+            .picker
+            .lock()
+            .unwrap()
+            .clear_deadlines(DeadlineSource::Reader(self.id));
+    }
+}
+
+/// Aborts the task when dropped, so stopping a torrent also stops what its task spawned
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn run_torrent(session: Arc<SessionInner>, entry: Arc<TorrentEntry>) {
+    // run in a task of its own, so a panic ends up in the state instead of leaving it as it was
+    let mut task = AbortOnDrop(tokio::spawn({
+        let session = Arc::clone(&session);
+        let entry = Arc::clone(&entry);
+        async move { try_run_torrent(&session, &entry).await }
+    }));
+    let result = match (&mut task.0).await {
+        Ok(result) => result,
+        Err(e) => Err(anyhow::anyhow!("Torrent task failed: {e}")),
+    };
+    if let Err(e) = result {
+        error!("{} failed: {e}", entry.torrent.info.name);
+        entry.state.send_replace(TorrentState::Error(e.to_string()));
+    }
+    // finished or failed, the next torrent in the queue can have its turn
+    session.manage_queue();
+}
+
+async fn try_run_torrent(
+    session: &Arc<SessionInner>,
+    entry: &Arc<TorrentEntry>,
+) -> Result<(), anyhow::Error> {
+    let torrent = &entry.torrent;
+    if !entry.checked.load(Ordering::Acquire) {
+        entry.state.send_replace(TorrentState::Checking);
+        check_files(session, entry).await?;
+        entry.checked.store(true, Ordering::Release);
+        session.save(entry);
+    }
+
+    let downloading = {
+        let mut picker = entry.picker.lock().unwrap();
+        let have = entry.have.lock().unwrap().clone();
+        picker.reset(entry.picker_priorities(), &have);
+        if !picker.is_empty() {
+            entry.state.send_replace(TorrentState::Downloading);
+        }
+        !picker.is_empty()
+    };
+    let config = session.config().clone();
+    if downloading {
+        let mut discoverer =
+            PeerDiscoverer::new(session.peer_id, session.announce_port(), torrent.clone()).await?;
+        discoverer.events = session.events.clone();
+        discoverer.timeout = config.tracker_timeout;
+        let mut downloader = Downloader::new(&discoverer, torrent, Arc::clone(&entry.storage));
+        downloader.picker = Arc::clone(&entry.picker);
+        downloader.have = Arc::clone(&entry.have);
+        downloader.choker = config.choker.clone();
+        downloader.bandwidth = entry.bandwidth.clone();
+        downloader.max_connections = config.torrent_max_connections;
+        downloader.peer_config = config.peer;
+        downloader.global_connections = Arc::clone(&session.connections);
+        downloader.bans = Arc::clone(&session.bans);
+        downloader.ip_filter = Arc::clone(&session.ip_filter);
+        downloader.peers = entry.peers.clone();
+        downloader.download().await?;
+
+        let storage = Arc::clone(&entry.storage);
+        tokio::task::spawn_blocking(move || storage.finish())
+            .await?
+            .inspect_err(|e| session.storage_error(entry, e))?;
+        session.save(entry);
+        // only when pieces were missing, a torrent that was complete already doesn't finish again
+        session.events.send(Event::TorrentFinished {
+            info_hash: entry.info_hash(),
+        });
+    }
+
+    // the configuration may have changed while downloading
+    let config = session.config().clone();
+    let complete = entry.have.lock().unwrap().iter().all(|have| *have);
+    if !(complete && config.seed) {
+        info!("{} finished", torrent.info.name);
+        entry.state.send_replace(TorrentState::Finished);
+        return Ok(());
+    }
+
+    let mut seeder = Seeder::new(torrent, Arc::clone(&entry.storage), session.peer_id)?;
+    seeder.super_seeding = config.super_seeding;
+    seeder.choker = config.choker.clone();
+    seeder.bandwidth = entry.bandwidth.clone();
+    seeder.max_connections = config.torrent_max_connections;
+    seeder.events = session.events.clone();
+    seeder.peers = entry.peers.clone();
+    let mut discoverer =
+        PeerDiscoverer::new(session.peer_id, session.announce_port(), torrent.clone()).await?;
+    discoverer.left = 0;
+    discoverer.events = session.events.clone();
+    discoverer.timeout = config.tracker_timeout;
+
+    let seeder = Arc::new(seeder);
+    *entry.seeder.lock().unwrap() = Some(Arc::clone(&seeder));
+    entry.state.send_replace(TorrentState::Seeding);
+    seeder.run(discoverer).await
+}
+
+/// Check the pieces that are already in the download directory, one at a time
+async fn check_files(
+    session: &Arc<SessionInner>,
+    entry: &Arc<TorrentEntry>,
+) -> Result<(), anyhow::Error> {
+    let session = Arc::clone(session);
+    let entry = Arc::clone(entry);
+    tokio::task::spawn_blocking(move || {
+        let torrent = &entry.torrent;
+        let info = &torrent.info;
+        let storage = &entry.storage;
+        let parts = storage
+            .load_part_file()
+            .inspect_err(|e| session.storage_error(&entry, e))?;
+        // pieces in the part file aren't stamped by the resume data, they are always checked
+        let mut trusted = entry
+            .trusted
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| vec![false; info.piece_count()]);
+        for index in &parts {
+            trusted[*index] = false;
+        }
+
+        let check = |index| -> Result<bool, anyhow::Error> {
+            let piece = storage
+                .read_piece(index)
+                .inspect_err(|e| session.storage_error(&entry, e))?;
+            peer_connection::check_piece(torrent, index, &piece)
+        };
+        let mut have = vec![false; info.piece_count()];
+        for (index, have) in have.iter_mut().enumerate() {
+            *have = trusted[index] || check(index)?;
+        }
+        // a piece in the part file that doesn't check out may still be complete in the files
+        for index in parts {
+            if !have[index] {
+                storage.forget_part(index);
+                have[index] = check(index)?;
+            }
+        }
+
+        let found = have.iter().filter(|have| **have).count();
+        info!(
+            "{} of {} pieces of {} are already there, {} of them from the resume data",
+            found,
+            have.len(),
+            info.name,
+            trusted.iter().filter(|trusted| **trusted).count()
+        );
+        *entry.have.lock().unwrap() = have;
+        Ok(())
+    })
+    .await?
+}
+
+/// Accept incoming connections and hand them to the seeder of the torrent they ask for
+async fn listen(listener: TcpListener, session: Arc<SessionInner>) {
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept a connection: {e}");
+                continue;
+            }
+        };
+        let SocketAddr::V4(addr) = addr else {
+            continue;
+        };
+        if session.ip_filter.is_blocked(addr.ip()) {
+            info!("Refusing {addr}, blocked by the IP filter");
+            continue;
+        }
+        if session.bans.is_banned(addr.ip()) {
+            continue;
+        }
+        // the handshake task holds the slot, so slow handshakes can't pile up past the limits
+        let Some(slot) = session.connections.try_incoming() else {
+            info!("Refusing {addr}, too many connections");
+            continue;
+        };
+
+        let session = Arc::clone(&session);
+        tokio::spawn(async move {
+            let handshake = match seeder::read_handshake(&mut stream).await {
+                Ok(handshake) => handshake,
+                Err(e) => {
+                    info!("Dropping {addr}: {e}");
+                    return;
+                }
+            };
+            let info_hash: [u8; 20] = handshake[28..48].try_into().unwrap();
+            let seeder = session.torrents.lock().unwrap().values().find_map(|entry| {
+                entry
+                    .seeder
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .filter(|seeder| seeder.serves(&info_hash))
+            });
+            match seeder {
+                Some(seeder) => {
+                    if !seeder.accept(stream, addr, handshake, slot) {
+                        info!("Refusing {addr}, the torrent is busy");
+                    }
+                }
+                None => info!("Refusing {addr}, not seeding {}", hex::encode(info_hash)),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{create_torrent, CreateOptions};
+
+    /// A download directory and a tracker that accepts connections but never answers, so
+    /// torrents stay downloading or seeding until they are stopped
+    struct Setup {
+        dir: tempfile::TempDir,
+        tracker: std::net::TcpListener,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            Self {
+                dir: tempfile::tempdir().unwrap(),
+                tracker: std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+            }
+        }
+
+        fn config(&self) -> SessionConfig {
+            SessionConfig {
+                download_dir: self.dir.path().join("downloads"),
+                seed: true,
+                tracker_timeout: Duration::from_secs(3600),
+                ..SessionConfig::default()
+            }
+        }
+
+        /// A torrent of a single file called `name`, already downloaded if `complete`
+        fn torrent(&self, name: &str, complete: bool) -> Torrent {
+            let downloads = self.dir.path().join("downloads");
+            std::fs::create_dir_all(&downloads).unwrap();
+            let path = downloads.join(name);
+            std::fs::write(&path, name.repeat(1000)).unwrap();
+            let options = CreateOptions {
+                trackers: vec![vec![format!(
+                    "http://{}/announce",
+                    self.tracker.local_addr().unwrap()
+                )]],
+                ..CreateOptions::default()
+            };
+            let torrent = create_torrent(&path, &options).unwrap();
+            if !complete {
+                std::fs::remove_file(&path).unwrap();
+            }
+            torrent
+        }
+    }
+
+    /// A torrent of the files `a` and `b` of 20000 bytes each, in pieces of 16 KiB. The second
+    /// piece holds both files, the third only `b`. Nothing is downloaded yet.
+    fn two_files(setup: &Setup) -> Torrent {
+        let root = setup.dir.path().join("two files");
+        std::fs::create_dir_all(&root).unwrap();
+        for name in ["a", "b"] {
+            std::fs::write(root.join(name), name.repeat(20_000)).unwrap();
+        }
+        let options = CreateOptions {
+            trackers: vec![vec!["http://127.0.0.1:7070/announce".to_string()]],
+            piece_length: Some(16384),
+            ..CreateOptions::default()
+        };
+        create_torrent(&root, &options).unwrap()
+    }
+
+    fn state(handle: &TorrentHandle) -> TorrentState {
+        handle.entry.state.borrow().clone()
+    }
+
+    async fn reaches(handle: &TorrentHandle, state: TorrentState) {
+        let mut states = handle.entry.state.subscribe();
+        let reached = time_out(states.wait_for(|current| *current == state)).await;
+        assert!(
+            reached,
+            "{} is {:?} instead of {state:?}",
+            handle.entry.torrent.info.name,
+            self::state(handle)
+        );
+    }
+
+    async fn time_out<T, E>(future: impl std::future::Future<Output = Result<T, E>>) -> bool {
+        tokio::time::timeout(Duration::from_secs(10), future)
+            .await
+            .is_ok_and(|result| result.is_ok())
+    }
+
+    #[tokio::test]
+    async fn queue_respects_the_active_limits() {
+        let setup = Setup::new();
+        let session = Session::new(SessionConfig {
+            active_downloads: Some(1),
+            active_seeds: Some(1),
+            active_limit: None,
+            ..setup.config()
+        })
+        .await
+        .unwrap();
+
+        let seed = session.add_torrent(setup.torrent("seed", true)).unwrap();
+        reaches(&seed, TorrentState::Seeding).await;
+        // it takes the download slot until its check shows that it is complete
+        let second_seed = session
+            .add_torrent(setup.torrent("second seed", true))
+            .unwrap();
+        reaches(&second_seed, TorrentState::Seeding).await;
+        session.inner.manage_queue();
+        assert_eq!(state(&second_seed), TorrentState::Queued);
+
+        let download = session
+            .add_torrent(setup.torrent("download", false))
+            .unwrap();
+        reaches(&download, TorrentState::Downloading).await;
+        let second_download = session
+            .add_torrent(setup.torrent("second download", false))
+            .unwrap();
+        assert_eq!(state(&second_download), TorrentState::Queued);
+        assert_eq!(state(&seed), TorrentState::Seeding);
+
+        // the total limit goes by queue position
+        session
+            .set_config(SessionConfig {
+                active_limit: Some(1),
+                ..session.config()
+            })
+            .await
+            .unwrap();
+        assert_eq!(state(&download), TorrentState::Queued);
+        assert_eq!(state(&seed), TorrentState::Seeding);
+
+        // a paused torrent leaves its turn to the next one
+        seed.pause();
+        reaches(&second_seed, TorrentState::Seeding).await;
+        assert_eq!(state(&download), TorrentState::Queued);
+        assert_eq!(state(&second_download), TorrentState::Queued);
+    }
+
+    #[tokio::test]
+    async fn slow_torrents_dont_count() {
+        let setup = Setup::new();
+        let session = Session::new(SessionConfig {
+            active_downloads: Some(1),
+            ..setup.config()
+        })
+        .await
+        .unwrap();
+
+        let slow = session.add_torrent(setup.torrent("slow", false)).unwrap();
+        reaches(&slow, TorrentState::Downloading).await;
+        let next = session.add_torrent(setup.torrent("next", false)).unwrap();
+        assert_eq!(state(&next), TorrentState::Queued);
+
+        // a torrent that just started always counts
+        session.inner.manage_queue();
+        assert_eq!(state(&next), TorrentState::Queued);
+
+        *slow.entry.started.lock().unwrap() = Instant::now().checked_sub(SLOW_GRACE * 2);
+        session.inner.manage_queue();
+        reaches(&next, TorrentState::Downloading).await;
+        assert_eq!(state(&slow), TorrentState::Downloading);
+
+        session
+            .set_config(SessionConfig {
+                dont_count_slow_torrents: false,
+                ..session.config()
+            })
+            .await
+            .unwrap();
+        assert_eq!(state(&next), TorrentState::Queued);
+        assert_eq!(state(&slow), TorrentState::Downloading);
+    }
+
+    #[tokio::test]
+    async fn moves_torrents_in_the_queue() {
+        let setup = Setup::new();
+        let session = Session::new(SessionConfig {
+            active_limit: Some(0),
+            ..setup.config()
+        })
+        .await
+        .unwrap();
+        let [a, b, c] =
+            ["a", "b", "c"].map(|name| session.add_torrent(setup.torrent(name, false)).unwrap());
+        let positions = || [&a, &b, &c].map(TorrentHandle::queue_position);
+        assert_eq!(positions(), [0, 1, 2]);
+
+        c.queue_up();
+        assert_eq!(positions(), [0, 2, 1]);
+        a.queue_down();
+        assert_eq!(positions(), [1, 2, 0]);
+        b.queue_top();
+        assert_eq!(positions(), [2, 0, 1]);
+        b.queue_bottom();
+        assert_eq!(positions(), [1, 2, 0]);
+        // nowhere to go
+        c.queue_up();
+        b.queue_down();
+        assert_eq!(positions(), [1, 2, 0]);
+
+        // moving changes whose turn it is right away
+        session
+            .set_config(SessionConfig {
+                active_limit: Some(1),
+                ..session.config()
+            })
+            .await
+            .unwrap();
+        reaches(&c, TorrentState::Downloading).await;
+        a.queue_top();
+        assert_eq!(state(&c), TorrentState::Queued);
+        reaches(&a, TorrentState::Downloading).await;
+        assert_eq!(state(&b), TorrentState::Queued);
+    }
+
+    #[tokio::test]
+    async fn reports_status() {
+        let setup = Setup::new();
+        let session = Session::new(SessionConfig {
+            active_limit: Some(0),
+            ..setup.config()
+        })
+        .await
+        .unwrap();
+        let handle = session.add_torrent(two_files(&setup)).unwrap();
+        let entry = &handle.entry;
+
+        let status = handle.status();
+        assert_eq!(status.name, "two files");
+        assert_eq!(status.state, TorrentState::Queued);
+        assert_eq!(status.piece_count, 3);
+        assert_eq!(
+            (status.total_bytes, status.wanted_bytes, status.done_bytes),
+            (40_000, 40_000, 0)
+        );
+        assert_eq!(status.eta, None);
+        assert_eq!(status.ratio, 0.0);
+
+        entry.have.lock().unwrap()[0] = true;
+        let status = handle.status();
+        assert_eq!(status.done_bytes, 16384);
+        assert_eq!(status.pieces_done, 1);
+        assert_eq!(status.pieces, [true, false, false]);
+
+        // pieces shared with a wanted file stay wanted
+        handle
+            .set_file_priorities(vec![FilePriority::Normal, FilePriority::Skip])
+            .unwrap();
+        let status = handle.status();
+        assert_eq!((status.wanted_bytes, status.done_bytes), (32768, 16384));
+        handle
+            .set_file_priorities(vec![FilePriority::Skip, FilePriority::Normal])
+            .unwrap();
+        let status = handle.status();
+        assert_eq!(
+            (status.wanted_bytes, status.done_bytes),
+            (40_000 - 16384, 0)
+        );
+        handle
+            .set_file_priorities(vec![FilePriority::Normal; 2])
+            .unwrap();
+
+        // the ETA goes by the download rate
+        let peer = entry.bandwidth.for_peer();
+        peer.download.acquire(1000).await;
+        peer.upload.acquire(8192).await;
+        let status = handle.status();
+        assert!(status.download_rate > 0.0);
+        let left = (40_000 - 16384) as f64;
+        assert_eq!(
+            status.eta,
+            Some(Duration::from_secs_f64(left / status.download_rate))
+        );
+        // as long as less was downloaded than is done, the ratio is of what is done
+        assert_eq!((status.downloaded, status.uploaded), (1000, 8192));
+        assert_eq!(status.ratio, 0.5);
+        peer.download.acquire(31_768).await;
+        assert_eq!(handle.status().ratio, 0.25);
+
+        entry.have.lock().unwrap().fill(true);
+        assert_eq!(handle.status().eta, Some(Duration::ZERO));
+        assert!(handle.status().is_complete());
+
+        // seeds are peers with every piece
+        for (last, pieces) in [(1, 3), (2, 1), (3, 3)] {
+            let addr = std::net::SocketAddrV4::new([10, 0, 0, last].into(), 6881);
+            let info = PeerInfo {
+                pieces_available: pieces,
+                ..PeerInfo::new(addr, None, Default::default())
+            };
+            entry
+                .peers
+                .insert(info, entry.bandwidth.for_peer(), Default::default());
+        }
+        let status = handle.status();
+        assert_eq!((status.seeds, status.peers), (2, 3));
+    }
+
+    #[tokio::test]
+    async fn restores_saved_torrents() {
+        let setup = Setup::new();
+        let config = SessionConfig {
+            state_dir: Some(setup.dir.path().join("state")),
+            active_limit: Some(0),
+            ..setup.config()
+        };
+        {
+            let session = Session::new(config.clone()).await.unwrap();
+            let paused = session.add_torrent(setup.torrent("paused", false)).unwrap();
+            let skipped = session
+                .add_torrent(setup.torrent("skipped", false))
+                .unwrap();
+            paused.pause();
+            skipped.queue_top();
+            skipped
+                .set_file_priorities(vec![FilePriority::Skip])
+                .unwrap();
+        }
+        // the files are gone, the torrents are still there
+        std::fs::remove_dir_all(setup.dir.path().join("downloads")).unwrap();
+
+        let session = Session::new(config).await.unwrap();
+        let mut torrents = session.torrents();
+        torrents.sort_by_key(TorrentHandle::queue_position);
+        let [skipped, paused] = &torrents[..] else {
+            panic!("{} torrents were restored", torrents.len());
+        };
+        assert_eq!(skipped.torrent().info.name, "skipped");
+        assert_eq!(state(skipped), TorrentState::Queued);
+        assert_eq!(
+            *skipped.entry.file_priorities.lock().unwrap(),
+            [FilePriority::Skip]
+        );
+        assert_eq!(skipped.save_path(), setup.dir.path().join("downloads"));
+        assert_eq!(paused.torrent().info.name, "paused");
+        assert_eq!(state(paused), TorrentState::Paused);
+
+        // removed torrents stay removed
+        paused.remove();
+        drop(session);
+        let session = Session::new(SessionConfig {
+            state_dir: Some(setup.dir.path().join("state")),
+            ..setup.config()
+        })
+        .await
+        .unwrap();
+        assert_eq!(session.torrents().len(), 1);
+    }
+}
@@ -0,0 +1,463 @@
+//! Settings of the client, loaded from a TOML file.
+//!
+//! Every setting has a default, so the file only needs the ones that differ:
+//!
+//! ```toml
+//! [network]
+//! listen_port = 6881
+//!
+//! [limits]
+//! upload_rate = 102400
+//!
+//! [log]
+//! level = "debug"
+//! ```
+//!
+//! Environment variables go over the file and overrides from the command line over both. They
+//! name a setting by section and key, `network.listen_port` is `RBITTORRENT_NETWORK_LISTEN_PORT`
+//! in the environment. Values are read as TOML values, anything that doesn't parse as one is
+//! taken as a string.
+//!
+//! Rates are in bytes per second, timeouts in seconds. A rate, connection or torrent count of 0
+//! is unlimited, a port of 0 doesn't listen.
+//!
+//! Some settings are accepted but not acted on yet: `protocol.dht`, `protocol.pex`,
+//! `protocol.lsd` and `protocol.encryption = "enabled"` only log a warning when the session
+//! starts, `protocol.encryption = "forced"` is refused.
+//!
+//! [`Settings::session_config`] turns the settings into what a session runs with, a running
+//! session takes changed ones through [`crate::Session::set_config`].
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use toml::{Table, Value};
+use tracing::level_filters::LevelFilter;
+
+use crate::choker::ChokerVariant;
+use crate::peer_connection::PeerConfig;
+use crate::session::SessionConfig;
+
+/// Environment variables starting with this override settings
+pub const ENV_PREFIX: &str = "RBITTORRENT_";
+/// Environment variable with the path of the settings file, it isn't a setting itself
+pub const CONFIG_ENV: &str = "RBITTORRENT_CONFIG";
+/// Range of [`ProtocolSettings::block_size`]
+const MIN_BLOCK_SIZE: usize = 1024;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Whether connections are encrypted (MSE/PE)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionPolicy {
+    /// Plaintext only
+    #[default]
+    Disabled,
+    /// Encrypted where the peer supports it, plaintext otherwise
+    Enabled,
+    /// Encrypted only, peers that don't support it are refused
+    Forced,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub network: NetworkSettings,
+    pub directories: DirectorySettings,
+    pub limits: LimitSettings,
+    pub queue: QueueSettings,
+    pub timeouts: TimeoutSettings,
+    pub seeding: SeedingSettings,
+    pub downloading: DownloadingSettings,
+    pub filter: FilterSettings,
+    pub protocol: ProtocolSettings,
+    pub log: LogSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkSettings {
+    /// Incoming peer connections, also announced to the trackers. A single port on every IPv4
+    /// interface, there is no list or range to fall back on: if it is taken the session doesn't
+    /// start.
+    pub listen_port: u16,
+    /// HTTP server that streams the files, only on loopback
+    pub stream_port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectorySettings {
+    /// Where new torrents are downloaded to
+    pub download: PathBuf,
+    /// Where the torrents are saved to survive a restart, left out forgets them
+    pub state: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitSettings {
+    /// Over all torrents
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    /// Of every single torrent
+    pub torrent_download_rate: u64,
+    pub torrent_upload_rate: u64,
+    /// Of every single connection
+    pub peer_download_rate: u64,
+    pub peer_upload_rate: u64,
+    /// Open connections over all torrents
+    pub connections: usize,
+    /// Connections still connecting or handshaking over all torrents
+    pub half_open: usize,
+    /// Open connections of every single torrent
+    pub torrent_connections: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueSettings {
+    pub active_downloads: usize,
+    pub active_seeds: usize,
+    /// Downloads and seeds together
+    pub active_limit: usize,
+    /// Torrents slower than the slow rates don't count towards the active limits
+    pub dont_count_slow_torrents: bool,
+    pub slow_download_rate: u64,
+    pub slow_upload_rate: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutSettings {
+    /// Connecting to a peer
+    pub connect: u64,
+    /// Every message of the handshake with a peer
+    pub handshake: u64,
+    /// Answers from trackers
+    pub tracker: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeedingSettings {
+    /// Keep seeding once a torrent is complete
+    pub enabled: bool,
+    /// BEP 16, implies `enabled`
+    pub super_seeding: bool,
+    pub upload_slots: usize,
+    /// `rate-based`, `round-robin` or `fastest-upload`
+    pub choker: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DownloadingSettings {
+    /// Pieces in order instead of rarest first
+    pub sequential: bool,
+    /// Bytes ahead of a streaming reader that are downloaded first
+    pub read_ahead: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterSettings {
+    /// Bans are loaded from and saved to this file
+    pub ban_file: Option<PathBuf>,
+    /// Blocklists, see [`crate::ip_filter`]
+    pub ip_filters: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolSettings {
+    /// Start of our peer ID, the rest is random
+    pub peer_id_prefix: String,
+    /// Bytes asked for per block request
+    pub block_size: usize,
+    /// Plaintext only so far, `enabled` warns and connects in plaintext, `forced` is refused
+    pub encryption: EncryptionPolicy,
+    /// Find peers through the DHT (BEP 5), peer exchange (BEP 11) and local service discovery
+    /// (BEP 14). Private torrents never do. Not supported yet, turning them on only warns.
+    pub dht: bool,
+    pub pex: bool,
+    pub lsd: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogSettings {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`
+    pub level: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::from(&SessionConfig::default())
+    }
+}
+
+/// The settings `config` runs with, logging at the info level
+impl From<&SessionConfig> for Settings {
+    fn from(config: &SessionConfig) -> Self {
+        let unlimited = |limit: Option<u64>| limit.unwrap_or(0);
+        Self {
+            network: NetworkSettings {
+                listen_port: config.listen_port.unwrap_or(0),
+                stream_port: config.stream_port.unwrap_or(0),
+            },
+            directories: DirectorySettings {
+                download: config.download_dir.clone(),
+                state: config.state_dir.clone(),
+            },
+            limits: LimitSettings {
+                download_rate: unlimited(config.download_limit),
+                upload_rate: unlimited(config.upload_limit),
+                torrent_download_rate: unlimited(config.torrent_download_limit),
+                torrent_upload_rate: unlimited(config.torrent_upload_limit),
+                peer_download_rate: unlimited(config.peer_download_limit),
+                peer_upload_rate: unlimited(config.peer_upload_limit),
+                connections: config.max_connections,
+                half_open: config.max_half_open,
+                torrent_connections: config.torrent_max_connections,
+            },
+            queue: QueueSettings {
+                active_downloads: config.active_downloads.unwrap_or(0),
+                active_seeds: config.active_seeds.unwrap_or(0),
+                active_limit: config.active_limit.unwrap_or(0),
+                dont_count_slow_torrents: config.dont_count_slow_torrents,
+                slow_download_rate: config.slow_download_rate,
+                slow_upload_rate: config.slow_upload_rate,
+            },
+            timeouts: TimeoutSettings {
+                connect: config.peer.connect_timeout.as_secs(),
+                handshake: config.peer.handshake_timeout.as_secs(),
+                tracker: config.tracker_timeout.as_secs(),
+            },
+            seeding: SeedingSettings {
+                enabled: config.seed,
+                super_seeding: config.super_seeding,
+                upload_slots: config.choker.unchoke_slots,
+                choker: config.choker.variant.to_string(),
+            },
+            downloading: DownloadingSettings {
+                sequential: config.sequential,
+                read_ahead: config.read_ahead,
+            },
+            filter: FilterSettings {
+                ban_file: config.ban_file.clone(),
+                ip_filters: config.ip_filter_files.clone(),
+            },
+            protocol: ProtocolSettings {
+                peer_id_prefix: config.peer_id_prefix.clone(),
+                block_size: config.peer.block_size,
+                encryption: config.encryption,
+                dht: config.dht,
+                pex: config.pex,
+                lsd: config.lsd,
+            },
+            log: LogSettings {
+                level: LevelFilter::INFO.to_string().to_lowercase(),
+            },
+        }
+    }
+}
+
+impl Settings {
+    /// Read the settings file at `path`, or start from the defaults without one, then apply the
+    /// environment and `overrides`. Overrides look like `section.key=value`.
+    pub fn load(path: Option<&Path>, overrides: &[String]) -> Result<Self, anyhow::Error> {
+        let mut table = Table::try_from(Self::default())?;
+        if let Some(path) = path {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+            let file = toml::from_str::<Table>(&content)
+                .map_err(|e| anyhow!("Invalid settings file {}: {e}", path.display()))?;
+            for (section, values) in file {
+                match (table.get_mut(&section), values) {
+                    (Some(Value::Table(defaults)), Value::Table(values)) => defaults.extend(values),
+                    (_, values) => {
+                        table.insert(section, values);
+                    }
+                }
+            }
+        }
+
+        for (name, value) in std::env::vars() {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_ENV {
+                continue;
+            }
+            // sections have no underscores, so the first one ends the section
+            let key = key.to_lowercase().replacen('_', ".", 1);
+            set(&mut table, &key, &value).map_err(|e| anyhow!("{name}: {e}"))?;
+        }
+        for assignment in overrides {
+            let (key, value) = assignment
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected section.key=value, got {assignment}"))?;
+            set(&mut table, key.trim(), value.trim())?;
+        }
+
+        let settings: Self = table.try_into()?;
+        settings.session_config()?;
+        settings.log_level()?;
+        Ok(settings)
+    }
+
+    /// What a session runs with, fails if a setting is out of range
+    pub fn session_config(&self) -> Result<SessionConfig, anyhow::Error> {
+        let port = |port: u16| (port > 0).then_some(port);
+        let limit = |rate: u64| (rate > 0).then_some(rate);
+        let count = |count: usize| (count > 0).then_some(count);
+        let timeout = |name: &str, secs: u64| {
+            if secs == 0 {
+                return Err(anyhow!("timeouts.{name} has to be at least 1 second"));
+            }
+            Ok(Duration::from_secs(secs))
+        };
+
+        let protocol = &self.protocol;
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&protocol.block_size) {
+            anyhow::bail!(
+                "protocol.block_size has to be between {MIN_BLOCK_SIZE} and {MAX_BLOCK_SIZE} bytes"
+            );
+        }
+        if protocol.peer_id_prefix.len() > 20 || !protocol.peer_id_prefix.is_ascii() {
+            anyhow::bail!("protocol.peer_id_prefix has to be at most 20 ASCII characters");
+        }
+
+        let defaults = SessionConfig::default();
+        let mut choker = defaults.choker.clone();
+        choker.unchoke_slots = self.seeding.upload_slots;
+        choker.variant = ChokerVariant::from_str(&self.seeding.choker)
+            .map_err(|e| anyhow!("seeding.choker: {e}"))?;
+
+        Ok(SessionConfig {
+            download_dir: self.directories.download.clone(),
+            listen_port: port(self.network.listen_port),
+            choker,
+            download_limit: limit(self.limits.download_rate),
+            upload_limit: limit(self.limits.upload_rate),
+            torrent_download_limit: limit(self.limits.torrent_download_rate),
+            torrent_upload_limit: limit(self.limits.torrent_upload_rate),
+            peer_download_limit: limit(self.limits.peer_download_rate),
+            peer_upload_limit: limit(self.limits.peer_upload_rate),
+            max_connections: self.limits.connections,
+            max_half_open: self.limits.half_open,
+            torrent_max_connections: self.limits.torrent_connections,
+            ban_file: self.filter.ban_file.clone(),
+            ip_filter_files: self.filter.ip_filters.clone(),
+            seed: self.seeding.enabled || self.seeding.super_seeding,
+            super_seeding: self.seeding.super_seeding,
+            sequential: self.downloading.sequential,
+            read_ahead: self.downloading.read_ahead,
+            stream_port: port(self.network.stream_port),
+            active_downloads: count(self.queue.active_downloads),
+            active_seeds: count(self.queue.active_seeds),
+            active_limit: count(self.queue.active_limit),
+            dont_count_slow_torrents: self.queue.dont_count_slow_torrents,
+            slow_download_rate: self.queue.slow_download_rate,
+            slow_upload_rate: self.queue.slow_upload_rate,
+            state_dir: self.directories.state.clone(),
+            peer: PeerConfig {
+                connect_timeout: timeout("connect", self.timeouts.connect)?,
+                handshake_timeout: timeout("handshake", self.timeouts.handshake)?,
+                block_size: protocol.block_size,
+            },
+            tracker_timeout: timeout("tracker", self.timeouts.tracker)?,
+            peer_id_prefix: protocol.peer_id_prefix.clone(),
+            encryption: protocol.encryption,
+            dht: protocol.dht,
+            pex: protocol.pex,
+            lsd: protocol.lsd,
+        })
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter, anyhow::Error> {
+        LevelFilter::from_str(&self.log.level)
+            .map_err(|_| anyhow!("log.level: unknown level {}", self.log.level))
+    }
+}
+
+/// Set `key`, `section.key`, in `table` to `value` read as a TOML value
+fn set(table: &mut Table, key: &str, value: &str) -> Result<(), anyhow::Error> {
+    let (section, key) = key
+        .split_once('.')
+        .ok_or_else(|| anyhow!("{key} isn't a setting, expected section.key"))?;
+    let value = value
+        .parse::<Value>()
+        .unwrap_or_else(|_| Value::String(value.to_string()));
+    match table.get_mut(section) {
+        Some(Value::Table(section)) => {
+            section.insert(key.to_string(), value);
+            Ok(())
+        }
+        _ => Err(anyhow!("{section} isn't a section")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(file: &str, overrides: &[&str]) -> Result<Settings, anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("settings.toml");
+        std::fs::write(&path, file)?;
+        let overrides: Vec<String> = overrides.iter().map(|o| o.to_string()).collect();
+        Settings::load(Some(&path), &overrides)
+    }
+
+    // the only test that touches the environment, Settings::load reads all of it
+    #[test]
+    fn command_line_beats_environment_beats_file() {
+        let file = "[limits]\nupload_rate = 1\ndownload_rate = 1\nconnections = 1\n";
+        std::env::set_var("RBITTORRENT_LIMITS_UPLOAD_RATE", "2");
+        std::env::set_var("RBITTORRENT_LIMITS_DOWNLOAD_RATE", "2");
+        let settings = load(file, &["limits.upload_rate=3"]);
+        std::env::remove_var("RBITTORRENT_LIMITS_UPLOAD_RATE");
+        std::env::remove_var("RBITTORRENT_LIMITS_DOWNLOAD_RATE");
+
+        let limits = settings.unwrap().limits;
+        assert_eq!(limits.upload_rate, 3);
+        assert_eq!(limits.download_rate, 2);
+        assert_eq!(limits.connections, 1);
+        assert_eq!(limits.half_open, Settings::default().limits.half_open);
+    }
+
+    #[test]
+    fn overrides() {
+        let settings = load("", &["log.level = debug", "network.listen_port=7000"]).unwrap();
+        assert_eq!(settings.log_level().unwrap(), LevelFilter::DEBUG);
+        assert_eq!(settings.session_config().unwrap().listen_port, Some(7000));
+
+        for overrides in [
+            &["listen_port=7000"][..],
+            &["network.listen_port"],
+            &["nowhere.listen_port=7000"],
+            &["network.listen_port=-1"],
+            &["network.unknown=1"],
+            &["timeouts.connect=0"],
+            &["protocol.block_size=100"],
+            &["log.level=loud"],
+        ] {
+            assert!(load("", overrides).is_err(), "{overrides:?}");
+        }
+    }
+
+    #[test]
+    fn file_keeps_the_defaults_it_leaves_out() {
+        let settings = load("[network]\nstream_port = 8080\n", &[]).unwrap();
+        assert_eq!(settings.network.stream_port, 8080);
+        assert_eq!(
+            settings.network.listen_port,
+            Settings::default().network.listen_port
+        );
+        assert!(load("[network]\nport = 1\n", &[]).is_err());
+        assert!(load("[network", &[]).is_err());
+    }
+}
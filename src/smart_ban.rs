@@ -0,0 +1,259 @@
+//! Banning peers that send corrupt data.
+//!
+//! A piece that fails its hash check doesn't say which block was bad, so banning on the spot
+//! could hit an innocent peer. Instead the hashes of every block of the failed piece are kept
+//! together with the peer that sent it. The piece is then downloaded again from someone else, and
+//! once it passes, every peer whose blocks differ from the good data is banned ("smart ban").
+//!
+//! Bans are by IP, so a peer can't get around one by connecting again from another port. They
+//! last for the session, with a ban file they are also loaded on start and appended to as they
+//! happen.
+//!
+//! Failed pieces are only kept for so long: at most [`MAX_FAILED_PIECES`] of them with
+//! [`MAX_COPIES`] copies each, the oldest go first.
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+
+use crate::merkle::BLOCK_SIZE;
+
+/// Failed pieces kept at once per torrent
+pub const MAX_FAILED_PIECES: usize = 64;
+/// Copies kept of every failed piece
+pub const MAX_COPIES: usize = 8;
+
+/// Peers banned for the rest of the session, shared by every torrent
+#[derive(Debug, Default)]
+pub struct BanList {
+    banned: std::sync::Mutex<HashSet<Ipv4Addr>>,
+    file: Option<PathBuf>,
+}
+
+impl BanList {
+    /// Load bans from `path`, one IP per line, and append new bans to it. A missing file is created
+    /// with the first ban. Lines with a port, as older versions wrote them, ban the IP.
+    pub fn with_file<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let mut banned = HashSet::new();
+        match std::fs::read_to_string(path) {
+            Ok(content) => {
+                for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                    let ip = line
+                        .parse()
+                        .or_else(|_| line.parse::<SocketAddrV4>().map(|addr| *addr.ip()));
+                    match ip {
+                        Ok(ip) => {
+                            banned.insert(ip);
+                        }
+                        Err(_) => warn!("Ignoring invalid entry {line:?} in {}", path.display()),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self {
+            banned: std::sync::Mutex::new(banned),
+            file: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn is_banned(&self, ip: &Ipv4Addr) -> bool {
+        self.banned.lock().unwrap().contains(ip)
+    }
+
+    pub fn ban(&self, ip: Ipv4Addr) {
+        if !self.banned.lock().unwrap().insert(ip) {
+            return;
+        }
+        warn!("Banned {ip} for sending corrupt data");
+
+        if let Some(path) = &self.file {
+            let written = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{ip}"));
+            if let Err(e) = written {
+                error!("Failed to save ban of {ip} to {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+type BlockHash = [u8; 20];
+
+fn block_hashes(data: &[u8]) -> Vec<BlockHash> {
+    data.chunks(BLOCK_SIZE)
+        .map(|block| Sha1::digest(block).into())
+        .collect()
+}
+
+/// Failed pieces of one torrent and who sent them
+#[derive(Debug, Default)]
+pub struct SmartBan {
+    /// For every failed piece, each copy we got with the IP that sent it, oldest first
+    failed: HashMap<usize, VecDeque<(Ipv4Addr, Vec<BlockHash>)>>,
+    /// The keys of `failed`, oldest first
+    order: VecDeque<usize>,
+}
+
+impl SmartBan {
+    /// Remember the blocks of a piece from `peer` that failed its hash check
+    pub fn piece_failed(&mut self, index: usize, peer: SocketAddrV4, data: &[u8]) {
+        if !self.failed.contains_key(&index) {
+            if self.order.len() >= MAX_FAILED_PIECES {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.failed.remove(&oldest);
+                }
+            }
+            self.order.push_back(index);
+        }
+        let copies = self.failed.entry(index).or_default();
+        if copies.len() >= MAX_COPIES {
+            copies.pop_front();
+        }
+        copies.push_back((*peer.ip(), block_hashes(data)));
+    }
+
+    /// Whether `peer` already sent a bad copy of the piece, see [`SmartBan::avoided_pieces`]
+    pub fn failed_by(&self, index: usize, peer: &SocketAddrV4) -> bool {
+        self.failed
+            .get(&index)
+            .is_some_and(|copies| copies.iter().any(|(ip, _)| ip == peer.ip()))
+    }
+
+    /// Pieces `peer` sent a bad copy of that it shouldn't get again, because a connected peer that
+    /// didn't has them too. With no one else to send a piece the peer gets another try, or the
+    /// download could never finish. `available` counts the connected peers that have a piece,
+    /// `connected` tells whether an IP is connected.
+    pub fn avoided_pieces(
+        &self,
+        peer: &SocketAddrV4,
+        available: impl Fn(usize) -> usize,
+        connected: impl Fn(&Ipv4Addr) -> bool,
+    ) -> HashSet<usize> {
+        let mut avoided = HashSet::new();
+        for (index, copies) in &self.failed {
+            if !copies.iter().any(|(ip, _)| ip == peer.ip()) {
+                continue;
+            }
+            let mut senders: Vec<&Ipv4Addr> = copies.iter().map(|(ip, _)| ip).collect();
+            senders.sort_unstable();
+            senders.dedup();
+            let connected_senders = senders.into_iter().filter(|ip| connected(ip)).count();
+            if available(*index) > connected_senders {
+                avoided.insert(*index);
+            }
+        }
+        avoided
+    }
+
+    /// The piece passed, returns the IPs that sent a block different from `data` in an earlier
+    /// copy
+    pub fn piece_passed(&mut self, index: usize, data: &[u8]) -> Vec<Ipv4Addr> {
+        let Some(copies) = self.failed.remove(&index) else {
+            return Vec::new();
+        };
+        self.order.retain(|failed| *failed != index);
+
+        let good = block_hashes(data);
+        let mut offenders = Vec::new();
+        for (peer, hashes) in copies {
+            if hashes.iter().zip(&good).any(|(bad, good)| bad != good) && !offenders.contains(&peer)
+            {
+                offenders.push(peer);
+            }
+        }
+        offenders
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::picker::Picker;
+    use crate::storage::FilePriority;
+
+    #[test]
+    fn bans_the_ip_whatever_the_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans");
+        std::fs::write(&path, "10.0.0.1:6881\n10.0.0.2\nnonsense\n").unwrap();
+        let bans = BanList::with_file(&path).unwrap();
+        assert!(bans.is_banned(&Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(bans.is_banned(&Ipv4Addr::new(10, 0, 0, 2)));
+
+        let mut smart_ban = SmartBan::default();
+        let good = vec![1u8; BLOCK_SIZE * 2];
+        let mut bad = good.clone();
+        bad[BLOCK_SIZE] = 0;
+        smart_ban.piece_failed(0, "10.0.0.3:1000".parse().unwrap(), &bad);
+        assert!(smart_ban.failed_by(0, &"10.0.0.3:2000".parse().unwrap()));
+        for ip in smart_ban.piece_passed(0, &good) {
+            bans.ban(ip);
+        }
+        assert!(bans.is_banned(&Ipv4Addr::new(10, 0, 0, 3)));
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.ends_with("10.0.0.3\n"), "{saved}");
+    }
+
+    #[test]
+    fn retries_a_piece_no_one_else_has() {
+        let mut smart_ban = SmartBan::default();
+        let seed: SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+        smart_ban.piece_failed(3, seed, &[0]);
+        let connected = |ip: &Ipv4Addr| ip == seed.ip();
+
+        // the only peer with the piece gets another try
+        let avoided = smart_ban.avoided_pieces(&seed, |_| 1, connected);
+        assert!(avoided.is_empty());
+        let mut picker = Picker::new(4);
+        picker.set_priorities(
+            vec![
+                FilePriority::Skip,
+                FilePriority::Skip,
+                FilePriority::Skip,
+                FilePriority::Normal,
+            ],
+            &[false; 4],
+        );
+        picker.add_peer(&[true; 4]);
+        assert_eq!(
+            picker.pick(|index| !avoided.contains(&index), false),
+            Some(3)
+        );
+
+        // once another peer has it, it comes from there
+        assert_eq!(
+            smart_ban.avoided_pieces(&seed, |_| 2, connected),
+            [3].into()
+        );
+        // pieces the peer didn't corrupt are never avoided
+        let other: SocketAddrV4 = "10.0.0.2:6881".parse().unwrap();
+        assert!(smart_ban.avoided_pieces(&other, |_| 2, |_| true).is_empty());
+        // a second bad sender doesn't count as someone else
+        smart_ban.piece_failed(3, other, &[1]);
+        assert!(smart_ban.avoided_pieces(&seed, |_| 2, |_| true).is_empty());
+    }
+
+    #[test]
+    fn keeps_a_bounded_number_of_failed_pieces() {
+        let mut smart_ban = SmartBan::default();
+        let peer = |port| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), port);
+        for index in 0..=MAX_FAILED_PIECES {
+            smart_ban.piece_failed(index, peer(1), &[0]);
+        }
+        for port in 0..MAX_COPIES as u16 * 2 {
+            smart_ban.piece_failed(1, peer(port), &[0]);
+        }
+        assert_eq!(smart_ban.failed.len(), MAX_FAILED_PIECES);
+        assert!(!smart_ban.failed_by(0, &peer(1)));
+        assert!(smart_ban.failed_by(MAX_FAILED_PIECES, &peer(1)));
+        assert_eq!(smart_ban.failed[&1].len(), MAX_COPIES);
+    }
+}
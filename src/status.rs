@@ -0,0 +1,186 @@
+//! Snapshots of how a torrent and its connections are doing.
+//!
+//! The peer tasks of the downloader and the seeder keep a [`PeerList`] up to date, the session
+//! reads it together with the piece state and the rate meters of the torrent's limiters when a
+//! [`TorrentStatus`] is asked for.
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::peer_id::ClientId;
+use crate::rate_limit::Bandwidth;
+use crate::session::TorrentState;
+
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub name: String,
+    pub info_hash: [u8; 20],
+    pub state: TorrentState,
+    /// See [`crate::session::TorrentHandle::queue_position`]
+    pub queue_position: usize,
+    pub total_bytes: u64,
+    /// Bytes of the pieces that touch a file that isn't skipped
+    pub wanted_bytes: u64,
+    /// Bytes of the wanted pieces that passed their hash check
+    pub done_bytes: u64,
+    /// Pieces that passed their hash check
+    pub pieces: Vec<bool>,
+    pub pieces_done: usize,
+    pub piece_count: usize,
+    /// Payload bytes per second, see [`crate::rate_limit::RATE_WINDOW`]
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// Time until the wanted pieces are done at the current download rate, `None` while nothing
+    /// is coming in
+    pub eta: Option<Duration>,
+    /// Connected peers that have every piece
+    pub seeds: usize,
+    /// Connected peers, seeds included
+    pub peers: usize,
+    /// Payload bytes received and sent since the torrent was added
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Uploaded over downloaded bytes. Data that was already on disk counts as downloaded, so a
+    /// seed that never downloaded anything still has a ratio.
+    pub ratio: f64,
+}
+
+impl TorrentStatus {
+    pub fn is_complete(&self) -> bool {
+        self.pieces_done == self.piece_count
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerFlags {
+    /// We want pieces from the peer
+    pub interested: bool,
+    /// We don't upload to the peer
+    pub choked: bool,
+    /// The peer wants pieces from us
+    pub remote_interested: bool,
+    /// The peer doesn't upload to us
+    pub remote_choked: bool,
+    /// The peer connected to us
+    pub incoming: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: SocketAddrV4,
+    pub client: Option<ClientId>,
+    pub flags: PeerFlags,
+    /// Payload bytes per second, see [`crate::rate_limit::RATE_WINDOW`]
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// Payload bytes received from and sent to the peer
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Pieces the peer announced
+    pub pieces_available: usize,
+    /// Block requests sent to the peer and not answered yet
+    pub queue_depth: usize,
+}
+
+impl PeerInfo {
+    /// A peer that just connected, the rates and counters are filled in by [`PeerList::peers`]
+    pub fn new(addr: SocketAddrV4, client: Option<ClientId>, flags: PeerFlags) -> Self {
+        Self {
+            addr,
+            client,
+            flags,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            downloaded: 0,
+            uploaded: 0,
+            pieces_available: 0,
+            queue_depth: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Tracked {
+    info: PeerInfo,
+    /// Limiters of the connection, they measure its rates
+    bandwidth: Bandwidth,
+    queue_depth: Arc<AtomicUsize>,
+}
+
+/// Connected peers of one torrent, cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct PeerList {
+    peers: Arc<std::sync::Mutex<HashMap<SocketAddrV4, Tracked>>>,
+}
+
+impl PeerList {
+    /// Track a connected peer. `bandwidth` are the limiters of its connection and `queue_depth`
+    /// counts its outstanding requests, both are read live.
+    pub fn insert(&self, info: PeerInfo, bandwidth: Bandwidth, queue_depth: Arc<AtomicUsize>) {
+        self.peers.lock().unwrap().insert(
+            info.addr,
+            Tracked {
+                info,
+                bandwidth,
+                queue_depth,
+            },
+        );
+    }
+
+    pub fn update(&self, addr: &SocketAddrV4, update: impl FnOnce(&mut PeerInfo)) {
+        if let Some(tracked) = self.peers.lock().unwrap().get_mut(addr) {
+            update(&mut tracked.info);
+        }
+    }
+
+    /// Whether a peer with `ip` is connected, on any port
+    pub fn contains_ip(&self, ip: &Ipv4Addr) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|addr| addr.ip() == ip)
+    }
+
+    pub fn remove(&self, addr: &SocketAddrV4) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    /// Forget every peer, for when the tasks that would remove them were aborted
+    pub fn clear(&self) {
+        self.peers.lock().unwrap().clear();
+    }
+
+    /// How many peers we download from faster than from `addr`
+    pub fn download_rank(&self, addr: &SocketAddrV4) -> usize {
+        let peers = self.peers.lock().unwrap();
+        let Some(rate) = peers
+            .get(addr)
+            .map(|tracked| tracked.bandwidth.download.rate())
+        else {
+            return peers.len();
+        };
+        peers
+            .values()
+            .filter(|tracked| tracked.bandwidth.download.rate() > rate)
+            .count()
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|tracked| PeerInfo {
+                download_rate: tracked.bandwidth.download.rate(),
+                upload_rate: tracked.bandwidth.upload.rate(),
+                downloaded: tracked.bandwidth.download.payload_bytes(),
+                uploaded: tracked.bandwidth.upload.payload_bytes(),
+                queue_depth: tracked.queue_depth.load(Ordering::Relaxed),
+                ..tracked.info.clone()
+            })
+            .collect()
+    }
+}
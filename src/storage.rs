@@ -0,0 +1,902 @@
+//! Writing the downloaded data to disk, and reading it back for seeding.
+//!
+//! [`Storage`] maps the piece address space onto the files of the torrent. Every piece is written
+//! as soon as it passes its hash check, and seeding reads it back from disk.
+//!
+//! Pieces that straddle a wanted and a skipped file are downloaded whole, but only the wanted
+//! file is written. The whole piece goes into a part file next to the torrent's files instead, so
+//! it can still be checked later and the skipped file doesn't have to be created.
+//!
+//! Names and paths in the metainfo come straight from whoever made the torrent, so every segment
+//! is sanitized before it touches the filesystem. Nothing is ever written outside of the download
+//! directory.
+use anyhow::anyhow;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::parser::{FileInfo, Info};
+
+/// How much a file of a torrent is wanted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum FilePriority {
+    /// Not downloaded and not written
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Most filesystems refuse names longer than this many bytes
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Names Windows refuses to create, no matter the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turn a single name from the metainfo into something that is safe to use as one path component.
+/// Separators, NULs, control and reserved characters are replaced, traversal components and
+/// reserved device names are escaped and overlong names are truncated.
+pub fn sanitize_segment(segment: &str) -> String {
+    let mut name: String = segment
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // windows silently drops trailing dots and spaces, which could merge two names into one.
+    // This also turns `.` and `..` into empty names
+    let trimmed = name.trim_end_matches(['.', ' ']).len();
+    name.truncate(trimmed);
+
+    if name.is_empty() {
+        return "_".to_string();
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        name.insert(0, '_');
+    }
+
+    truncate_name(&name, MAX_NAME_LENGTH)
+}
+
+/// Cut `name` down to `max` bytes on a char boundary, keeping a short extension if there is one
+fn truncate_name(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+
+    let extension = name
+        .rfind('.')
+        .map(|i| &name[i..])
+        .filter(|ext| ext.len() <= 16)
+        .unwrap_or_default();
+
+    let mut end = max - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+/// Append ` (n)` before the extension
+fn with_suffix(name: &str, n: usize) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    truncate_name(&format!("{stem} ({n}){extension}"), MAX_NAME_LENGTH)
+}
+
+/// Sanitized path of every file relative to the torrent root, `None` for padding files. Paths
+/// that collide after sanitizing (or where a file would have to be a directory at the same time)
+/// get a ` (n)` suffix, the first file keeps its name. Case doesn't tell paths apart, they would
+/// collide on case-insensitive filesystems.
+pub fn plan_paths(info: &Info) -> Vec<Option<PathBuf>> {
+    // both keyed by the case folded path
+    let mut files: HashSet<String> = HashSet::new();
+    let mut dirs: HashSet<String> = HashSet::new();
+    let fold = |path: &Path| path.to_string_lossy().to_lowercase();
+    let mut planned = Vec::new();
+
+    for file_info in info.files() {
+        if file_info.is_padding() {
+            planned.push(None);
+            continue;
+        }
+
+        let segments: Vec<String> = if file_info.path.is_empty() {
+            vec!["_".to_string()]
+        } else {
+            file_info.path.iter().map(|s| sanitize_segment(s)).collect()
+        };
+        let (file_name, parents) = segments.split_last().unwrap();
+
+        let mut path = PathBuf::new();
+        for segment in parents {
+            let mut candidate = path.join(segment);
+            let mut n = 1;
+            while files.contains(&fold(&candidate)) {
+                candidate = path.join(with_suffix(segment, n));
+                n += 1;
+            }
+            dirs.insert(fold(&candidate));
+            path = candidate;
+        }
+
+        let mut candidate = path.join(file_name);
+        let mut n = 1;
+        while files.contains(&fold(&candidate)) || dirs.contains(&fold(&candidate)) {
+            candidate = path.join(with_suffix(file_name, n));
+            n += 1;
+        }
+        files.insert(fold(&candidate));
+        planned.push(Some(candidate));
+    }
+
+    planned
+}
+
+/// Make sure `path` resolves to somewhere inside `root`, catches symlinks that already exist in
+/// the download directory
+fn ensure_inside(root: &Path, path: &Path) -> Result<(), anyhow::Error> {
+    let root = root.canonicalize()?;
+    let resolved = path.canonicalize()?;
+    if !resolved.starts_with(&root) {
+        return Err(anyhow!(
+            "{} resolves outside of the download directory",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Create the directories of `relative` below `root` one at a time. A component that already
+/// exists has to be a real directory, a symlink there could point anywhere and is refused before
+/// anything gets created through it.
+fn create_dirs_inside(root: &Path, relative: &Path) -> Result<(), anyhow::Error> {
+    let mut path = root.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => {
+                return Err(anyhow!(
+                    "{} is in the way of a directory of the torrent",
+                    path.display()
+                ))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir(&path)?,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    ensure_inside(root, &path)
+}
+
+/// Open a file of the torrent for writing, creating it if it isn't there. Whatever is already at
+/// `path` was checked against the metainfo and is ours, unless it is a symlink: writing through
+/// that could reach anything.
+fn open_for_writing(root: &Path, path: &Path) -> Result<File, anyhow::Error> {
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_symlink()) {
+        return Err(anyhow!(
+            "{} is a symlink, not writing through it",
+            path.display()
+        ));
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    ensure_inside(root, path)?;
+    Ok(file)
+}
+
+/// The files of one torrent in its download directory. Pieces are written as soon as they pass
+/// their hash check and read back from disk, nothing of the torrent is kept in memory.
+#[derive(Debug)]
+pub struct Storage {
+    info: Info,
+    files: Vec<FileInfo>,
+    download_dir: PathBuf,
+    /// Where every file lives below `download_dir`, `None` for padding files
+    paths: Vec<Option<PathBuf>>,
+    /// Indexed like `files`. Held while a piece is written, so it goes where the priorities say.
+    priorities: Mutex<Vec<FilePriority>>,
+    /// Pieces in the part file and where their data starts in it
+    parts: Mutex<HashMap<usize, u64>>,
+}
+
+/// Where a file overlaps a range of the piece address space
+struct Span {
+    /// Index of the file
+    file: usize,
+    /// Where the overlap starts in the file
+    offset: u64,
+    /// The overlap, relative to the start of the range
+    range: Range<usize>,
+}
+
+impl Storage {
+    /// `priorities` are indexed like [`Info::files`], skipped files are not written
+    pub fn new(info: &Info, download_dir: &Path, priorities: Vec<FilePriority>) -> Self {
+        let name = PathBuf::from(sanitize_segment(&info.name));
+        let paths = if info.is_single_file() {
+            vec![Some(name)]
+        } else {
+            plan_paths(info)
+                .into_iter()
+                .map(|planned| planned.map(|path| name.join(path)))
+                .collect()
+        };
+        Self {
+            info: info.clone(),
+            files: info.files(),
+            download_dir: download_dir.to_path_buf(),
+            paths,
+            priorities: Mutex::new(priorities),
+            parts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The files `length` bytes at `offset` of the piece address space fall into
+    fn spans(&self, offset: usize, length: usize) -> Vec<Span> {
+        let end = offset + length;
+        let mut spans = Vec::new();
+        let mut file_start = 0;
+        for (file, file_info) in self.files.iter().enumerate() {
+            let file_end = file_start + file_info.length;
+            let (start, stop) = (offset.max(file_start), end.min(file_end));
+            if start < stop {
+                spans.push(Span {
+                    file,
+                    offset: (start - file_start) as u64,
+                    range: start - offset..stop - offset,
+                });
+            }
+            if file_end >= end {
+                break;
+            }
+            file_start = file_end;
+        }
+        spans
+    }
+
+    /// Read `length` bytes at `offset` of the piece address space. Pieces in the part file are
+    /// read from there. Padding, symlinks and whatever is missing on disk read as zeros, checking
+    /// the pieces tells what is actually there.
+    pub fn read(&self, offset: usize, length: usize) -> Result<Vec<u8>, anyhow::Error> {
+        let mut buffer = vec![0u8; length];
+        for span in self.spans(offset, length) {
+            let Some(path) = &self.paths[span.file] else {
+                continue;
+            };
+            if self.files[span.file].is_symlink() {
+                continue;
+            }
+            read_at(
+                &self.download_dir.join(path),
+                span.offset,
+                &mut buffer[span.range],
+            )?;
+        }
+
+        let parts = self.parts.lock().unwrap();
+        if length == 0 || parts.is_empty() {
+            return Ok(buffer);
+        }
+        let part_file = part_file_path(&self.info, &self.download_dir);
+        let piece_length = self.info.piece_length;
+        for index in offset / piece_length..=(offset + length - 1) / piece_length {
+            let Some(data_offset) = parts.get(&index) else {
+                continue;
+            };
+            let piece_start = index * piece_length;
+            let start = offset.max(piece_start);
+            let end = (offset + length).min(piece_start + self.info.piece_size(index));
+            read_at(
+                &part_file,
+                data_offset + (start - piece_start) as u64,
+                &mut buffer[start - offset..end - offset],
+            )?;
+        }
+        Ok(buffer)
+    }
+
+    pub fn read_piece(&self, index: usize) -> Result<Vec<u8>, anyhow::Error> {
+        self.read(index * self.info.piece_length, self.info.piece_size(index))
+    }
+
+    /// Write a piece that passed its hash check into its files. A piece that also belongs to a
+    /// skipped file goes into the part file as a whole, so it can still be checked later and the
+    /// skipped file doesn't have to be created.
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> Result<(), anyhow::Error> {
+        let priorities = self.priorities.lock().unwrap();
+        if self.write_wanted(index, data, &priorities)? {
+            self.append_part(index, data)?;
+        }
+        Ok(())
+    }
+
+    /// Write the parts of a piece that belong to wanted files, returns whether it also belongs to
+    /// a skipped one
+    fn write_wanted(
+        &self,
+        index: usize,
+        data: &[u8],
+        priorities: &[FilePriority],
+    ) -> Result<bool, anyhow::Error> {
+        let mut skipped = false;
+        for span in self.spans(index * self.info.piece_length, data.len()) {
+            // padding still occupies piece space, it just never ends up on disk
+            let Some(path) = &self.paths[span.file] else {
+                continue;
+            };
+            if priorities.get(span.file) == Some(&FilePriority::Skip) {
+                skipped = true;
+                continue;
+            }
+            let file_info = &self.files[span.file];
+            if file_info.is_symlink() {
+                continue;
+            }
+            self.write_at(
+                path,
+                file_info.length as u64,
+                span.offset,
+                &data[span.range],
+            )?;
+        }
+        Ok(skipped)
+    }
+
+    /// Write `data` at `offset` of the file at `relative`, which is `length` bytes long. The file
+    /// and its directories are created if they aren't there yet.
+    fn write_at(
+        &self,
+        relative: &Path,
+        length: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        std::fs::create_dir_all(&self.download_dir)?;
+        if let Some(parent) = relative.parent() {
+            create_dirs_inside(&self.download_dir, parent)?;
+        }
+        let path = self.download_dir.join(relative);
+        let mut file = open_for_writing(&self.download_dir, &path)?;
+        // whatever was there past the end of the file isn't part of the torrent
+        if file.metadata()?.len() > length {
+            file.set_len(length)?;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+            .map_err(|e| anyhow!("Failed to write {}: {e}", path.display()))
+    }
+
+    /// Append a piece to the part file, as its index followed by its data
+    fn append_part(&self, index: usize, data: &[u8]) -> Result<(), anyhow::Error> {
+        let path = part_file_path(&self.info, &self.download_dir);
+        std::fs::create_dir_all(&self.download_dir)?;
+        let mut file = open_for_writing(&self.download_dir, &path)?;
+        let position = file.seek(SeekFrom::End(0))?;
+        file.write_all(&(index as u32).to_be_bytes())?;
+        file.write_all(data)?;
+        self.parts.lock().unwrap().insert(index, position + 4);
+        debug!("Kept boundary piece {index} in {}", path.display());
+        Ok(())
+    }
+
+    /// Find the pieces in the part file an earlier run left behind, returns their indices. They
+    /// still have to be checked, the ones that don't pass go through [`Storage::forget_part`].
+    pub fn load_part_file(&self) -> Result<Vec<usize>, anyhow::Error> {
+        let path = part_file_path(&self.info, &self.download_dir);
+        let mut parts = self.parts.lock().unwrap();
+        parts.clear();
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow!("Failed to open {}: {e}", path.display())),
+        };
+
+        let length = file.metadata()?.len();
+        let mut position = 0;
+        while position + 4 <= length {
+            let mut header = [0u8; 4];
+            file.seek(SeekFrom::Start(position))?;
+            file.read_exact(&mut header)?;
+            let index = u32::from_be_bytes(header) as usize;
+            if index >= self.info.piece_count() {
+                warn!(
+                    "{} has an invalid piece {index}, ignoring the rest",
+                    path.display()
+                );
+                break;
+            }
+            let end = position + 4 + self.info.piece_size(index) as u64;
+            if end > length {
+                warn!("{} is truncated", path.display());
+                break;
+            }
+            // a piece written again later wins
+            parts.insert(index, position + 4);
+            position = end;
+        }
+        // pieces appended later have to be found again
+        if position < length {
+            open_for_writing(&self.download_dir, &path)?.set_len(position)?;
+        }
+        Ok(parts.keys().copied().collect())
+    }
+
+    /// Stop reading piece `index` from the part file
+    pub fn forget_part(&self, index: usize) {
+        self.parts.lock().unwrap().remove(&index);
+    }
+
+    /// Change which files are written. The pieces in the part file are written into the files
+    /// that are wanted now, the part file is removed once no skipped file needs it any more.
+    pub fn set_priorities(&self, priorities: Vec<FilePriority>) -> Result<(), anyhow::Error> {
+        let mut current = self.priorities.lock().unwrap();
+        *current = priorities;
+        let parts: Vec<usize> = self.parts.lock().unwrap().keys().copied().collect();
+        if parts.is_empty() {
+            return Ok(());
+        }
+
+        let mut needed = false;
+        for index in parts {
+            let data = self.read_piece(index)?;
+            needed |= self.write_wanted(index, &data, &current)?;
+        }
+        if !needed {
+            self.parts.lock().unwrap().clear();
+            let path = part_file_path(&self.info, &self.download_dir);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => debug!("Removed {}", path.display()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the empty files and the symlinks of the wanted files and apply the BEP 47
+    /// attributes, once every wanted piece is there
+    pub fn finish(&self) -> Result<(), anyhow::Error> {
+        let priorities = self.priorities.lock().unwrap();
+        // symlinks are created last, so their targets already exist
+        let mut symlinks = Vec::new();
+        for (index, (file_info, planned)) in self.files.iter().zip(&self.paths).enumerate() {
+            let Some(relative) = planned else {
+                continue;
+            };
+            if priorities.get(index) == Some(&FilePriority::Skip) {
+                continue;
+            }
+            if file_info.is_symlink() {
+                symlinks.push((relative, file_info));
+                continue;
+            }
+            // no piece covers an empty file
+            if file_info.length == 0 {
+                self.write_at(relative, 0, 0, &[])?;
+            }
+            apply_attributes(&self.download_dir.join(relative), file_info)?;
+        }
+
+        for (relative, file_info) in symlinks {
+            if let Some(parent) = relative.parent() {
+                create_dirs_inside(&self.download_dir, parent)?;
+            }
+            let path = self.download_dir.join(relative);
+            if let Err(e) = create_symlink(&path, file_info) {
+                warn!("Not creating symlink {}: {e}", path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fill `target` from `offset` of the file at `path`, leaving what isn't there as it is
+fn read_at(path: &Path, offset: u64, target: &mut [u8]) -> Result<(), anyhow::Error> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(anyhow!("Failed to open {}: {e}", path.display())),
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    let mut read = 0;
+    while read < target.len() {
+        match file.read(&mut target[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(anyhow!("Failed to read {}: {e}", path.display())),
+        }
+    }
+    Ok(())
+}
+
+/// Where every file of the torrent lives below `download_dir`, `None` for padding files
+pub fn file_paths(info: &Info, download_dir: &Path) -> Vec<Option<PathBuf>> {
+    let name = sanitize_segment(&info.name);
+    if info.is_single_file() {
+        return vec![Some(download_dir.join(name))];
+    }
+    let base_dir = download_dir.join(name);
+    plan_paths(info)
+        .into_iter()
+        .map(|planned| planned.map(|path| base_dir.join(path)))
+        .collect()
+}
+
+/// Where the boundary pieces of a torrent with skipped files are kept
+pub fn part_file_path(info: &Info, download_dir: &Path) -> PathBuf {
+    download_dir.join(format!(".{}.parts", sanitize_segment(&info.name)))
+}
+
+fn apply_attributes(path: &Path, file_info: &FileInfo) -> Result<(), anyhow::Error> {
+    #[cfg(unix)]
+    if file_info.is_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        // same as chmod +x, but only where the file is already readable
+        let mode = permissions.mode();
+        permissions.set_mode(mode | ((mode & 0o444) >> 2));
+        std::fs::set_permissions(path, permissions)?;
+    }
+
+    // hidden files on unix are hidden by their leading dot, which is already part of the name
+    if file_info.is_hidden() {
+        debug!("{} is marked as hidden", path.display());
+    }
+
+    Ok(())
+}
+
+/// Create the symlink at `path`. The target is relative to the torrent root, so the link is only
+/// created if the target stays inside of it.
+fn create_symlink(path: &Path, file_info: &FileInfo) -> Result<(), anyhow::Error> {
+    let target = file_info
+        .symlink_path
+        .as_ref()
+        .ok_or_else(|| anyhow!("missing symlink path"))?;
+
+    if target.is_empty()
+        || target.iter().any(|segment| {
+            segment.is_empty()
+                || segment == "."
+                || segment == ".."
+                || segment.contains(['/', '\\', '\0'])
+        })
+    {
+        return Err(anyhow!("unsafe symlink target {:?}", target));
+    }
+
+    // the link lives `depth` directories below the torrent root
+    let depth = file_info.path.len().saturating_sub(1);
+    let mut relative = PathBuf::new();
+    for _ in 0..depth {
+        relative.push("..");
+    }
+    for segment in target {
+        relative.push(sanitize_segment(segment));
+    }
+
+    #[cfg(unix)]
+    {
+        if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(path)?;
+        }
+        std::os::unix::fs::symlink(&relative, path)?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    {
+        Err(anyhow!(
+            "symlinks are only supported on unix, wanted {}",
+            relative.display()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::FileTree;
+
+    #[cfg(unix)]
+    #[test]
+    fn directories_are_not_created_through_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+
+        assert!(create_dirs_inside(root.path(), Path::new("link/sub")).is_err());
+        assert!(!outside.path().join("sub").exists());
+    }
+
+    /// Files `a` and `b` of 10 bytes each in pieces of 16, the first piece straddles both
+    fn two_files() -> Info {
+        let file = |name: &str| FileInfo {
+            length: 10,
+            path: vec![name.to_string()],
+            attr: None,
+            symlink_path: None,
+            sha1: None,
+        };
+        Info {
+            name: "two".to_string(),
+            piece_length: 16,
+            pieces: vec![0; 40],
+            file_tree: FileTree::MultiFile {
+                files: vec![file("a"), file("b")],
+            },
+            private: None,
+            source: None,
+            meta_version: None,
+            v2_file_tree: None,
+        }
+    }
+
+    fn with_files(files: Vec<FileInfo>) -> Info {
+        Info {
+            file_tree: FileTree::MultiFile { files },
+            ..two_files()
+        }
+    }
+
+    fn file_at(path: &[&str]) -> FileInfo {
+        FileInfo {
+            length: 1,
+            path: path.iter().map(|s| s.to_string()).collect(),
+            attr: None,
+            symlink_path: None,
+            sha1: None,
+        }
+    }
+
+    #[test]
+    fn sanitizes_segments() {
+        let cases = [
+            ("", "_"),
+            (".", "_"),
+            ("..", "_"),
+            ("name. . ", "name"),
+            (".hidden", ".hidden"),
+            ("/etc", "_etc"),
+            ("C:", "C_"),
+            ("C:\\Windows", "C__Windows"),
+            ("\\\\server\\share", "__server_share"),
+            ("a\0b", "a_b"),
+            ("a\nb\u{7f}", "a_b_"),
+            ("a<b>c:d\"e|f?g*h", "a_b_c_d_e_f_g_h"),
+            ("CON", "_CON"),
+            ("con.txt", "_con.txt"),
+            ("Com1.tar.gz", "_Com1.tar.gz"),
+            ("lpt9", "_lpt9"),
+            ("CONSOLE", "CONSOLE"),
+            ("ünïcödé", "ünïcödé"),
+        ];
+        for (segment, sanitized) in cases {
+            assert_eq!(sanitize_segment(segment), sanitized, "{segment:?}");
+        }
+
+        let long = "a".repeat(300);
+        assert_eq!(sanitize_segment(&long), "a".repeat(MAX_NAME_LENGTH));
+        let with_extension = sanitize_segment(&format!("{long}.mkv"));
+        assert_eq!(with_extension.len(), MAX_NAME_LENGTH);
+        assert!(with_extension.ends_with("a.mkv"));
+        // cut on a char boundary
+        let wide = sanitize_segment(&"é".repeat(200));
+        assert_eq!(wide, "é".repeat(127));
+    }
+
+    #[test]
+    fn plans_paths_inside_the_torrent() {
+        let mut padding = file_at(&[".pad", "1"]);
+        padding.attr = Some("p".to_string());
+        let info = with_files(vec![
+            file_at(&["..", "..", "etc", "passwd"]),
+            file_at(&["/abs"]),
+            file_at(&[]),
+            padding,
+            file_at(&["a.txt"]),
+            file_at(&["A.TXT"]),
+            file_at(&["a.txt."]),
+            file_at(&["x"]),
+            file_at(&["X", "y"]),
+            file_at(&["dir", "f"]),
+            file_at(&["DIR", "F"]),
+            file_at(&["Dir"]),
+            file_at(&["b?"]),
+            file_at(&["b*"]),
+            file_at(&[&"c".repeat(300), "d"]),
+            file_at(&[&"c".repeat(256), "d"]),
+        ]);
+        let planned: Vec<Option<String>> = plan_paths(&info)
+            .into_iter()
+            .map(|path| path.map(|path| path.to_string_lossy().into_owned()))
+            .collect();
+        let long = "c".repeat(MAX_NAME_LENGTH);
+        let expected = [
+            Some("_/_/etc/passwd".to_string()),
+            Some("_abs".to_string()),
+            // `_` is already the directory of the first file
+            Some("_ (1)".to_string()),
+            None,
+            Some("a.txt".to_string()),
+            Some("A (1).TXT".to_string()),
+            Some("a (2).txt".to_string()),
+            Some("x".to_string()),
+            Some("X (1)/y".to_string()),
+            Some("dir/f".to_string()),
+            Some("DIR/F (1)".to_string()),
+            Some("Dir (1)".to_string()),
+            Some("b_".to_string()),
+            Some("b_ (1)".to_string()),
+            Some(format!("{long}/d")),
+            Some(format!("{long}/d (1)")),
+        ];
+        assert_eq!(planned, expected);
+        for path in planned.into_iter().flatten() {
+            let path = Path::new(&path);
+            assert!(path.is_relative());
+            assert!(path
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_))));
+        }
+    }
+
+    #[test]
+    fn boundary_pieces_go_into_the_part_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = two_files();
+        let data: Vec<u8> = (0..20).collect();
+        let storage = Storage::new(
+            &info,
+            dir.path(),
+            vec![FilePriority::Normal, FilePriority::Skip],
+        );
+        storage.write_piece(0, &data[..16]).unwrap();
+        storage.write_piece(1, &data[16..]).unwrap();
+
+        assert_eq!(
+            std::fs::read(dir.path().join("two/a")).unwrap(),
+            &data[..10]
+        );
+        assert!(!dir.path().join("two/b").exists());
+        assert_eq!(storage.read(0, 20).unwrap(), data);
+
+        // a new run finds the pieces in the part file again
+        let storage = Storage::new(
+            &info,
+            dir.path(),
+            vec![FilePriority::Normal, FilePriority::Skip],
+        );
+        let mut parts = storage.load_part_file().unwrap();
+        parts.sort();
+        assert_eq!(parts, [0, 1]);
+        assert_eq!(storage.read_piece(0).unwrap(), &data[..16]);
+
+        // once nothing is skipped they are written to their file and the part file goes
+        storage
+            .set_priorities(vec![FilePriority::Normal; 2])
+            .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("two/b")).unwrap(),
+            &data[10..]
+        );
+        assert!(!part_file_path(&info, dir.path()).exists());
+        assert_eq!(storage.read(0, 20).unwrap(), data);
+    }
+
+    #[test]
+    fn padding_takes_up_space_but_is_never_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut a = file_at(&["a"]);
+        a.length = 10;
+        let mut padding = file_at(&[".pad", "6"]);
+        padding.length = 6;
+        padding.attr = Some("p".to_string());
+        let mut b = file_at(&["b"]);
+        b.length = 10;
+        let info = with_files(vec![a, padding, b]);
+        assert_eq!(info.piece_count(), 2);
+
+        let data: Vec<u8> = (1..=20).collect();
+        let mut piece = data[..10].to_vec();
+        piece.resize(16, 0);
+        let storage = Storage::new(&info, dir.path(), vec![FilePriority::Normal; 3]);
+        storage.write_piece(0, &piece).unwrap();
+        // `b` starts with the second piece
+        storage.write_piece(1, &data[10..]).unwrap();
+        storage.finish().unwrap();
+
+        assert_eq!(
+            std::fs::read(dir.path().join("two/a")).unwrap(),
+            &data[..10]
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("two/b")).unwrap(),
+            &data[10..]
+        );
+        assert!(!dir.path().join("two/.pad").exists());
+        assert_eq!(storage.read_piece(0).unwrap(), piece);
+        assert_eq!(storage.read_piece(1).unwrap(), &data[10..]);
+        assert_eq!(file_paths(&info, dir.path())[1], None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_stay_inside_the_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = |path: &[&str], target: &[&str]| FileInfo {
+            length: 0,
+            attr: Some("l".to_string()),
+            symlink_path: Some(target.iter().map(|s| s.to_string()).collect()),
+            ..file_at(path)
+        };
+        let mut target = file_at(&["target"]);
+        target.length = 16;
+        let info = with_files(vec![
+            target,
+            link(&["sub", "inside"], &["target"]),
+            link(&["up"], &["..", "..", "etc", "passwd"]),
+            link(&["absolute"], &["/etc/passwd"]),
+            link(&["dot"], &[".", "target"]),
+            link(&["empty"], &[]),
+        ]);
+        let storage = Storage::new(&info, dir.path(), vec![FilePriority::Normal; 6]);
+        storage.write_piece(0, &[5; 16]).unwrap();
+        storage.finish().unwrap();
+
+        let root = dir.path().join("two");
+        assert_eq!(
+            std::fs::read_link(root.join("sub/inside")).unwrap(),
+            Path::new("../target")
+        );
+        assert_eq!(std::fs::read(root.join("sub/inside")).unwrap(), [5; 16]);
+        for refused in ["up", "absolute", "dot", "empty"] {
+            assert!(root.join(refused).symlink_metadata().is_err(), "{refused}");
+        }
+    }
+
+    #[test]
+    fn directories_are_created_inside() {
+        let root = tempfile::tempdir().unwrap();
+        create_dirs_inside(root.path(), Path::new("a/b")).unwrap();
+        assert!(root.path().join("a/b").is_dir());
+        // existing directories are fine
+        create_dirs_inside(root.path(), Path::new("a/b/c")).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_are_not_written_through_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let target = outside.path().join("target");
+        std::fs::write(&target, b"precious").unwrap();
+        std::os::unix::fs::symlink(&target, root.path().join("file")).unwrap();
+
+        assert!(open_for_writing(root.path(), &root.path().join("file")).is_err());
+        assert_eq!(std::fs::read(&target).unwrap(), b"precious");
+    }
+}
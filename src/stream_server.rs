@@ -0,0 +1,442 @@
+//! HTTP server that streams the files of torrents while they download.
+//!
+//! `GET /` lists the files, `GET /<info hash>/<file index>` serves one. Single byte ranges (RFC
+//! 9110 `Range: bytes=...`) are supported so media players can seek, other range requests get the
+//! whole file. Bytes that aren't downloaded yet are waited for and the pieces holding them jump
+//! the queue, see [`crate::session::TorrentReader::read`].
+//!
+//! The server only listens on loopback, but a web page can still reach it through the browser.
+//! Requests have to name the server in their `Host`, which DNS rebinding can't fake, and only
+//! media is served inline. Everything else is sent as a download so a torrent's HTML can't run
+//! with the server's origin.
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info};
+
+use crate::session::TorrentHandle;
+
+/// Longest request head we accept
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// The bytes of a file a request asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Range {
+    Whole,
+    /// First and last byte, both inclusive
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// Serve the torrents `torrents` returns until the listener fails
+pub async fn run(
+    listener: TcpListener,
+    torrents: impl Fn() -> Vec<TorrentHandle> + Send + Sync + 'static,
+) -> Result<(), anyhow::Error> {
+    let torrents = Arc::new(torrents);
+    let port = listener.local_addr()?.port();
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let torrents = Arc::clone(&torrents);
+        tokio::spawn(async move {
+            if let Err(e) = handle_http(stream, remote, port, torrents()).await {
+                error!("Stream request from {remote} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_http(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    port: u16,
+    torrents: Vec<TorrentHandle>,
+) -> Result<(), anyhow::Error> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST_SIZE {
+            anyhow::bail!("Request head too large");
+        }
+    }
+
+    let head = String::from_utf8_lossy(&request);
+    let host = header(&head, "host").unwrap_or_default();
+    if ![format!("127.0.0.1:{port}"), format!("localhost:{port}")]
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        info!("Refusing stream request from {remote} for host {host:?}");
+        return respond(
+            &mut stream,
+            "403 Forbidden",
+            &[("Content-Length", "0")],
+            b"",
+        )
+        .await;
+    }
+
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = (parts.next(), parts.next().unwrap_or_default());
+    let range_header = header(&head, "range");
+    let head_only = match method {
+        Some("GET") => false,
+        Some("HEAD") => true,
+        _ => {
+            return respond(&mut stream, "405 Method Not Allowed", &[], b"").await;
+        }
+    };
+
+    let path = target.split('?').next().unwrap_or_default();
+    if path == "/" {
+        let listing = list_files(&torrents);
+        let body: &[u8] = if head_only { b"" } else { listing.as_bytes() };
+        let length = listing.len().to_string();
+        return respond(
+            &mut stream,
+            "200 OK",
+            &[("Content-Type", "text/plain"), ("Content-Length", &length)],
+            body,
+        )
+        .await;
+    }
+
+    let Some((handle, index)) = find_file(&torrents, path) else {
+        return respond(
+            &mut stream,
+            "404 Not Found",
+            &[("Content-Length", "0")],
+            b"",
+        )
+        .await;
+    };
+    let info = &handle.torrent().info;
+    let files = info.files();
+    let file = &files[index];
+    let file_offset: u64 = files[..index].iter().map(|f| f.length as u64).sum();
+    let length = file.length as u64;
+    let content_type = content_type(file.path.last().map_or("", String::as_str));
+    let mut headers = vec![
+        ("Content-Type", content_type),
+        ("Accept-Ranges", "bytes"),
+        ("X-Content-Type-Options", "nosniff"),
+    ];
+    if !is_media(content_type) {
+        headers.push(("Content-Disposition", "attachment"));
+    }
+
+    let (first, last) = match range_header
+        .map(|value| parse_range(value, length))
+        .unwrap_or(Range::Whole)
+    {
+        Range::Unsatisfiable => {
+            let content_range = format!("bytes */{length}");
+            return respond(
+                &mut stream,
+                "416 Range Not Satisfiable",
+                &[("Content-Range", &content_range), ("Content-Length", "0")],
+                b"",
+            )
+            .await;
+        }
+        Range::Whole => {
+            let content_length = length.to_string();
+            headers.push(("Content-Length", &content_length));
+            respond(&mut stream, "200 OK", &headers, b"").await?;
+            (0, length)
+        }
+        Range::Part(first, last) => {
+            let content_length = (last - first + 1).to_string();
+            let content_range = format!("bytes {first}-{last}/{length}");
+            headers.push(("Content-Length", &content_length));
+            headers.push(("Content-Range", &content_range));
+            respond(&mut stream, "206 Partial Content", &headers, b"").await?;
+            (first, last + 1)
+        }
+    };
+    if head_only {
+        return Ok(());
+    }
+
+    info!(
+        "Streaming bytes {first}..{last} of {} to {remote}",
+        file.path.join("/")
+    );
+    // a piece at a time, so the read-ahead window moves along with the reader
+    let piece_length = info.piece_length as u64;
+    let reader = handle.reader();
+    let mut position = first;
+    while position < last {
+        let start = file_offset + position;
+        let end = (start / piece_length + 1) * piece_length;
+        let end = end.min(file_offset + last);
+        let data = reader.read(start, (end - start) as usize).await?;
+        stream.write_all(&data).await?;
+        position += data.len() as u64;
+    }
+    Ok(())
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<(), anyhow::Error> {
+    let mut head = format!("HTTP/1.1 {status}\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    Ok(())
+}
+
+/// The value of the first header called `name` in a request head
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// One line per file, its URL path, size and path in the torrent
+fn list_files(torrents: &[TorrentHandle]) -> String {
+    let mut listing = String::new();
+    for handle in torrents {
+        let info = &handle.torrent().info;
+        let info_hash = hex::encode(handle.info_hash());
+        for (index, file) in info.files().iter().enumerate() {
+            if file.is_padding() {
+                continue;
+            }
+            listing.push_str(&format!(
+                "/{info_hash}/{index}\t{}\t{}\n",
+                file.length,
+                file.path.join("/")
+            ));
+        }
+    }
+    listing
+}
+
+/// The torrent and the file index of `/<info hash>/<file index>`
+fn find_file(torrents: &[TorrentHandle], path: &str) -> Option<(TorrentHandle, usize)> {
+    let (info_hash, index) = path.trim_start_matches('/').split_once('/')?;
+    let index: usize = index.parse().ok()?;
+    let handle = torrents
+        .iter()
+        .find(|handle| hex::encode(handle.info_hash()).eq_ignore_ascii_case(info_hash))?;
+    let file = handle.torrent().info.files().into_iter().nth(index)?;
+    (!file.is_padding()).then(|| (handle.clone(), index))
+}
+
+/// Parse a `Range` header for a file of `length` bytes. Anything but a single byte range is
+/// ignored, like the RFC allows.
+fn parse_range(value: &str, length: u64) -> Range {
+    let Some(spec) = value.strip_prefix("bytes=") else {
+        return Range::Whole;
+    };
+    if spec.contains(',') {
+        return Range::Whole;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Range::Whole;
+    };
+
+    if first.is_empty() {
+        // the last `last` bytes
+        return match last.parse::<u64>() {
+            Ok(0) => Range::Unsatisfiable,
+            Ok(_) if length == 0 => Range::Unsatisfiable,
+            Ok(suffix) => Range::Part(length.saturating_sub(suffix), length - 1),
+            Err(_) => Range::Whole,
+        };
+    }
+    let Ok(first) = first.parse::<u64>() else {
+        return Range::Whole;
+    };
+    let last = match last {
+        "" => u64::MAX,
+        last => match last.parse::<u64>() {
+            Ok(last) if last >= first => last,
+            _ => return Range::Whole,
+        },
+    };
+    if first >= length {
+        return Range::Unsatisfiable;
+    }
+    Range::Part(first, last.min(length - 1))
+}
+
+/// Whether a browser plays or shows `content_type` without running anything
+fn is_media(content_type: &str) -> bool {
+    ["video/", "audio/", "image/"]
+        .iter()
+        .any(|kind| content_type.starts_with(kind))
+}
+
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map_or("", |(_, extension)| extension);
+    match extension.to_ascii_lowercase().as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{self, CreateOptions};
+    use crate::session::{Session, SessionConfig};
+    use std::io::{Read, Write};
+    use tokio::sync::watch;
+
+    #[test]
+    fn ranges() {
+        let cases = [
+            ("bytes=0-99", Range::Part(0, 99)),
+            ("bytes=100-", Range::Part(100, 999)),
+            ("bytes=900-2000", Range::Part(900, 999)),
+            ("bytes=-100", Range::Part(900, 999)),
+            ("bytes=-5000", Range::Part(0, 999)),
+            ("bytes=-0", Range::Unsatisfiable),
+            ("bytes=1000-", Range::Unsatisfiable),
+            ("bytes=1000-1001", Range::Unsatisfiable),
+            ("bytes=0-9,20-29", Range::Whole),
+            ("bytes=20-10", Range::Whole),
+            ("bytes=a-b", Range::Whole),
+            ("items=0-9", Range::Whole),
+        ];
+        for (value, range) in cases {
+            assert_eq!(parse_range(value, 1000), range, "{value}");
+        }
+        assert_eq!(parse_range("bytes=-10", 0), Range::Unsatisfiable);
+    }
+
+    #[test]
+    fn only_media_is_inline() {
+        assert!(is_media(content_type("clip.MP4")));
+        assert!(!is_media(content_type("index.html")));
+        assert!(!is_media(content_type("notes")));
+    }
+
+    /// Send a request the blocking way, like a player would, returns the head and the body
+    fn get(port: u16, host: &str, path: &str, range: &str) -> (String, Vec<u8>) {
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nRange: {range}\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let body = response.split_off(split + 4);
+        (String::from_utf8(response).unwrap(), body)
+    }
+
+    /// A web seed that answers every request with `data` once `open` is set
+    async fn web_seed(data: Vec<u8>, open: watch::Receiver<bool>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (data, mut open) = (data.clone(), open.clone());
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4096];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = open.wait_for(|open| *open).await;
+                    let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len());
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&data).await;
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn read_waits_for_the_piece() {
+        let source = tempfile::tempdir().unwrap();
+        let download = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(source.path().join("clip.mp4"), &data).unwrap();
+
+        let (open, closed) = watch::channel(false);
+        let seed_port = web_seed(data.clone(), closed).await;
+        let options = CreateOptions {
+            // nobody listens there, the web seed is all there is
+            trackers: vec![vec!["http://127.0.0.1:9/announce".to_string()]],
+            piece_length: Some(16 * 1024),
+            web_seeds: vec![format!("http://127.0.0.1:{seed_port}/clip.mp4")],
+            ..CreateOptions::default()
+        };
+        let torrent = create::create_torrent(source.path().join("clip.mp4"), &options).unwrap();
+        let session = Session::new(SessionConfig {
+            download_dir: download.path().to_path_buf(),
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let handle = session.add_torrent(torrent).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handles = vec![handle.clone()];
+        tokio::spawn(run(listener, move || handles.clone()));
+        let path = format!("/{}/0", hex::encode(handle.info_hash()));
+
+        let (head, _) = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || get(port, "attacker.example", &path, "bytes=0-9")
+        })
+        .await
+        .unwrap();
+        assert!(head.starts_with("HTTP/1.1 403"), "{head}");
+
+        let request = tokio::task::spawn_blocking(move || {
+            get(
+                port,
+                &format!("localhost:{port}"),
+                &path,
+                "bytes=20000-20099",
+            )
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(
+            !request.is_finished(),
+            "answered before the piece was there"
+        );
+        open.send_replace(true);
+
+        let (head, body) = tokio::time::timeout(Duration::from_secs(20), request)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(head.starts_with("HTTP/1.1 206"), "{head}");
+        assert!(head.contains("Content-Type: video/mp4"), "{head}");
+        assert!(!head.contains("Content-Disposition"), "{head}");
+        assert_eq!(body, &data[20_000..20_100]);
+    }
+}
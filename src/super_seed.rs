@@ -0,0 +1,164 @@
+//! Super-seeding, also known as initial seeding (BEP 16).
+//!
+//! A new torrent starts with us as the only seed. Sending a full bitfield lets every peer request
+//! whatever it likes, and the same popular pieces get uploaded over and over. A super-seed instead
+//! pretends to have nothing and offers each peer one piece at a time with a `have`, always the
+//! rarest one. The peer only gets its next piece once another peer announces the piece it was
+//! offered, proof that it passed the piece on instead of keeping it to itself. That way every byte
+//! we upload ends up multiplied by the swarm.
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+
+#[derive(Debug)]
+pub struct SuperSeed {
+    /// Peers known to have each piece, from their bitfields and `have` messages
+    availability: Vec<u32>,
+    /// How often each piece was offered, so offers spread over all pieces
+    offers: Vec<u32>,
+    /// Pieces offered to each peer, the last one is the current offer
+    offered: HashMap<SocketAddrV4, Vec<usize>>,
+}
+
+impl SuperSeed {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            availability: vec![0; piece_count],
+            offers: vec![0; piece_count],
+            offered: HashMap::new(),
+        }
+    }
+
+    /// Pick the next piece to offer `peer`, the rarest one it doesn't have. `None` if it has
+    /// everything.
+    pub fn next_offer(&mut self, peer: SocketAddrV4, peer_has: &[bool]) -> Option<usize> {
+        let offered = self.offered.entry(peer).or_default();
+        let rarity = |index: usize| (self.availability[index], self.offers[index]);
+        let candidates: Vec<usize> = (0..self.availability.len())
+            .filter(|index| !peer_has.get(*index).copied().unwrap_or(false))
+            .filter(|index| !offered.contains(index))
+            .collect();
+        let rarest = candidates.iter().map(|index| rarity(*index)).min()?;
+
+        // ties are broken randomly, so peers connecting at the same time get different pieces
+        let ties: Vec<usize> = candidates
+            .into_iter()
+            .filter(|index| rarity(*index) == rarest)
+            .collect();
+        let index = ties[rand::random_range(0..ties.len())];
+
+        self.offers[index] += 1;
+        offered.push(index);
+        Some(index)
+    }
+
+    /// The piece `peer` is waiting to spread
+    pub fn current_offer(&self, peer: &SocketAddrV4) -> Option<usize> {
+        self.offered.get(peer)?.last().copied()
+    }
+
+    /// Whether `peer` may request `index`, it only gets pieces that were offered to it
+    pub fn may_upload(&self, peer: &SocketAddrV4, index: usize) -> bool {
+        self.offered
+            .get(peer)
+            .is_some_and(|offered| offered.contains(&index))
+    }
+
+    /// `peer` announced the piece `index`. Returns the peers whose current offer was `index`, it
+    /// has now been seen spreading and they should get a new piece.
+    pub fn peer_has(&mut self, peer: SocketAddrV4, index: usize) -> Vec<SocketAddrV4> {
+        let Some(available) = self.availability.get_mut(index) else {
+            return Vec::new();
+        };
+        *available += 1;
+
+        self.offered
+            .iter()
+            .filter(|(addr, offered)| **addr != peer && offered.last() == Some(&index))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// `peer` disconnected, `peer_has` are the pieces it announced
+    pub fn peer_left(&mut self, peer: &SocketAddrV4, peer_has: &[bool]) {
+        self.offered.remove(peer);
+        for (available, has) in self.availability.iter_mut().zip(peer_has) {
+            if *has {
+                *available = available.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> SocketAddrV4 {
+        SocketAddrV4::new([10, 0, 0, last].into(), 6881)
+    }
+
+    #[test]
+    fn next_offer_waits_for_another_peer_to_announce() {
+        let (a, b) = (addr(1), addr(2));
+        let mut seed = SuperSeed::new(4);
+        let first = seed.next_offer(a, &[false; 4]).unwrap();
+        assert_eq!(seed.current_offer(&a), Some(first));
+
+        // announcing it itself proves nothing
+        assert!(seed.peer_has(a, first).is_empty());
+        let other = seed.next_offer(b, &[false; 4]).unwrap();
+        assert_ne!(other, first);
+        assert!(seed.peer_has(b, other).is_empty());
+
+        assert_eq!(seed.peer_has(b, first), [a]);
+        let mut has = [false; 4];
+        has[first] = true;
+        let second = seed.next_offer(a, &has).unwrap();
+        assert_ne!(second, first);
+        assert_eq!(seed.current_offer(&a), Some(second));
+        // an old offer spreading doesn't earn another piece
+        assert!(seed.peer_has(addr(3), first).is_empty());
+    }
+
+    #[test]
+    fn only_offered_pieces_may_be_uploaded() {
+        let (a, b) = (addr(1), addr(2));
+        let mut seed = SuperSeed::new(3);
+        assert!(!seed.may_upload(&a, 0));
+
+        let first = seed.next_offer(a, &[false; 3]).unwrap();
+        for index in 0..3 {
+            assert_eq!(seed.may_upload(&a, index), index == first);
+            assert!(!seed.may_upload(&b, index));
+        }
+        let second = seed.next_offer(a, &[false; 3]).unwrap();
+        assert!(seed.may_upload(&a, first) && seed.may_upload(&a, second));
+
+        seed.peer_left(&a, &[false; 3]);
+        assert!(!seed.may_upload(&a, first));
+    }
+
+    #[test]
+    fn offers_the_rarest_pieces() {
+        let mut seed = SuperSeed::new(4);
+        // a peer that isn't offered anything spreads pieces 0 to 2
+        let other = addr(9);
+        for index in 0..3 {
+            seed.peer_has(other, index);
+        }
+        assert_eq!(seed.next_offer(addr(1), &[false; 4]), Some(3));
+
+        // with equal availability offers spread over the pieces
+        seed.peer_has(other, 3);
+        let offers: std::collections::HashSet<_> = (2..5)
+            .map(|peer| seed.next_offer(addr(peer), &[false; 4]).unwrap())
+            .collect();
+        assert_eq!(offers, [0, 1, 2].into());
+
+        // peers that left no longer count
+        seed.peer_left(&other, &[true, true, true, false]);
+        assert_eq!(seed.availability, [0, 0, 0, 1]);
+        // nothing left to offer
+        assert_eq!(seed.next_offer(addr(5), &[true; 4]), None);
+    }
+}
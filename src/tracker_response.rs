@@ -3,7 +3,7 @@ use serde::{Deserialize, Deserializer};
 use std::fmt;
 use std::net::{Ipv4Addr, SocketAddrV4};
 
-use crate::peer_connection::Peer;
+use crate::peer_connection::{Peer, PeerSource};
 
 #[derive(Debug)]
 pub struct TrackerResponse {
@@ -99,12 +99,7 @@ impl<'de> Deserialize<'de> for TrackerResponse {
                                 .map(|chunk| {
                                     let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
                                     let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-                                    Peer {
-                                        sock_ip: SocketAddrV4::new(ip, port),
-                                        available: Vec::new(),
-                                        conn: None,
-                                        peer_choking: true,
-                                    }
+                                    Peer::new(SocketAddrV4::new(ip, port), PeerSource::Tracker)
                                 })
                                 .collect();
 
@@ -0,0 +1,792 @@
+//! Built-in tracker for private swarms, so we dont need a separate tracker daemon.
+//!
+//! Speaks both the HTTP announce/scrape protocol (BEP 3, BEP 23 for the compact peer list, BEP 48
+//! for scrape) and the UDP tracker protocol (BEP 15). Both front ends share one in-memory
+//! [`SwarmTable`], peers that stop announcing get dropped after `peer_timeout`.
+use serde::Serialize;
+use serde_bencode::value::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::udp_tracker::{
+    Action, AnnounceRequest, AnnounceResponse, ConnectRequest, ConnectResponse, Event,
+    ScrapeRequest, ScrapeResponse, ScrapeStats, TrackerError, PROTOCOL_ID,
+};
+
+/// Amount of peers handed out when the client doesnt say how many it wants
+const DEFAULT_NUM_WANT: usize = 50;
+/// Hard upper bound so one announce cant make us dump the whole swarm
+const MAX_NUM_WANT: usize = 200;
+/// BEP 15: a connection ID is valid for two minutes after it was handed out
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(120);
+/// Max size of the HTTP request head we are willing to buffer
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    pub http_port: Option<u16>,
+    pub udp_port: Option<u16>,
+    /// Seconds clients should wait between announces
+    pub interval: u32,
+    /// Peers that didnt announce for this long are removed from their swarm
+    pub peer_timeout: Duration,
+    /// If set, only these info hashes are tracked, everything else gets a failure reply
+    pub allow_list: Option<HashSet<[u8; 20]>>,
+    /// Hand out the address a peer announces (`ip`) even if the announce comes from a public
+    /// address. Otherwise only loopback and private addresses may announce one, anyone else could
+    /// point the swarm at a host that never asked for it.
+    pub trust_announced_ip: bool,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            http_port: Some(6969),
+            udp_port: Some(6969),
+            interval: 1800,
+            peer_timeout: Duration::from_secs(2 * 1800 + 60),
+            allow_list: None,
+            trust_announced_ip: false,
+        }
+    }
+}
+
+impl TrackerConfig {
+    /// The interval as it goes on the wire
+    fn wire_interval(&self) -> i32 {
+        i32::try_from(self.interval).unwrap_or(i32::MAX)
+    }
+
+    /// The address to hand out for a peer that announced from `remote`, `announced` if it said
+    /// one and may do so
+    fn peer_ip(&self, announced: Option<Ipv4Addr>, remote: IpAddr) -> Option<Ipv4Addr> {
+        let remote = match remote {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+        };
+        let trusted =
+            self.trust_announced_ip || remote.is_some_and(|ip| ip.is_loopback() || ip.is_private());
+        match announced {
+            Some(ip) if trusted => Some(ip),
+            _ => remote,
+        }
+    }
+}
+
+/// BEP 15 connection IDs. They are derived from the client's address and the time instead of
+/// kept in a table, so a flood of connect requests costs nothing to remember.
+struct ConnectionIds {
+    secret: [u8; 32],
+    started: Instant,
+}
+
+impl ConnectionIds {
+    fn new() -> Self {
+        Self {
+            secret: rand::random(),
+            started: Instant::now(),
+        }
+    }
+
+    /// IDs are handed out for the current window and accepted in the next one as well, so they
+    /// live between one and two windows
+    fn window(&self) -> u64 {
+        self.started.elapsed().as_secs() / (CONNECTION_ID_TTL.as_secs() / 2)
+    }
+
+    fn id(&self, remote: SocketAddr, window: u64) -> i64 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.secret);
+        hasher.update(window.to_be_bytes());
+        match remote.ip() {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(remote.port().to_be_bytes());
+        i64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+    }
+
+    fn issue(&self, remote: SocketAddr) -> i64 {
+        self.id(remote, self.window())
+    }
+
+    fn is_valid(&self, connection_id: i64, remote: SocketAddr) -> bool {
+        let window = self.window();
+        connection_id == self.id(remote, window)
+            || (window > 0 && connection_id == self.id(remote, window - 1))
+    }
+}
+
+/// Load an allow-list file, one hex encoded info hash per line. Empty lines and lines starting
+/// with `#` are skipped.
+pub fn load_allow_list<P: AsRef<Path>>(path: P) -> Result<HashSet<[u8; 20]>, anyhow::Error> {
+    let content = std::fs::read_to_string(path)?;
+    let mut allow_list = HashSet::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut info_hash = [0u8; 20];
+        hex::decode_to_slice(line, &mut info_hash)
+            .map_err(|e| anyhow::anyhow!("Invalid info hash '{line}' in allow-list: {e}"))?;
+        allow_list.insert(info_hash);
+    }
+
+    Ok(allow_list)
+}
+
+#[derive(Debug)]
+struct SwarmPeer {
+    addr: SocketAddrV4,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    /// keyed by peer ID so a peer that changes its port replaces the old entry
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    /// number of `completed` events we have seen
+    completed: u32,
+}
+
+impl Swarm {
+    fn seeders(&self) -> usize {
+        self.peers.values().filter(|p| p.left == 0).count()
+    }
+
+    fn leechers(&self) -> usize {
+        self.peers.len() - self.seeders()
+    }
+
+    fn stats(&self) -> ScrapeStats {
+        ScrapeStats {
+            seeders: self.seeders() as i32,
+            completed: self.completed as i32,
+            leechers: self.leechers() as i32,
+        }
+    }
+}
+
+/// Everything the tracker needs from an announce, independent of HTTP or UDP
+#[derive(Debug)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub addr: SocketAddrV4,
+    pub left: u64,
+    pub event: Event,
+    pub num_want: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct AnnounceReply {
+    pub seeders: usize,
+    pub leechers: usize,
+    /// (peer ID, address) of the other peers in the swarm
+    pub peers: Vec<([u8; 20], SocketAddrV4)>,
+}
+
+/// In-memory table of every swarm the tracker knows about
+#[derive(Debug, Default)]
+pub struct SwarmTable {
+    swarms: HashMap<[u8; 20], Swarm>,
+    allow_list: Option<HashSet<[u8; 20]>>,
+}
+
+impl SwarmTable {
+    pub fn new(allow_list: Option<HashSet<[u8; 20]>>) -> Self {
+        Self {
+            swarms: HashMap::new(),
+            allow_list,
+        }
+    }
+
+    pub fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        self.allow_list
+            .as_ref()
+            .is_none_or(|list| list.contains(info_hash))
+    }
+
+    pub fn announce(&mut self, announce: Announce) -> Result<AnnounceReply, anyhow::Error> {
+        if !self.is_allowed(&announce.info_hash) {
+            anyhow::bail!("Torrent is not allowed on this tracker");
+        }
+
+        let swarm = self.swarms.entry(announce.info_hash).or_default();
+
+        match announce.event {
+            Event::Stopped => {
+                swarm.peers.remove(&announce.peer_id);
+            }
+            event => {
+                if event == Event::Completed {
+                    swarm.completed += 1;
+                }
+                swarm.peers.insert(
+                    announce.peer_id,
+                    SwarmPeer {
+                        addr: announce.addr,
+                        left: announce.left,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        let num_want = announce
+            .num_want
+            .unwrap_or(DEFAULT_NUM_WANT)
+            .min(MAX_NUM_WANT);
+
+        // seeders dont need other seeders
+        let is_seeder = announce.left == 0;
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(id, _)| **id != announce.peer_id)
+            .filter(|(_, p)| !(is_seeder && p.left == 0))
+            .take(num_want)
+            .map(|(id, p)| (*id, p.addr))
+            .collect();
+
+        Ok(AnnounceReply {
+            seeders: swarm.seeders(),
+            leechers: swarm.leechers(),
+            peers,
+        })
+    }
+
+    /// Stats for the given info hashes, an empty list scrapes every swarm
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<([u8; 20], ScrapeStats)> {
+        if info_hashes.is_empty() {
+            return self
+                .swarms
+                .iter()
+                .map(|(hash, swarm)| (*hash, swarm.stats()))
+                .collect();
+        }
+
+        info_hashes
+            .iter()
+            .map(|hash| {
+                let stats = self
+                    .swarms
+                    .get(hash)
+                    .map(|swarm| swarm.stats())
+                    .unwrap_or_default();
+                (*hash, stats)
+            })
+            .collect()
+    }
+
+    /// Drop every peer that hasnt announced within `timeout`, and swarms that became empty
+    pub fn expire(&mut self, timeout: Duration) -> usize {
+        let mut removed = 0;
+        for swarm in self.swarms.values_mut() {
+            let before = swarm.peers.len();
+            swarm.peers.retain(|_, p| p.last_seen.elapsed() < timeout);
+            removed += before - swarm.peers.len();
+        }
+        self.swarms
+            .retain(|_, swarm| !swarm.peers.is_empty() || swarm.completed > 0);
+        removed
+    }
+}
+
+/// Run the tracker until one of the front ends fails
+pub async fn run(config: TrackerConfig) -> Result<(), anyhow::Error> {
+    let config = Arc::new(config);
+    let table = Arc::new(Mutex::new(SwarmTable::new(config.allow_list.clone())));
+    let mut tasks = tokio::task::JoinSet::new();
+
+    if let Some(port) = config.http_port {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+        info!("HTTP tracker listening on {}", listener.local_addr()?);
+        tasks.spawn(run_http(listener, table.clone(), Arc::clone(&config)));
+    }
+
+    if let Some(port) = config.udp_port {
+        let socket = UdpSocket::bind(("0.0.0.0", port)).await?;
+        info!("UDP tracker listening on {}", socket.local_addr()?);
+        tasks.spawn(run_udp(socket, table.clone(), Arc::clone(&config)));
+    }
+
+    if tasks.is_empty() {
+        anyhow::bail!("Tracker needs at least one of the HTTP or UDP ports enabled");
+    }
+
+    // expire stale peers in the background
+    let expiry_table = table.clone();
+    let peer_timeout = config.peer_timeout;
+    tasks.spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            ticker.tick().await;
+            let removed = expiry_table.lock().await.expire(peer_timeout);
+            if removed > 0 {
+                info!("Expired {removed} stale peers");
+            }
+        }
+    });
+
+    match tasks.join_next().await {
+        Some(Ok(result)) => result,
+        Some(Err(e)) => Err(anyhow::anyhow!("Tracker task panicked: {e}")),
+        None => Ok(()),
+    }
+}
+
+async fn run_http(
+    listener: TcpListener,
+    table: Arc<Mutex<SwarmTable>>,
+    config: Arc<TrackerConfig>,
+) -> Result<(), anyhow::Error> {
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let table = table.clone();
+        let config = Arc::clone(&config);
+        tokio::spawn(async move {
+            if let Err(e) = handle_http(stream, remote, table, &config).await {
+                error!("HTTP tracker request from {remote} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_http(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    table: Arc<Mutex<SwarmTable>>,
+    config: &TrackerConfig,
+) -> Result<(), anyhow::Error> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    // we only care about the request line, but read the whole head so the client doesnt get a
+    // reset while it is still sending headers
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST_SIZE {
+            anyhow::bail!("Request head too large");
+        }
+    }
+
+    let head = String::from_utf8_lossy(&request);
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next(), parts.next());
+
+    let body = match (method, target) {
+        (Some("GET"), Some(target)) => {
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            let params = parse_query(query);
+            match path.trim_end_matches('/') {
+                "/announce" | "" => http_announce(&params, remote, &table, config).await,
+                "/scrape" => http_scrape(&params, &table).await,
+                _ => {
+                    stream
+                        .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+        _ => failure("Invalid request"),
+    };
+
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    Ok(())
+}
+
+/// Split the query string into key/value pairs, values stay raw bytes since `info_hash` and
+/// `peer_id` are binary
+fn parse_query(query: &str) -> HashMap<String, Vec<Vec<u8>>> {
+    let mut params: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = String::from_utf8_lossy(&percent_decode(key)).into_owned();
+        params.entry(key).or_default().push(percent_decode(value));
+    }
+    params
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match hex::decode(&bytes[i + 1..i + 3]) {
+                Ok(decoded) if decoded.len() == 1 => {
+                    out.push(decoded[0]);
+                    i += 3;
+                }
+                _ => {
+                    out.push(b'%');
+                    i += 1;
+                }
+            },
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn param_str<'a>(params: &'a HashMap<String, Vec<Vec<u8>>>, key: &str) -> Option<&'a [u8]> {
+    params
+        .get(key)
+        .and_then(|v| v.first())
+        .map(|v| v.as_slice())
+}
+
+fn param_num<T: std::str::FromStr>(params: &HashMap<String, Vec<Vec<u8>>>, key: &str) -> Option<T> {
+    param_str(params, key)
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .and_then(|v| v.parse().ok())
+}
+
+fn param_hash(params: &HashMap<String, Vec<Vec<u8>>>, key: &str) -> Option<[u8; 20]> {
+    param_str(params, key).and_then(|v| v.try_into().ok())
+}
+
+/// Bencoded `failure reason` reply
+fn failure(reason: &str) -> Vec<u8> {
+    let mut dict = HashMap::new();
+    dict.insert(
+        b"failure reason".to_vec(),
+        Value::Bytes(reason.as_bytes().to_vec()),
+    );
+    serde_bencode::to_bytes(&Value::Dict(dict)).unwrap_or_default()
+}
+
+#[derive(Serialize)]
+struct CompactAnnounceReply {
+    interval: i32,
+    complete: usize,
+    incomplete: usize,
+    #[serde(with = "serde_bytes")]
+    peers: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct HttpPeer {
+    #[serde(rename = "peer id", with = "serde_bytes")]
+    peer_id: Vec<u8>,
+    ip: String,
+    port: u16,
+}
+
+#[derive(Serialize)]
+struct AnnounceReplyDict {
+    interval: i32,
+    complete: usize,
+    incomplete: usize,
+    peers: Vec<HttpPeer>,
+}
+
+async fn http_announce(
+    params: &HashMap<String, Vec<Vec<u8>>>,
+    remote: SocketAddr,
+    table: &Mutex<SwarmTable>,
+    config: &TrackerConfig,
+) -> Vec<u8> {
+    let Some(info_hash) = param_hash(params, "info_hash") else {
+        return failure("Missing or invalid info_hash");
+    };
+    let Some(peer_id) = param_hash(params, "peer_id") else {
+        return failure("Missing or invalid peer_id");
+    };
+    let Some(port) = param_num::<u16>(params, "port") else {
+        return failure("Missing or invalid port");
+    };
+    let event = match Event::from_http(&String::from_utf8_lossy(
+        param_str(params, "event").unwrap_or_default(),
+    )) {
+        Ok(event) => event,
+        Err(e) => return failure(&e.to_string()),
+    };
+
+    let Some(left) = param_num(params, "left") else {
+        return failure("Missing or invalid left");
+    };
+
+    // an explicit `ip` that doesn't parse is ignored like one that isn't trusted
+    let announced = param_str(params, "ip")
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .and_then(|v| v.parse::<Ipv4Addr>().ok());
+    let Some(ip) = config.peer_ip(announced, remote.ip()) else {
+        return failure("Only IPv4 peers are supported");
+    };
+
+    let announce = Announce {
+        info_hash,
+        peer_id,
+        addr: SocketAddrV4::new(ip, port),
+        left,
+        event,
+        num_want: param_num(params, "numwant"),
+    };
+
+    let reply = match table.lock().await.announce(announce) {
+        Ok(reply) => reply,
+        Err(e) => return failure(&e.to_string()),
+    };
+
+    // BEP 23: compact is the default, only fall back to the dictionary model when asked to
+    let compact = param_num::<u8>(params, "compact").unwrap_or(1) == 1;
+    let encoded = if compact {
+        let mut peers = Vec::with_capacity(reply.peers.len() * 6);
+        for (_, addr) in &reply.peers {
+            peers.extend_from_slice(&addr.ip().octets());
+            peers.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        serde_bencode::to_bytes(&CompactAnnounceReply {
+            interval: config.wire_interval(),
+            complete: reply.seeders,
+            incomplete: reply.leechers,
+            peers,
+        })
+    } else {
+        let no_peer_id = param_num::<u8>(params, "no_peer_id").unwrap_or(0) == 1;
+        serde_bencode::to_bytes(&AnnounceReplyDict {
+            interval: config.wire_interval(),
+            complete: reply.seeders,
+            incomplete: reply.leechers,
+            peers: reply
+                .peers
+                .iter()
+                .map(|(id, addr)| HttpPeer {
+                    peer_id: if no_peer_id { Vec::new() } else { id.to_vec() },
+                    ip: addr.ip().to_string(),
+                    port: addr.port(),
+                })
+                .collect(),
+        })
+    };
+
+    encoded.unwrap_or_else(|e| failure(&e.to_string()))
+}
+
+async fn http_scrape(params: &HashMap<String, Vec<Vec<u8>>>, table: &Mutex<SwarmTable>) -> Vec<u8> {
+    let info_hashes: Vec<[u8; 20]> = params
+        .get("info_hash")
+        .map(|hashes| {
+            hashes
+                .iter()
+                .filter_map(|h| h.as_slice().try_into().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let table = table.lock().await;
+    let mut files = HashMap::new();
+    for (hash, stats) in table.scrape(&info_hashes) {
+        if !table.is_allowed(&hash) {
+            continue;
+        }
+        let mut entry = HashMap::new();
+        entry.insert(b"complete".to_vec(), Value::Int(stats.seeders as i64));
+        entry.insert(b"downloaded".to_vec(), Value::Int(stats.completed as i64));
+        entry.insert(b"incomplete".to_vec(), Value::Int(stats.leechers as i64));
+        files.insert(hash.to_vec(), Value::Dict(entry));
+    }
+
+    let mut reply = HashMap::new();
+    reply.insert(b"files".to_vec(), Value::Dict(files));
+    serde_bencode::to_bytes(&Value::Dict(reply)).unwrap_or_else(|e| failure(&e.to_string()))
+}
+
+async fn run_udp(
+    socket: UdpSocket,
+    table: Arc<Mutex<SwarmTable>>,
+    config: Arc<TrackerConfig>,
+) -> Result<(), anyhow::Error> {
+    let connections = ConnectionIds::new();
+    let mut buf = vec![0u8; 2048];
+
+    loop {
+        let (len, remote) = socket.recv_from(&mut buf).await?;
+        let packet = &buf[..len];
+
+        let reply = match handle_udp(packet, remote, &table, &connections, &config).await {
+            Ok(Some(reply)) => reply,
+            Ok(None) => continue,
+            Err((transaction_id, e)) => {
+                TrackerError::new(transaction_id, &e.to_string()).serialize()
+            }
+        };
+
+        if let Err(e) = socket.send_to(&reply, remote).await {
+            error!("Failed to send UDP tracker reply to {remote}: {e}");
+        }
+    }
+}
+
+/// Returns `Ok(None)` for packets that are not worth answering, errors carry the transaction ID
+/// so the client can match the error to its request
+async fn handle_udp(
+    packet: &[u8],
+    remote: SocketAddr,
+    table: &Mutex<SwarmTable>,
+    connections: &ConnectionIds,
+    config: &TrackerConfig,
+) -> Result<Option<Vec<u8>>, (i32, anyhow::Error)> {
+    if packet.len() < 16 {
+        return Ok(None);
+    }
+
+    let connection_id = i64::from_be_bytes(packet[0..8].try_into().unwrap());
+    let action = i32::from_be_bytes(packet[8..12].try_into().unwrap());
+    let transaction_id = i32::from_be_bytes(packet[12..16].try_into().unwrap());
+
+    if connection_id == PROTOCOL_ID && action == Action::Connect as i32 {
+        ConnectRequest::parse(packet).map_err(|e| (transaction_id, e))?;
+        return Ok(Some(
+            ConnectResponse::new(transaction_id, connections.issue(remote)).serialize(),
+        ));
+    }
+
+    // every other action needs a connection ID we handed out to this address
+    if !connections.is_valid(connection_id, remote) {
+        return Err((
+            transaction_id,
+            anyhow::anyhow!("Invalid or expired connection ID"),
+        ));
+    }
+
+    match Action::from_i32(action).map_err(|e| (transaction_id, e))? {
+        Action::Announce => {
+            let request = AnnounceRequest::parse(packet).map_err(|e| (transaction_id, e))?;
+            let event = Event::from_i32(request.event).map_err(|e| (transaction_id, e))?;
+
+            let announced = (request.ip_address != 0).then(|| Ipv4Addr::from(request.ip_address));
+            let ip = config.peer_ip(announced, remote.ip()).ok_or((
+                transaction_id,
+                anyhow::anyhow!("Only IPv4 peers are supported"),
+            ))?;
+
+            let announce = Announce {
+                info_hash: request.info_hash,
+                peer_id: request.peer_id,
+                addr: SocketAddrV4::new(ip, request.port),
+                left: request.left.max(0) as u64,
+                event,
+                num_want: usize::try_from(request.num_want).ok(),
+            };
+
+            let reply = table
+                .lock()
+                .await
+                .announce(announce)
+                .map_err(|e| (transaction_id, e))?;
+
+            Ok(Some(
+                AnnounceResponse::new(
+                    transaction_id,
+                    config.wire_interval(),
+                    reply.leechers as i32,
+                    reply.seeders as i32,
+                    reply.peers.into_iter().map(|(_, addr)| addr).collect(),
+                )
+                .serialize(),
+            ))
+        }
+        Action::Scrape => {
+            let request = ScrapeRequest::parse(packet).map_err(|e| (transaction_id, e))?;
+            let table = table.lock().await;
+            // BEP 15 scrape always lists hashes explicitly, unknown ones are reported as zeros
+            let stats = request
+                .info_hashes
+                .iter()
+                .map(|hash| {
+                    if table.is_allowed(hash) {
+                        table.scrape(&[*hash])[0].1
+                    } else {
+                        ScrapeStats::default()
+                    }
+                })
+                .collect();
+            Ok(Some(ScrapeResponse::new(transaction_id, stats).serialize()))
+        }
+        Action::Connect | Action::Error => Err((
+            transaction_id,
+            anyhow::anyhow!("Unexpected action {action}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announced_ip_is_only_trusted_from_private_addresses() {
+        let config = TrackerConfig::default();
+        let announced = Some(Ipv4Addr::new(203, 0, 113, 7));
+        let public = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        assert_eq!(
+            config.peer_ip(announced, public),
+            Some(Ipv4Addr::new(198, 51, 100, 1))
+        );
+        let private = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+        assert_eq!(config.peer_ip(announced, private), announced);
+        assert_eq!(
+            config.peer_ip(announced, IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            announced
+        );
+
+        let config = TrackerConfig {
+            trust_announced_ip: true,
+            ..TrackerConfig::default()
+        };
+        assert_eq!(config.peer_ip(announced, public), announced);
+    }
+
+    #[test]
+    fn connection_ids_are_bound_to_the_address() {
+        let connections = ConnectionIds::new();
+        let remote: SocketAddr = "198.51.100.1:6881".parse().unwrap();
+        let id = connections.issue(remote);
+        assert!(connections.is_valid(id, remote));
+        assert!(!connections.is_valid(id, "198.51.100.1:6882".parse().unwrap()));
+        assert!(!connections.is_valid(id.wrapping_add(1), remote));
+    }
+
+    #[tokio::test]
+    async fn announce_without_left_fails() {
+        let table = Mutex::new(SwarmTable::default());
+        let params =
+            parse_query("info_hash=aaaaaaaaaaaaaaaaaaaa&peer_id=bbbbbbbbbbbbbbbbbbbb&port=6881");
+        let remote: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let reply = http_announce(&params, remote, &table, &TrackerConfig::default()).await;
+        assert!(String::from_utf8_lossy(&reply).contains("Missing or invalid left"));
+    }
+}
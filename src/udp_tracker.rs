@@ -27,8 +27,41 @@ impl Action {
     }
 }
 
+/// The announce event, shared between the UDP wire format and the HTTP `event` parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Event {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+impl Event {
+    pub fn from_i32(val: i32) -> Result<Self, anyhow::Error> {
+        match val {
+            0 => Ok(Event::None),
+            1 => Ok(Event::Completed),
+            2 => Ok(Event::Started),
+            3 => Ok(Event::Stopped),
+            other => Err(anyhow::anyhow!(format!("Unknown event: {}", other))),
+        }
+    }
+
+    /// HTTP trackers send the event as a string, an empty or missing one means `None`
+    pub fn from_http(val: &str) -> Result<Self, anyhow::Error> {
+        match val {
+            "" | "empty" => Ok(Event::None),
+            "completed" => Ok(Event::Completed),
+            "started" => Ok(Event::Started),
+            "stopped" => Ok(Event::Stopped),
+            other => Err(anyhow::anyhow!(format!("Unknown event: {}", other))),
+        }
+    }
+}
+
 /// BEP 15 defines the connect package as this:
-/// ```
+/// ```text
 /// 0       64-bit integer  protocol_id     0x41727101980 // magic constant
 /// 8       32-bit integer  action          0 // connect
 /// 12      32-bit integer  transaction_id
@@ -58,12 +91,32 @@ impl ConnectRequest {
         buf.put_i32(self.transaction_id); // Big-endian
         buf
     }
+
+    /// Used by the tracker server, checks the magic constant so random UDP noise is rejected
+    pub fn parse(mut src: &[u8]) -> Result<Self, anyhow::Error> {
+        if src.len() < 16 {
+            return Err(anyhow::anyhow!("Packet too short for ConnectRequest"));
+        }
+
+        let protocol_id = src.get_i64();
+        if protocol_id != PROTOCOL_ID {
+            return Err(anyhow::anyhow!("Invalid protocol id: {:#x}", protocol_id));
+        }
+        let action = src.get_i32();
+        let transaction_id = src.get_i32();
+
+        Ok(Self {
+            protocol_id,
+            action,
+            transaction_id,
+        })
+    }
 }
 
 /// 2. Tracker response
 ///    Note the connection and transaction_id for later use
 ///
-/// ```
+/// ```text
 ///Offset  Size            Name            Value
 /// 0       32-bit integer  action          0 // connect
 /// 4       32-bit integer  transaction_id
@@ -79,6 +132,22 @@ pub struct ConnectResponse {
 }
 
 impl ConnectResponse {
+    pub fn new(transaction_id: i32, connection_id: i64) -> Self {
+        Self {
+            action: Action::Connect,
+            transaction_id,
+            connection_id,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16);
+        buf.put_i32(self.action as i32);
+        buf.put_i32(self.transaction_id);
+        buf.put_i64(self.connection_id);
+        buf
+    }
+
     pub fn parse(mut src: &[u8]) -> Result<Self, anyhow::Error> {
         if src.len() < 16 {
             return Err(anyhow::anyhow!("Packet too short for ConnectResponse"));
@@ -96,7 +165,7 @@ impl ConnectResponse {
     }
 }
 
-/// ```
+/// ```text
 /// Offset  Size    Name    Value
 /// 0       64-bit integer  connection_id
 /// 8       32-bit integer  action          1 // announce
@@ -115,25 +184,25 @@ impl ConnectResponse {
 /// ```
 #[derive(Debug)]
 pub struct AnnounceRequest {
-    connection_id: i64,
+    pub connection_id: i64,
     action: i32,
-    transaction_id: i32,
-    info_hash: [u8; 20],
-    peer_id: [u8; 20],
-    downloaded: i64,
-    left: i64,
-    uploaded: i64,
+    pub transaction_id: i32,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub downloaded: i64,
+    pub left: i64,
+    pub uploaded: i64,
     /// The event, one of
     /// none = 0
     /// completed = 1
     /// started = 2
     /// stopped = 3
-    event: i32,
-    ip_address: u32,
+    pub event: i32,
+    pub ip_address: u32,
     /// A unique key that is randomized by the client.
     key: u32,
-    num_want: i32,
-    port: u16,
+    pub num_want: i32,
+    pub port: u16,
 }
 
 impl AnnounceRequest {
@@ -181,9 +250,39 @@ impl AnnounceRequest {
         buf.put_u16(self.port);
         buf
     }
+
+    pub fn parse(mut src: &[u8]) -> Result<Self, anyhow::Error> {
+        if src.len() < 98 {
+            return Err(anyhow::anyhow!("Packet too short for AnnounceRequest"));
+        }
+
+        let connection_id = src.get_i64();
+        let action = src.get_i32();
+        let transaction_id = src.get_i32();
+        let mut info_hash = [0u8; 20];
+        src.copy_to_slice(&mut info_hash);
+        let mut peer_id = [0u8; 20];
+        src.copy_to_slice(&mut peer_id);
+
+        Ok(Self {
+            connection_id,
+            action,
+            transaction_id,
+            info_hash,
+            peer_id,
+            downloaded: src.get_i64(),
+            left: src.get_i64(),
+            uploaded: src.get_i64(),
+            event: src.get_i32(),
+            ip_address: src.get_u32(),
+            key: src.get_u32(),
+            num_want: src.get_i32(),
+            port: src.get_u16(),
+        })
+    }
 }
 
-/// ```
+/// ```text
 /// Offset      Size            Name            Value
 /// 0           32-bit integer  action          1 // announce
 /// 4           32-bit integer  transaction_id
@@ -207,6 +306,37 @@ pub struct AnnounceResponse {
 }
 
 impl AnnounceResponse {
+    pub fn new(
+        transaction_id: i32,
+        interval: i32,
+        leechers: i32,
+        seeders: i32,
+        peers: Vec<SocketAddrV4>,
+    ) -> Self {
+        Self {
+            action: Action::Announce,
+            transaction_id,
+            interval,
+            leechers,
+            seeders,
+            peers,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(20 + 6 * self.peers.len());
+        buf.put_i32(self.action as i32);
+        buf.put_i32(self.transaction_id);
+        buf.put_i32(self.interval);
+        buf.put_i32(self.leechers);
+        buf.put_i32(self.seeders);
+        for peer in &self.peers {
+            buf.put_slice(&peer.ip().octets());
+            buf.put_u16(peer.port());
+        }
+        buf
+    }
+
     pub fn parse(mut src: &[u8]) -> Result<Self, anyhow::Error> {
         if src.len() < 20 {
             return Err(anyhow::anyhow!(
@@ -253,6 +383,22 @@ pub struct TrackerError {
 }
 
 impl TrackerError {
+    pub fn new(transaction_id: i32, error_string: &str) -> Self {
+        Self {
+            action: Action::Error,
+            transaction_id,
+            error_string: error_string.to_string(),
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(8 + self.error_string.len());
+        buf.put_i32(self.action as i32);
+        buf.put_i32(self.transaction_id);
+        buf.put_slice(self.error_string.as_bytes());
+        buf
+    }
+
     pub fn parse(mut src: &[u8]) -> Result<Self, anyhow::Error> {
         if src.len() < 8 {
             return Err(anyhow::anyhow!("Packet too short for TrackerError"));
@@ -269,3 +415,144 @@ impl TrackerError {
         })
     }
 }
+
+/// ```text
+/// Offset          Size            Name            Value
+/// 0               64-bit integer  connection_id
+/// 8               32-bit integer  action          2 // scrape
+/// 12              32-bit integer  transaction_id
+/// 16 + 20 * n     20-byte string  info_hash
+/// 16 + 20 * N
+/// ```
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct ScrapeRequest {
+    pub connection_id: i64,
+    action: i32,
+    pub transaction_id: i32,
+    pub info_hashes: Vec<[u8; 20]>,
+}
+
+impl ScrapeRequest {
+    pub fn new(connection_id: i64, transaction_id: i32, info_hashes: Vec<[u8; 20]>) -> Self {
+        Self {
+            connection_id,
+            action: Action::Scrape as i32,
+            transaction_id,
+            info_hashes,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16 + 20 * self.info_hashes.len());
+        buf.put_i64(self.connection_id);
+        buf.put_i32(self.action);
+        buf.put_i32(self.transaction_id);
+        for info_hash in &self.info_hashes {
+            buf.put_slice(info_hash);
+        }
+        buf
+    }
+
+    pub fn parse(mut src: &[u8]) -> Result<Self, anyhow::Error> {
+        if src.len() < 16 {
+            return Err(anyhow::anyhow!("Packet too short for ScrapeRequest"));
+        }
+
+        let connection_id = src.get_i64();
+        let action = src.get_i32();
+        let transaction_id = src.get_i32();
+
+        if !src.remaining().is_multiple_of(20) {
+            return Err(anyhow::anyhow!("Invalid payload size for info hash list"));
+        }
+
+        let mut info_hashes = Vec::with_capacity(src.remaining() / 20);
+        while src.has_remaining() {
+            let mut info_hash = [0u8; 20];
+            src.copy_to_slice(&mut info_hash);
+            info_hashes.push(info_hash);
+        }
+
+        Ok(Self {
+            connection_id,
+            action,
+            transaction_id,
+            info_hashes,
+        })
+    }
+}
+
+/// One entry per requested info hash, in the same order as the request
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScrapeStats {
+    pub seeders: i32,
+    pub completed: i32,
+    pub leechers: i32,
+}
+
+/// ```text
+/// Offset      Size            Name            Value
+/// 0           32-bit integer  action          2 // scrape
+/// 4           32-bit integer  transaction_id
+/// 8 + 12 * n  32-bit integer  seeders
+/// 12 + 12 * n 32-bit integer  completed
+/// 16 + 12 * n 32-bit integer  leechers
+/// 8 + 12 * N
+/// ```
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct ScrapeResponse {
+    action: Action,
+    pub transaction_id: i32,
+    pub stats: Vec<ScrapeStats>,
+}
+
+impl ScrapeResponse {
+    pub fn new(transaction_id: i32, stats: Vec<ScrapeStats>) -> Self {
+        Self {
+            action: Action::Scrape,
+            transaction_id,
+            stats,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(8 + 12 * self.stats.len());
+        buf.put_i32(self.action as i32);
+        buf.put_i32(self.transaction_id);
+        for stat in &self.stats {
+            buf.put_i32(stat.seeders);
+            buf.put_i32(stat.completed);
+            buf.put_i32(stat.leechers);
+        }
+        buf
+    }
+
+    pub fn parse(mut src: &[u8]) -> Result<Self, anyhow::Error> {
+        if src.len() < 8 {
+            return Err(anyhow::anyhow!("Packet too short for ScrapeResponse"));
+        }
+
+        let action = Action::from_i32(src.get_i32())?;
+        if action != Action::Scrape {
+            anyhow::bail!("Expected a scrape response, got {action:?}");
+        }
+        let transaction_id = src.get_i32();
+
+        let mut stats = Vec::with_capacity(src.remaining() / 12);
+        while src.remaining() >= 12 {
+            stats.push(ScrapeStats {
+                seeders: src.get_i32(),
+                completed: src.get_i32(),
+                leechers: src.get_i32(),
+            });
+        }
+
+        Ok(Self {
+            action,
+            transaction_id,
+            stats,
+        })
+    }
+}
@@ -0,0 +1,751 @@
+//! Web seeds, servers that have every piece of a torrent and are treated as virtual peers.
+//!
+//! * `url-list` (BEP 19): HTTP and FTP servers hosting the content as plain files. A piece is
+//!   fetched with one ranged request per file it overlaps.
+//! * `httpseeds` (BEP 17): scripts that serve pieces by index, queried with
+//!   `?info_hash=&piece=&ranges=`. `ranges` is only sent for pieces that are partly padding,
+//!   which is all zeros and never asked for.
+//!
+//! Pieces are checked exactly like a piece from a peer. Mirrors that fail are retried with an
+//! exponential backoff and dropped after too many failures in a row.
+use anyhow::anyhow;
+use serde_bytes::ByteBuf;
+use std::{collections::HashMap, fmt::Display, ops::Range, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tracing::{info, warn};
+use url::{form_urlencoded, Url};
+
+use crate::parser::{calculate_info_hash_bytes, FileInfo, Info, Torrent};
+use crate::peer_connection::{needed_piece_layer, verify_piece};
+use crate::rate_limit::Bandwidth;
+
+/// First retry delay after a failure, doubled for every failure in a row
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// Consecutive failures after which a mirror is given up on
+const MAX_FAILURES: u32 = 6;
+/// Upper bound for a single ranged request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How pieces are requested from a web seed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedProtocol {
+    /// BEP 19, byte ranges of the plain files
+    UrlList,
+    /// BEP 17, whole pieces from a script, only works for swarms with a v1 info hash
+    HttpSeed { info_hash: [u8; 20] },
+}
+
+#[derive(Debug)]
+pub struct WebSeed {
+    pub url: String,
+    pub protocol: SeedProtocol,
+    /// Limiters every transfer from this seed goes through
+    pub bandwidth: Bandwidth,
+    client: reqwest::Client,
+    /// Failures since the last piece that was downloaded successfully
+    failures: u32,
+    /// Delay the server asked for with `Retry-After`
+    retry_after: Option<Duration>,
+}
+
+impl Display for WebSeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.protocol {
+            SeedProtocol::UrlList => write!(f, "web seed {}", self.url),
+            SeedProtocol::HttpSeed { .. } => write!(f, "HTTP seed {}", self.url),
+        }
+    }
+}
+
+impl WebSeed {
+    pub fn new(url: &str, protocol: SeedProtocol) -> Self {
+        Self {
+            url: url.to_string(),
+            protocol,
+            bandwidth: Bandwidth::default(),
+            client: reqwest::Client::new(),
+            failures: 0,
+            retry_after: None,
+        }
+    }
+
+    /// URL of one file of the torrent. For single file torrents the URL is the file itself, unless
+    /// it ends in a slash. For multi file torrents it names the directory containing the torrent
+    /// root.
+    fn file_url(&self, info_dict: &Info, file: &FileInfo) -> Result<Url, anyhow::Error> {
+        let mut url = Url::parse(&self.url)?;
+        if info_dict.is_single_file() && !self.url.ends_with('/') {
+            return Ok(url);
+        }
+
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| anyhow!("{} can not be a base URL", self.url))?;
+            segments.pop_if_empty().push(&info_dict.name);
+            if !info_dict.is_single_file() {
+                segments.extend(&file.path);
+            }
+        }
+        Ok(url)
+    }
+
+    /// Download and verify the piece at `index`. `piece_layers` are the verified BEP 52 piece
+    /// layers, web seeds can't hand out hashes so v2 only pieces need their layer to be known.
+    pub async fn get_piece(
+        &mut self,
+        info_dict: &Info,
+        piece_layers: &Mutex<HashMap<ByteBuf, ByteBuf>>,
+        index: usize,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let piece = match self.protocol {
+            SeedProtocol::UrlList => self.fetch_from_files(info_dict, index).await?,
+            SeedProtocol::HttpSeed { info_hash } => {
+                let len = info_dict.piece_size(index);
+                let ranges = wanted_ranges(info_dict, index);
+                tokio::time::timeout(
+                    REQUEST_TIMEOUT,
+                    self.fetch_from_script(&info_hash, index, len, &ranges),
+                )
+                .await
+                .map_err(|_| anyhow!("Timed out fetching piece {index} from {}", self.url))??
+            }
+        };
+
+        let layer = match needed_piece_layer(info_dict, index)? {
+            Some((pieces_root, _)) => Some(
+                piece_layers
+                    .lock()
+                    .await
+                    .get(&ByteBuf::from(pieces_root.to_vec()))
+                    .cloned()
+                    .ok_or_else(|| anyhow!("No piece layer known to check piece {index}"))?
+                    .into_vec(),
+            ),
+            None => None,
+        };
+
+        verify_piece(info_dict, index, &piece, layer.as_deref())?;
+        Ok(piece)
+    }
+
+    /// Assemble the piece at `index` from ranges of the files it overlaps
+    async fn fetch_from_files(
+        &mut self,
+        info_dict: &Info,
+        index: usize,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let piece_start = index * info_dict.piece_length;
+        let piece_end = piece_start + info_dict.piece_size(index);
+        // padding files are never requested, they are all zeros
+        let mut piece = vec![0u8; piece_end - piece_start];
+
+        let mut file_start = 0;
+        for file in info_dict.files() {
+            let file_end = file_start + file.length;
+            let start = piece_start.max(file_start);
+            let end = piece_end.min(file_end);
+
+            if start < end && !file.is_padding() && !file.is_symlink() {
+                let url = self.file_url(info_dict, &file)?;
+                let data = self
+                    .fetch_range(&url, start - file_start, end - start)
+                    .await?;
+                piece[start - piece_start..end - piece_start].copy_from_slice(&data);
+            }
+
+            file_start = file_end;
+            if file_start >= piece_end {
+                break;
+            }
+        }
+
+        Ok(piece)
+    }
+
+    /// Ask a BEP 17 script for the piece at `index`, `len` bytes long. With `ranges` only those
+    /// parts of the piece are requested and the rest is left zero, otherwise the whole piece. A
+    /// busy script answers with 503 and the number of seconds to wait as the body.
+    async fn fetch_from_script(
+        &mut self,
+        info_hash: &[u8; 20],
+        index: usize,
+        len: usize,
+        ranges: &[Range<usize>],
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let mut url = format!(
+            "{}{}info_hash={}&piece={}",
+            self.url,
+            separator,
+            form_urlencoded::byte_serialize(info_hash).collect::<String>(),
+            index
+        );
+        // the ends of the ranges are inclusive
+        if !ranges.is_empty() {
+            let ranges: Vec<String> = ranges
+                .iter()
+                .map(|range| format!("{}-{}", range.start, range.end - 1))
+                .collect();
+            url = format!("{url}&ranges={}", ranges.join(","));
+        }
+        let expected = if ranges.is_empty() {
+            len
+        } else {
+            ranges.iter().map(|range| range.len()).sum()
+        };
+        info!("Fetching piece {index} from {url}");
+        self.bandwidth.download.acquire(expected).await;
+
+        let response = self.client.get(&url).send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+
+        match status {
+            reqwest::StatusCode::OK if body.len() == expected && ranges.is_empty() => {
+                Ok(body.to_vec())
+            }
+            // the ranges come back one after the other
+            reqwest::StatusCode::OK if body.len() == expected => {
+                let mut piece = vec![0u8; len];
+                let mut offset = 0;
+                for range in ranges {
+                    piece[range.clone()].copy_from_slice(&body[offset..offset + range.len()]);
+                    offset += range.len();
+                }
+                Ok(piece)
+            }
+            reqwest::StatusCode::OK => Err(anyhow!(
+                "{} returned {} bytes for piece {index} instead of {expected}",
+                self.url,
+                body.len()
+            )),
+            reqwest::StatusCode::SERVICE_UNAVAILABLE => {
+                self.retry_after = std::str::from_utf8(&body)
+                    .ok()
+                    .and_then(|seconds| seconds.trim().parse().ok())
+                    .map(Duration::from_secs);
+                Err(anyhow!(
+                    "{} is busy, retry after {:?}",
+                    self.url,
+                    self.retry_after
+                ))
+            }
+            status => Err(anyhow!("{} answered with {status}", self.url)),
+        }
+    }
+
+    async fn fetch_range(
+        &mut self,
+        url: &Url,
+        start: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        info!("Fetching {len} bytes at {start} from {url}");
+        self.bandwidth.download.acquire(len).await;
+        let data = match url.scheme() {
+            "http" | "https" => {
+                tokio::time::timeout(REQUEST_TIMEOUT, self.http_range(url, start, len)).await
+            }
+            "ftp" => tokio::time::timeout(REQUEST_TIMEOUT, ftp_range(url, start, len)).await,
+            scheme => return Err(anyhow!("Unsupported web seed scheme {scheme}")),
+        }
+        .map_err(|_| anyhow!("Timed out fetching {url}"))??;
+
+        if data.len() != len {
+            return Err(anyhow!(
+                "{url} returned {} bytes instead of {len}",
+                data.len()
+            ));
+        }
+        Ok(data)
+    }
+
+    async fn http_range(
+        &mut self,
+        url: &Url,
+        start: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let mut response = self
+            .client
+            .get(url.clone())
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", start, start + len - 1),
+            )
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+            || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        {
+            self.retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs);
+        }
+
+        match status {
+            reqwest::StatusCode::PARTIAL_CONTENT => Ok(response.bytes().await?.to_vec()),
+            // the server ignored the range and sends the whole file, keep the part that is wanted
+            // and hang up after it rather than buffering a file that may be gigabytes
+            reqwest::StatusCode::OK => {
+                let mut data = Vec::with_capacity(len);
+                let mut offset = 0;
+                while data.len() < len {
+                    let Some(chunk) = response.chunk().await? else {
+                        return Err(anyhow!("{url} is shorter than expected"));
+                    };
+                    let chunk_start = offset;
+                    offset += chunk.len();
+                    if offset > start {
+                        let from = start.saturating_sub(chunk_start);
+                        let to = (start + len - chunk_start).min(chunk.len());
+                        data.extend_from_slice(&chunk[from..to]);
+                    }
+                }
+                Ok(data)
+            }
+            status => Err(anyhow!("{url} answered with {status}")),
+        }
+    }
+
+    /// Reset the backoff after a successful piece
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.retry_after = None;
+    }
+
+    /// Record a failure and return how long to wait before the next request, `None` if the
+    /// mirror failed too often and should be dropped
+    pub fn backoff(&mut self) -> Option<Duration> {
+        self.failures += 1;
+        if self.failures > MAX_FAILURES {
+            return None;
+        }
+
+        let delay = self
+            .retry_after
+            .take()
+            .unwrap_or_else(|| BASE_BACKOFF * 2u32.pow(self.failures - 1));
+        Some(delay.min(MAX_BACKOFF))
+    }
+}
+
+/// Read one (possibly multi line) FTP reply and return its code
+async fn ftp_reply(control: &mut BufReader<TcpStream>) -> Result<(u16, String), anyhow::Error> {
+    let mut line = String::new();
+    if control.read_line(&mut line).await? == 0 {
+        return Err(anyhow!("FTP server closed the connection"));
+    }
+    let code: u16 = line
+        .get(..3)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow!("Malformed FTP reply {line:?}"))?;
+
+    // multi line replies start with `123-` and end with `123 `
+    if line.as_bytes().get(3) == Some(&b'-') {
+        let end = format!("{code} ");
+        loop {
+            let mut next = String::new();
+            if control.read_line(&mut next).await? == 0 {
+                return Err(anyhow!("FTP server closed the connection"));
+            }
+            if next.starts_with(&end) {
+                break;
+            }
+        }
+    }
+    Ok((code, line.trim_end().to_string()))
+}
+
+async fn ftp_command(
+    control: &mut BufReader<TcpStream>,
+    command: &str,
+    expected: &[u16],
+) -> Result<String, anyhow::Error> {
+    control
+        .get_mut()
+        .write_all(format!("{command}\r\n").as_bytes())
+        .await?;
+    let (code, line) = ftp_reply(control).await?;
+    if !expected.contains(&code) {
+        let verb = command.split(' ').next().unwrap_or_default();
+        return Err(anyhow!("FTP {verb} failed: {line}"));
+    }
+    Ok(line)
+}
+
+/// Fetch `len` bytes at `start` of the file at `url` over passive mode FTP
+async fn ftp_range(url: &Url, start: usize, len: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let host = url.host_str().ok_or_else(|| anyhow!("{url} has no host"))?;
+    let port = url.port().unwrap_or(21);
+    let mut control = BufReader::new(TcpStream::connect((host, port)).await?);
+
+    let (code, line) = ftp_reply(&mut control).await?;
+    if code != 220 {
+        return Err(anyhow!("FTP server refused the connection: {line}"));
+    }
+
+    let user = match url.username() {
+        "" => "anonymous".to_string(),
+        user => ftp_argument(percent_decode(user))?,
+    };
+    let password = ftp_argument(percent_decode(url.password().unwrap_or("anonymous@")))?;
+    let line = ftp_command(&mut control, &format!("USER {user}"), &[230, 331]).await?;
+    if line.starts_with("331") {
+        ftp_command(&mut control, &format!("PASS {password}"), &[230, 202]).await?;
+    }
+    ftp_command(&mut control, "TYPE I", &[200]).await?;
+
+    // 227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)
+    let line = ftp_command(&mut control, "PASV", &[227]).await?;
+    let numbers: Vec<u16> = line
+        .split(['(', ')'])
+        .nth(1)
+        .ok_or_else(|| anyhow!("Malformed PASV reply {line:?}"))?
+        .split(',')
+        .map(|n| n.trim().parse())
+        .collect::<Result<_, _>>()?;
+    let [_, _, _, _, p1, p2] = numbers[..] else {
+        return Err(anyhow!("Malformed PASV reply {line:?}"));
+    };
+    // the address in the reply is often wrong behind NAT, the control connection's host is not
+    let mut data = TcpStream::connect((host, p1 * 256 + p2)).await?;
+
+    ftp_command(&mut control, &format!("REST {start}"), &[350]).await?;
+    // the path comes from the torrent, escaped line breaks in it must not end the command
+    let path = ftp_argument(percent_decode(url.path()))?;
+    ftp_command(&mut control, &format!("RETR {path}"), &[125, 150]).await?;
+
+    let mut buf = vec![0u8; len];
+    data.read_exact(&mut buf).await?;
+    drop(data);
+
+    // the transfer was cut short on purpose, the server's reply to that doesn't matter
+    let _ = control.get_mut().write_all(b"QUIT\r\n").await;
+    Ok(buf)
+}
+
+/// The parts of the piece at `index` that aren't padding, relative to the piece. Empty if that is
+/// the whole piece.
+fn wanted_ranges(info_dict: &Info, index: usize) -> Vec<Range<usize>> {
+    let piece_start = index * info_dict.piece_length;
+    let piece_end = piece_start + info_dict.piece_size(index);
+
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut file_start = 0;
+    for file in info_dict.files() {
+        let file_end = file_start + file.length;
+        let start = piece_start.max(file_start);
+        let end = piece_end.min(file_end);
+        if start < end && !file.is_padding() {
+            let range = start - piece_start..end - piece_start;
+            // neighbouring files make one range
+            match ranges.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => ranges.push(range),
+            }
+        }
+        file_start = file_end;
+        if file_start >= piece_end {
+            break;
+        }
+    }
+
+    if ranges.len() == 1 && ranges[0] == (0..piece_end - piece_start) {
+        ranges.clear();
+    }
+    ranges
+}
+
+/// Refuse an argument that would end the FTP command early and smuggle in another one
+fn ftp_argument(value: String) -> Result<String, anyhow::Error> {
+    if value.contains(['\r', '\n', '\0']) {
+        return Err(anyhow!(
+            "FTP argument {value:?} contains a line break or NUL"
+        ));
+    }
+    Ok(value)
+}
+
+/// Decode `%XX` escapes of a URL component
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match hex::decode(bytes.get(i + 1..i + 3).unwrap_or_default()) {
+            Ok(decoded) if bytes[i] == b'%' && decoded.len() == 1 => {
+                out.push(decoded[0]);
+                i += 3;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Web seeds from the metainfo, anything that isn't HTTP or FTP is skipped
+pub fn from_torrent(torrent: &Torrent) -> Vec<WebSeed> {
+    let supported = |url: &String, schemes: &[&str]| match Url::parse(url) {
+        Ok(parsed) if schemes.contains(&parsed.scheme()) => true,
+        _ => {
+            warn!("Ignoring unsupported web seed {url}");
+            false
+        }
+    };
+
+    let mut seeds: Vec<WebSeed> = torrent
+        .url_list
+        .iter()
+        .filter(|url| supported(url, &["http", "https", "ftp"]))
+        .map(|url| WebSeed::new(url, SeedProtocol::UrlList))
+        .collect();
+
+    if !torrent.http_seeds.is_empty() {
+        // the script protocol only knows v1 info hashes and piece hashes
+        match calculate_info_hash_bytes(&torrent.info) {
+            Ok(info_hash) if torrent.info.is_v1() => seeds.extend(
+                torrent
+                    .http_seeds
+                    .iter()
+                    .filter(|url| supported(url, &["http", "https"]))
+                    .map(|url| WebSeed::new(url, SeedProtocol::HttpSeed { info_hash })),
+            ),
+            _ => warn!("Ignoring HTTP seeds of a torrent without v1 piece hashes"),
+        }
+    }
+
+    seeds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    /// Answer one HTTP request with `response`, the request head is sent back
+    async fn http_server(response: Vec<u8>) -> (Url, oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/file", listener.local_addr().unwrap())).unwrap();
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                head.extend_from_slice(&buf[..n]);
+            }
+            tx.send(String::from_utf8_lossy(&head).into_owned()).ok();
+            stream.write_all(&response).await.unwrap();
+        });
+        (url, rx)
+    }
+
+    fn reply(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut reply = format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n{headers}\r\n",
+            body.len()
+        )
+        .into_bytes();
+        reply.extend_from_slice(body);
+        reply
+    }
+
+    fn file() -> Vec<u8> {
+        (0..100u8).collect()
+    }
+
+    #[tokio::test]
+    async fn http_range_request() {
+        let (url, head) = http_server(reply("206 Partial Content", "", &file()[10..15])).await;
+        let mut seed = WebSeed::new(url.as_str(), SeedProtocol::UrlList);
+
+        assert_eq!(
+            seed.fetch_range(&url, 10, 5).await.unwrap(),
+            &file()[10..15]
+        );
+        assert!(head
+            .await
+            .unwrap()
+            .to_lowercase()
+            .contains("range: bytes=10-14"));
+    }
+
+    #[tokio::test]
+    async fn http_range_ignored_by_server() {
+        let (url, _) = http_server(reply("200 OK", "", &file())).await;
+        let mut seed = WebSeed::new(url.as_str(), SeedProtocol::UrlList);
+
+        assert_eq!(
+            seed.fetch_range(&url, 40, 20).await.unwrap(),
+            &file()[40..60]
+        );
+    }
+
+    #[tokio::test]
+    async fn http_range_beyond_end_of_file() {
+        let (url, _) = http_server(reply("200 OK", "", &file())).await;
+        let mut seed = WebSeed::new(url.as_str(), SeedProtocol::UrlList);
+
+        assert!(seed.fetch_range(&url, 90, 20).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn busy_mirror_sets_the_backoff() {
+        let (url, _) =
+            http_server(reply("503 Service Unavailable", "Retry-After: 7\r\n", b"")).await;
+        let mut seed = WebSeed::new(url.as_str(), SeedProtocol::UrlList);
+
+        assert!(seed.fetch_range(&url, 0, 5).await.is_err());
+        assert_eq!(seed.backoff(), Some(Duration::from_secs(7)));
+        // the next failure without a Retry-After falls back to the exponential delay
+        assert_eq!(seed.backoff(), Some(BASE_BACKOFF * 2));
+    }
+
+    #[tokio::test]
+    async fn busy_http_seed_script() {
+        let (url, head) = http_server(reply("503 Service Unavailable", "", b"30")).await;
+        let mut seed = WebSeed::new(url.as_str(), SeedProtocol::HttpSeed { info_hash: [1; 20] });
+
+        assert!(seed.fetch_from_script(&[1; 20], 3, 16, &[]).await.is_err());
+        assert_eq!(seed.backoff(), Some(Duration::from_secs(30)));
+        assert!(head.await.unwrap().contains("&piece=3 "));
+    }
+
+    #[tokio::test]
+    async fn http_seed_partial_piece() {
+        let (url, head) = http_server(reply("200 OK", "", &[7; 6])).await;
+        let mut seed = WebSeed::new(url.as_str(), SeedProtocol::HttpSeed { info_hash: [1; 20] });
+
+        let piece = seed
+            .fetch_from_script(&[1; 20], 0, 10, &[0..2, 6..10])
+            .await
+            .unwrap();
+        assert_eq!(piece, [7, 7, 0, 0, 0, 0, 7, 7, 7, 7]);
+        assert!(head.await.unwrap().contains("&ranges=0-1,6-9 "));
+    }
+
+    #[test]
+    fn backoff_gives_up() {
+        let mut seed = WebSeed::new("http://127.0.0.1/", SeedProtocol::UrlList);
+        for failure in 0..MAX_FAILURES {
+            assert_eq!(seed.backoff(), Some(BASE_BACKOFF * 2u32.pow(failure)));
+        }
+        assert_eq!(seed.backoff(), None);
+
+        seed.succeeded();
+        assert_eq!(seed.backoff(), Some(BASE_BACKOFF));
+    }
+
+    /// A passive mode FTP server serving `content` for every file, the commands it got are
+    /// recorded
+    async fn ftp_server(content: Vec<u8>) -> (u16, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let commands = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&commands);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut control = BufReader::new(stream);
+            control.get_mut().write_all(b"220 ready\r\n").await.unwrap();
+            let mut data_listener = None;
+            let mut offset = 0;
+            loop {
+                let mut line = String::new();
+                if control.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                recorded.lock().unwrap().push(line.clone());
+                let reply = match line.split(' ').next().unwrap() {
+                    "USER" => "331 password please".to_string(),
+                    "PASS" => "230 logged in".to_string(),
+                    "TYPE" => "200 binary".to_string(),
+                    "PASV" => {
+                        let data = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                        let port = data.local_addr().unwrap().port();
+                        data_listener = Some(data);
+                        format!(
+                            "227 Entering Passive Mode (127,0,0,1,{},{})",
+                            port / 256,
+                            port % 256
+                        )
+                    }
+                    "REST" => {
+                        offset = line[5..].parse().unwrap();
+                        "350 restarting".to_string()
+                    }
+                    "RETR" => {
+                        control
+                            .get_mut()
+                            .write_all(b"150 sending\r\n")
+                            .await
+                            .unwrap();
+                        let (mut data, _) = data_listener.take().unwrap().accept().await.unwrap();
+                        // the client hangs up once it has what it wants
+                        data.write_all(&content[offset..]).await.ok();
+                        continue;
+                    }
+                    _ => break,
+                };
+                control
+                    .get_mut()
+                    .write_all(format!("{reply}\r\n").as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+        (port, commands)
+    }
+
+    #[tokio::test]
+    async fn ftp_range_request() {
+        let (port, commands) = ftp_server(file()).await;
+        let url = Url::parse(&format!("ftp://127.0.0.1:{port}/dir/a%20file")).unwrap();
+
+        assert_eq!(ftp_range(&url, 30, 10).await.unwrap(), &file()[30..40]);
+        let commands = commands.lock().unwrap();
+        assert!(commands.contains(&"USER anonymous".to_string()));
+        assert!(commands.contains(&"REST 30".to_string()));
+        assert!(commands.contains(&"RETR /dir/a file".to_string()));
+    }
+
+    #[tokio::test]
+    async fn ftp_refuses_line_breaks_in_the_path() {
+        let (port, commands) = ftp_server(file()).await;
+        let url = Url::parse(&format!("ftp://127.0.0.1:{port}/a%0D%0ADELE%20b")).unwrap();
+
+        assert!(ftp_range(&url, 0, 10).await.is_err());
+        assert!(!commands
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|command| command.starts_with("RETR") || command.starts_with("DELE")));
+    }
+
+    #[tokio::test]
+    async fn ftp_refuses_line_breaks_in_the_user() {
+        let (port, commands) = ftp_server(file()).await;
+        let url = Url::parse(&format!("ftp://me%0D%0ADELE%20b@127.0.0.1:{port}/a")).unwrap();
+
+        assert!(ftp_range(&url, 0, 10).await.is_err());
+        assert!(commands.lock().unwrap().is_empty());
+    }
+}